@@ -0,0 +1,457 @@
+use std::sync::{Arc, Mutex};
+
+use http_body_util::{combinators::BoxBody, BodyExt};
+use hyper::{
+    body::{Bytes, Incoming},
+    header::WWW_AUTHENTICATE,
+    http::HeaderValue,
+    Method, Request, Response, StatusCode,
+};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    auth::{hash_password, is_hashed, parse_basic_auth, verify_password},
+    config::{
+        check_config, prepare_config, read_config, save_config, AdminConfig, Config, ConfigError,
+        EncryptionConfig, ExtractConfig, Rule, SharesConfig, ThumbnailsConfig, TrashConfig,
+        UnionSource, VersionsConfig,
+    },
+    lock, reload, session,
+    share::{self, Share},
+    storage::redact_path,
+    util::{decode_uri, full, get_header},
+};
+
+lazy_static! {
+    // 串行化对配置文件的修改
+    static ref WRITE_LOCK: Mutex<()> = Mutex::new(());
+}
+
+struct ApiError(StatusCode, Value);
+
+impl ApiError {
+    fn new(status: StatusCode, message: &str) -> ApiError {
+        ApiError(status, json!({ "error": message }))
+    }
+}
+
+impl From<ConfigError> for ApiError {
+    fn from(err: ConfigError) -> ApiError {
+        match err {
+            ConfigError::Validation(issues) => ApiError(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                json!({
+                    "error": "invalid config",
+                    "issues": issues.iter().map(|i| i.to_string()).collect::<Vec<_>>(),
+                }),
+            ),
+            ConfigError::Invalid(message) => {
+                ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, &message)
+            }
+            err => ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+        }
+    }
+}
+
+type ApiResult = Result<(StatusCode, Value), ApiError>;
+
+// 返回给客户端的规则，不包含密码，存储参数中的密钥已隐藏
+#[derive(Debug, Serialize)]
+struct RuleView {
+    index: usize,
+    user: String,
+    guest: bool,
+    path: String,
+    permission: String,
+    server_prefix: String,
+    browse_archives: bool,
+    sources: Vec<UnionSource>,
+    encryption: Option<EncryptionConfig>,
+    trash: Option<TrashConfig>,
+    versions: Option<VersionsConfig>,
+    upload_conflict: String,
+    extract: Option<ExtractConfig>,
+    thumbnails: Option<ThumbnailsConfig>,
+    media_metadata: bool,
+    password_hashed: bool,
+}
+
+impl RuleView {
+    fn new(index: usize, rule: &Rule) -> RuleView {
+        RuleView {
+            index,
+            user: rule.user.to_string(),
+            guest: rule.user.is_empty() || rule.password.is_empty(),
+            path: redact_path(&rule.path),
+            permission: rule.permission.to_string(),
+            server_prefix: rule.server_prefix.to_string(),
+            browse_archives: rule.browse_archives,
+            sources: rule
+                .sources
+                .iter()
+                .map(|source| UnionSource {
+                    path: redact_path(&source.path),
+                    read_only: source.read_only,
+                })
+                .collect(),
+            encryption: rule.encryption.clone(),
+            trash: rule.trash.clone(),
+            versions: rule.versions.clone(),
+            upload_conflict: rule.upload_conflict.to_string(),
+            extract: rule.extract.clone(),
+            thumbnails: rule.thumbnails.clone(),
+            media_metadata: rule.media_metadata,
+            password_hashed: is_hashed(&rule.password),
+        }
+    }
+}
+
+// 创建或修改规则时的请求体，修改时只更新提供了的字段
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleInput {
+    path: Option<String>,
+    user: Option<String>,
+    password: Option<String>,
+    permission: Option<String>,
+    server_prefix: Option<String>,
+    browse_archives: Option<bool>,
+    sources: Option<Vec<UnionSource>>,
+    encryption: Option<EncryptionConfig>,
+    trash: Option<TrashConfig>,
+    versions: Option<VersionsConfig>,
+    upload_conflict: Option<String>,
+    extract: Option<ExtractConfig>,
+    thumbnails: Option<ThumbnailsConfig>,
+    media_metadata: Option<bool>,
+}
+
+impl RuleInput {
+    fn apply(self, rule: &mut Rule) -> Result<(), ApiError> {
+        if let Some(path) = self.path {
+            rule.path = path;
+        }
+        if let Some(user) = self.user {
+            rule.user = user;
+        }
+        if let Some(password) = self.password {
+            set_password(rule, &password)?;
+        }
+        if let Some(permission) = self.permission {
+            rule.permission = permission;
+        }
+        if let Some(server_prefix) = self.server_prefix {
+            rule.server_prefix = server_prefix;
+        }
+        if let Some(browse_archives) = self.browse_archives {
+            rule.browse_archives = browse_archives;
+        }
+        if let Some(sources) = self.sources {
+            rule.sources = sources;
+        }
+        if let Some(encryption) = self.encryption {
+            rule.encryption = Some(encryption);
+        }
+        if let Some(trash) = self.trash {
+            rule.trash = Some(trash);
+        }
+        if let Some(versions) = self.versions {
+            rule.versions = Some(versions);
+        }
+        if let Some(upload_conflict) = self.upload_conflict {
+            rule.upload_conflict = upload_conflict;
+        }
+        if let Some(extract) = self.extract {
+            rule.extract = Some(extract);
+        }
+        if let Some(thumbnails) = self.thumbnails {
+            rule.thumbnails = Some(thumbnails);
+        }
+        if let Some(media_metadata) = self.media_metadata {
+            rule.media_metadata = media_metadata;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PasswordInput {
+    password: String,
+}
+
+// 管理接口与 webdav 共用端口时，按前缀判断是否为管理请求
+pub fn is_admin_request(cfg: &Config, req: &Request<Incoming>) -> bool {
+    match &cfg.admin {
+        Some(admin) => {
+            admin.port == 0
+                && !admin.prefix.is_empty()
+                && strip_prefix(req.uri().path(), &admin.prefix).is_some()
+        }
+        None => false,
+    }
+}
+
+fn strip_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let rest = path.strip_prefix(prefix.trim_end_matches('/'))?;
+    if rest.is_empty() || rest.starts_with('/') {
+        return Some(rest);
+    }
+    None
+}
+
+pub async fn handle_request(
+    req: Request<Incoming>,
+    cfg: Arc<Config>,
+) -> Response<BoxBody<Bytes, std::io::Error>> {
+    let admin = match &cfg.admin {
+        Some(admin) => admin,
+        None => return json_response(StatusCode::NOT_FOUND, json!({ "error": "not found" })),
+    };
+    if !is_authorized(admin, &req).await {
+        let mut resp = json_response(StatusCode::UNAUTHORIZED, json!({ "error": "unauthorized" }));
+        resp.headers_mut().insert(
+            WWW_AUTHENTICATE,
+            HeaderValue::from_static("Basic realm='rhfs admin'"),
+        );
+        return resp;
+    }
+    let path = req.uri().path().to_string();
+    let rest = strip_prefix(&path, &admin.prefix).unwrap_or(&path);
+    let segments: Vec<String> = rest
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(decode_uri)
+        .collect();
+    let segments: Vec<&str> = segments.iter().map(|s| s.as_str()).collect();
+    let method = req.method().clone();
+    let body = match req.collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return json_response(StatusCode::BAD_REQUEST, json!({ "error": "bad body" })),
+    };
+    log::info!("admin: {} {}", method, path);
+    let result = route(&cfg, &method, &segments, &body);
+    match result {
+        Ok((status, value)) => json_response(status, value),
+        Err(ApiError(status, value)) => json_response(status, value),
+    }
+}
+
+async fn is_authorized(admin: &AdminConfig, req: &Request<Incoming>) -> bool {
+    if admin.user.is_empty() || admin.password.is_empty() {
+        return false;
+    }
+    match parse_basic_auth(get_header(req, "Authorization", "")) {
+        Some((user, password)) => {
+            user == admin.user && verify_password(&admin.password, &password).await
+        }
+        None => false,
+    }
+}
+
+fn route(cfg: &Config, method: &Method, segments: &[&str], body: &[u8]) -> ApiResult {
+    match (method.clone(), segments) {
+        (Method::GET, ["users"]) => Ok((StatusCode::OK, json!(list_rules(cfg, true)))),
+        (Method::POST, ["users"]) => create_rule(parse_body(body)?, true),
+        (Method::GET, ["users", name]) => {
+            let (index, rule) = find_user(&cfg.rules, name)?;
+            Ok((StatusCode::OK, json!(RuleView::new(index, rule))))
+        }
+        (Method::PATCH, ["users", name]) => {
+            let input: RuleInput = parse_body(body)?;
+            update_rule(|rules| Ok(find_user(rules, name)?.0), input)
+        }
+        (Method::DELETE, ["users", name]) => delete_rule(|rules| Ok(find_user(rules, name)?.0)),
+        (Method::PUT, ["users", name, "password"]) => {
+            let input: PasswordInput = parse_body(body)?;
+            let input = RuleInput {
+                password: Some(input.password),
+                ..Default::default()
+            };
+            update_rule(|rules| Ok(find_user(rules, name)?.0), input)
+        }
+        (Method::GET, ["rules"]) => Ok((StatusCode::OK, json!(list_rules(cfg, false)))),
+        (Method::POST, ["rules"]) => create_rule(parse_body(body)?, false),
+        (Method::GET, ["rules", index]) => {
+            let index = find_index(&cfg.rules, index)?;
+            Ok((
+                StatusCode::OK,
+                json!(RuleView::new(index, &cfg.rules[index])),
+            ))
+        }
+        (Method::PATCH, ["rules", index]) => {
+            let input: RuleInput = parse_body(body)?;
+            update_rule(|rules| find_index(rules, index), input)
+        }
+        (Method::DELETE, ["rules", index]) => delete_rule(|rules| find_index(rules, index)),
+        (Method::GET, ["sessions"]) => Ok((StatusCode::OK, json!(session::list()))),
+        (Method::GET, ["locks"]) => Ok((StatusCode::OK, json!(lock::list()))),
+        (Method::GET, ["shares"]) => {
+            let shares: Vec<Value> = share::list(shares_config(cfg)?, None, None)
+                .iter()
+                .map(share_view)
+                .collect();
+            Ok((StatusCode::OK, json!(shares)))
+        }
+        (Method::DELETE, ["shares", token]) => {
+            match share::remove(shares_config(cfg)?, token, None) {
+                Some(share) => Ok((StatusCode::OK, share_view(&share))),
+                None => Err(ApiError::new(StatusCode::NOT_FOUND, "share not found")),
+            }
+        }
+        (_, ["users"])
+        | (_, ["users", _])
+        | (_, ["users", _, "password"])
+        | (_, ["rules"])
+        | (_, ["rules", _])
+        | (_, ["sessions"])
+        | (_, ["locks"])
+        | (_, ["shares"])
+        | (_, ["shares", _]) => Err(ApiError::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method not allowed",
+        )),
+        _ => Err(ApiError::new(StatusCode::NOT_FOUND, "not found")),
+    }
+}
+
+fn list_rules(cfg: &Config, users_only: bool) -> Vec<RuleView> {
+    cfg.rules
+        .iter()
+        .enumerate()
+        .filter(|(_, rule)| !users_only || !rule.user.is_empty())
+        .map(|(index, rule)| RuleView::new(index, rule))
+        .collect()
+}
+
+fn find_user<'a>(rules: &'a [Rule], name: &str) -> Result<(usize, &'a Rule), ApiError> {
+    rules
+        .iter()
+        .enumerate()
+        .find(|(_, rule)| rule.user == name)
+        .ok_or(ApiError::new(StatusCode::NOT_FOUND, "user not found"))
+}
+
+fn shares_config(cfg: &Config) -> Result<&SharesConfig, ApiError> {
+    cfg.shares
+        .as_ref()
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "shares are not enabled"))
+}
+
+// 不返回分享密码的哈希
+fn share_view(share: &Share) -> Value {
+    json!({
+        "token": share.token,
+        "owner": share.owner,
+        "path": share.path,
+        "mode": share.mode,
+        "created": share::format_time(share.created),
+        "expires": (share.expires != 0).then(|| share::format_time(share.expires)),
+        "max_downloads": share.max_downloads,
+        "downloads": share.downloads,
+        "has_password": !share.password.is_empty(),
+    })
+}
+
+fn find_index(rules: &[Rule], index: &str) -> Result<usize, ApiError> {
+    match index.parse::<usize>() {
+        Ok(index) if index < rules.len() => Ok(index),
+        _ => Err(ApiError::new(StatusCode::NOT_FOUND, "rule not found")),
+    }
+}
+
+fn parse_body<T: for<'de> Deserialize<'de>>(body: &[u8]) -> Result<T, ApiError> {
+    serde_json::from_slice(body)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, &format!("invalid body: {}", e)))
+}
+
+// 明文密码保存前转为 argon2 哈希
+fn set_password(rule: &mut Rule, password: &str) -> Result<(), ApiError> {
+    rule.password = if password.is_empty() || is_hashed(password) {
+        password.to_string()
+    } else {
+        hash_password(password)
+            .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()))?
+    };
+    rule.password_file = "".to_string();
+    Ok(())
+}
+
+fn create_rule(input: RuleInput, is_user: bool) -> ApiResult {
+    if is_user && (input.user.is_none() || input.password.is_none()) {
+        return Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "user and password are required",
+        ));
+    }
+    let mut rule = Rule {
+        permission: "R".to_string(),
+        ..Default::default()
+    };
+    input.apply(&mut rule)?;
+    if rule.server_prefix.is_empty() && !rule.user.is_empty() {
+        rule.server_prefix = format!("/{}", rule.user);
+    }
+    update_config(move |config| {
+        config.rules.push(rule);
+        let index = config.rules.len() - 1;
+        Ok((
+            StatusCode::CREATED,
+            json!(RuleView::new(index, &config.rules[index])),
+        ))
+    })
+}
+
+fn update_rule<F>(find: F, input: RuleInput) -> ApiResult
+where
+    F: FnOnce(&[Rule]) -> Result<usize, ApiError>,
+{
+    update_config(move |config| {
+        let index = find(&config.rules)?;
+        input.apply(&mut config.rules[index])?;
+        Ok((
+            StatusCode::OK,
+            json!(RuleView::new(index, &config.rules[index])),
+        ))
+    })
+}
+
+fn delete_rule<F>(find: F) -> ApiResult
+where
+    F: FnOnce(&[Rule]) -> Result<usize, ApiError>,
+{
+    update_config(move |config| {
+        let index = find(&config.rules)?;
+        let rule = config.rules.remove(index);
+        Ok((StatusCode::OK, json!(RuleView::new(index, &rule))))
+    })
+}
+
+// 修改配置文件中的规则，校验通过后写回文件并重新加载
+fn update_config<F>(change: F) -> ApiResult
+where
+    F: FnOnce(&mut Config) -> ApiResult,
+{
+    let _guard = WRITE_LOCK.lock().unwrap();
+    let (path, overrides) = reload::config_source().ok_or(ApiError::new(
+        StatusCode::CONFLICT,
+        "the server was not started with a config file",
+    ))?;
+    let mut config = read_config(&path)?;
+    let result = change(&mut config)?;
+    let mut candidate = prepare_config(config.clone())?;
+    overrides.apply(&mut candidate);
+    check_config(&candidate)?;
+    save_config(&path, &config)?;
+    reload::reload(&path, &overrides);
+    Ok(result)
+}
+
+fn json_response(status: StatusCode, value: Value) -> Response<BoxBody<Bytes, std::io::Error>> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json; charset=utf-8")
+        .body(full(value.to_string()))
+        .unwrap()
+}
@@ -0,0 +1,73 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use base64::{engine::general_purpose, Engine};
+use lazy_static::lazy_static;
+use tokio::sync::Semaphore;
+
+use crate::cache;
+
+// 同时进行的 argon2 校验数量，大量错误的密码只会排队，不会占满运行时的线程
+const VERIFY_WORKERS: usize = 4;
+
+lazy_static! {
+    static ref VERIFIERS: Semaphore = Semaphore::new(VERIFY_WORKERS);
+}
+
+// 生成 argon2 哈希，可直接作为 rule 的 password 使用
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default().hash_password(password.as_bytes(), &salt)?;
+    Ok(hash.to_string())
+}
+
+pub fn is_hashed(password: &str) -> bool {
+    password.starts_with("$argon2")
+}
+
+// 校验密码，配置中的密码既可以是明文也可以是 argon2 哈希
+pub async fn verify_password(stored: &str, password: &str) -> bool {
+    if !is_hashed(stored) {
+        return stored == password;
+    }
+    // argon2 校验较慢，而客户端每个请求都会带上认证头，所以缓存校验通过的结果。
+    // 错误的密码不缓存，否则缓存会随尝试的密码无限增长
+    let cache_key = format!(
+        "auth:{:x}",
+        md5::compute(format!("{}:{}", stored, password))
+    );
+    if cache::exist(&cache_key) {
+        return true;
+    }
+    let _permit = match VERIFIERS.acquire().await {
+        Ok(permit) => permit,
+        Err(_) => return false,
+    };
+    let (stored, password) = (stored.to_string(), password.to_string());
+    let verified = tokio::task::spawn_blocking(move || verify_hash(&stored, &password))
+        .await
+        .unwrap_or(false);
+    if verified {
+        cache::set(&cache_key, "1");
+    }
+    verified
+}
+
+fn verify_hash(stored: &str, password: &str) -> bool {
+    match PasswordHash::new(stored) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+// 解析 Basic 认证头，返回 (用户名, 密码)
+pub fn parse_basic_auth(header: &str) -> Option<(String, String)> {
+    let encoded = header.strip_prefix("Basic ")?;
+    let decoded = general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
@@ -0,0 +1,102 @@
+use clap::{Args, Parser, Subcommand};
+
+/// A simple webdav server written by Rust.
+#[derive(Debug, Parser)]
+#[command(name = "rhfs", version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Start the webdav server
+    Serve(ServeArgs),
+    /// Load a config file and report problems without starting the server
+    CheckConfig {
+        /// Path to the config file
+        #[arg(short, long)]
+        config: String,
+    },
+    /// Print an argon2 hash usable as a rule's `password`
+    HashPassword {
+        /// Password to hash, read from stdin when omitted
+        password: Option<String>,
+    },
+    /// Manage basic auth users in a config file
+    #[command(subcommand)]
+    User(UserCommand),
+}
+
+#[derive(Debug, Args)]
+pub struct ServeArgs {
+    /// Path to the config file
+    #[arg(short, long, conflicts_with = "dir")]
+    pub config: Option<String>,
+    /// Share this directory to guests without a config file
+    pub dir: Option<String>,
+    /// Allow guests to write to the ad-hoc share
+    #[arg(long, requires = "dir")]
+    pub rw: bool,
+    /// Url prefix of the ad-hoc share
+    #[arg(long, default_value = "/", requires = "dir")]
+    pub prefix: String,
+    /// Override the listening port
+    #[arg(short, long)]
+    pub port: Option<u16>,
+    /// Override the log level: off/error/warn/info/debug/trace
+    #[arg(short, long)]
+    pub log: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum UserCommand {
+    /// Add a basic auth user with its own share
+    Add(UserAddArgs),
+    /// Remove a basic auth user
+    Remove {
+        /// Path to the config file
+        #[arg(short, long)]
+        config: String,
+        /// User name
+        name: String,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct UserAddArgs {
+    /// Path to the config file
+    #[arg(short, long)]
+    pub config: String,
+    /// User name
+    pub name: String,
+    /// Password, read from stdin when omitted. It is stored as an argon2 hash
+    #[arg(long)]
+    pub password: Option<String>,
+    /// Shared directory
+    #[arg(long)]
+    pub path: String,
+    /// Permissions, letters "RWD" ==> READ/WRITE/DELETE, "U" for an upload-only drop box,
+    /// or names such as "list,read,create"
+    #[arg(long, default_value = "R")]
+    pub permission: String,
+    /// Url prefix, defaults to "/<name>"
+    #[arg(long)]
+    pub prefix: Option<String>,
+}
+
+// 兼容旧的启动方式: ./rhfs "config=/path/to/config.json"
+pub fn normalize_args(args: impl Iterator<Item = String>) -> Vec<String> {
+    let args: Vec<String> = args.collect();
+    if args.len() == 2 {
+        if let Some(path) = args[1].strip_prefix("config=") {
+            return vec![
+                args[0].to_string(),
+                "serve".to_string(),
+                "--config".to_string(),
+                path.to_string(),
+            ];
+        }
+    }
+    args
+}
@@ -1,21 +1,56 @@
-use std::{collections::HashMap, env, fs};
-
-use base64::{engine::general_purpose, Engine};
-use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    env, fmt, fs, io,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
 
 use lazy_static::lazy_static;
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
 
-use crate::cache::set;
+use crate::{
+    share::Share,
+    validate::{has_errors, validate_config, ConfigIssue, Severity},
+};
 
 lazy_static! {
-    static ref CONFIG: Config = load_config();
+    static ref CONFIG: RwLock<Arc<Config>> = RwLock::new(Arc::new(Config::default()));
+}
+
+const ENV_PREFIX: &str = "RHFS_";
+// 可以通过环境变量覆盖的顶层字段，不配置时不出现在序列化结果中的字段也要列出
+const ENV_FIELDS: [&str; 6] = ["port", "log", "mode", "admin", "shares", "rules"];
+
+// 返回当前配置的快照，一个请求从头到尾使用同一个快照，重新加载配置不影响进行中的请求
+pub fn get_config() -> Arc<Config> {
+    CONFIG.read().unwrap().clone()
+}
+
+pub fn set_config(config: Config) {
+    *CONFIG.write().unwrap() = Arc::new(config);
 }
 
-pub fn get_config() -> &'static Config {
-    &CONFIG
+// 命令行参数对配置的覆盖，重新加载配置时同样生效
+#[derive(Debug, Clone, Default)]
+pub struct ConfigOverrides {
+    pub port: Option<u16>,
+    pub log: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl ConfigOverrides {
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(log) = &self.log {
+            config.log = log.to_string();
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct Config {
     #[serde(default)]
     pub port: u16,
@@ -23,13 +58,20 @@ pub struct Config {
     pub log: String,
     #[serde(default)]
     pub mode: String,
+    // 管理接口，不配置时关闭
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub admin: Option<AdminConfig>,
+    // 分享链接，不配置时关闭
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shares: Option<SharesConfig>,
     #[serde(default)]
     pub rules: Vec<Rule>,
-    #[serde(default)]
+    // 由 init_user 根据 rules 生成，key 为用户名，游客为 "guest"
+    #[serde(skip)]
     pub user_rule: HashMap<String, Rule>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct Rule {
     #[serde(default)]
     pub path: String,
@@ -37,42 +79,515 @@ pub struct Rule {
     pub user: String,
     #[serde(default)]
     pub password: String,
+    // 从文件读取密码，优先于 password
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub password_file: String,
     #[serde(default)]
     pub permission: String,
     #[serde(default)]
     pub server_prefix: String,
+    // 把 zip/tar/tar.gz 文件当作只读目录浏览
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub browse_archives: bool,
+    // path 为 "union://name" 时合并的目录，同名时排在前面的优先
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<UnionSource>,
+    // 文件加密后再写入存储
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<EncryptionConfig>,
+    // 删除的文件先移到回收站
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trash: Option<TrashConfig>,
+    // 覆盖文件前保存历史版本
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub versions: Option<VersionsConfig>,
+    // POST 表单上传遇到同名文件时：reject、overwrite 或 rename，为空时同 rename
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub upload_conflict: String,
+    // 在服务器上解压时的限制，不配置时使用默认值
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extract: Option<ExtractConfig>,
+    // GET ?thumbnail=WxH 返回图片的缩略图
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnails: Option<ThumbnailsConfig>,
+    // PROPFIND 返回图片的拍摄时间和宽高、音频标签和时长等属性
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub media_metadata: bool,
+    // 通过分享链接访问时由 share::resolve 生成，只能访问分享的路径
+    #[serde(skip)]
+    pub share: Option<Share>,
+}
+
+// 防止解压炸弹：解压后的总大小、成员数量和压缩比超过限制时中止
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ExtractConfig {
+    // 例如 "10G"，为空时默认 10G
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub max_size: String,
+    // 为 0 时默认 100000
+    #[serde(default)]
+    pub max_entries: u64,
+    // 解压后与压缩包大小之比，为 0 时默认 200
+    #[serde(default)]
+    pub max_ratio: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ThumbnailsConfig {
+    // 缓存缩略图的本地目录，为空时使用系统临时目录下的 rhfs-thumbnails
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub cache_dir: String,
+    // 缩略图宽高的上限，为 0 时默认 1024
+    #[serde(default)]
+    pub max_dimension: u32,
+    // 超过这个大小的图片不生成缩略图，例如 "50M"，为空时默认 50M
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub max_source_size: String,
+    // 原图宽高的上限，解码前按图片头检查，为 0 时默认 10000
+    #[serde(default)]
+    pub max_source_dimension: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct VersionsConfig {
+    // 每个文件最多保留的版本数，为 0 时不限
+    #[serde(default)]
+    pub max_count: u32,
+    // 超过这个时间的版本被清除，例如 "90d"，为空时不限
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub max_age: String,
+    // 普通的 PUT 也保存版本，为 false 时只有 DeltaV 的 CHECKIN 保存版本，
+    // 受版本控制的文件需要先 CHECKOUT 才能修改
+    #[serde(default = "default_auto_version")]
+    pub auto_version: bool,
+}
+
+fn default_auto_version() -> bool {
+    true
+}
+
+impl Default for VersionsConfig {
+    fn default() -> Self {
+        VersionsConfig {
+            max_count: 0,
+            max_age: String::new(),
+            auto_version: default_auto_version(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TrashConfig {
+    // 超过这个时间的文件被清除，例如 "30d"，为空时不限
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub max_age: String,
+    // 回收站总大小超过时先清除最早删除的，例如 "10G"，为空时不限
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub max_size: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct EncryptionConfig {
+    // 32 字节密钥，原始字节或 64 个十六进制字符
+    pub key_file: String,
+    // 同时加密文件名和目录名
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub encrypt_names: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct UnionSource {
+    pub path: String,
+    // 只读的目录不会写入新文件，其中的文件也不能修改或删除
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub read_only: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct AdminConfig {
+    // 管理接口的访问路径前缀，例如 "/_admin"
+    #[serde(default)]
+    pub prefix: String,
+    // 不为 0 时管理接口单独监听这个端口，否则与 webdav 共用端口
+    #[serde(default)]
+    pub port: u16,
+    #[serde(default)]
+    pub user: String,
+    #[serde(default)]
+    pub password: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub password_file: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SharesConfig {
+    // 分享链接的访问路径前缀，为空时为 "/s"
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub prefix: String,
+    // 保存分享的 JSON 文件，为空时只保存在内存中，重启后失效
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub file: String,
 }
 
-fn load_config() -> Config {
-    let args: Vec<String> = env::args().skip(1).collect();
-    let mut config_json_path = "".to_string();
-    for arg in args {
-        let (key, value) = &arg.split_once('=').unwrap();
-        match key {
-            &"config" => config_json_path = value.to_string(),
-            _ => {}
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String, io::Error),
+    Parse(String, String),
+    Invalid(String),
+    // 校验发现的错误，见 validate::validate_config
+    Validation(Vec<ConfigIssue>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "failed to read {}: {}", path, err),
+            ConfigError::Parse(path, err) => {
+                write!(f, "failed to parse config file {}: {}", path, err)
+            }
+            ConfigError::Invalid(msg) => write!(f, "{}", msg),
+            ConfigError::Validation(issues) => {
+                write!(f, "invalid config")?;
+                for issue in issues {
+                    write!(f, "\n  {}", issue)?;
+                }
+                Ok(())
+            }
         }
     }
-    let json_str = &fs::read_to_string(config_json_path).unwrap();
-    let mut config: Config = serde_json::from_str(json_str).unwrap();
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigFormat {
+    // 允许 // 和 /* */ 注释
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    // 根据扩展名选择格式，其余扩展名一律按 JSON 处理
+    pub fn from_path(path: &str) -> ConfigFormat {
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
+        match extension.as_deref() {
+            Some("toml") => ConfigFormat::Toml,
+            Some("yaml") | Some("yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Json,
+        }
+    }
+}
+
+// 读取配置文件，不做任何处理
+pub fn read_config(path: &str) -> Result<Config, ConfigError> {
+    let content = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_string(), e))?;
+    let result = match ConfigFormat::from_path(path) {
+        ConfigFormat::Json => {
+            serde_json::from_str(&strip_json_comments(&content)).map_err(|e| e.to_string())
+        }
+        ConfigFormat::Toml => toml::from_str(&content).map_err(|e| e.to_string()),
+        ConfigFormat::Yaml => serde_yaml::from_str(&content).map_err(|e| e.to_string()),
+    };
+    result.map_err(|e| ConfigError::Parse(path.to_string(), e))
+}
+
+// 按原格式写回，注释会丢失
+pub fn save_config(path: &str, config: &Config) -> Result<(), ConfigError> {
+    let result = match ConfigFormat::from_path(path) {
+        ConfigFormat::Json => serde_json::to_string_pretty(config)
+            .map(|s| s + "\n")
+            .map_err(|e| e.to_string()),
+        ConfigFormat::Toml => toml::to_string_pretty(config).map_err(|e| e.to_string()),
+        ConfigFormat::Yaml => serde_yaml::to_string(config).map_err(|e| e.to_string()),
+    };
+    let content = result.map_err(|e| ConfigError::Parse(path.to_string(), e))?;
+    fs::write(path, content).map_err(|e| ConfigError::Io(path.to_string(), e))
+}
+
+pub fn load_config(path: &str) -> Result<Config, ConfigError> {
+    prepare_config(read_config(path)?)
+}
+
+// 应用环境变量、读取密码文件并生成 user_rule
+pub fn prepare_config(mut config: Config) -> Result<Config, ConfigError> {
+    apply_env_overrides(&mut config)?;
+    resolve_secrets(&mut config)?;
     init_user(&mut config);
-    config
+    Ok(config)
+}
+
+// 去掉 JSON 中的注释，保留换行以便报错行号不变
+pub fn strip_json_comments(content: &str) -> String {
+    let mut result = String::with_capacity(content.len());
+    let mut chars = content.chars().peekable();
+    let mut in_string = false;
+    while let Some(c) = chars.next() {
+        if in_string {
+            result.push(c);
+            if c == '\\' {
+                if let Some(escaped) = chars.next() {
+                    result.push(escaped);
+                }
+            } else if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+        match (c, chars.peek()) {
+            ('"', _) => {
+                in_string = true;
+                result.push(c);
+            }
+            ('/', Some('/')) => {
+                while let Some(&next) = chars.peek() {
+                    if next == '\n' {
+                        break;
+                    }
+                    chars.next();
+                }
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                let mut last = ' ';
+                for next in chars.by_ref() {
+                    if last == '*' && next == '/' {
+                        break;
+                    }
+                    if next == '\n' {
+                        result.push(next);
+                    }
+                    last = next;
+                }
+            }
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+// 顶层字段都可以通过 RHFS_<字段名> 环境变量覆盖，例如 RHFS_PORT=8080、RHFS_RULES='[...]'，
+// 也可以用 RHFS_<字段名>_FILE 指定从文件读取
+pub fn apply_env_overrides(config: &mut Config) -> Result<(), ConfigError> {
+    let mut value = serde_json::to_value(&*config)
+        .map_err(|e| ConfigError::Invalid(format!("failed to apply environment: {}", e)))?;
+    let mut overridden = false;
+    if let Some(fields) = value.as_object_mut() {
+        for key in ENV_FIELDS {
+            let name = format!("{}{}", ENV_PREFIX, key.to_uppercase());
+            let raw = match read_env(&name)? {
+                Some(raw) => raw,
+                None => continue,
+            };
+            // 配置文件中没有的字段按 null 处理
+            let field = fields
+                .entry(key.to_string())
+                .or_insert(serde_json::Value::Null);
+            // 字符串字段直接使用原值，其余字段按 JSON 解析
+            *field = if field.is_string() {
+                serde_json::Value::String(raw)
+            } else {
+                serde_json::from_str(&raw)
+                    .map_err(|e| ConfigError::Invalid(format!("invalid {}: {}", name, e)))?
+            };
+            overridden = true;
+        }
+    }
+    if overridden {
+        *config = serde_json::from_value(value)
+            .map_err(|e| ConfigError::Invalid(format!("invalid environment override: {}", e)))?;
+    }
+    Ok(())
+}
+
+// 不对应任何字段的 RHFS_ 环境变量，多半是拼写错误
+pub fn unknown_env_overrides() -> Vec<String> {
+    let mut names: Vec<String> = env::vars_os()
+        .filter_map(|(name, _)| name.into_string().ok())
+        .filter(|name| {
+            let field = match name.strip_prefix(ENV_PREFIX) {
+                Some(field) => field.strip_suffix("_FILE").unwrap_or(field),
+                None => return false,
+            };
+            !ENV_FIELDS.contains(&field.to_lowercase().as_str())
+        })
+        .collect();
+    names.sort();
+    names
+}
+
+fn read_env(name: &str) -> Result<Option<String>, ConfigError> {
+    if let Ok(value) = env::var(name) {
+        return Ok(Some(value));
+    }
+    let file_name = format!("{}_FILE", name);
+    if let Ok(path) = env::var(&file_name) {
+        return read_secret(&path).map(Some);
+    }
+    Ok(None)
+}
+
+// rule 中的 password_file 指向的文件内容作为密码，便于配合 docker/k8s secret 使用
+pub fn resolve_secrets(config: &mut Config) -> Result<(), ConfigError> {
+    if let Some(admin) = config.admin.as_mut() {
+        if !admin.password_file.is_empty() {
+            admin.password = read_secret(&admin.password_file)?;
+        }
+    }
+    for rule in config.rules.iter_mut() {
+        if !rule.password_file.is_empty() {
+            rule.password = read_secret(&rule.password_file)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_secret(path: &str) -> Result<String, ConfigError> {
+    let content = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_string(), e))?;
+    Ok(content.trim_end_matches(['\r', '\n']).to_string())
+}
+
+// 校验配置，有错误时返回全部错误，否则返回警告
+pub fn check_config(config: &Config) -> Result<Vec<ConfigIssue>, ConfigError> {
+    let issues = validate_config(config);
+    if has_errors(&issues) {
+        let errors = issues
+            .into_iter()
+            .filter(|issue| issue.severity == Severity::Error)
+            .collect();
+        return Err(ConfigError::Validation(errors));
+    }
+    Ok(issues)
+}
+
+// 日志级别为空时默认 info
+pub fn parse_log_level(log: &str) -> Result<LevelFilter, ConfigError> {
+    if log.is_empty() {
+        return Ok(LevelFilter::Info);
+    }
+    LevelFilter::from_str(log).map_err(|_| {
+        ConfigError::Invalid(format!(
+            "invalid log level \"{}\", expected one of off/error/warn/info/debug/trace",
+            log
+        ))
+    })
+}
+
+pub fn add_user(path: &str, rule: Rule) -> Result<(), ConfigError> {
+    let mut config = read_config(path)?;
+    if config.rules.iter().any(|r| r.user == rule.user) {
+        return Err(ConfigError::Invalid(format!(
+            "user \"{}\" already exists",
+            rule.user
+        )));
+    }
+    config.rules.push(rule);
+    save_config(path, &config)
+}
+
+pub fn remove_user(path: &str, user: &str) -> Result<(), ConfigError> {
+    let mut config = read_config(path)?;
+    let count = config.rules.len();
+    config.rules.retain(|r| r.user != user);
+    if config.rules.len() == count {
+        return Err(ConfigError::Invalid(format!("user \"{}\" not found", user)));
+    }
+    save_config(path, &config)
 }
 
 pub fn init_user(config: &mut Config) {
     let rules = &config.rules;
     for rule in rules {
-        let server_prefix = &rule.server_prefix;
         let user = &rule.user;
         let password = &rule.password;
         // guest is no user and no password
-        if user.len() > 0 && password.len() > 0 {
-            let b64 = general_purpose::STANDARD.encode(user.to_string() + ":" + &password);
-            let auth_b64 = &("Basic ".to_string() + &b64.to_string());
-            config.user_rule.insert(auth_b64.to_string(), rule.clone());
+        if !user.is_empty() && !password.is_empty() {
+            config.user_rule.insert(user.to_string(), rule.clone());
         } else {
             config.user_rule.insert("guest".to_string(), rule.clone());
-            set("guest_server_prefix", &server_prefix);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_line_and_block_comments() {
+        let content = "{\n  // 端口\n  \"port\": 80, /* 注释 */ \"log\": \"info\"\n}";
+        assert_eq!(
+            strip_json_comments(content),
+            "{\n  \n  \"port\": 80,  \"log\": \"info\"\n}"
+        );
+    }
+
+    #[test]
+    fn keep_comment_markers_inside_strings() {
+        let content = r#"{"path": "http://x/*y*/", "user": "a//b"}"#;
+        assert_eq!(strip_json_comments(content), content);
+    }
+
+    #[test]
+    fn keep_escaped_quotes_inside_strings() {
+        let content = r#"{"user": "a\"// b", "password": "c\\"} // 注释"#;
+        assert_eq!(
+            strip_json_comments(content),
+            r#"{"user": "a\"// b", "password": "c\\"} "#
+        );
+    }
+
+    #[test]
+    fn block_comment_keeps_line_numbers() {
+        let content = "{\n/* 第一行\n第二行\n*/ \"port\": 80\n}";
+        assert_eq!(strip_json_comments(content), "{\n\n\n \"port\": 80\n}");
+    }
+
+    #[test]
+    fn unterminated_block_comment_is_dropped() {
+        let content = "{\"port\": 80}\n/* 没有结束\n\"log\": \"debug\"";
+        assert_eq!(strip_json_comments(content), "{\"port\": 80}\n\n");
+    }
+
+    // 环境变量是进程共享的，所有覆盖的情况放在同一个测试里
+    #[test]
+    fn env_overrides_merge_into_config() {
+        let mode_file = env::temp_dir().join(format!("rhfs-mode-{}", std::process::id()));
+        fs::write(&mode_file, "prod\n").unwrap();
+        env::set_var("RHFS_PORT", "8080");
+        env::set_var("RHFS_LOG", "debug");
+        env::set_var("RHFS_MODE_FILE", &mode_file);
+        env::set_var(
+            "RHFS_RULES",
+            r#"[{"path": "/srv", "user": "bob", "server_prefix": "/bob"}]"#,
+        );
+        let mut config: Config = serde_json::from_str(
+            r#"{"port": 80, "log": "info", "mode": "dev",
+                "admin": {"user": "root", "password": "pw"},
+                "rules": [{"path": "/data", "user": "alice"}]}"#,
+        )
+        .unwrap();
+        let result = apply_env_overrides(&mut config);
+        env::set_var("RHFS_PORT", "not a number");
+        let invalid = apply_env_overrides(&mut Config::default());
+        for name in ["RHFS_PORT", "RHFS_LOG", "RHFS_MODE_FILE", "RHFS_RULES"] {
+            env::remove_var(name);
+        }
+        fs::remove_file(&mode_file).unwrap();
+
+        assert!(result.is_ok());
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.log, "debug");
+        assert_eq!(config.mode, "prod");
+        assert_eq!(config.rules.len(), 1);
+        assert_eq!(config.rules[0].path, "/srv");
+        assert_eq!(config.rules[0].user, "bob");
+        assert_eq!(config.rules[0].server_prefix, "/bob");
+        // 没有环境变量的字段保持配置文件中的值
+        assert_eq!(config.admin.as_ref().unwrap().user, "root");
+        assert!(matches!(invalid, Err(ConfigError::Invalid(_))));
+    }
+}
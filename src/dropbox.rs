@@ -0,0 +1,400 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use hyper::{http::HeaderValue, Method, Request, StatusCode};
+use lazy_static::lazy_static;
+
+use crate::{
+    config::Rule,
+    exmethod::ExtendMethod,
+    permission::Permissions,
+    session::SESSION_IDLE_TIMEOUT,
+    share,
+    storage::Storage,
+    util::{get_destination_path, get_header, get_query_param},
+};
+
+// 区分上传者的 cookie，值为随机 token
+const COOKIE_NAME: &str = "rhfs_dropbox";
+// 每个上传者记录的新建路径数量上限，达到后不能再新建
+const MAX_CREATED_PATHS: usize = 10_000;
+
+lazy_static! {
+    // key 为用户和 cookie 中的 token，同一个账号或分享链接的上传者之间互相看不到。
+    // 第一次新建内容时才记录，不带 cookie 的客户端每次请求都是新的上传者
+    static ref UPLOADERS: Mutex<HashMap<String, Uploader>> = Mutex::new(HashMap::new());
+}
+
+struct Uploader {
+    // 新建的文件和目录，相对共享根目录
+    created: HashSet<String>,
+    last_seen: SystemTime,
+}
+
+// 投递箱中的一个上传者，没有带 cookie 时生成新的 token，响应中要设置 cookie
+pub struct DropBoxSession {
+    key: String,
+    token: String,
+    issued: bool,
+}
+
+fn cookie_token<B>(req: &Request<B>) -> Option<&str> {
+    get_header(req, "cookie", "")
+        .split(';')
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == COOKIE_NAME)
+        .map(|(_, token)| token)
+        .filter(|token| token.len() == 32 && token.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+pub fn begin<B>(req: &Request<B>, user: &str) -> DropBoxSession {
+    let (token, issued) = match cookie_token(req) {
+        Some(token) => (token.to_string(), false),
+        None => (share::new_token(), true),
+    };
+    let now = SystemTime::now();
+    let mut uploaders = UPLOADERS.lock().unwrap();
+    uploaders.retain(|_, uploader| {
+        now.duration_since(uploader.last_seen)
+            .map(|idle| idle < SESSION_IDLE_TIMEOUT)
+            .unwrap_or(true)
+    });
+    let key = format!("{}\n{}", user, token);
+    if let Some(uploader) = uploaders.get_mut(&key) {
+        uploader.last_seen = now;
+    }
+    DropBoxSession { key, token, issued }
+}
+
+impl DropBoxSession {
+    // 本次会话新建的路径
+    pub fn created(&self) -> HashSet<String> {
+        UPLOADERS
+            .lock()
+            .unwrap()
+            .get(&self.key)
+            .map(|uploader| uploader.created.clone())
+            .unwrap_or_default()
+    }
+
+    // 新发放的 token 要在响应中设置 cookie
+    pub fn cookie(&self, rule: &Rule) -> Option<HeaderValue> {
+        if !self.issued {
+            return None;
+        }
+        let path = match rule.server_prefix.trim_end_matches('/') {
+            "" => "/",
+            prefix => prefix,
+        };
+        format!(
+            "{}={}; Path={}; HttpOnly; SameSite=Lax",
+            COOKIE_NAME, self.token, path
+        )
+        .parse()
+        .ok()
+    }
+}
+
+// 权限中有 U（drop-box）时为投递箱：只能新建文件和目录，看不到也不能修改别人的内容，
+// 本次会话中新建的内容可以查看、覆盖、移动和删除
+pub fn is_drop_box(rule: &Rule) -> bool {
+    Permissions::of(rule).contains(Permissions::DROP_BOX)
+}
+
+// POST 表单上传成功的路径，放在响应的 extensions 中，由 server 记录到会话
+#[derive(Debug, Clone)]
+pub struct Uploaded(pub Vec<String>);
+
+// 请求成功后要记录到会话中的变化
+#[derive(Debug)]
+pub enum Change {
+    Created(String),
+    Removed(String),
+    Moved(String, String),
+}
+
+// path 或它的上级目录是本次会话新建的，根目录不属于任何上传者
+fn is_own(created: &HashSet<String>, path: &str) -> bool {
+    created
+        .iter()
+        .filter(|own| !own.trim_end_matches('/').is_empty())
+        .any(|own| own == path || path.starts_with(&format!("{}/", own.trim_end_matches('/'))))
+}
+
+// 列目录时显示根目录、自己新建的内容和它们的上级目录
+pub fn is_visible(created: &HashSet<String>, path: &str) -> bool {
+    path == "/"
+        || is_own(created, path)
+        || created
+            .iter()
+            .any(|own| own.starts_with(&format!("{}/", path.trim_end_matches('/'))))
+}
+
+async fn exists(storage: &dyn Storage, path: &str) -> Result<Option<bool>, StatusCode> {
+    match storage.stat(path).await {
+        Ok(metadata) => Ok(Some(metadata.is_dir)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => {
+            log::error!("failed to stat {}: {}", path, err);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+// 检查投递箱中的请求，看不到的内容返回 404，不能修改的返回 403，
+// 允许时返回成功后要记录的变化
+pub async fn check<B>(
+    req: &Request<B>,
+    rule: &Rule,
+    session: &DropBoxSession,
+    storage: &dyn Storage,
+    path: &str,
+) -> Result<Option<Change>, StatusCode> {
+    let created = session.created();
+    let result = check_method(req, rule, &created, storage, path).await;
+    // 达到上限后拒绝新建，而不是忘掉已经新建的内容
+    let creates = matches!(result, Ok(Some(Change::Created(_))))
+        || (*req.method() == Method::POST && result.is_ok());
+    if creates && created.len() >= MAX_CREATED_PATHS {
+        log::warn!("drop box uploader created too many paths, refused {}", path);
+        return Err(StatusCode::FORBIDDEN);
+    }
+    result
+}
+
+async fn check_method<B>(
+    req: &Request<B>,
+    rule: &Rule,
+    created: &HashSet<String>,
+    storage: &dyn Storage,
+    path: &str,
+) -> Result<Option<Change>, StatusCode> {
+    let method = req.method();
+    let own = is_own(created, path);
+    if *method == Method::GET || *method == Method::HEAD {
+        return match exists(storage, path).await? {
+            Some(false) if own => Ok(None),
+            _ => Err(StatusCode::NOT_FOUND),
+        };
+    }
+    if *method == Method::from(ExtendMethod::PROPFIND) {
+        return if is_visible(created, path) {
+            Ok(None)
+        } else {
+            Err(StatusCode::NOT_FOUND)
+        };
+    }
+    if *method == Method::PUT {
+        return match exists(storage, path).await? {
+            None => Ok(Some(Change::Created(path.to_string()))),
+            Some(false) if own => Ok(None),
+            _ => Err(StatusCode::FORBIDDEN),
+        };
+    }
+    // 已有的目录（包括根目录）不能再新建，否则会被记为自己新建的
+    if *method == Method::from(ExtendMethod::MKCOL) {
+        return match exists(storage, path).await? {
+            None => Ok(Some(Change::Created(path.to_string()))),
+            Some(_) => Err(StatusCode::METHOD_NOT_ALLOWED),
+        };
+    }
+    // 只能表单上传，不能解压、打包或分享
+    if *method == Method::POST {
+        return match get_query_param(req, "action") {
+            None => Ok(None),
+            Some(_) => Err(StatusCode::FORBIDDEN),
+        };
+    }
+    // 客户端上传前可能先锁定新文件
+    if *method == Method::from(ExtendMethod::LOCK) {
+        return match exists(storage, path).await? {
+            None => Ok(Some(Change::Created(path.to_string()))),
+            Some(_) if own => Ok(None),
+            _ => Err(StatusCode::FORBIDDEN),
+        };
+    }
+    if *method == Method::from(ExtendMethod::UNLOCK) {
+        return Ok(None);
+    }
+    if !own {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    if *method == Method::DELETE {
+        return Ok(Some(Change::Removed(path.to_string())));
+    }
+    if *method == Method::from(ExtendMethod::MOVE) || *method == Method::from(ExtendMethod::COPY) {
+        let destination = get_destination_path(req, rule).ok_or(StatusCode::BAD_REQUEST)?;
+        if exists(storage, &destination).await?.is_some() && !is_own(created, &destination) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        return Ok(Some(if *method == Method::from(ExtendMethod::MOVE) {
+            Change::Moved(path.to_string(), destination)
+        } else {
+            Change::Created(destination)
+        }));
+    }
+    if *method == Method::from(ExtendMethod::PROPPATCH) {
+        return Ok(None);
+    }
+    Err(StatusCode::FORBIDDEN)
+}
+
+// 请求成功后把新建、删除和移动的路径记录到会话
+pub fn record(session: &DropBoxSession, change: Option<Change>, uploaded: Option<&Uploaded>) {
+    let has_uploads = uploaded.is_some_and(|Uploaded(paths)| !paths.is_empty());
+    if change.is_none() && !has_uploads {
+        return;
+    }
+    let mut uploaders = UPLOADERS.lock().unwrap();
+    let uploader = uploaders
+        .entry(session.key.to_string())
+        .or_insert_with(|| Uploader {
+            created: HashSet::new(),
+            last_seen: SystemTime::now(),
+        });
+    let created = &mut uploader.created;
+    match change {
+        Some(Change::Created(path)) => {
+            created.insert(path);
+        }
+        Some(Change::Removed(path)) => {
+            let prefix = format!("{}/", path.trim_end_matches('/'));
+            created.retain(|own| *own != path && !own.starts_with(&prefix));
+        }
+        Some(Change::Moved(from, to)) => {
+            let prefix = format!("{}/", from.trim_end_matches('/'));
+            created.retain(|own| *own != from && !own.starts_with(&prefix));
+            created.insert(to);
+        }
+        None => {}
+    }
+    if let Some(Uploaded(paths)) = uploaded {
+        created.extend(paths.iter().cloned());
+    }
+    // 根目录不记录，否则所有内容都成了自己新建的
+    created.retain(|own| !own.trim_end_matches('/').is_empty());
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::stream;
+    use hyper::body::Bytes;
+
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    fn request(method: &str, path: &str) -> Request<()> {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .body(())
+            .unwrap()
+    }
+
+    async fn check_path(
+        storage: &dyn Storage,
+        created: &[&str],
+        method: &str,
+        path: &str,
+    ) -> Result<Option<Change>, StatusCode> {
+        let created = created.iter().map(|path| path.to_string()).collect();
+        let rule = Rule::default();
+        check_method(&request(method, path), &rule, &created, storage, path).await
+    }
+
+    async fn storage() -> MemoryStorage {
+        let storage = MemoryStorage::new(u64::MAX);
+        storage.mkdir("/theirs").await.unwrap();
+        storage.mkdir("/mine").await.unwrap();
+        for path in ["/theirs.txt", "/mine/a.txt"] {
+            let body = Box::pin(stream::iter([Ok(Bytes::from("data"))]));
+            storage.write(path, body).await.unwrap();
+        }
+        storage
+    }
+
+    #[tokio::test]
+    async fn mkcol_only_creates_new_directories() {
+        let storage = storage().await;
+        let mine = ["/mine"];
+        for path in ["/", "/theirs", "/mine", "/theirs.txt"] {
+            assert_eq!(
+                check_path(&storage, &mine, "MKCOL", path).await.err(),
+                Some(StatusCode::METHOD_NOT_ALLOWED),
+                "{}",
+                path
+            );
+        }
+        assert!(matches!(
+            check_path(&storage, &mine, "MKCOL", "/new").await,
+            Ok(Some(Change::Created(path))) if path == "/new"
+        ));
+    }
+
+    #[tokio::test]
+    async fn put_cannot_overwrite_other_uploads() {
+        let storage = storage().await;
+        let mine = ["/mine"];
+        assert_eq!(
+            check_path(&storage, &mine, "PUT", "/theirs.txt")
+                .await
+                .err(),
+            Some(StatusCode::FORBIDDEN)
+        );
+        assert!(matches!(
+            check_path(&storage, &mine, "PUT", "/mine/a.txt").await,
+            Ok(None)
+        ));
+        assert!(matches!(
+            check_path(&storage, &mine, "PUT", "/new.txt").await,
+            Ok(Some(Change::Created(_)))
+        ));
+        // 记录了根目录也不能覆盖别人的文件
+        assert_eq!(
+            check_path(&storage, &["/"], "PUT", "/theirs.txt")
+                .await
+                .err(),
+            Some(StatusCode::FORBIDDEN)
+        );
+    }
+
+    #[tokio::test]
+    async fn propfind_shows_only_own_paths_and_their_parents() {
+        let storage = storage().await;
+        let mine = ["/mine/a.txt"];
+        for path in ["/", "/mine", "/mine/a.txt"] {
+            assert!(check_path(&storage, &mine, "PROPFIND", path).await.is_ok());
+        }
+        for path in ["/theirs", "/theirs.txt", "/mine/b.txt"] {
+            assert_eq!(
+                check_path(&storage, &mine, "PROPFIND", path).await.err(),
+                Some(StatusCode::NOT_FOUND),
+                "{}",
+                path
+            );
+        }
+        let created: HashSet<String> = ["/".to_string()].into();
+        assert!(!is_own(&created, "/theirs.txt"));
+        assert!(!is_visible(&created, "/theirs.txt"));
+    }
+
+    #[test]
+    fn record_ignores_the_root() {
+        let session = DropBoxSession {
+            key: "dropbox-test\nroot".to_string(),
+            token: String::new(),
+            issued: false,
+        };
+        record(&session, Some(Change::Created("/".to_string())), None);
+        record(
+            &session,
+            Some(Change::Moved("/a".to_string(), "/".to_string())),
+            Some(&Uploaded(vec!["/".to_string(), "/b.txt".to_string()])),
+        );
+        assert_eq!(session.created(), ["/b.txt".to_string()].into());
+    }
+}
@@ -12,6 +12,11 @@ impl From<ExtendMethod> for Method {
             ExtendMethod::PROPFIND => Method::from_bytes(b"PROPFIND").unwrap(),
             ExtendMethod::PROPPATCH => Method::from_bytes(b"PROPPATCH").unwrap(),
             ExtendMethod::UNLOCK => Method::from_bytes(b"UNLOCK").unwrap(),
+            ExtendMethod::VERSIONCONTROL => Method::from_bytes(b"VERSION-CONTROL").unwrap(),
+            ExtendMethod::CHECKOUT => Method::from_bytes(b"CHECKOUT").unwrap(),
+            ExtendMethod::CHECKIN => Method::from_bytes(b"CHECKIN").unwrap(),
+            ExtendMethod::UNCHECKOUT => Method::from_bytes(b"UNCHECKOUT").unwrap(),
+            ExtendMethod::REPORT => Method::from_bytes(b"REPORT").unwrap(),
         }
     }
 }
@@ -28,6 +33,11 @@ impl FromStr for ExtendMethod {
             "PROPFIND" => Ok(ExtendMethod::PROPFIND),
             "PROPPATCH" => Ok(ExtendMethod::PROPPATCH),
             "UNLOCK" => Ok(ExtendMethod::UNLOCK),
+            "VERSION-CONTROL" => Ok(ExtendMethod::VERSIONCONTROL),
+            "CHECKOUT" => Ok(ExtendMethod::CHECKOUT),
+            "CHECKIN" => Ok(ExtendMethod::CHECKIN),
+            "UNCHECKOUT" => Ok(ExtendMethod::UNCHECKOUT),
+            "REPORT" => Ok(ExtendMethod::REPORT),
             _ => Err(()),
         }
     }
@@ -43,6 +53,11 @@ impl fmt::Display for ExtendMethod {
             ExtendMethod::PROPFIND => write!(f, "PROPFIND"),
             ExtendMethod::PROPPATCH => write!(f, "PROPPATCH"),
             ExtendMethod::UNLOCK => write!(f, "UNLOCK"),
+            ExtendMethod::VERSIONCONTROL => write!(f, "VERSION-CONTROL"),
+            ExtendMethod::CHECKOUT => write!(f, "CHECKOUT"),
+            ExtendMethod::CHECKIN => write!(f, "CHECKIN"),
+            ExtendMethod::UNCHECKOUT => write!(f, "UNCHECKOUT"),
+            ExtendMethod::REPORT => write!(f, "REPORT"),
         }
     }
 }
@@ -62,4 +77,14 @@ pub enum ExtendMethod {
     PROPPATCH,
     // 解除资源的锁定
     UNLOCK,
+    // DeltaV (RFC 3253)：开始对资源进行版本控制
+    VERSIONCONTROL,
+    // DeltaV：签出受版本控制的资源以便修改
+    CHECKOUT,
+    // DeltaV：签入修改，保存为新版本
+    CHECKIN,
+    // DeltaV：放弃签出后的修改
+    UNCHECKOUT,
+    // DeltaV：获取报告，目前支持 version-tree
+    REPORT,
 }
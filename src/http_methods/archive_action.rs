@@ -0,0 +1,476 @@
+use std::{
+    collections::HashSet,
+    io::{self, Read},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures_util::TryStreamExt;
+use http_body_util::{combinators::BoxBody, BodyExt, StreamBody};
+use hyper::{
+    body::{Bytes, Frame, Incoming},
+    Request, Response, StatusCode,
+};
+use serde::Serialize;
+use tokio::{runtime::Handle, sync::mpsc, task::JoinHandle};
+
+use crate::{
+    config::Rule,
+    http_methods::{
+        download,
+        post::{resolve_target, write_file, ConflictPolicy},
+    },
+    pack::{self, ChannelWriter, ExtractLimits, Format, Member, PackStats},
+    storage::{
+        self,
+        archive::{archive_kind, ArchiveKind, RangeReader},
+        file_name, join_path, parent_path, receiver_stream, redact_path, Storage,
+    },
+    trash,
+    util::{get_destination_path, get_header, get_query_param, map_io_result, status_response},
+    versions,
+};
+
+// 进度最多每隔这么久报告一次
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+// 每行一个 JSON 对象，最后一行 done 为 true 或带有 error
+#[derive(Serialize)]
+struct Report<'a> {
+    done: bool,
+    #[serde(flatten)]
+    stats: PackStats,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// 把进度写入响应，客户端断开后操作继续进行
+struct Progress {
+    sender: mpsc::Sender<io::Result<Bytes>>,
+    last: Instant,
+}
+
+impl Progress {
+    fn send(&self, report: &Report, wait: bool) {
+        let mut line = serde_json::to_string(report).unwrap();
+        line.push('\n');
+        let chunk = Ok(Bytes::from(line));
+        if wait {
+            let _ = self.sender.blocking_send(chunk);
+        } else {
+            let _ = self.sender.try_send(chunk);
+        }
+    }
+
+    fn update(&mut self, stats: &PackStats) {
+        if self.last.elapsed() < PROGRESS_INTERVAL {
+            return;
+        }
+        self.last = Instant::now();
+        let report = Report {
+            done: false,
+            stats: *stats,
+            target: None,
+            error: None,
+        };
+        self.send(&report, false);
+    }
+
+    fn finish(&self, result: io::Result<PackStats>, target: &str) {
+        let report = match result {
+            Ok(stats) => Report {
+                done: true,
+                stats,
+                target: Some(target),
+                error: None,
+            },
+            Err(err) => Report {
+                done: false,
+                stats: PackStats::default(),
+                target: Some(target),
+                error: Some(err.to_string()),
+            },
+        };
+        self.send(&report, true);
+    }
+}
+
+// POST ?action=extract 把压缩包解压到目录，POST ?action=pack 把目录打包为压缩包文件，
+// Destination 指定目标，响应中逐行报告进度
+pub async fn handle_resp(
+    req: &Request<Incoming>,
+    rule: &Rule,
+    action: &str,
+    path: &str,
+) -> Response<BoxBody<Bytes, std::io::Error>> {
+    // 浏览压缩包时压缩包显示为目录，这里需要操作压缩包文件本身
+    let mut plain = rule.clone();
+    plain.browse_archives = false;
+    let storage = match storage::for_rule(&plain) {
+        Ok(storage) => storage,
+        Err(err) => {
+            log::error!(
+                "failed to open storage {}: {}",
+                redact_path(&rule.path),
+                err
+            );
+            return status_response(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    let metadata = match storage.stat(path).await {
+        Ok(metadata) => metadata,
+        Err(err) => return status_response(map_io_result::<()>(Err(err), StatusCode::OK)),
+    };
+    let destination = if get_header(req, "destination", "").is_empty() {
+        None
+    } else {
+        match get_destination_path(req, rule) {
+            Some(destination) => Some(destination),
+            None => return status_response(StatusCode::BAD_REQUEST),
+        }
+    };
+    if let Some(destination) = &destination {
+        if is_protected(rule, destination) {
+            return status_response(StatusCode::FORBIDDEN);
+        }
+    }
+    let policy = ConflictPolicy::parse(&rule.upload_conflict).unwrap_or(ConflictPolicy::Rename);
+    match action {
+        "extract" => {
+            let kind = match archive_kind(file_name(path)) {
+                Some(kind) if !metadata.is_dir => kind,
+                _ => return status_response(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            };
+            let target = destination.unwrap_or_else(|| extract_target(path));
+            match storage.stat(&target).await {
+                Ok(metadata) if metadata.is_dir => {}
+                Ok(_) => return status_response(StatusCode::CONFLICT),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    if let Err(err) = storage.mkdir(&target).await {
+                        log::error!("failed to create {}: {}", target, err);
+                        return status_response(map_io_result::<()>(Err(err), StatusCode::OK));
+                    }
+                }
+                Err(err) => return status_response(map_io_result::<()>(Err(err), StatusCode::OK)),
+            }
+            let job = ExtractJob {
+                rule: rule.clone(),
+                storage,
+                handle: Handle::current(),
+                policy,
+                limits: ExtractLimits::from_config(rule.extract.as_ref()),
+                created: HashSet::new(),
+            };
+            let archive = path.to_string();
+            let len = metadata.len;
+            run(move |progress| {
+                let result = job.run(&archive, kind, len, &target, progress);
+                (result, target)
+            })
+        }
+        "pack" => {
+            if !metadata.is_dir {
+                return status_response(StatusCode::METHOD_NOT_ALLOWED);
+            }
+            let format = match get_query_param(req, "format") {
+                Some(value) => match Format::parse(&value) {
+                    Some(format) => format,
+                    None => return status_response(StatusCode::BAD_REQUEST),
+                },
+                None => destination
+                    .as_deref()
+                    .and_then(|destination| match archive_kind(destination) {
+                        Some(ArchiveKind::Zip) => Some(Format::Zip),
+                        Some(_) => Some(Format::TarGz),
+                        None => None,
+                    })
+                    .unwrap_or(Format::Zip),
+            };
+            let destination = destination.unwrap_or_else(|| pack_target(path, format));
+            let dir = parent_path(&destination);
+            match storage.stat(&dir).await {
+                Ok(metadata) if metadata.is_dir => {}
+                _ => return status_response(StatusCode::CONFLICT),
+            }
+            let target =
+                match resolve_target(storage.as_ref(), &dir, file_name(&destination), policy).await
+                {
+                    Ok(target) => target,
+                    Err(err) => {
+                        return status_response(map_io_result::<()>(Err(err), StatusCode::OK))
+                    }
+                };
+            let rule = rule.clone();
+            let handle = Handle::current();
+            let root = path.to_string();
+            run(move |progress| {
+                let result = pack_to(&rule, storage, handle, &root, format, &target, progress);
+                (result, target)
+            })
+        }
+        _ => status_response(StatusCode::BAD_REQUEST),
+    }
+}
+
+// 默认解压到与压缩包同名的目录，例如 /a/b.tar.gz ==> /a/b
+fn extract_target(path: &str) -> String {
+    join_path(&parent_path(path), archive_stem(file_name(path)))
+}
+
+// 默认打包到目录旁边，例如 /a/b ==> /a/b.zip
+fn pack_target(path: &str, format: Format) -> String {
+    let name = match file_name(path) {
+        "" => "archive",
+        name => name,
+    };
+    join_path(
+        &parent_path(path),
+        &format!("{}.{}", name, format.extension()),
+    )
+}
+
+// 解压或打包要写入的路径，锁定检查时使用，不是这两个操作时返回 None
+pub fn write_target(
+    req: &Request<Incoming>,
+    rule: &Rule,
+    action: &str,
+    path: &str,
+) -> Option<String> {
+    if action != "extract" && action != "pack" {
+        return None;
+    }
+    if let Some(destination) = get_destination_path(req, rule) {
+        return Some(destination);
+    }
+    if action == "extract" {
+        return Some(extract_target(path));
+    }
+    let format = get_query_param(req, "format")
+        .and_then(|value| Format::parse(&value))
+        .unwrap_or(Format::Zip);
+    Some(pack_target(path, format))
+}
+
+// 回收站和历史版本中不能写入
+fn is_protected(rule: &Rule, path: &str) -> bool {
+    trash::is_trash_path(rule, path) || versions::is_versions_path(rule, path)
+}
+
+fn archive_stem(name: &str) -> &str {
+    let lower = name.to_lowercase();
+    for extension in [".tar.gz", ".tgz", ".tar", ".zip"] {
+        if lower.ends_with(extension) && lower.len() > extension.len() {
+            return &name[..name.len() - extension.len()];
+        }
+    }
+    name
+}
+
+// 在 spawn_blocking 中执行 job，边执行边返回进度
+fn run<F>(job: F) -> Response<BoxBody<Bytes, std::io::Error>>
+where
+    F: FnOnce(&mut dyn FnMut(&PackStats)) -> (io::Result<PackStats>, String) + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(16);
+    tokio::task::spawn_blocking(move || {
+        let mut progress = Progress {
+            sender,
+            last: Instant::now(),
+        };
+        let (result, target) = job(&mut |stats| progress.update(stats));
+        progress.finish(result, &target);
+    });
+    let body = StreamBody::new(receiver_stream(receiver).map_ok(Frame::data)).boxed();
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/x-ndjson")
+        .header("Cache-Control", "no-cache")
+        .body(body)
+        .unwrap()
+}
+
+// 在异步任务中写入 path，返回写入的 channel
+fn spawn_write(
+    rule: &Rule,
+    storage: &Arc<dyn Storage>,
+    handle: &Handle,
+    path: &str,
+) -> (mpsc::Sender<io::Result<Bytes>>, JoinHandle<io::Result<u64>>) {
+    let (sender, receiver) = mpsc::channel(4);
+    let rule = rule.clone();
+    let storage = storage.clone();
+    let path = path.to_string();
+    let task = handle.spawn(async move {
+        let result = write_file(&rule, storage.as_ref(), &path, receiver_stream(receiver)).await;
+        // 不留下不完整的文件
+        if result.is_err() {
+            let _ = storage.remove(&path).await;
+        }
+        result
+    });
+    (sender, task)
+}
+
+fn join_write(handle: &Handle, task: JoinHandle<io::Result<u64>>) -> io::Result<u64> {
+    handle.block_on(task).map_err(io::Error::other)?
+}
+
+fn pack_to(
+    rule: &Rule,
+    storage: Arc<dyn Storage>,
+    handle: Handle,
+    root: &str,
+    format: Format,
+    target: &str,
+    progress: &mut dyn FnMut(&PackStats),
+) -> io::Result<PackStats> {
+    let (sender, task) = spawn_write(rule, &storage, &handle, target);
+    // 打包出错时通过 sender 让写入失败，而不是留下不完整的压缩包
+    let out = ChannelWriter::new(sender.clone());
+    let filter = |path: &str| download::is_packed(rule, path) && path != target;
+    match pack::pack(
+        storage,
+        handle.clone(),
+        root,
+        format,
+        &filter,
+        out,
+        progress,
+    ) {
+        Ok(stats) => {
+            drop(sender);
+            join_write(&handle, task)?;
+            log::info!("packed {} into {}", root, target);
+            Ok(stats)
+        }
+        Err(err) => {
+            log::error!("failed to pack {} into {}: {}", root, target, err);
+            let _ = sender.blocking_send(Err(io::Error::new(err.kind(), err.to_string())));
+            drop(sender);
+            let _ = join_write(&handle, task);
+            Err(err)
+        }
+    }
+}
+
+struct ExtractJob {
+    rule: Rule,
+    storage: Arc<dyn Storage>,
+    handle: Handle,
+    policy: ConflictPolicy,
+    limits: ExtractLimits,
+    // 已经创建的目录
+    created: HashSet<String>,
+}
+
+impl ExtractJob {
+    fn run(
+        mut self,
+        archive: &str,
+        kind: ArchiveKind,
+        len: u64,
+        target: &str,
+        progress: &mut dyn FnMut(&PackStats),
+    ) -> io::Result<PackStats> {
+        let reader = RangeReader::new(self.storage.clone(), archive, len, self.handle.clone());
+        let limits = self.limits;
+        let result = pack::unpack(
+            reader,
+            kind,
+            len,
+            &limits,
+            &mut |member| self.extract(target, member),
+            progress,
+        );
+        match &result {
+            Ok(stats) => log::info!(
+                "extracted {} into {}: {} files, {} dirs, {} bytes, {} skipped",
+                archive,
+                target,
+                stats.files,
+                stats.dirs,
+                stats.bytes,
+                stats.skipped
+            ),
+            Err(err) => log::error!("failed to extract {} into {}: {}", archive, target, err),
+        }
+        result
+    }
+
+    fn extract(&mut self, target: &str, member: Member) -> io::Result<bool> {
+        match member {
+            Member::Dir(name) => {
+                let dir = join_path(target, &name);
+                if is_protected(&self.rule, &dir) {
+                    return Ok(false);
+                }
+                self.create_dir(&dir)?;
+                Ok(true)
+            }
+            Member::File(name, reader) => {
+                let path = join_path(target, &name);
+                if is_protected(&self.rule, &path) {
+                    return Ok(false);
+                }
+                let dir = parent_path(&path);
+                self.create_dir(&dir)?;
+                let path = match self.handle.block_on(resolve_target(
+                    self.storage.as_ref(),
+                    &dir,
+                    file_name(&path),
+                    self.policy,
+                )) {
+                    Ok(path) => path,
+                    Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                        log::warn!("skipped archive entry {}: {}", name, err);
+                        return Ok(false);
+                    }
+                    Err(err) => return Err(err),
+                };
+                self.write(&path, reader)?;
+                Ok(true)
+            }
+        }
+    }
+
+    fn create_dir(&mut self, dir: &str) -> io::Result<()> {
+        if self.created.contains(dir) {
+            return Ok(());
+        }
+        self.handle.block_on(self.storage.mkdir(dir))?;
+        self.created.insert(dir.to_string());
+        Ok(())
+    }
+
+    fn write(&self, path: &str, reader: &mut dyn Read) -> io::Result<()> {
+        let (sender, task) = spawn_write(&self.rule, &self.storage, &self.handle, path);
+        let mut buffer = vec![0u8; 64 * 1024];
+        let mut failed = None;
+        loop {
+            match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => {
+                    let chunk = Bytes::copy_from_slice(&buffer[..read]);
+                    // 写入任务已经出错结束
+                    if sender.blocking_send(Ok(chunk)).is_err() {
+                        break;
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    let _ = sender.blocking_send(Err(io::Error::new(err.kind(), err.to_string())));
+                    failed = Some(err);
+                    break;
+                }
+            }
+        }
+        drop(sender);
+        let result = join_write(&self.handle, task);
+        match failed {
+            Some(err) => Err(err),
+            None => result.map(|_| ()),
+        }
+    }
+}
@@ -1,22 +1,40 @@
-use std::{
-    fs, path::PathBuf
-};
-
 use http_body_util::combinators::BoxBody;
 use hyper::{body::Bytes, Response, StatusCode};
 
-use crate::util::{empty, map_io_result};
+use crate::{
+    config::Rule,
+    storage::{remove_all, Storage},
+    thumbnails, trash,
+    util::{empty, map_io_result},
+    versions,
+};
 
-pub async fn handle_resp(path: &PathBuf) -> Response<BoxBody<Bytes, std::io::Error>> {
+pub async fn handle_resp(
+    rule: &Rule,
+    user: &str,
+    storage: &dyn Storage,
+    path: &str,
+) -> Response<BoxBody<Bytes, std::io::Error>> {
     let mut response = Response::new(empty());
-    let file_result = fs::remove_file(path);
-    if file_result.is_ok() {
-        let status_code = map_io_result(file_result, StatusCode::NO_CONTENT);
-        *response.status_mut() = status_code;
-        return response;
+    // 启用回收站时移到回收站，回收站和历史版本中的文件直接删除
+    let remove_result = match &rule.trash {
+        _ if versions::is_versions_path(rule, path) => remove_all(storage, path).await,
+        Some(_) if trash::is_trash_path(rule, path) => trash::delete(storage, path).await,
+        Some(config) if path != "/" => trash::move_to_trash(storage, config, user, path).await,
+        _ => storage.remove(path).await,
+    };
+    // 彻底删除时历史版本也删除，移到回收站时已随文件移动
+    if remove_result.is_ok()
+        && rule.trash.is_none()
+        && rule.versions.is_some()
+        && !versions::is_versions_path(rule, path)
+    {
+        versions::forget(storage, path).await;
+    }
+    if remove_result.is_ok() {
+        thumbnails::invalidate(rule, path).await;
     }
-    let dir_err = fs::remove_dir(path);
-    let status_code = map_io_result(dir_err, StatusCode::NO_CONTENT);
+    let status_code = map_io_result(remove_result, StatusCode::NO_CONTENT);
     response
         .headers_mut()
         .insert("Content-Length", format!("{}", 0).parse().unwrap());
@@ -0,0 +1,307 @@
+use http_body_util::{combinators::BoxBody, BodyExt};
+use hyper::{
+    body::{Bytes, Incoming},
+    Request, Response, StatusCode,
+};
+
+use crate::{
+    config::Rule,
+    http_methods::propfind::entry_href,
+    storage::{join_path, Storage},
+    util::{empty, encode_uri, format_date_time, full, map_io_result, status_response},
+    versions::{self, State},
+};
+
+type DavResponse = Response<BoxBody<Bytes, std::io::Error>>;
+
+fn io_error_response(err: std::io::Error) -> DavResponse {
+    status_response(map_io_result::<()>(Err(err), StatusCode::OK))
+}
+
+// 前置条件不满足时返回 DAV:error，例如 <D:must-be-checked-out/>
+fn condition_response(status: StatusCode, condition: &str) -> DavResponse {
+    let xml = format!(
+        r#"<?xml version="1.0" encoding="utf-8"?><D:error xmlns:D="DAV:"><D:{}/></D:error>"#,
+        condition
+    );
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/xml; charset=utf-8")
+        .body(full(Bytes::from(xml)))
+        .unwrap()
+}
+
+// 检查 rule 是否启用了版本，并返回资源当前的状态
+async fn check_state(rule: &Rule, storage: &dyn Storage, path: &str) -> Result<State, DavResponse> {
+    if rule.versions.is_none() {
+        return Err(status_response(StatusCode::METHOD_NOT_ALLOWED));
+    }
+    // 只支持文件，版本目录中的文件本身不能再受版本控制
+    if versions::is_versions_path(rule, path) {
+        return Err(status_response(StatusCode::FORBIDDEN));
+    }
+    match storage.stat(path).await {
+        Ok(metadata) if metadata.is_dir => {
+            return Err(status_response(StatusCode::METHOD_NOT_ALLOWED))
+        }
+        Ok(_) => {}
+        Err(err) => return Err(io_error_response(err)),
+    }
+    versions::state(storage, path)
+        .await
+        .map_err(io_error_response)
+}
+
+pub async fn version_control(rule: &Rule, storage: &dyn Storage, path: &str) -> DavResponse {
+    if let Err(response) = check_state(rule, storage, path).await {
+        return response;
+    }
+    match versions::version_control(storage, path).await {
+        Ok(_) => status_response(StatusCode::OK),
+        Err(err) => io_error_response(err),
+    }
+}
+
+pub async fn checkout(rule: &Rule, storage: &dyn Storage, path: &str) -> DavResponse {
+    match check_state(rule, storage, path).await {
+        Ok(State::CheckedIn) => {}
+        Ok(State::CheckedOut) => {
+            return condition_response(StatusCode::CONFLICT, "must-be-checked-in")
+        }
+        Ok(State::Unversioned) => {
+            return condition_response(StatusCode::CONFLICT, "must-be-version-controlled")
+        }
+        Err(response) => return response,
+    }
+    match versions::checkout(storage, path).await {
+        Ok(_) => status_response(StatusCode::OK),
+        Err(err) => io_error_response(err),
+    }
+}
+
+pub async fn checkin(rule: &Rule, storage: &dyn Storage, path: &str) -> DavResponse {
+    match check_state(rule, storage, path).await {
+        Ok(State::CheckedOut) => {}
+        Ok(_) => return condition_response(StatusCode::CONFLICT, "must-be-checked-out"),
+        Err(response) => return response,
+    }
+    let config = rule.versions.as_ref().unwrap();
+    match versions::checkin(storage, config, path).await {
+        Ok(version_path) => Response::builder()
+            .status(StatusCode::CREATED)
+            .header("Location", encode_uri(&entry_href(rule, &version_path)))
+            .body(empty())
+            .unwrap(),
+        Err(err) => io_error_response(err),
+    }
+}
+
+pub async fn uncheckout(rule: &Rule, storage: &dyn Storage, path: &str) -> DavResponse {
+    match check_state(rule, storage, path).await {
+        Ok(State::CheckedOut) => {}
+        Ok(_) => {
+            return condition_response(
+                StatusCode::CONFLICT,
+                "must-be-checked-out-version-controlled-resource",
+            )
+        }
+        Err(response) => return response,
+    }
+    match versions::uncheckout(storage, path).await {
+        Ok(_) => status_response(StatusCode::OK),
+        Err(err) => io_error_response(err),
+    }
+}
+
+// REPORT version-tree：列出全部版本，每个版本的前一个和后一个版本
+pub async fn report(
+    req: Request<Incoming>,
+    rule: &Rule,
+    storage: &dyn Storage,
+    path: &str,
+) -> DavResponse {
+    if let Err(response) = check_state(rule, storage, path).await {
+        return response;
+    }
+    let body = match req.collect().await {
+        Ok(body) => String::from_utf8_lossy(&body.to_bytes()).to_string(),
+        Err(_) => return status_response(StatusCode::BAD_REQUEST),
+    };
+    if !body.contains("version-tree") {
+        return condition_response(StatusCode::FORBIDDEN, "supported-report");
+    }
+    let versions = match versions::list(storage, path).await {
+        Ok(versions) => versions,
+        Err(err) => return io_error_response(err),
+    };
+    let dir = versions::versions_dir(path);
+    let hrefs: Vec<String> = versions
+        .iter()
+        .map(|(_, entry)| encode_uri(&entry_href(rule, &join_path(&dir, &entry.name))))
+        .collect();
+    let mut xml = String::new();
+    xml.push_str(r#"<?xml version="1.0" encoding="utf-8"?>"#);
+    xml.push_str(r#"<D:multistatus xmlns:D="DAV:">"#);
+    for (index, (number, entry)) in versions.iter().enumerate() {
+        let predecessor = match index {
+            0 => String::new(),
+            _ => format!("<D:href>{}</D:href>", hrefs[index - 1]),
+        };
+        let successor = match hrefs.get(index + 1) {
+            Some(href) => format!("<D:href>{}</D:href>", href),
+            None => String::new(),
+        };
+        xml.push_str("<D:response>\n");
+        xml.push_str(&format!("<D:href>{}</D:href>\n", hrefs[index]));
+        xml.push_str("<D:propstat>\n<D:prop>\n");
+        xml.push_str(&format!("<D:version-name>{}</D:version-name>\n", number));
+        xml.push_str(&format!(
+            "<D:getcontentlength>{}</D:getcontentlength>\n",
+            entry.metadata.len
+        ));
+        xml.push_str(&format!(
+            "<D:getlastmodified>{}</D:getlastmodified>\n",
+            format_date_time(entry.metadata.modified)
+        ));
+        xml.push_str(&format!(
+            "<D:predecessor-set>{}</D:predecessor-set>\n",
+            predecessor
+        ));
+        xml.push_str(&format!(
+            "<D:successor-set>{}</D:successor-set>\n",
+            successor
+        ));
+        xml.push_str("</D:prop>\n<D:status>HTTP/1.1 200 OK</D:status>\n</D:propstat>\n");
+        xml.push_str("</D:response>\n");
+    }
+    xml.push_str("</D:multistatus>\n");
+    Response::builder()
+        .status(StatusCode::MULTI_STATUS)
+        .header("Content-Type", "application/xml; charset=utf-8")
+        .body(full(Bytes::from(xml)))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{stream, TryStreamExt};
+
+    use super::*;
+    use crate::{config::VersionsConfig, storage::memory::MemoryStorage};
+
+    fn rule(versions: Option<VersionsConfig>) -> Rule {
+        Rule {
+            server_prefix: "/dav".to_string(),
+            versions,
+            ..Rule::default()
+        }
+    }
+
+    async fn write(storage: &dyn Storage, path: &str, data: &str) {
+        let body = Box::pin(stream::iter([Ok(Bytes::from(data.to_string()))]));
+        storage.write(path, body).await.unwrap();
+    }
+
+    async fn read(storage: &dyn Storage, path: &str) -> String {
+        let chunks: Vec<Bytes> = storage
+            .read(path, None)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        String::from_utf8(chunks.concat()).unwrap()
+    }
+
+    async fn error_body(response: DavResponse) -> String {
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn checkout_checkin_and_uncheckout_follow_the_state_machine() {
+        let storage = MemoryStorage::new(u64::MAX);
+        let rule = rule(Some(VersionsConfig {
+            auto_version: false,
+            ..VersionsConfig::default()
+        }));
+        write(&storage, "/a.txt", "one").await;
+
+        // 未受版本控制时不能 CHECKOUT
+        let response = checkout(&rule, &storage, "/a.txt").await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert!(error_body(response)
+            .await
+            .contains("must-be-version-controlled"));
+
+        let response = version_control(&rule, &storage, "/a.txt").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = checkin(&rule, &storage, "/a.txt").await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert!(error_body(response).await.contains("must-be-checked-out"));
+
+        assert_eq!(
+            checkout(&rule, &storage, "/a.txt").await.status(),
+            StatusCode::OK
+        );
+        let response = checkout(&rule, &storage, "/a.txt").await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert!(error_body(response).await.contains("must-be-checked-in"));
+
+        // CHECKIN 保存新版本并返回它的位置
+        write(&storage, "/a.txt", "two").await;
+        let response = checkin(&rule, &storage, "/a.txt").await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let location = response.headers()["Location"].to_str().unwrap().to_string();
+        let version = location.strip_prefix("/dav").unwrap();
+        assert_eq!(read(&storage, version).await, "two");
+        assert_eq!(
+            versions::state(&storage, "/a.txt").await.unwrap(),
+            State::CheckedIn
+        );
+
+        // UNCHECKOUT 放弃修改，恢复为最新版本
+        let response = uncheckout(&rule, &storage, "/a.txt").await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(
+            checkout(&rule, &storage, "/a.txt").await.status(),
+            StatusCode::OK
+        );
+        write(&storage, "/a.txt", "three").await;
+        assert_eq!(
+            uncheckout(&rule, &storage, "/a.txt").await.status(),
+            StatusCode::OK
+        );
+        assert_eq!(read(&storage, "/a.txt").await, "two");
+        assert_eq!(
+            versions::state(&storage, "/a.txt").await.unwrap(),
+            State::CheckedIn
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_directories_version_files_and_rules_without_versions() {
+        let storage = MemoryStorage::new(u64::MAX);
+        storage.mkdir("/docs").await.unwrap();
+        write(&storage, "/docs/a.txt", "one").await;
+
+        let plain = rule(None);
+        let response = version_control(&plain, &storage, "/docs/a.txt").await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        let rule = rule(Some(VersionsConfig::default()));
+        let response = version_control(&rule, &storage, "/docs").await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        let response = checkout(&rule, &storage, "/docs/missing.txt").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        version_control(&rule, &storage, "/docs/a.txt").await;
+        let version = versions::list(&storage, "/docs/a.txt").await.unwrap()[0]
+            .1
+            .name
+            .clone();
+        let version = join_path(&versions::versions_dir("/docs/a.txt"), &version);
+        let response = checkout(&rule, &storage, &version).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+}
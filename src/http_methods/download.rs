@@ -0,0 +1,135 @@
+use std::sync::Arc;
+
+use futures_util::TryStreamExt;
+use http_body_util::{combinators::BoxBody, BodyExt, StreamBody};
+use hyper::{
+    body::{Bytes, Frame},
+    Request, Response, StatusCode,
+};
+use tokio::{runtime::Handle, sync::mpsc};
+
+use crate::{
+    config::Rule,
+    pack::{self, ChannelWriter, Format},
+    share,
+    storage::{self, file_name, receiver_stream, redact_path, Storage},
+    trash,
+    util::{empty, encode_uri, get_header, get_query_param},
+    versions,
+};
+
+// GET 目录时要下载的压缩包格式：?archive=zip|tar.gz，或 Accept 中指定的类型
+pub fn requested_format<B>(req: &Request<B>) -> Option<Result<Format, String>> {
+    if let Some(value) = get_query_param(req, "archive") {
+        return Some(Format::parse(&value).ok_or(value));
+    }
+    let accept = get_header(req, "accept", "");
+    if accept.contains("text/html") {
+        return None;
+    }
+    if accept.contains("application/zip") {
+        return Some(Ok(Format::Zip));
+    }
+    if [
+        "application/gzip",
+        "application/x-gzip",
+        "application/x-gtar",
+    ]
+    .iter()
+    .any(|content_type| accept.contains(content_type))
+    {
+        return Some(Ok(Format::TarGz));
+    }
+    None
+}
+
+// 打包时跳过的路径
+pub fn is_packed(rule: &Rule, path: &str) -> bool {
+    !trash::is_hidden(rule, path) && !versions::is_hidden(rule, path)
+}
+
+// 浏览压缩包时压缩包显示为目录，打包时使用压缩包文件本身，除非要下载的就是压缩包中的目录
+async fn pack_storage(rule: &Rule, path: &str) -> std::io::Result<Arc<dyn Storage>> {
+    if rule.browse_archives {
+        let mut plain = rule.clone();
+        plain.browse_archives = false;
+        let storage = storage::for_rule(&plain)?;
+        if let Ok(metadata) = storage.stat(path).await {
+            if metadata.is_dir {
+                return Ok(storage);
+            }
+        }
+    }
+    storage::for_rule(rule)
+}
+
+// 边打包边发送，内存占用与目录大小无关
+pub async fn handle_resp(
+    rule: &Rule,
+    path: &str,
+    format: Format,
+) -> Response<BoxBody<Bytes, std::io::Error>> {
+    let storage = match pack_storage(rule, path).await {
+        Ok(storage) => storage,
+        Err(err) => {
+            log::error!(
+                "failed to open storage {}: {}",
+                redact_path(&rule.path),
+                err
+            );
+            let mut response = Response::new(empty());
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            return response;
+        }
+    };
+    // 分享的根目录使用被分享的目录名
+    let owner_path = share::owner_path(rule, path);
+    let name = match file_name(&owner_path) {
+        "" => match file_name(&rule.path) {
+            "" => "archive",
+            name => name,
+        },
+        name => name,
+    };
+    let filename = format!("{}.{}", name, format.extension());
+    let (sender, receiver) = mpsc::channel(4);
+    let handle = Handle::current();
+    let rule = rule.clone();
+    let root = path.to_string();
+    tokio::task::spawn_blocking(move || {
+        let filter = |path: &str| is_packed(&rule, path);
+        let out = ChannelWriter::new(sender.clone());
+        match pack::pack(storage, handle, &root, format, &filter, out, &mut |_| {}) {
+            Ok(stats) => log::info!(
+                "packed {} as {}: {} files, {} dirs, {} bytes, {} skipped",
+                root,
+                format.extension(),
+                stats.files,
+                stats.dirs,
+                stats.bytes,
+                stats.skipped
+            ),
+            Err(err) => {
+                log::error!("failed to pack {}: {}", root, err);
+                // 让响应异常结束，客户端不会把不完整的压缩包当作成功
+                let _ = sender.blocking_send(Err(err));
+            }
+        }
+    });
+    let body = StreamBody::new(receiver_stream(receiver).map_ok(Frame::data)).boxed();
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", format.content_type())
+        .header(
+            "Content-Disposition",
+            format!(
+                "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+                filename
+                    .replace(['"', '\\'], "_")
+                    .replace(|c: char| !c.is_ascii(), "_"),
+                encode_uri(&filename).replace('/', "%2F")
+            ),
+        )
+        .body(body)
+        .unwrap()
+}
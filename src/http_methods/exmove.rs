@@ -1,38 +1,76 @@
-use std::path::{Path, PathBuf};
-
-use crate::util::{
-    decode_uri, empty, extract_relative_path, get_base_dir, get_header, get_server_prefix, map_io_result
-};
+use crate::config::Rule;
+use crate::storage::Storage;
+use crate::thumbnails;
+use crate::trash;
+use crate::util::{empty, get_destination_path, get_header, map_io_result};
+use crate::versions;
 use http_body_util::combinators::BoxBody;
 use hyper::{
     body::{Bytes, Incoming},
     Request, Response, StatusCode,
 };
-use tokio::fs;
 
-pub async fn handle_resp(req: &Request<Incoming>, from_path: &PathBuf) -> Response<BoxBody<Bytes, std::io::Error>> {
+pub async fn handle_resp(
+    req: &Request<Incoming>,
+    rule: &Rule,
+    storage: &dyn Storage,
+    from_path: &str,
+) -> Response<BoxBody<Bytes, std::io::Error>> {
     // 创建响应
     let mut response = Response::new(empty());
-    let destination = get_header(req, "destination", "");
-    let host = get_header(req, "host", "");
-    // log::info!("destination: {}, host: {}", destination, host);
-    let rel_path_result = extract_relative_path(destination, host);
-    // log::info!("rel_path_result: {:?}", rel_path_result.clone());
-    if rel_path_result.is_none() {
-        *response.status_mut() = StatusCode::NOT_FOUND;
+    let to_path = match get_destination_path(req, rule) {
+        Some(to_path) => to_path,
+        None => {
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            return response;
+        }
+    };
+    // 回收站只能通过 DELETE 放入，移出回收站即为恢复；历史版本只能复制
+    if trash::is_trash_path(rule, &to_path)
+        || (trash::is_trash_path(rule, from_path) && trash::item_name(from_path).is_none())
+        || versions::is_versions_path(rule, &to_path)
+        || versions::is_versions_path(rule, from_path)
+    {
+        *response.status_mut() = StatusCode::FORBIDDEN;
+        return response;
+    }
+    // Overwrite: F 时目标已存在则不移动
+    if get_header(req, "overwrite", "T").eq_ignore_ascii_case("F")
+        && storage.stat(&to_path).await.is_ok()
+    {
+        *response.status_mut() = StatusCode::PRECONDITION_FAILED;
         return response;
     }
-    let mut rel_path = rel_path_result.unwrap();
-    let server_prefix = get_server_prefix(req);
-    let base_dir = get_base_dir(req);
-    rel_path = rel_path.replacen(&server_prefix, "", 1);
-    let to_path = Path::new(&base_dir).join(rel_path.trim_start_matches('/'));
     // log::info!("to path: {:?}", to_path);
-    let move_result = fs::rename(
-        decode_uri(from_path.to_str().unwrap()),
-        decode_uri(to_path.to_str().unwrap()),
-    )
-    .await;
+    // 和 PUT、COPY 一样，替换已有文件前保存旧内容
+    let mut save_new = false;
+    if let Some(config) = &rule.versions {
+        match versions::before_write(storage, config, &to_path).await {
+            Ok(save) => save_new = save,
+            Err(err) => {
+                log::error!("cannot overwrite {}: {}", to_path, err);
+                *response.status_mut() = map_io_result::<()>(Err(err), StatusCode::CREATED);
+                return response;
+            }
+        }
+    }
+    let mut move_result = storage.rename(from_path, &to_path).await;
+    // 历史版本跟随文件，从回收站恢复时也一起恢复
+    if move_result.is_ok() && rule.versions.is_some() {
+        move_result = versions::moved(storage, from_path, &to_path).await;
+    }
+    if let Some(config) = &rule.versions {
+        versions::after_write(storage, config, &to_path, save_new && move_result.is_ok()).await;
+    }
+    if move_result.is_ok() {
+        thumbnails::invalidate(rule, from_path).await;
+        thumbnails::invalidate(rule, &to_path).await;
+    }
+    if move_result.is_ok() && trash::is_trash_path(rule, from_path) {
+        if let Some(name) = trash::item_name(from_path) {
+            trash::forget(storage, name).await;
+        }
+    }
     let status_code = map_io_result(move_result, StatusCode::CREATED);
     *response.status_mut() = status_code;
     response
@@ -1,135 +1,164 @@
-use std::{
-    fs::File, io::{self, Read, Seek}, path::PathBuf
-};
-
-use http_body_util::{combinators::BoxBody, BodyExt, StreamBody};
 use futures_util::TryStreamExt;
+use http_body_util::{combinators::BoxBody, BodyExt, StreamBody};
 use hyper::{
     body::{Bytes, Frame, Incoming},
     Request, Response, StatusCode,
 };
 use mime_guess::from_path;
-use tokio_util::io::ReaderStream;
 
-use crate::{config, util::{empty, full, get_header}};
+use crate::{
+    config::{Config, Rule},
+    http_methods::{download, listing, share, thumbnail, webui},
+    storage::{Metadata, Storage},
+    util::{empty, get_header, get_query_param, map_io_result},
+};
 
-pub async fn handle_resp(req: &Request<Incoming>, file_path: &PathBuf) -> Response<BoxBody<Bytes, std::io::Error>> {
+pub async fn handle_resp(
+    req: &Request<Incoming>,
+    cfg: &Config,
+    rule: &Rule,
+    storage: &dyn Storage,
+    path: &str,
+) -> Response<BoxBody<Bytes, std::io::Error>> {
+    // ?shares 列出在这个路径下创建的分享
+    if get_query_param(req, "shares").is_some() {
+        return share::list(req, cfg, rule, path);
+    }
     let mut response = Response::new(empty());
-    let range = get_header(req, "range", "");
-    if range.len() > 0 && range != "bytes=0-" {
-        let mut file = File::open(file_path).unwrap();
-        let metadata = file.metadata().unwrap();
-        let file_len = metadata.len();
-        let mime_type = from_path(file_path).first_or_octet_stream();
-        response
-            .headers_mut()
-            .insert("Content-Type", format!("{}", mime_type).parse().unwrap());
-        let mut start = 0;
-        let end: u64;
-        let bounds = range.strip_prefix("bytes=").unwrap();
-        let max_chunk_size = (file_len / 1024) * 30;
-        // log::info!("file_len: {}, max chunk size: {}", file_len, max_chunk_size);
-        if bounds.contains("-") {
-            let parts = bounds.split('-').collect::<Vec<_>>();
-            start = parts[0].parse::<u64>().unwrap();
-            if parts.len() == 1 || parts[1] == "" {
-                end = file_len - 1;
-            } else {
-                end = parts[1].parse::<u64>().unwrap();
+    let metadata = match storage.stat(path).await {
+        // 浏览器访问目录时显示文件列表，?ui 时返回管理文件的页面
+        Ok(metadata) if metadata.is_dir => {
+            if get_query_param(req, "ui").is_some() {
+                return webui::handle_resp();
             }
-        } else {
-            end = bounds.parse::<u64>().unwrap();
+            match download::requested_format(req) {
+                Some(Ok(format)) => return download::handle_resp(rule, path, format).await,
+                Some(Err(value)) => {
+                    log::error!("unsupported archive format {}", value);
+                    *response.status_mut() = StatusCode::BAD_REQUEST;
+                    return response;
+                }
+                None => {}
+            }
+            return listing::handle_resp(req, rule, storage, path).await;
         }
-        // let mut concurrent_count = 0;
-        // if let Some(concurrent_count_string) = get("over_size") {
-        //     let concurrent_count_result = concurrent_count_string.parse::<u32>();
-        //     if !concurrent_count_result.is_err() {
-        //         concurrent_count = concurrent_count_result.unwrap();
-        //     }
-        // }
-
-        let is_over_size = (end - start) > max_chunk_size;
-        let cfg = config::get_config();
-        if is_over_size && cfg.mode == "dev" {
+        Ok(metadata) => metadata,
+        _ => {
+            log::error!("not found file");
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            return response;
+        }
+    };
+    // 启用缩略图时 ?thumbnail=WxH 返回缩小后的图片
+    if let Some(config) = &rule.thumbnails {
+        match thumbnail::requested_size(req, config) {
+            Some(Ok(size)) => {
+                return thumbnail::handle_resp(req, rule, config, storage, path, &metadata, size)
+                    .await
+            }
+            Some(Err(value)) => {
+                log::error!("invalid thumbnail size {}", value);
+                *response.status_mut() = StatusCode::BAD_REQUEST;
+                return response;
+            }
+            None => {}
+        }
+    }
+    let range = get_header(req, "range", "");
+    if range.is_empty() || range == "bytes=0-" {
+        return get_all_resp(storage, path, &metadata).await;
+    }
+    let file_len = metadata.len;
+    let mime_type = from_path(path).first_or_octet_stream();
+    response
+        .headers_mut()
+        .insert("Content-Type", format!("{}", mime_type).parse().unwrap());
+    let (start, end) = match parse_range(range, file_len) {
+        Some(bounds) => bounds,
+        None => {
             *response.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
             return response;
         }
-        file.seek(io::SeekFrom::Start(start)).unwrap();
-        let mut stream = Vec::with_capacity((end - start + 1) as usize);
-        file.take((end - start + 1) as u64)
-            .read_to_end(&mut stream)
-            .unwrap();
-        *response.status_mut() = StatusCode::PARTIAL_CONTENT;
-        response.headers_mut().insert(
-            "Content-Range",
-            format!("bytes {}-{}/{}", start, end, file_len)
-                .parse()
-                .unwrap(),
-        );
-        *response.body_mut() = full(Bytes::from(stream));
-    } else {
-        response = get_all_resp(file_path).await;
+    };
+    let max_chunk_size = (file_len / 1024) * 30;
+    // log::info!("file_len: {}, max chunk size: {}", file_len, max_chunk_size);
+    let is_over_size = (end - start) > max_chunk_size;
+    if is_over_size && cfg.mode == "dev" {
+        *response.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+        return response;
     }
-
+    let stream = match storage.read(path, Some((start, end))).await {
+        Ok(stream) => stream,
+        Err(err) => {
+            *response.status_mut() = map_io_result::<()>(Err(err), StatusCode::OK);
+            return response;
+        }
+    };
+    *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+    response.headers_mut().insert(
+        "Content-Range",
+        format!("bytes {}-{}/{}", start, end, file_len)
+            .parse()
+            .unwrap(),
+    );
+    response.headers_mut().insert(
+        "Content-Length",
+        format!("{}", end - start + 1).parse().unwrap(),
+    );
+    *response.body_mut() = StreamBody::new(stream.map_ok(Frame::data)).boxed();
     response
 }
 
-pub async fn get_all_resp(file_path: &PathBuf) -> Response<BoxBody<Bytes, std::io::Error>> {
-    // Open file for reading
-    let file = tokio::fs::File::open(file_path).await;
-    if file.is_err() {
-        log::error!("not found file");
-        return Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body(empty())
-            .unwrap();
+// 解析 Range 头，返回闭区间 [start, end]，不合法或超出文件长度时返回 None
+pub fn parse_range(range: &str, file_len: u64) -> Option<(u64, u64)> {
+    let bounds = range.strip_prefix("bytes=")?;
+    // 只支持单个区间
+    let bounds = bounds.split(',').next()?.trim();
+    let (start, end) = match bounds.split_once('-') {
+        // bytes=-500 表示最后 500 个字节
+        Some(("", suffix)) => {
+            let suffix = suffix.parse::<u64>().ok()?.min(file_len);
+            (file_len.checked_sub(suffix)?, file_len.checked_sub(1)?)
+        }
+        Some((start, "")) => (start.parse::<u64>().ok()?, file_len.checked_sub(1)?),
+        Some((start, end)) => (
+            start.parse::<u64>().ok()?,
+            end.parse::<u64>().ok()?.min(file_len.checked_sub(1)?),
+        ),
+        None => (0, bounds.parse::<u64>().ok()?.min(file_len.checked_sub(1)?)),
+    };
+    if start > end {
+        return None;
     }
+    Some((start, end))
+}
 
-    let file: tokio::fs::File = file.unwrap();
-
-    // Wrap to a tokio_util::io::ReaderStream
-    let reader_stream: ReaderStream<tokio::fs::File> = ReaderStream::new(file);
+pub async fn get_all_resp(
+    storage: &dyn Storage,
+    path: &str,
+    metadata: &Metadata,
+) -> Response<BoxBody<Bytes, std::io::Error>> {
+    // Open file for reading
+    let stream = match storage.read(path, None).await {
+        Ok(stream) => stream,
+        Err(_) => {
+            log::error!("not found file");
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(empty())
+                .unwrap();
+        }
+    };
+    let mime_type = from_path(path).first_or_octet_stream();
 
     // Convert to http_body_util::BoxBody
-    let stream_body = StreamBody::new(reader_stream.map_ok(Frame::data));
+    let stream_body = StreamBody::new(stream.map_ok(Frame::data));
     let boxed_body = stream_body.boxed();
     // Send response
     Response::builder()
         .status(StatusCode::OK)
+        .header("Content-Type", format!("{}", mime_type))
+        .header("Content-Length", metadata.len)
         .body(boxed_body)
         .unwrap()
 }
-
-// async fn handle_get_all_resp(file_path: &PathBuf) -> Response<BoxBody<Bytes, std::io::Error>> {
-//     let file = match File::open(file_path) {
-//         Ok(file) => file,
-//         Err(_) => {
-//             log::error!("not found file");
-//             return Response::builder()
-//                 .status(StatusCode::NOT_FOUND)
-//                 .body(empty())
-//                 .unwrap();
-//         }
-//     };
-//     let mime_type = from_path(file_path).first_or_octet_stream();
-//     // 读取文件内容
-//     let mut buffer = Vec::new();
-//     match file.take(usize::MAX as u64).read_to_end(&mut buffer) {
-//         Ok(_) => {
-//             let mut response = Response::new(empty());
-//             response.headers_mut().insert(
-//                 "Content-Type",
-//                 format!("{}", mime_type.as_ref()).parse().unwrap(),
-//             );
-//             *response.body_mut() = full(Bytes::from(buffer));
-//             response
-//         }
-//         Err(_) => {
-//             log::error!("not write buffer");
-//             return Response::builder()
-//                 .status(StatusCode::INTERNAL_SERVER_ERROR)
-//                 .body(empty())
-//                 .unwrap();
-//         }
-//     }
-// }
@@ -1,18 +1,27 @@
-use std::{fs::File, path::PathBuf};
-
 use http_body_util::combinators::BoxBody;
-use hyper::{body::Bytes, Response};
+use hyper::{body::Bytes, Response, StatusCode};
 use mime_guess::from_path;
 
-use crate::util::{empty, format_date_time};
+use crate::{
+    storage::Storage,
+    util::{empty, format_date_time, map_io_result},
+};
 
-pub async fn handle_resp(file_path: &PathBuf) -> Response<BoxBody<Bytes, std::io::Error>> {
+pub async fn handle_resp(
+    storage: &dyn Storage,
+    path: &str,
+) -> Response<BoxBody<Bytes, std::io::Error>> {
     let mut response = Response::new(empty());
-    let file = File::open(file_path).unwrap();
-    let metadata = file.metadata().unwrap();
-    let file_len = metadata.len();
-    let mime_type = from_path(file_path).first_or_octet_stream();
-    let last_modified = metadata.modified().unwrap();
+    let metadata = match storage.stat(path).await {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            *response.status_mut() = map_io_result::<()>(Err(err), StatusCode::OK);
+            return response;
+        }
+    };
+    let file_len = metadata.len;
+    let mime_type = from_path(path).first_or_octet_stream();
+    let last_modified = metadata.modified;
     response
         .headers_mut()
         .insert("Content-Type", format!("{}", mime_type).parse().unwrap());
@@ -21,9 +30,7 @@ pub async fn handle_resp(file_path: &PathBuf) -> Response<BoxBody<Bytes, std::io
         .insert("Content-Length", format!("{}", file_len).parse().unwrap());
     response.headers_mut().insert(
         "Last-Modified",
-        format!("{}", format_date_time(last_modified))
-            .parse()
-            .unwrap(),
+        format_date_time(last_modified).to_string().parse().unwrap(),
     );
 
     response
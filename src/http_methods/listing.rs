@@ -0,0 +1,290 @@
+use std::{cmp::Ordering, time::UNIX_EPOCH};
+
+use chrono::{DateTime, Local};
+use http_body_util::combinators::BoxBody;
+use hyper::{
+    body::{Bytes, Incoming},
+    Request, Response, StatusCode,
+};
+use quick_xml::escape::escape;
+use serde::Serialize;
+
+use crate::{
+    config::Rule,
+    http_methods::propfind::entry_href,
+    permission::Permissions,
+    storage::{archive::archive_kind, join_path, parent_path, Entry, Storage},
+    trash,
+    util::{empty, encode_uri, full, get_header, get_query_param, map_io_result},
+    versions,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+
+impl SortKey {
+    fn parse(value: &str) -> SortKey {
+        match value {
+            "size" => SortKey::Size,
+            "modified" => SortKey::Modified,
+            _ => SortKey::Name,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Modified => "modified",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonEntry {
+    name: String,
+    href: String,
+    is_dir: bool,
+    size: u64,
+    // unix 秒
+    modified: u64,
+}
+
+#[derive(Serialize)]
+struct JsonListing {
+    path: String,
+    // 共享根目录的地址，以 / 结尾
+    root: String,
+    // 当前用户的权限，网页据此隐藏不允许的操作
+    permission: String,
+    // 解析后的权限名，例如 ["list", "read", "create"]
+    permissions: Vec<&'static str>,
+    entries: Vec<JsonEntry>,
+}
+
+// GET 目录时返回文件列表，Accept 只要 application/json 时返回 JSON，否则返回 HTML
+pub async fn handle_resp(
+    req: &Request<Incoming>,
+    rule: &Rule,
+    storage: &dyn Storage,
+    path: &str,
+) -> Response<BoxBody<Bytes, std::io::Error>> {
+    let mut entries: Vec<Entry> = match storage.list(path).await {
+        Ok(entries) => entries,
+        Err(err) => {
+            let mut response = Response::new(empty());
+            *response.status_mut() = map_io_result::<()>(Err(err), StatusCode::OK);
+            return response;
+        }
+    };
+    entries.retain(|entry| {
+        let entry_path = join_path(path, &entry.name);
+        !trash::is_hidden(rule, &entry_path) && !versions::is_hidden(rule, &entry_path)
+    });
+    let key = SortKey::parse(&get_query_param(req, "sort").unwrap_or_default());
+    let desc = get_query_param(req, "order").as_deref() == Some("desc");
+    sort_entries(&mut entries, key, desc);
+
+    let (content_type, body) = if wants_json(req) {
+        ("application/json", json_listing(rule, path, &entries))
+    } else {
+        (
+            "text/html; charset=utf-8",
+            html_listing(rule, path, &entries, key, desc),
+        )
+    };
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", content_type)
+        .header("Content-Length", body.len())
+        .body(full(Bytes::from(body)))
+        .unwrap()
+}
+
+fn wants_json(req: &Request<Incoming>) -> bool {
+    let accept = get_header(req, "accept", "");
+    accept.contains("application/json") && !accept.contains("text/html")
+}
+
+// 目录总是排在文件前面
+fn sort_entries(entries: &mut [Entry], key: SortKey, desc: bool) {
+    entries.sort_by(|a, b| {
+        let ordering = match key {
+            SortKey::Name => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+            SortKey::Size => a.metadata.len.cmp(&b.metadata.len),
+            SortKey::Modified => a.metadata.modified.cmp(&b.metadata.modified),
+        }
+        .then_with(|| a.name.cmp(&b.name));
+        let ordering = if desc { ordering.reverse() } else { ordering };
+        match (a.metadata.is_dir, b.metadata.is_dir) {
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            _ => ordering,
+        }
+    });
+}
+
+// 目录的链接以 / 结尾，相对路径在浏览器中才能正确解析
+fn href(rule: &Rule, path: &str, is_dir: bool) -> String {
+    let href = encode_uri(&entry_href(rule, path));
+    if is_dir && !href.ends_with('/') {
+        format!("{}/", href)
+    } else {
+        href
+    }
+}
+
+fn json_listing(rule: &Rule, path: &str, entries: &[Entry]) -> String {
+    let listing = JsonListing {
+        path: path.to_string(),
+        root: href(rule, "/", true),
+        permission: rule.permission.to_uppercase(),
+        permissions: Permissions::of(rule).names(),
+        entries: entries
+            .iter()
+            .map(|entry| JsonEntry {
+                name: entry.name.to_string(),
+                href: href(rule, &join_path(path, &entry.name), entry.metadata.is_dir),
+                is_dir: entry.metadata.is_dir,
+                size: entry.metadata.len,
+                modified: entry
+                    .metadata
+                    .modified
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+            })
+            .collect(),
+    };
+    serde_json::to_string(&listing).unwrap()
+}
+
+fn format_size(len: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = len as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", len)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+// 路径导航：/ > a > b
+fn breadcrumbs(rule: &Rule, path: &str) -> String {
+    let mut html = format!(r#"<a href="{}">/</a>"#, href(rule, "/", true));
+    let mut current = String::from("/");
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        current = join_path(&current, name);
+        html.push_str(&format!(
+            r#" <a href="{}">{}</a> /"#,
+            href(rule, &current, true),
+            escape(name)
+        ));
+    }
+    html
+}
+
+// 表头链接：点击当前排序的列时切换升降序
+fn sort_link(column: SortKey, title: &str, key: SortKey, desc: bool) -> String {
+    let order = if column == key && !desc {
+        "desc"
+    } else {
+        "asc"
+    };
+    let arrow = match (column == key, desc) {
+        (true, false) => " ▲",
+        (true, true) => " ▼",
+        _ => "",
+    };
+    format!(
+        r#"<a href="?sort={}&amp;order={}">{}{}</a>"#,
+        column.as_str(),
+        order,
+        title,
+        arrow
+    )
+}
+
+fn html_listing(rule: &Rule, path: &str, entries: &[Entry], key: SortKey, desc: bool) -> String {
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str(r#"<meta name="viewport" content="width=device-width, initial-scale=1">"#);
+    html.push_str(&format!("\n<title>Index of {}</title>\n", escape(path)));
+    html.push_str(
+        "<style>body{font-family:sans-serif;margin:2em}table{border-collapse:collapse;width:100%}\
+         th,td{text-align:left;padding:4px 12px}th a{color:inherit}tr:hover{background:#f3f3f3}\
+         td.size{text-align:right;white-space:nowrap}th.size{text-align:right}</style>\n",
+    );
+    html.push_str("</head>\n<body>\n");
+    html.push_str(&format!("<h1>Index of {}</h1>\n", breadcrumbs(rule, path)));
+    let manage = Permissions::CREATE | Permissions::MOVE | Permissions::DELETE;
+    if Permissions::of(rule).intersects(manage) {
+        html.push_str(r#"<p><a href="?ui">Manage files</a></p>"#);
+        html.push('\n');
+    }
+    html.push_str("<table>\n<thead><tr>");
+    html.push_str(&format!(
+        "<th>{}</th>",
+        sort_link(SortKey::Name, "Name", key, desc)
+    ));
+    html.push_str(&format!(
+        r#"<th class="size">{}</th>"#,
+        sort_link(SortKey::Size, "Size", key, desc)
+    ));
+    html.push_str(&format!(
+        "<th>{}</th>",
+        sort_link(SortKey::Modified, "Modified", key, desc)
+    ));
+    html.push_str("</tr></thead>\n<tbody>\n");
+    if path != "/" {
+        html.push_str(&format!(
+            r#"<tr><td><a href="{}">../</a></td><td></td><td></td></tr>"#,
+            href(rule, &parent_path(path), true)
+        ));
+        html.push('\n');
+    }
+    for entry in entries {
+        let entry_path = join_path(path, &entry.name);
+        let is_dir = entry.metadata.is_dir;
+        let name = if is_dir {
+            format!("{}/", escape(&entry.name))
+        } else {
+            escape(&entry.name).to_string()
+        };
+        let size = if is_dir {
+            "-".to_string()
+        } else {
+            format_size(entry.metadata.len)
+        };
+        let modified = DateTime::<Local>::from(entry.metadata.modified).format("%Y-%m-%d %H:%M:%S");
+        // 浏览压缩包时压缩包显示为目录，另给出下载压缩包本身的链接
+        let raw = if is_dir && rule.browse_archives && archive_kind(&entry.name).is_some() {
+            format!(
+                r#" <a href="{}?raw">(download)</a>"#,
+                href(rule, &entry_path, false)
+            )
+        } else {
+            String::new()
+        };
+        html.push_str(&format!(
+            r#"<tr><td><a href="{}">{}</a>{}</td><td class="size">{}</td><td>{}</td></tr>"#,
+            href(rule, &entry_path, is_dir),
+            name,
+            raw,
+            size,
+            modified
+        ));
+        html.push('\n');
+    }
+    html.push_str("</tbody>\n</table>\n</body>\n</html>\n");
+    html
+}
@@ -0,0 +1,223 @@
+use std::path::PathBuf;
+
+use futures_util::stream;
+use http_body_util::{combinators::BoxBody, BodyExt};
+use hyper::{
+    body::{Bytes, Incoming},
+    Method, Request, Response, StatusCode,
+};
+
+use crate::{
+    config::Rule,
+    exmethod::ExtendMethod,
+    http_methods::archive_action,
+    lock::{self, Lock, DEFAULT_LOCK_TIMEOUT, MAX_LOCK_TIMEOUT},
+    share,
+    storage::Storage,
+    util::{
+        empty, encode_uri, full, get_destination_path, get_header, get_query_param, get_req_path,
+        map_io_result,
+    },
+};
+
+pub async fn handle_resp(
+    req: Request<Incoming>,
+    user: &str,
+    rule: &Rule,
+    storage: &dyn Storage,
+    path: &str,
+) -> Response<BoxBody<Bytes, std::io::Error>> {
+    let mut response = Response::new(empty());
+    let timeout = parse_timeout(get_header(&req, "timeout", ""));
+    let tokens = submitted_tokens(&req);
+    let depth = match get_header(&req, "depth", "infinity") {
+        "0" => "0",
+        _ => "infinity",
+    };
+    let href = encode_uri(&get_req_path(&req));
+    let file_path = resource(rule, path);
+    let body = match req.collect().await {
+        Ok(body) => String::from_utf8_lossy(&body.to_bytes()).to_string(),
+        Err(_) => {
+            *response.status_mut() = StatusCode::BAD_REQUEST;
+            return response;
+        }
+    };
+
+    // 没有请求体时是刷新已有的锁
+    if body.trim().is_empty() {
+        let token = tokens.first().cloned().unwrap_or_default();
+        return match lock::refresh(&token, user, &file_path, timeout) {
+            Some(lock) => lock_response(StatusCode::OK, &lock),
+            None => {
+                *response.status_mut() = StatusCode::PRECONDITION_FAILED;
+                response
+            }
+        };
+    }
+
+    let exclusive = !extract_element(&body, "lockscope")
+        .unwrap_or_default()
+        .contains("shared");
+    let owner = extract_element(&body, "owner").unwrap_or_default();
+    let lock = match lock::acquire(&href, &file_path, user, &owner, exclusive, depth, timeout) {
+        Some(lock) => lock,
+        None => {
+            *response.status_mut() = StatusCode::LOCKED;
+            return response;
+        }
+    };
+    // 锁定不存在的资源时创建一个空文件
+    let mut status_code = StatusCode::OK;
+    if storage.stat(path).await.is_err() {
+        let create_result = storage.write(path, Box::pin(stream::empty())).await;
+        status_code = map_io_result(create_result, StatusCode::CREATED);
+        if status_code != StatusCode::CREATED {
+            lock::release(&lock.token, user, &file_path);
+            *response.status_mut() = status_code;
+            return response;
+        }
+    }
+    lock_response(status_code, &lock)
+}
+
+fn lock_response(status_code: StatusCode, lock: &Lock) -> Response<BoxBody<Bytes, std::io::Error>> {
+    let scope = if lock.exclusive {
+        "exclusive"
+    } else {
+        "shared"
+    };
+    let mut xml = String::new();
+    xml.push_str(r#"<?xml version="1.0" encoding="utf-8"?>"#);
+    xml.push_str(r#"<D:prop xmlns:D="DAV:"><D:lockdiscovery><D:activelock>"#);
+    xml.push_str("<D:locktype><D:write/></D:locktype>");
+    xml.push_str(format!("<D:lockscope><D:{}/></D:lockscope>", scope).as_str());
+    xml.push_str(format!("<D:depth>{}</D:depth>", lock.depth).as_str());
+    if !lock.owner.is_empty() {
+        xml.push_str(format!("<D:owner>{}</D:owner>", lock.owner).as_str());
+    }
+    xml.push_str(format!("<D:timeout>Second-{}</D:timeout>", lock.timeout).as_str());
+    xml.push_str(format!("<D:locktoken><D:href>{}</D:href></D:locktoken>", lock.token).as_str());
+    xml.push_str(format!("<D:lockroot><D:href>{}</D:href></D:lockroot>", lock.href).as_str());
+    xml.push_str("</D:activelock></D:lockdiscovery></D:prop>\n");
+    Response::builder()
+        .status(status_code)
+        .header("Content-Type", "application/xml; charset=utf-8")
+        .header("Lock-Token", format!("<{}>", lock.token))
+        .body(full(Bytes::from(xml)))
+        .unwrap()
+}
+
+// Timeout: Second-3600 或 Infinite
+fn parse_timeout(timeout: &str) -> u64 {
+    let first = timeout.split(',').next().unwrap_or("").trim();
+    match first.strip_prefix("Second-") {
+        Some(seconds) => seconds
+            .parse::<u64>()
+            .map(|s| s.min(MAX_LOCK_TIMEOUT))
+            .unwrap_or(DEFAULT_LOCK_TIMEOUT),
+        None => DEFAULT_LOCK_TIMEOUT,
+    }
+}
+
+// 以共享目录加相对路径区分被锁定的资源，分享链接使用创建者的路径
+pub fn resource(rule: &Rule, path: &str) -> PathBuf {
+    PathBuf::from(format!(
+        "{}{}",
+        rule.path.trim_end_matches('/'),
+        share::owner_path(rule, path)
+    ))
+}
+
+// If 头和 Lock-Token 头中的所有 token，例如 (<opaquelocktoken:a>) <http://host/b> (<opaquelocktoken:b>)
+fn submitted_tokens(req: &Request<Incoming>) -> Vec<String> {
+    let headers = format!(
+        "{} {}",
+        get_header(req, "if", ""),
+        get_header(req, "lock-token", "")
+    );
+    headers
+        .split('<')
+        .filter_map(|part| part.split('>').next())
+        .filter(|token| token.starts_with("opaquelocktoken:"))
+        .map(|token| token.to_string())
+        .collect()
+}
+
+// 修改资源前检查锁，被别人锁定或没有带上锁的 token 时返回 false
+pub fn check(req: &Request<Incoming>, user: &str, rule: &Rule, path: &str) -> bool {
+    let method = req.method();
+    // (路径, 是否包括子资源, 是否增删路径本身)
+    let mut targets = Vec::new();
+    if *method == Method::PUT || *method == Method::from(ExtendMethod::MKCOL) {
+        targets.push((path.to_string(), false, true));
+    } else if *method == Method::DELETE {
+        targets.push((path.to_string(), true, true));
+    } else if *method == Method::from(ExtendMethod::MOVE)
+        || *method == Method::from(ExtendMethod::COPY)
+    {
+        if *method == Method::from(ExtendMethod::MOVE) {
+            targets.push((path.to_string(), true, true));
+        }
+        if let Some(destination) = get_destination_path(req, rule) {
+            targets.push((destination, true, true));
+        }
+    } else if *method == Method::from(ExtendMethod::PROPPATCH)
+        || *method == Method::from(ExtendMethod::VERSIONCONTROL)
+        || *method == Method::from(ExtendMethod::CHECKOUT)
+        || *method == Method::from(ExtendMethod::CHECKIN)
+        || *method == Method::from(ExtendMethod::UNCHECKOUT)
+    {
+        targets.push((path.to_string(), false, false));
+    } else if *method == Method::POST {
+        // 上传写入 path 目录下，解压和打包还会写入目标目录或文件
+        match get_query_param(req, "action").as_deref() {
+            Some("share") | Some("unshare") => {}
+            Some(action) => {
+                targets.push((path.to_string(), true, false));
+                if let Some(target) = archive_action::write_target(req, rule, action, path) {
+                    targets.push((target, true, true));
+                }
+            }
+            None => targets.push((path.to_string(), true, false)),
+        }
+    }
+    if targets.is_empty() {
+        return true;
+    }
+    let tokens = submitted_tokens(req);
+    targets.iter().all(|(target, recursive, member)| {
+        lock::check(&resource(rule, target), *recursive, *member, user, &tokens)
+    })
+}
+
+// Lock-Token 头中的 token，例如 (<opaquelocktoken:xxx>)
+pub fn extract_token(header: &str) -> String {
+    match (header.find('<'), header.find('>')) {
+        (Some(start), Some(end)) if start < end => header[start + 1..end].to_string(),
+        _ => header.trim().to_string(),
+    }
+}
+
+// 取出 xml 中某个元素的内容，忽略命名空间前缀
+fn extract_element(xml: &str, name: &str) -> Option<String> {
+    let mut offset = 0;
+    while let Some(found) = xml[offset..].find('<') {
+        let start = offset + found;
+        let tag_end = start + xml[start..].find('>')?;
+        let tag = &xml[start + 1..tag_end];
+        offset = tag_end;
+        let tag_name = tag.split_whitespace().next().unwrap_or("");
+        let local_name = tag_name.rsplit(':').next().unwrap_or("");
+        if tag.starts_with('/') || local_name != name {
+            continue;
+        }
+        if tag.ends_with('/') {
+            return Some(String::new());
+        }
+        let close = format!("</{}>", tag_name);
+        let content_end = tag_end + xml[tag_end..].find(&close)?;
+        return Some(xml[tag_end + 1..content_end].trim().to_string());
+    }
+    None
+}
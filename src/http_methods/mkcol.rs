@@ -1,15 +1,17 @@
-use std::{
-    fs, path::PathBuf
-};
-
 use http_body_util::combinators::BoxBody;
 use hyper::{body::Bytes, Response, StatusCode};
 
-use crate::util::{empty, map_io_result};
+use crate::{
+    storage::Storage,
+    util::{empty, map_io_result},
+};
 
-pub async fn handle_resp(dir_path: &PathBuf) -> Response<BoxBody<Bytes, std::io::Error>> {
+pub async fn handle_resp(
+    storage: &dyn Storage,
+    path: &str,
+) -> Response<BoxBody<Bytes, std::io::Error>> {
     let mut response = Response::new(empty());
-    let status_code = map_io_result(fs::create_dir_all(dir_path), StatusCode::CREATED);
+    let status_code = map_io_result(storage.mkdir(path).await, StatusCode::CREATED);
     response
         .headers_mut()
         .insert("Content-Length", format!("{}", 0).parse().unwrap());
@@ -1,9 +1,19 @@
+pub mod archive_action;
 pub mod copy;
 pub mod delete;
+pub mod deltav;
+pub mod download;
 pub mod exmove;
 pub mod get;
 pub mod head;
+pub mod listing;
+pub mod lock;
 pub mod mkcol;
 pub mod options;
+pub mod post;
 pub mod propfind;
 pub mod put;
+pub mod share;
+pub mod thumbnail;
+pub mod unlock;
+pub mod webui;
@@ -4,12 +4,11 @@ use hyper::{body::Bytes, Response, StatusCode};
 use crate::util::empty;
 
 pub async fn handle_resp() -> Response<BoxBody<Bytes, std::io::Error>> {
-    let allow_methods =
-        "OPTIONS, GET, HEAD, POST, PUT, DELETE, PROPFIND, MKCOL, COPY, MOVE, MKCOL, DELETE";
+    let allow_methods = "OPTIONS, GET, HEAD, POST, PUT, DELETE, PROPFIND, MKCOL, COPY, MOVE, LOCK, UNLOCK, VERSION-CONTROL, CHECKOUT, CHECKIN, UNCHECKOUT, REPORT";
     Response::builder()
         .status(StatusCode::OK)
         .header("Allow", allow_methods)
-        .header("DAV", "1")
+        .header("DAV", "1, 2, version-control")
         .body(empty())
         .unwrap()
 }
@@ -0,0 +1,335 @@
+use std::{io, path::Path};
+
+use futures_util::TryStreamExt;
+use http_body_util::{combinators::BoxBody, BodyExt};
+use hyper::{
+    body::{Bytes, Incoming},
+    Request, Response, StatusCode,
+};
+use multer::Multipart;
+use serde::Serialize;
+use sync_wrapper::SyncStream;
+
+use crate::{
+    config::{Config, Rule},
+    dropbox,
+    http_methods::{archive_action, share},
+    storage::{join_path, ByteStream, Storage},
+    thumbnails, trash,
+    util::{full, get_header, get_header_value, get_query_param, map_io_result, status_response},
+    versions,
+};
+
+// 表单上传遇到同名文件时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictPolicy {
+    // 跳过这个文件，在结果中报告冲突
+    Reject,
+    Overwrite,
+    // 改名为 "a (1).txt"、"a (2).txt" ...
+    Rename,
+}
+
+impl ConflictPolicy {
+    pub fn parse(value: &str) -> Option<ConflictPolicy> {
+        match value {
+            "reject" => Some(ConflictPolicy::Reject),
+            "overwrite" => Some(ConflictPolicy::Overwrite),
+            "" | "rename" => Some(ConflictPolicy::Rename),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Uploaded {
+    // 表单中的文件名
+    name: String,
+    // 写入的路径，改名后与 name 不同
+    path: String,
+    size: u64,
+}
+
+#[derive(Serialize)]
+struct Failed {
+    name: String,
+    status: u16,
+    error: String,
+}
+
+#[derive(Serialize, Default)]
+struct Summary {
+    uploaded: Vec<Uploaded>,
+    failed: Vec<Failed>,
+}
+
+// POST multipart/form-data 到目录：每个文件字段边接收边写入目录，返回 JSON 结果
+pub async fn handle_resp(
+    req: Request<Incoming>,
+    cfg: &Config,
+    rule: &Rule,
+    storage: &dyn Storage,
+    path: &str,
+) -> Response<BoxBody<Bytes, std::io::Error>> {
+    // 表单和简单请求可以由其他网站的页面跨站提交，这类请求必须来自同源页面或脚本
+    if !is_same_origin(&req) {
+        log::warn!("refused cross-site POST to {}", path);
+        return status_response(StatusCode::FORBIDDEN);
+    }
+    match get_query_param(&req, "action").as_deref() {
+        Some("share") => return share::create(req, cfg, rule, storage, path).await,
+        Some("unshare") => return share::remove(&req, cfg, rule),
+        Some(action) => return archive_action::handle_resp(&req, rule, action, path).await,
+        None => {}
+    }
+    match storage.stat(path).await {
+        Ok(metadata) if metadata.is_dir => {}
+        Ok(_) => return status_response(StatusCode::METHOD_NOT_ALLOWED),
+        Err(err) => return status_response(map_io_result::<()>(Err(err), StatusCode::OK)),
+    }
+    let boundary = match multer::parse_boundary(get_header(&req, "content-type", "")) {
+        Ok(boundary) => boundary,
+        Err(_) => return status_response(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+    };
+    let mut policy = ConflictPolicy::parse(&rule.upload_conflict).unwrap_or(ConflictPolicy::Rename);
+    // 投递箱不能覆盖已有的文件
+    if policy == ConflictPolicy::Overwrite && dropbox::is_drop_box(rule) {
+        policy = ConflictPolicy::Rename;
+    }
+    let mut multipart = Multipart::new(req.into_body().into_data_stream(), boundary);
+    let mut summary = Summary::default();
+    let mut malformed = false;
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(err) => {
+                log::error!("failed to parse upload to {}: {}", path, err);
+                malformed = true;
+                break;
+            }
+        };
+        // 只处理文件字段，其余字段忽略
+        let name = match field.file_name() {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let file_name = match sanitize_name(&name) {
+            Some(file_name) => file_name,
+            None => {
+                summary.failed.push(Failed {
+                    name,
+                    status: StatusCode::BAD_REQUEST.as_u16(),
+                    error: "invalid file name".to_string(),
+                });
+                continue;
+            }
+        };
+        // 不能上传到回收站和历史版本目录，也不能用这两个名字
+        if is_reserved(rule, &join_path(path, &file_name)) {
+            summary.failed.push(Failed {
+                name,
+                status: StatusCode::FORBIDDEN.as_u16(),
+                error: "reserved file name".to_string(),
+            });
+            continue;
+        }
+        let target = match resolve_target(storage, path, &file_name, policy).await {
+            Ok(target) => target,
+            Err(err) => {
+                summary.failed.push(failure(name, err));
+                continue;
+            }
+        };
+        let body: ByteStream = Box::pin(SyncStream::new(field.map_err(io::Error::other)));
+        match write_file(rule, storage, &target, body).await {
+            Ok(size) => {
+                log::info!("uploaded {} as {}", name, target);
+                summary.uploaded.push(Uploaded {
+                    name,
+                    path: target,
+                    size,
+                });
+            }
+            Err(err) => {
+                log::error!("failed to upload {} to {}: {}", name, target, err);
+                summary.failed.push(failure(name, err));
+            }
+        }
+    }
+    let status = if malformed {
+        StatusCode::BAD_REQUEST
+    } else if summary.failed.is_empty() && !summary.uploaded.is_empty() {
+        StatusCode::CREATED
+    } else if summary.uploaded.is_empty() {
+        // 没有文件上传成功时使用第一个失败的状态码
+        summary
+            .failed
+            .first()
+            .and_then(|failed| StatusCode::from_u16(failed.status).ok())
+            .unwrap_or(StatusCode::BAD_REQUEST)
+    } else {
+        StatusCode::OK
+    };
+    let uploaded = summary
+        .uploaded
+        .iter()
+        .map(|uploaded| uploaded.path.to_string())
+        .collect();
+    let mut response = Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(full(serde_json::to_string(&summary).unwrap()))
+        .unwrap();
+    response
+        .extensions_mut()
+        .insert(dropbox::Uploaded(uploaded));
+    response
+}
+
+fn failure(name: String, err: io::Error) -> Failed {
+    Failed {
+        name,
+        status: map_io_result::<()>(Err(io::Error::from(err.kind())), StatusCode::OK).as_u16(),
+        error: err.to_string(),
+    }
+}
+
+// 浏览器跨站提交时总会带上 Origin，带 X-Requested-With 的请求只能由同源脚本发出（跨站需要预检）。
+// 拒绝 Origin 与 Host 不一致的请求，以及浏览器标记为跨站又带着 Cookie 或 Authorization 的请求，
+// 没有 Origin 的请求（例如 curl）允许
+fn is_same_origin<B>(req: &Request<B>) -> bool {
+    if get_header_value(req, "x-requested-with").is_some() {
+        return true;
+    }
+    if let Some(origin) = get_header_value(req, "origin") {
+        let host = origin.split_once("://").map(|(_, host)| host);
+        return host.is_some() && host == get_header_value(req, "host");
+    }
+    let credentials = get_header_value(req, "cookie").is_some()
+        || get_header_value(req, "authorization").is_some();
+    !(credentials && get_header_value(req, "sec-fetch-site") == Some("cross-site"))
+}
+
+fn is_reserved(rule: &Rule, path: &str) -> bool {
+    trash::is_trash_path(rule, path) || versions::is_versions_path(rule, path)
+}
+
+// 只取文件名部分，有的浏览器会带上客户端的路径
+fn sanitize_name(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next()?.trim();
+    if name.is_empty() || name == "." || name == ".." {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+// 按冲突处理方式决定写入的路径
+pub async fn resolve_target(
+    storage: &dyn Storage,
+    dir: &str,
+    name: &str,
+    policy: ConflictPolicy,
+) -> io::Result<String> {
+    let target = join_path(dir, name);
+    let existing = match storage.stat(&target).await {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(target),
+        Err(err) => return Err(err),
+    };
+    match policy {
+        ConflictPolicy::Overwrite if !existing.is_dir => Ok(target),
+        ConflictPolicy::Reject | ConflictPolicy::Overwrite => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", target),
+        )),
+        ConflictPolicy::Rename => {
+            let path = Path::new(name);
+            let stem = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_else(|| name.to_string());
+            let extension = path
+                .extension()
+                .map(|extension| format!(".{}", extension.to_string_lossy()))
+                .unwrap_or_default();
+            let mut counter = 1;
+            loop {
+                let target = join_path(dir, &format!("{} ({}){}", stem, counter, extension));
+                match storage.stat(&target).await {
+                    Ok(_) => counter += 1,
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(target),
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+    }
+}
+
+// 与 PUT 相同，覆盖时保存历史版本
+pub async fn write_file(
+    rule: &Rule,
+    storage: &dyn Storage,
+    path: &str,
+    body: ByteStream,
+) -> io::Result<u64> {
+    let mut save_new = false;
+    if let Some(config) = &rule.versions {
+        save_new = versions::before_write(storage, config, path).await?;
+    }
+    let result = storage.write(path, body).await;
+    if let Some(config) = &rule.versions {
+        versions::after_write(storage, config, path, save_new && result.is_ok()).await;
+    }
+    thumbnails::invalidate(rule, path).await;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> Request<()> {
+        let mut builder = Request::post("/a/docs/");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn same_origin_check() {
+        // curl -F -u 没有 Origin
+        assert!(is_same_origin(&request(&[
+            ("host", "example.com"),
+            ("authorization", "Basic dTpw")
+        ])));
+        assert!(is_same_origin(&request(&[
+            ("host", "example.com"),
+            ("origin", "http://example.com")
+        ])));
+        assert!(is_same_origin(&request(&[
+            ("host", "example.com"),
+            ("origin", "https://evil.test"),
+            ("x-requested-with", "XMLHttpRequest")
+        ])));
+        assert!(!is_same_origin(&request(&[
+            ("host", "example.com"),
+            ("origin", "https://evil.test"),
+            ("authorization", "Basic dTpw")
+        ])));
+        assert!(!is_same_origin(&request(&[
+            ("host", "example.com"),
+            ("origin", "null")
+        ])));
+        assert!(!is_same_origin(&request(&[
+            ("host", "example.com"),
+            ("sec-fetch-site", "cross-site"),
+            ("cookie", "a=b")
+        ])));
+        assert!(is_same_origin(&request(&[
+            ("host", "example.com"),
+            ("sec-fetch-site", "cross-site")
+        ])));
+    }
+}
@@ -1,28 +1,87 @@
-use std::{fs, path::PathBuf};
+use std::collections::HashSet;
 
-use http_body_util::combinators::BoxBody;
+use http_body_util::{combinators::BoxBody, BodyExt};
 use hyper::{
     body::{Bytes, Incoming},
     Request, Response, StatusCode,
 };
 use mime_guess::from_path;
 
-use crate::util::{encode_uri, format_date_time, full, get_base_dir, get_header, get_server_prefix};
+use quick_xml::escape::escape;
 
-pub async fn handle_resp(req: &Request<Incoming>, file_path: PathBuf) -> Response<BoxBody<Bytes, std::io::Error>> {
-    let depth = get_header(req, "depth", "0");
+use crate::{
+    config::Rule,
+    dropbox,
+    media::{self, MediaInfo},
+    share,
+    storage::{self, file_name, join_path, Metadata, Storage},
+    trash,
+    util::{empty, encode_uri, format_date_time, full, get_header, map_io_result},
+    versions,
+};
+
+// 非 DAV: 的属性使用的命名空间
+pub const RHFS_NAMESPACE: &str = "urn:rhfs";
+
+pub async fn handle_resp(
+    req: Request<Incoming>,
+    rule: &Rule,
+    storage: &dyn Storage,
+    path: &str,
+    visible: Option<&HashSet<String>>,
+) -> Response<BoxBody<Bytes, std::io::Error>> {
+    let depth = get_header(&req, "depth", "0").to_string();
+    let body = match req.collect().await {
+        Ok(body) => String::from_utf8_lossy(&body.to_bytes()).to_string(),
+        Err(_) => {
+            let mut response = Response::new(empty());
+            *response.status_mut() = StatusCode::BAD_REQUEST;
+            return response;
+        }
+    };
+    // 版本状态要逐个读取版本目录，列目录时只在请求了这两个属性时返回
+    let child_versions = body.contains("checked-in") || body.contains("checked-out");
+    let metadata = match storage.stat(path).await {
+        Ok(metadata) => metadata,
+        Err(err) => return error_response(err),
+    };
     let mut multistatus_xml = String::new();
     multistatus_xml.push_str(r#"<?xml version="1.0" encoding="utf-8"?>"#);
-    multistatus_xml.push_str(r#"<D:multistatus xmlns:D="DAV:">"#);
+    multistatus_xml.push_str(&format!(
+        r#"<D:multistatus xmlns:D="DAV:" xmlns:R="{}">"#,
+        RHFS_NAMESPACE
+    ));
     log::info!("start depth");
-    if depth == "0" {
-        generate_content_xml(req, &mut multistatus_xml, file_path);
+    if depth == "0" || !metadata.is_dir {
+        let mut extra_props = extra_props(rule, storage, path, true).await;
+        extra_props.push_str(&media_props(rule, path, &metadata, true).await);
+        generate_content_xml(rule, &mut multistatus_xml, path, &metadata, &extra_props);
     } else {
-        for entry in fs::read_dir(&file_path).unwrap() {
-            if let Ok(entry) = entry {
-                let entry_path = entry.path();
-                generate_content_xml(req, &mut multistatus_xml, entry_path);
+        let entries = match storage.list(path).await {
+            Ok(entries) => entries,
+            Err(err) => return error_response(err),
+        };
+        for entry in entries {
+            let entry_path = join_path(path, &entry.name);
+            if trash::is_hidden(rule, &entry_path) || versions::is_hidden(rule, &entry_path) {
+                continue;
+            }
+            // 投递箱只列出本次会话新建的内容
+            if let Some(created) = visible {
+                if !dropbox::is_visible(created, &entry_path) {
+                    continue;
+                }
             }
+            let with_versions = child_versions && !entry.metadata.is_dir;
+            let mut extra_props = extra_props(rule, storage, &entry_path, with_versions).await;
+            extra_props.push_str(&media_props(rule, &entry_path, &entry.metadata, false).await);
+            generate_content_xml(
+                rule,
+                &mut multistatus_xml,
+                &entry_path,
+                &entry.metadata,
+                &extra_props,
+            );
         }
     }
     multistatus_xml.push_str("</D:multistatus>\n");
@@ -35,35 +94,132 @@ pub async fn handle_resp(req: &Request<Incoming>, file_path: PathBuf) -> Respons
         .unwrap()
 }
 
+fn error_response(err: std::io::Error) -> Response<BoxBody<Bytes, std::io::Error>> {
+    let mut response = Response::new(empty());
+    *response.status_mut() = map_io_result::<()>(Err(err), StatusCode::OK);
+    response
+}
+
+async fn extra_props(
+    rule: &Rule,
+    storage: &dyn Storage,
+    path: &str,
+    with_versions: bool,
+) -> String {
+    let mut props = trash_props(rule, storage, path).await;
+    if with_versions {
+        props.push_str(&version_props(rule, storage, path).await);
+    }
+    props
+}
+
+// 回收站中的文件显示原路径、删除时间和删除者
+async fn trash_props(rule: &Rule, storage: &dyn Storage, path: &str) -> String {
+    if !trash::is_trash_path(rule, path) {
+        return String::new();
+    }
+    let info = match trash::item_name(path) {
+        Some(name) => match trash::read_info(storage, name).await {
+            Ok(info) => info,
+            Err(_) => return String::new(),
+        },
+        None => return String::new(),
+    };
+    format!(
+        "<R:original-path>{}</R:original-path>\n<R:deleted-at>{}</R:deleted-at>\n<R:deleted-by>{}</R:deleted-by>\n<R:trash-size>{}</R:trash-size>\n",
+        escape(&info.path),
+        format_date_time(info.deleted_time()),
+        escape(&info.deleted_by),
+        info.size
+    )
+}
+
+// 受版本控制的文件显示 DAV:checked-in 或 DAV:checked-out，指向最新版本
+async fn version_props(rule: &Rule, storage: &dyn Storage, path: &str) -> String {
+    if rule.versions.is_none() || versions::is_versions_path(rule, path) {
+        return String::new();
+    }
+    let name = match versions::state(storage, path).await {
+        Ok(versions::State::CheckedIn) => "checked-in",
+        Ok(versions::State::CheckedOut) => "checked-out",
+        _ => return String::new(),
+    };
+    let latest = match versions::list(storage, path).await {
+        Ok(list) => match list.last() {
+            Some((_, entry)) => join_path(&versions::versions_dir(path), &entry.name),
+            None => return String::new(),
+        },
+        Err(_) => return String::new(),
+    };
+    let href = encode_uri(&entry_href(rule, &latest));
+    format!("<D:{}><D:href>{}</D:href></D:{}>\n", name, href, name)
+}
+
+// 启用 media_metadata 时显示从文件内容读取的信息，列目录时只使用已缓存的
+async fn media_props(rule: &Rule, path: &str, metadata: &Metadata, wait: bool) -> String {
+    if !rule.media_metadata {
+        return String::new();
+    }
+    let storage = match storage::for_rule(rule) {
+        Ok(storage) => storage,
+        Err(_) => return String::new(),
+    };
+    // 通过分享访问时与创建者使用同一份缓存
+    let rule_path = format!("{}{}", rule.path, share::root_prefix(rule));
+    match media::lookup(&rule_path, storage, path, metadata, wait).await {
+        Some(info) => format_media_props(&info),
+        None => String::new(),
+    }
+}
+
+fn format_media_props(info: &MediaInfo) -> String {
+    let mut props = String::new();
+    let mut push = |name: &str, value: Option<String>| {
+        if let Some(value) = value {
+            props.push_str(&format!("<R:{}>{}</R:{}>\n", name, escape(&value), name));
+        }
+    };
+    push("width", info.width.map(|width| width.to_string()));
+    push("height", info.height.map(|height| height.to_string()));
+    push("capture-date", info.capture_date.clone());
+    push(
+        "duration",
+        info.duration.map(|duration| format!("{:.3}", duration)),
+    );
+    push("title", info.title.clone());
+    push("artist", info.artist.clone());
+    push("album", info.album.clone());
+    push("year", info.year.map(|year| year.to_string()));
+    props
+}
+
+// 资源的访问路径：webdav 前缀加上相对共享根目录的路径
+pub fn entry_href(rule: &Rule, path: &str) -> String {
+    format!("{}{}", rule.server_prefix.trim_end_matches('/'), path)
+}
+
 fn generate_content_xml(
-    req: &Request<Incoming>,
+    rule: &Rule,
     multistatus_xml: &mut String,
-    entry_path: PathBuf,
+    entry_path: &str,
+    metadata: &Metadata,
+    extra_props: &str,
 ) {
-    let base_dir = get_base_dir(req);
-    let mut server_prefix_with_suffix = get_server_prefix(req).to_string();
-    if !server_prefix_with_suffix.ends_with("/") {
-        server_prefix_with_suffix += "/";
-    }
-    let mut relative_path = entry_path.to_string_lossy().to_owned().to_string();
-    relative_path = relative_path.replacen(&base_dir, &server_prefix_with_suffix, 1);
+    let relative_path = entry_href(rule, entry_path);
     multistatus_xml.push_str("<D:response>\n");
     let encode_relative_path = encode_uri(&relative_path);
-    multistatus_xml
-        .push_str(format!("<D:href>{}</D:href>\n", format!("{}", encode_relative_path)).as_str());
+    multistatus_xml.push_str(format!("<D:href>{}</D:href>\n", encode_relative_path).as_str());
     multistatus_xml.push_str("<D:propstat>\n");
     multistatus_xml.push_str("<D:prop>\n");
 
-    let is_dir = entry_path.is_dir();
-    let metadata = fs::metadata(&entry_path).unwrap();
-    let last_modified = metadata.modified().unwrap();
-    if is_dir {
+    let last_modified = metadata.modified;
+    if metadata.is_dir {
         multistatus_xml.push_str("<D:resourcetype><D:collection/></D:resourcetype>\n");
     } else {
-        let mime_type = from_path(&entry_path).first_or_octet_stream().to_string();
-        let content_length = metadata.len();
-        multistatus_xml.push_str(format!("<D:resourcetype/>\n").as_str());
-        multistatus_xml.push_str(format!("<D:supportedlock/>\n").as_str());
+        let mime_type = from_path(entry_path).first_or_octet_stream().to_string();
+        let content_length = metadata.len;
+        multistatus_xml.push_str("<D:resourcetype/>\n");
+        multistatus_xml.push_str("<D:supportedlock/>\n");
         multistatus_xml.push_str(
             format!(
                 "<D:getcontentlength>{}</D:getcontentlength>\n",
@@ -74,7 +230,9 @@ fn generate_content_xml(
         multistatus_xml
             .push_str(format!("<D:getcontenttype>{}</D:getcontenttype>\n", mime_type).as_str());
     }
-    let mtime = last_modified.duration_since(std::time::UNIX_EPOCH).unwrap();
+    let mtime = last_modified
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
     let mtime_secs = mtime.as_secs();
     let etag = md5::compute(mtime_secs.to_string());
     multistatus_xml.push_str(format!("<D:getetag>{:?}</D:getetag>\n", etag).as_str());
@@ -85,18 +243,13 @@ fn generate_content_xml(
         )
         .as_str(),
     );
-    // let creationdate = get_creation_date(&entry_path.to_string_lossy());
-    // if creationdate.len() > 0 {
-    //     multistatus_xml
-    //         .push_str(format!("<D:creationdate>{}</D:creationdate>\n", creationdate).as_str());
-    // }
-    multistatus_xml.push_str(
-        format!(
-            "<D:displayname>{}</D:displayname>\n",
-            entry_path.file_name().unwrap().to_str().unwrap()
-        )
-        .as_str(),
-    );
+    // 根目录没有文件名，使用共享目录的名字
+    let mut display_name = file_name(entry_path);
+    if display_name.is_empty() {
+        display_name = file_name(&rule.path);
+    }
+    multistatus_xml.push_str(format!("<D:displayname>{}</D:displayname>\n", display_name).as_str());
+    multistatus_xml.push_str(extra_props);
     multistatus_xml.push_str("</D:prop>\n");
     multistatus_xml.push_str("<D:status>HTTP/1.1 200 OK</D:status>\n");
     multistatus_xml.push_str("</D:propstat>\n");
@@ -1,31 +1,45 @@
-use std::{fs::File, io::Write, path::PathBuf};
-
-use http_body_util::{combinators::BoxBody, BodyExt};
+use http_body_util::combinators::BoxBody;
 use hyper::{
     body::{Bytes, Incoming},
     Request, Response, StatusCode,
 };
 
-use crate::util::{decode_path, empty, map_io_result};
+use crate::{
+    config::Rule,
+    storage::Storage,
+    thumbnails,
+    util::{body_stream, empty, map_io_result},
+    versions,
+};
 
 pub async fn handle_resp(
     req: Request<Incoming>,
-    path: &PathBuf,
-) -> Result<Response<BoxBody<Bytes, std::io::Error>>, Box<dyn std::error::Error>> {
+    rule: &Rule,
+    storage: &dyn Storage,
+    path: &str,
+) -> Response<BoxBody<Bytes, std::io::Error>> {
     // 创建响应
     let mut response = Response::new(empty());
-    *response.status_mut() = StatusCode::CREATED;
-    // 创建用于写入的文件
-    let file_result = File::create(decode_path(path));
-    if file_result.is_err() {
-        let status_code = map_io_result(file_result, StatusCode::CREATED);
-        *response.status_mut() = status_code;
-        return Ok(response);
+    // 覆盖前保存旧内容，受版本控制的文件需要先 CHECKOUT
+    let mut save_new = false;
+    if let Some(config) = &rule.versions {
+        match versions::before_write(storage, config, path).await {
+            Ok(save) => save_new = save,
+            Err(err) => {
+                log::error!("cannot overwrite {}: {}", path, err);
+                *response.status_mut() = map_io_result::<()>(Err(err), StatusCode::CREATED);
+                return response;
+            }
+        }
+    }
+    // 边接收边写入，不把整个请求体读入内存
+    let write_result = storage.write(path, body_stream(req)).await;
+    if let Some(config) = &rule.versions {
+        versions::after_write(storage, config, path, save_new && write_result.is_ok()).await;
     }
-    let mut file = file_result.unwrap();
-    let whole_body = req.collect().await?.to_bytes();
-    let write_result = file.write_all(&whole_body);
+    // 即使写入失败，原文件也可能已被截断
+    thumbnails::invalidate(rule, path).await;
     let status_code = map_io_result(write_result, StatusCode::CREATED);
     *response.status_mut() = status_code;
-    Ok(response)
+    response
 }
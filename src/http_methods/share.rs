@@ -0,0 +1,211 @@
+use http_body_util::{combinators::BoxBody, BodyExt};
+use hyper::{
+    body::{Bytes, Incoming},
+    Request, Response, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::hash_password,
+    config::{Config, Rule, SharesConfig},
+    share::{self, Share, ShareMode},
+    storage::Storage,
+    trash,
+    util::{full, get_header, get_query_param, map_io_result, parse_duration, status_response},
+    versions,
+};
+
+// POST ?action=share 的请求体，都可以省略
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ShareInput {
+    // 为空时为 read
+    mode: Option<ShareMode>,
+    // 有效期，例如 "7d"，为空时永不过期
+    expires: String,
+    password: String,
+    max_downloads: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct ShareView {
+    token: String,
+    href: String,
+    // 根据 Host 生成的完整链接
+    #[serde(skip_serializing_if = "String::is_empty")]
+    url: String,
+    path: String,
+    mode: ShareMode,
+    created: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires: Option<String>,
+    max_downloads: u64,
+    downloads: u64,
+    has_password: bool,
+}
+
+impl ShareView {
+    fn new(host: &str, config: &SharesConfig, share: &Share) -> ShareView {
+        let href = format!("{}/{}", share::prefix(config), share.token);
+        let url = if host.is_empty() {
+            String::new()
+        } else {
+            format!("http://{}{}", host, href)
+        };
+        ShareView {
+            token: share.token.to_string(),
+            href,
+            url,
+            path: share.path.to_string(),
+            mode: share.mode,
+            created: share::format_time(share.created),
+            expires: (share.expires != 0).then(|| share::format_time(share.expires)),
+            max_downloads: share.max_downloads,
+            downloads: share.downloads,
+            has_password: !share.password.is_empty(),
+        }
+    }
+}
+
+fn json_response<T: Serialize>(
+    status: StatusCode,
+    value: &T,
+) -> Response<BoxBody<Bytes, std::io::Error>> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(full(serde_json::to_vec(value).unwrap()))
+        .unwrap()
+}
+
+// 未启用分享或通过分享链接访问时不能管理分享
+fn shares_config<'a>(cfg: &'a Config, rule: &Rule) -> Result<&'a SharesConfig, StatusCode> {
+    match &cfg.shares {
+        Some(_) if rule.share.is_some() => Err(StatusCode::FORBIDDEN),
+        Some(config) => Ok(config),
+        None => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+// POST <path>?action=share 新建分享，返回分享链接
+pub async fn create(
+    req: Request<Incoming>,
+    cfg: &Config,
+    rule: &Rule,
+    storage: &dyn Storage,
+    path: &str,
+) -> Response<BoxBody<Bytes, std::io::Error>> {
+    let config = match shares_config(cfg, rule) {
+        Ok(config) => config,
+        Err(status) => return status_response(status),
+    };
+    if trash::is_trash_path(rule, path) || versions::is_versions_path(rule, path) {
+        return status_response(StatusCode::FORBIDDEN);
+    }
+    let metadata = match storage.stat(path).await {
+        Ok(metadata) => metadata,
+        Err(err) => return status_response(map_io_result::<()>(Err(err), StatusCode::OK)),
+    };
+    let host = get_header(&req, "host", "").to_string();
+    let body = match req.into_body().collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return status_response(StatusCode::BAD_REQUEST),
+    };
+    let input: ShareInput = if body.iter().all(|b| b.is_ascii_whitespace()) {
+        ShareInput::default()
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(input) => input,
+            Err(err) => {
+                log::error!("invalid share request: {}", err);
+                return status_response(StatusCode::BAD_REQUEST);
+            }
+        }
+    };
+    let mode = input.mode.unwrap_or(ShareMode::Read);
+    // 上传分享只能是目录
+    if mode == ShareMode::Upload && !metadata.is_dir {
+        return status_response(StatusCode::CONFLICT);
+    }
+    let expires = if input.expires.is_empty() {
+        0
+    } else {
+        match parse_duration(&input.expires) {
+            Some(duration) if !duration.is_zero() => share::now() + duration.as_secs(),
+            _ => {
+                log::error!("invalid share expiry {}", input.expires);
+                return status_response(StatusCode::BAD_REQUEST);
+            }
+        }
+    };
+    let password = if input.password.is_empty() {
+        String::new()
+    } else {
+        match hash_password(&input.password) {
+            Ok(hash) => hash,
+            Err(err) => {
+                log::error!("failed to hash share password: {}", err);
+                return status_response(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+    };
+    let created = share::create(
+        config,
+        Share {
+            token: String::new(),
+            owner: share::owner_of(rule).to_string(),
+            path: path.to_string(),
+            mode,
+            created: 0,
+            expires,
+            password,
+            max_downloads: input.max_downloads,
+            downloads: 0,
+        },
+    );
+    log::info!("created {:?} share of {}", mode, path);
+    json_response(
+        StatusCode::CREATED,
+        &ShareView::new(&host, config, &created),
+    )
+}
+
+// POST ?action=unshare&token=T 删除自己创建的分享
+pub fn remove(
+    req: &Request<Incoming>,
+    cfg: &Config,
+    rule: &Rule,
+) -> Response<BoxBody<Bytes, std::io::Error>> {
+    let config = match shares_config(cfg, rule) {
+        Ok(config) => config,
+        Err(status) => return status_response(status),
+    };
+    let token = match get_query_param(req, "token") {
+        Some(token) => token,
+        None => return status_response(StatusCode::BAD_REQUEST),
+    };
+    if share::remove(config, &token, Some(share::owner_of(rule))).is_some() {
+        status_response(StatusCode::NO_CONTENT)
+    } else {
+        status_response(StatusCode::NOT_FOUND)
+    }
+}
+
+// GET <path>?shares 列出自己在 path 下创建的分享
+pub fn list(
+    req: &Request<Incoming>,
+    cfg: &Config,
+    rule: &Rule,
+    path: &str,
+) -> Response<BoxBody<Bytes, std::io::Error>> {
+    let config = match shares_config(cfg, rule) {
+        Ok(config) => config,
+        Err(status) => return status_response(status),
+    };
+    let host = get_header(req, "host", "");
+    let shares: Vec<ShareView> = share::list(config, Some(share::owner_of(rule)), Some(path))
+        .iter()
+        .map(|share| ShareView::new(host, config, share))
+        .collect();
+    json_response(StatusCode::OK, &shares)
+}
@@ -0,0 +1,145 @@
+use futures_util::StreamExt;
+use http_body_util::combinators::BoxBody;
+use hyper::{
+    body::{Bytes, Incoming},
+    Request, Response, StatusCode,
+};
+
+use crate::{
+    config::{Rule, ThumbnailsConfig},
+    storage::{Metadata, Storage},
+    thumbnails,
+    util::{full, get_header, get_query_param, map_io_result, status_response},
+};
+
+// ?thumbnail=WxH 或 ?thumbnail=N（N x N），宽高不能超过 max_dimension
+pub fn requested_size<B>(
+    req: &Request<B>,
+    config: &ThumbnailsConfig,
+) -> Option<Result<(u32, u32), String>> {
+    let value = get_query_param(req, "thumbnail")?;
+    let (width, height) = value.split_once(['x', 'X']).unwrap_or((&value, &value));
+    let max = thumbnails::max_dimension(config);
+    match (width.parse::<u32>(), height.parse::<u32>()) {
+        (Ok(width), Ok(height)) if (1..=max).contains(&width) && (1..=max).contains(&height) => {
+            Some(Ok((width, height)))
+        }
+        _ => Some(Err(value)),
+    }
+}
+
+// 优先使用缓存，没有时读取原图生成缩略图并写入缓存
+pub async fn handle_resp(
+    req: &Request<Incoming>,
+    rule: &Rule,
+    config: &ThumbnailsConfig,
+    storage: &dyn Storage,
+    path: &str,
+    metadata: &Metadata,
+    (width, height): (u32, u32),
+) -> Response<BoxBody<Bytes, std::io::Error>> {
+    let key = thumbnails::source_key(metadata);
+    let etag = format!("\"{}-{}x{}\"", key, width, height);
+    if get_header(req, "if-none-match", "") == etag {
+        let mut response = status_response(StatusCode::NOT_MODIFIED);
+        response.headers_mut().insert("ETag", etag.parse().unwrap());
+        return response;
+    }
+    let data = match thumbnails::read_cached(config, rule, path, &key, width, height).await {
+        Some(data) => data,
+        None => {
+            let _lock = thumbnails::lock_render(rule, path, &key, width, height).await;
+            // 等待期间可能已经由同时到达的请求生成
+            match thumbnails::read_cached(config, rule, path, &key, width, height).await {
+                Some(data) => data,
+                None => match generate(config, storage, path, metadata, (width, height)).await {
+                    Ok(data) => {
+                        thumbnails::write_cached(config, rule, path, &key, width, height, &data)
+                            .await;
+                        data
+                    }
+                    Err(status) => return status_response(status),
+                },
+            }
+        }
+    };
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", thumbnails::content_type(&data))
+        .header("Content-Length", data.len())
+        .header("ETag", etag)
+        .body(full(data))
+        .unwrap()
+}
+
+// 读取原图生成缩略图，失败时返回响应的状态码
+async fn generate(
+    config: &ThumbnailsConfig,
+    storage: &dyn Storage,
+    path: &str,
+    metadata: &Metadata,
+    (width, height): (u32, u32),
+) -> Result<Vec<u8>, StatusCode> {
+    if metadata.len > thumbnails::max_source_size(config) {
+        log::error!("{} is too large for a thumbnail", path);
+        return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+    let _permit = thumbnails::render_permit().await;
+    let mut stream = storage
+        .read(path, None)
+        .await
+        .map_err(|err| map_io_result::<()>(Err(err), StatusCode::OK))?;
+    let mut source = Vec::with_capacity(metadata.len as usize);
+    while let Some(chunk) = stream.next().await {
+        match chunk {
+            Ok(chunk) => source.extend_from_slice(&chunk),
+            Err(err) => {
+                log::error!("failed to read {}: {}", path, err);
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+    }
+    let max_source = thumbnails::max_source_dimension(config);
+    let result =
+        tokio::task::spawn_blocking(move || thumbnails::render(&source, width, height, max_source))
+            .await
+            .map_err(std::io::Error::other)
+            .and_then(|result| result);
+    result.map_err(|err| {
+        log::error!("failed to create thumbnail of {}: {}", path, err);
+        StatusCode::UNSUPPORTED_MEDIA_TYPE
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size(query: &str, max_dimension: u32) -> Option<Result<(u32, u32), String>> {
+        let req = Request::get(format!("/a/b.jpg{}", query)).body(()).unwrap();
+        let config = ThumbnailsConfig {
+            max_dimension,
+            ..ThumbnailsConfig::default()
+        };
+        requested_size(&req, &config)
+    }
+
+    #[test]
+    fn requested_size_parses_and_limits() {
+        assert_eq!(size("", 0), None);
+        assert_eq!(size("?other=1", 0), None);
+        assert_eq!(size("?thumbnail=320x240", 0), Some(Ok((320, 240))));
+        assert_eq!(size("?thumbnail=320X240", 0), Some(Ok((320, 240))));
+        assert_eq!(size("?thumbnail=256", 0), Some(Ok((256, 256))));
+        assert_eq!(size("?thumbnail=1024", 0), Some(Ok((1024, 1024))));
+        assert_eq!(size("?thumbnail=1025", 0), Some(Err("1025".to_string())));
+        assert_eq!(
+            size("?thumbnail=200x100", 100),
+            Some(Err("200x100".to_string()))
+        );
+        assert_eq!(size("?thumbnail=0x10", 0), Some(Err("0x10".to_string())));
+        assert_eq!(size("?thumbnail=-1", 0), Some(Err("-1".to_string())));
+        assert_eq!(size("?thumbnail=10x", 0), Some(Err("10x".to_string())));
+        assert_eq!(size("?thumbnail=", 0), Some(Err("".to_string())));
+    }
+}
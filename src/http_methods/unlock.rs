@@ -0,0 +1,30 @@
+use http_body_util::combinators::BoxBody;
+use hyper::{
+    body::{Bytes, Incoming},
+    Request, Response, StatusCode,
+};
+
+use crate::{
+    config::Rule,
+    http_methods::lock::{extract_token, resource},
+    lock,
+    util::{empty, get_header},
+};
+
+// 只能解除自己的、作用于请求路径的锁
+pub async fn handle_resp(
+    req: &Request<Incoming>,
+    user: &str,
+    rule: &Rule,
+    path: &str,
+) -> Response<BoxBody<Bytes, std::io::Error>> {
+    let mut response = Response::new(empty());
+    let token = extract_token(get_header(req, "lock-token", ""));
+    let status_code = if lock::release(&token, user, &resource(rule, path)) {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::CONFLICT
+    };
+    *response.status_mut() = status_code;
+    response
+}
@@ -0,0 +1,17 @@
+use http_body_util::combinators::BoxBody;
+use hyper::{body::Bytes, Response, StatusCode};
+
+use crate::util::full;
+
+// 浏览器中管理文件的页面，通过 JSON 列表和 WebDAV 方法操作当前目录
+const INDEX_HTML: &str = include_str!("webui.html");
+
+pub fn handle_resp() -> Response<BoxBody<Bytes, std::io::Error>> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "text/html; charset=utf-8")
+        .header("Content-Length", INDEX_HTML.len())
+        .header("Cache-Control", "no-cache")
+        .body(full(INDEX_HTML))
+        .unwrap()
+}
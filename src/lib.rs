@@ -1,6 +1,22 @@
+pub mod admin;
+pub mod auth;
 pub mod cache;
+pub mod cli;
 pub mod config;
+pub mod dropbox;
 pub mod exmethod;
 pub mod http_methods;
+pub mod lock;
+pub mod media;
+pub mod pack;
+pub mod permission;
+pub mod reload;
 pub mod server;
+pub mod session;
+pub mod share;
+pub mod storage;
+pub mod thumbnails;
+pub mod trash;
 pub mod util;
+pub mod validate;
+pub mod versions;
@@ -0,0 +1,193 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use crate::util::format_date_time;
+
+// 客户端未指定或要求 Infinite 时使用的超时时间
+pub const DEFAULT_LOCK_TIMEOUT: u64 = 3600;
+pub const MAX_LOCK_TIMEOUT: u64 = 7 * 24 * 3600;
+
+lazy_static! {
+    // key 为锁的 token，修改被锁定的资源时需要在 If 头中带上 token，见 check
+    static ref LOCKS: Mutex<HashMap<String, Lock>> = Mutex::new(HashMap::new());
+    static ref LOCK_COUNTER: Mutex<u64> = Mutex::new(0);
+}
+
+#[derive(Debug, Clone)]
+pub struct Lock {
+    pub token: String,
+    // 被锁定资源的访问路径
+    pub href: String,
+    pub file_path: PathBuf,
+    pub user: String,
+    pub owner: String,
+    pub exclusive: bool,
+    // "0" 或 "infinity"
+    pub depth: String,
+    pub timeout: u64,
+    pub expires: SystemTime,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LockView {
+    pub token: String,
+    pub href: String,
+    pub user: String,
+    pub owner: String,
+    pub scope: String,
+    pub depth: String,
+    pub expires: String,
+}
+
+impl Lock {
+    // 两个锁是否作用于同一资源（考虑 depth infinity 的锁覆盖子资源）
+    fn covers(&self, file_path: &Path, depth: &str) -> bool {
+        self.file_path == file_path
+            || (self.depth == "infinity" && file_path.starts_with(&self.file_path))
+            || (depth == "infinity" && self.file_path.starts_with(file_path))
+    }
+
+    // file_path 在锁的范围内
+    fn in_scope(&self, file_path: &Path) -> bool {
+        self.covers(file_path, "0")
+    }
+}
+
+fn remove_expired(locks: &mut HashMap<String, Lock>) {
+    let now = SystemTime::now();
+    locks.retain(|_, lock| lock.expires > now);
+}
+
+fn new_token(file_path: &Path) -> String {
+    let mut counter = LOCK_COUNTER.lock().unwrap();
+    *counter += 1;
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let digest = format!(
+        "{:x}",
+        md5::compute(format!("{}:{}:{:?}", nanos, counter, file_path))
+    );
+    format!(
+        "opaquelocktoken:{}-{}-{}-{}-{}",
+        &digest[0..8],
+        &digest[8..12],
+        &digest[12..16],
+        &digest[16..20],
+        &digest[20..32]
+    )
+}
+
+// 加锁，与已有的锁冲突时返回 None
+pub fn acquire(
+    href: &str,
+    file_path: &Path,
+    user: &str,
+    owner: &str,
+    exclusive: bool,
+    depth: &str,
+    timeout: u64,
+) -> Option<Lock> {
+    let mut locks = LOCKS.lock().unwrap();
+    remove_expired(&mut locks);
+    let conflict = locks
+        .values()
+        .any(|lock| lock.covers(file_path, depth) && (lock.exclusive || exclusive));
+    if conflict {
+        return None;
+    }
+    let lock = Lock {
+        token: new_token(file_path),
+        href: href.to_string(),
+        file_path: file_path.to_path_buf(),
+        user: user.to_string(),
+        owner: owner.to_string(),
+        exclusive,
+        depth: depth.to_string(),
+        timeout,
+        expires: SystemTime::now() + Duration::from_secs(timeout),
+    };
+    locks.insert(lock.token.to_string(), lock.clone());
+    Some(lock)
+}
+
+// 刷新锁的超时时间，只能刷新自己的、作用于 file_path 的锁
+pub fn refresh(token: &str, user: &str, file_path: &Path, timeout: u64) -> Option<Lock> {
+    let mut locks = LOCKS.lock().unwrap();
+    remove_expired(&mut locks);
+    let lock = locks
+        .get_mut(token)
+        .filter(|lock| lock.user == user && lock.in_scope(file_path))?;
+    lock.timeout = timeout;
+    lock.expires = SystemTime::now() + Duration::from_secs(timeout);
+    Some(lock.clone())
+}
+
+// 解锁，只能解除自己的、作用于 file_path 的锁
+pub fn release(token: &str, user: &str, file_path: &Path) -> bool {
+    let mut locks = LOCKS.lock().unwrap();
+    remove_expired(&mut locks);
+    match locks.get(token) {
+        Some(lock) if lock.user == user && lock.in_scope(file_path) => {
+            locks.remove(token);
+            true
+        }
+        _ => false,
+    }
+}
+
+// 修改 file_path 前检查锁：作用于它的锁（recursive 时包括子资源上的锁）都需要是 user 的，
+// 并且 token 在 tokens 中。member 为 true 时会增删 file_path，上级目录的锁也要检查
+pub fn check(
+    file_path: &Path,
+    recursive: bool,
+    member: bool,
+    user: &str,
+    tokens: &[String],
+) -> bool {
+    let mut locks = LOCKS.lock().unwrap();
+    remove_expired(&mut locks);
+    let depth = if recursive { "infinity" } else { "0" };
+    let parent = file_path.parent().filter(|_| member);
+    locks
+        .values()
+        .filter(|lock| {
+            lock.covers(file_path, depth)
+                || parent
+                    .map(|parent| lock.file_path == parent)
+                    .unwrap_or(false)
+        })
+        .all(|lock| lock.user == user && tokens.contains(&lock.token))
+}
+
+pub fn list() -> Vec<LockView> {
+    let mut locks = LOCKS.lock().unwrap();
+    remove_expired(&mut locks);
+    let mut views: Vec<LockView> = locks
+        .values()
+        .map(|lock| LockView {
+            token: lock.token.to_string(),
+            href: lock.href.to_string(),
+            user: lock.user.to_string(),
+            owner: lock.owner.to_string(),
+            scope: if lock.exclusive {
+                "exclusive"
+            } else {
+                "shared"
+            }
+            .to_string(),
+            depth: lock.depth.to_string(),
+            expires: format_date_time(lock.expires),
+        })
+        .collect();
+    views.sort_by(|a, b| a.href.cmp(&b.href));
+    views
+}
@@ -1,32 +1,182 @@
+use clap::Parser;
 use hyper::{server::conn::http1, service::service_fn};
 use hyper_util::rt::TokioIo;
 use log::LevelFilter;
-use rhfs::{config, server::handle_request};
+use rhfs::{
+    admin, auth,
+    cli::{normalize_args, Cli, Command, ServeArgs, UserAddArgs, UserCommand},
+    config::{self, Config, ConfigError, ConfigOverrides, Rule},
+    reload,
+    server::handle_request,
+    trash, validate, versions,
+};
 use std::{
-    net::{Ipv6Addr, SocketAddrV6},
-    str::FromStr,
+    env, io,
+    net::{Ipv6Addr, SocketAddr, SocketAddrV6},
+    path::Path,
+    process,
 };
 use tokio::net::{TcpListener, TcpStream};
 
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    println!("Hello, world!");
-    let cfg = config::get_config();
-    println!("参数:{:?}", cfg);
+async fn main() {
+    let cli = Cli::parse_from(normalize_args(env::args()));
+    if let Err(err) = run(cli).await {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), BoxError> {
+    match cli.command {
+        Command::Serve(args) => serve(args).await,
+        Command::CheckConfig { config } => check_config(&config),
+        Command::HashPassword { password } => {
+            let password = password_or_stdin(password)?;
+            println!("{}", auth::hash_password(&password)?);
+            Ok(())
+        }
+        Command::User(UserCommand::Add(args)) => add_user(args),
+        Command::User(UserCommand::Remove { config, name }) => {
+            config::remove_user(&config, &name)?;
+            println!("removed user \"{}\"", name);
+            Ok(())
+        }
+    }
+}
+
+async fn serve(args: ServeArgs) -> Result<(), BoxError> {
+    let mut cfg = match (&args.config, &args.dir) {
+        (Some(path), _) => config::load_config(path)?,
+        (None, Some(dir)) => adhoc_config(dir, &args)?,
+        (None, None) => {
+            return Err(ConfigError::Invalid(
+                "either --config or a directory to share is required".to_string(),
+            )
+            .into())
+        }
+    };
+    let overrides = ConfigOverrides {
+        port: args.port,
+        log: args.log,
+    };
+    overrides.apply(&mut cfg);
+    // 有错误时拒绝启动
+    let warnings = config::check_config(&cfg)?;
+    let level = config::parse_log_level(&cfg.log)?;
+    // 日志级别由 log::set_max_level 控制，以便重新加载配置时修改
     env_logger::builder()
-        .filter_level(LevelFilter::from_str(&cfg.log).unwrap())
+        .filter_level(LevelFilter::Trace)
         .init();
-    let addr_v6 = SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, cfg.port, 0, 0);
-    let listener_v6 = TcpListener::bind(addr_v6).await?;
+    log::set_max_level(level);
+    for warning in warnings {
+        log::warn!("{}", warning);
+    }
+    log::debug!("参数:{:?}", cfg);
+    let port = cfg.port;
+    let admin_port = cfg.admin.as_ref().map(|admin| admin.port).unwrap_or(0);
+    config::set_config(cfg);
+    if let Some(path) = args.config {
+        reload::spawn_watcher(path, overrides);
+    }
+    trash::spawn_purger();
+    versions::spawn_pruner();
+
+    let addr_v6 = SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0);
+    let listener_v6 = TcpListener::bind(addr_v6)
+        .await
+        .map_err(|e| format!("failed to listen on port {}: {}", port, e))?;
+    log::info!("listening on port {}", port);
+    if admin_port != 0 {
+        let admin_addr = SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, admin_port, 0, 0);
+        let admin_listener = TcpListener::bind(admin_addr)
+            .await
+            .map_err(|e| format!("failed to listen on admin port {}: {}", admin_port, e))?;
+        log::info!("admin api listening on port {}", admin_port);
+        tokio::spawn(async move {
+            loop {
+                match admin_listener.accept().await {
+                    Ok((stream, _)) => serve_admin_connection(stream).await,
+                    Err(err) => log::error!("Error accepting admin connection: {:?}", err),
+                }
+            }
+        });
+    }
 
     // We start a loop to continuously accept incoming connections
     loop {
-        let (stream_v6, _) = listener_v6.accept().await?;
-        serve_connection(stream_v6).await;
+        let (stream_v6, remote_addr) = listener_v6.accept().await?;
+        serve_connection(stream_v6, remote_addr).await;
     }
 }
 
-async fn serve_connection(stream: TcpStream) {
+// 列出全部问题，有错误时返回失败
+fn check_config(path: &str) -> Result<(), BoxError> {
+    let cfg = config::load_config(path)?;
+    let issues = validate::validate_config(&cfg);
+    for issue in &issues {
+        println!("{}", issue);
+    }
+    if validate::has_errors(&issues) {
+        return Err(format!("{} is invalid", path).into());
+    }
+    println!("{}: ok", path);
+    Ok(())
+}
+
+// 不使用配置文件，直接把一个目录共享给游客
+fn adhoc_config(dir: &str, args: &ServeArgs) -> Result<Config, BoxError> {
+    let path = Path::new(dir)
+        .canonicalize()
+        .map_err(|e| format!("cannot share {}: {}", dir, e))?;
+    if !path.is_dir() {
+        return Err(format!("cannot share {}: not a directory", dir).into());
+    }
+    let rule = Rule {
+        path: format!("{}/", path.to_string_lossy().trim_end_matches('/')),
+        permission: if args.rw { "RW" } else { "R" }.to_string(),
+        server_prefix: args.prefix.to_string(),
+        ..Default::default()
+    };
+    let mut cfg = Config {
+        port: 9988,
+        rules: vec![rule],
+        ..Default::default()
+    };
+    config::init_user(&mut cfg);
+    Ok(cfg)
+}
+
+fn add_user(args: UserAddArgs) -> Result<(), BoxError> {
+    let password = password_or_stdin(args.password)?;
+    if password.is_empty() {
+        return Err("password must not be empty".into());
+    }
+    let rule = Rule {
+        path: args.path,
+        user: args.name.to_string(),
+        password: auth::hash_password(&password)?,
+        permission: args.permission,
+        server_prefix: args.prefix.unwrap_or(format!("/{}", args.name)),
+        ..Default::default()
+    };
+    config::add_user(&args.config, rule)?;
+    println!("added user \"{}\"", args.name);
+    Ok(())
+}
+
+fn password_or_stdin(password: Option<String>) -> io::Result<String> {
+    if let Some(password) = password {
+        return Ok(password);
+    }
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+async fn serve_connection(stream: TcpStream, remote_addr: SocketAddr) {
     // Spawn a tokio task to serve multiple connections concurrently
     tokio::task::spawn(async move {
         // Use an adapter to access something implementing `tokio::io` traits as if they implement
@@ -36,10 +186,23 @@ async fn serve_connection(stream: TcpStream) {
         if let Err(err) = http1::Builder::new()
             .keep_alive(true)
             // `service_fn` converts our function in a `Service`
-            .serve_connection(io, service_fn(handle_request))
+            .serve_connection(io, service_fn(move |req| handle_request(req, remote_addr)))
             .await
         {
             log::error!("Error serving connection: {:?}", err);
         }
     });
 }
+
+// 管理接口单独监听端口时，所有请求都交给管理接口处理
+async fn serve_admin_connection(stream: TcpStream) {
+    tokio::task::spawn(async move {
+        let io = TokioIo::new(stream);
+        let service = service_fn(|req| async {
+            Ok::<_, io::Error>(admin::handle_request(req, config::get_config()).await)
+        });
+        if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
+            log::error!("Error serving admin connection: {:?}", err);
+        }
+    });
+}
@@ -0,0 +1,298 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use image::ImageReader;
+use lazy_static::lazy_static;
+use lofty::{file::AudioFile, file::TaggedFileExt, tag::Accessor};
+use mime_guess::from_path;
+use tokio::{runtime::Handle, sync::Semaphore};
+
+use crate::storage::{archive::RangeReader, Metadata, Storage};
+
+// 缓存的文件数量上限
+const MAX_CACHED_FILES: usize = 100_000;
+// 后台同时读取的文件数量
+const BACKGROUND_WORKERS: usize = 4;
+
+lazy_static! {
+    // key 为 rule 的 path 加文件路径，文件的大小或修改时间变化后重新读取
+    static ref CACHE: Mutex<HashMap<String, Arc<Cached>>> = Mutex::new(HashMap::new());
+    // 正在后台读取的文件
+    static ref PENDING: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+    static ref WORKERS: Semaphore = Semaphore::new(BACKGROUND_WORKERS);
+}
+
+// 从文件内容中读取的信息，没有的项为 None
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MediaInfo {
+    // 图片按 EXIF 方向旋转后的宽高
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    // EXIF 中的拍摄时间，例如 "2024-01-02T03:04:05"
+    pub capture_date: Option<String>,
+    // 音频和视频的时长，单位为秒
+    pub duration: Option<f64>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub year: Option<u32>,
+}
+
+#[derive(Debug)]
+struct Cached {
+    len: u64,
+    modified: SystemTime,
+    info: MediaInfo,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Image,
+    Audio,
+    // 只读取 MP4/QuickTime 容器的时长
+    Video,
+}
+
+fn kind(path: &str) -> Option<Kind> {
+    let mime = from_path(path).first()?;
+    match (mime.type_().as_str(), mime.subtype().as_str()) {
+        ("image", _) => Some(Kind::Image),
+        ("audio", _) => Some(Kind::Audio),
+        ("video", "mp4" | "quicktime") => Some(Kind::Video),
+        _ => None,
+    }
+}
+
+fn cache_key(rule_path: &str, path: &str) -> String {
+    format!("{}{}", rule_path, path)
+}
+
+fn cached(key: &str, metadata: &Metadata) -> Option<MediaInfo> {
+    let cache = CACHE.lock().unwrap();
+    let cached = cache.get(key)?;
+    if cached.len != metadata.len || cached.modified != metadata.modified {
+        return None;
+    }
+    Some(cached.info.clone())
+}
+
+fn store(key: String, metadata: &Metadata, info: MediaInfo) {
+    let mut cache = CACHE.lock().unwrap();
+    if cache.len() >= MAX_CACHED_FILES {
+        cache.clear();
+    }
+    cache.insert(
+        key,
+        Arc::new(Cached {
+            len: metadata.len,
+            modified: metadata.modified,
+            info,
+        }),
+    );
+}
+
+async fn read_info(
+    storage: Arc<dyn Storage>,
+    path: &str,
+    metadata: &Metadata,
+    kind: Kind,
+) -> MediaInfo {
+    let handle = Handle::current();
+    let reader = RangeReader::new(storage, path, metadata.len, handle);
+    let path = path.to_string();
+    tokio::task::spawn_blocking(move || {
+        let mut reader = BufReader::new(reader);
+        let result = match kind {
+            Kind::Image => read_image(&mut reader),
+            Kind::Audio => read_audio(&mut reader),
+            Kind::Video => read_video(&mut reader),
+        };
+        // 读取失败的文件同样缓存，避免每次列目录都重新读取
+        result.unwrap_or_else(|err| {
+            log::debug!("failed to read media metadata of {}: {}", path, err);
+            MediaInfo::default()
+        })
+    })
+    .await
+    .unwrap_or_default()
+}
+
+// 返回图片、音频和视频的信息，其它文件返回 None。
+// wait 为 false 时只使用缓存，没有缓存时在后台读取，下次再返回，避免列目录时逐个读取文件
+pub async fn lookup(
+    rule_path: &str,
+    storage: Arc<dyn Storage>,
+    path: &str,
+    metadata: &Metadata,
+    wait: bool,
+) -> Option<MediaInfo> {
+    if metadata.is_dir || metadata.len == 0 {
+        return None;
+    }
+    let kind = kind(path)?;
+    let key = cache_key(rule_path, path);
+    if let Some(info) = cached(&key, metadata) {
+        return Some(info);
+    }
+    if wait {
+        let info = read_info(storage, path, metadata, kind).await;
+        store(key, metadata, info.clone());
+        return Some(info);
+    }
+    if !PENDING.lock().unwrap().insert(key.clone()) {
+        return None;
+    }
+    let path = path.to_string();
+    let metadata = metadata.clone();
+    tokio::spawn(async move {
+        if let Ok(_permit) = WORKERS.acquire().await {
+            let info = read_info(storage, &path, &metadata, kind).await;
+            store(key.clone(), &metadata, info);
+        }
+        PENDING.lock().unwrap().remove(&key);
+    });
+    None
+}
+
+fn invalid_data<E: std::fmt::Display>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+fn read_image<R: Read + Seek>(reader: &mut BufReader<R>) -> io::Result<MediaInfo> {
+    let mut info = MediaInfo::default();
+    let mut orientation = 1;
+    if let Ok(exif) = exif::Reader::new().read_from_container(reader) {
+        let field = |tag| exif.get_field(tag, exif::In::PRIMARY);
+        if let Some(field) = field(exif::Tag::DateTimeOriginal).or(field(exif::Tag::DateTime)) {
+            if let exif::Value::Ascii(values) = &field.value {
+                if let Some(time) = values
+                    .first()
+                    .and_then(|value| exif::DateTime::from_ascii(value).ok())
+                {
+                    info.capture_date = Some(format!(
+                        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+                        time.year, time.month, time.day, time.hour, time.minute, time.second
+                    ));
+                }
+            }
+        }
+        if let Some(field) = field(exif::Tag::Orientation) {
+            orientation = field.value.get_uint(0).unwrap_or(1);
+        }
+        info.width = field(exif::Tag::PixelXDimension).and_then(|field| field.value.get_uint(0));
+        info.height = field(exif::Tag::PixelYDimension).and_then(|field| field.value.get_uint(0));
+    }
+    // 文件头中的宽高比 EXIF 中的可靠
+    reader.seek(SeekFrom::Start(0))?;
+    if let Ok((width, height)) = ImageReader::new(&mut *reader)
+        .with_guessed_format()
+        .map_err(invalid_data)
+        .and_then(|reader| reader.into_dimensions().map_err(invalid_data))
+    {
+        info.width = Some(width);
+        info.height = Some(height);
+    }
+    // 方向为 5 到 8 时图片需要旋转 90 度显示
+    if (5..=8).contains(&orientation) {
+        std::mem::swap(&mut info.width, &mut info.height);
+    }
+    Ok(info)
+}
+
+fn read_audio<R: Read + Seek>(reader: &mut BufReader<R>) -> io::Result<MediaInfo> {
+    let file = lofty::probe::Probe::new(reader)
+        .guess_file_type()?
+        .read()
+        .map_err(invalid_data)?;
+    let mut info = MediaInfo::default();
+    let duration = file.properties().duration();
+    if !duration.is_zero() {
+        info.duration = Some(duration.as_secs_f64());
+    }
+    if let Some(tag) = file.primary_tag().or(file.first_tag()) {
+        info.title = tag.title().map(|value| value.to_string());
+        info.artist = tag.artist().map(|value| value.to_string());
+        info.album = tag.album().map(|value| value.to_string());
+        info.year = tag.date().map(|date| date.year.into());
+    }
+    Ok(info)
+}
+
+// 读取 box 头，返回类型和内容的长度
+fn read_box_header<R: Read>(reader: &mut R, remaining: u64) -> io::Result<([u8; 4], u64)> {
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header)?;
+    let size = u32::from_be_bytes(header[0..4].try_into().unwrap()) as u64;
+    let kind: [u8; 4] = header[4..8].try_into().unwrap();
+    let (size, header_len) = match size {
+        // 一直到文件末尾
+        0 => (remaining, 8),
+        1 => {
+            let mut large = [0u8; 8];
+            reader.read_exact(&mut large)?;
+            (u64::from_be_bytes(large), 16)
+        }
+        size => (size, 8),
+    };
+    if size < header_len || size > remaining {
+        return Err(invalid_data("invalid box size"));
+    }
+    Ok((kind, size - header_len))
+}
+
+// 在 [start, start + len) 范围内查找类型为 kind 的 box，返回内容的位置和长度
+fn find_box<R: Read + Seek>(
+    reader: &mut R,
+    start: u64,
+    len: u64,
+    kind: &[u8; 4],
+) -> io::Result<(u64, u64)> {
+    let mut pos = start;
+    while pos + 8 <= start + len {
+        reader.seek(SeekFrom::Start(pos))?;
+        let (found, body_len) = read_box_header(reader, start + len - pos)?;
+        let body_start = reader.stream_position()?;
+        if &found == kind {
+            return Ok((body_start, body_len));
+        }
+        pos = body_start + body_len;
+    }
+    Err(invalid_data(format!(
+        "box {} not found",
+        String::from_utf8_lossy(kind)
+    )))
+}
+
+// 视频时长来自 moov/mvhd，moov 可能在文件末尾
+fn read_video<R: Read + Seek>(reader: &mut BufReader<R>) -> io::Result<MediaInfo> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    let (moov, moov_len) = find_box(reader, 0, len, b"moov")?;
+    let (mvhd, _) = find_box(reader, moov, moov_len, b"mvhd")?;
+    reader.seek(SeekFrom::Start(mvhd))?;
+    let mut version = [0u8; 4];
+    reader.read_exact(&mut version)?;
+    let (timescale, duration) = if version[0] == 1 {
+        let mut body = [0u8; 28];
+        reader.read_exact(&mut body)?;
+        (
+            u32::from_be_bytes(body[16..20].try_into().unwrap()),
+            u64::from_be_bytes(body[20..28].try_into().unwrap()),
+        )
+    } else {
+        let mut body = [0u8; 16];
+        reader.read_exact(&mut body)?;
+        (
+            u32::from_be_bytes(body[8..12].try_into().unwrap()),
+            u32::from_be_bytes(body[12..16].try_into().unwrap()) as u64,
+        )
+    };
+    let mut info = MediaInfo::default();
+    if timescale > 0 {
+        info.duration = Some(duration as f64 / timescale as f64);
+    }
+    Ok(info)
+}
@@ -0,0 +1,1153 @@
+use std::{
+    cell::Cell,
+    io::{self, Read, Seek, Write},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{DateTime, Datelike, Local, Timelike};
+use flate2::{
+    read::GzDecoder,
+    write::{DeflateEncoder, GzEncoder},
+    Compression, Crc,
+};
+use futures_util::StreamExt;
+use hyper::body::Bytes;
+use serde::Serialize;
+use tokio::{runtime::Handle, sync::mpsc};
+
+use crate::{
+    config::ExtractConfig,
+    storage::{
+        archive::{invalid_archive, ArchiveKind},
+        join_path, parse_size, ByteStream, Storage,
+    },
+};
+
+// 攒够这么多字节再发送到 channel
+const CHUNK_SIZE: usize = 64 * 1024;
+// 超过这个大小的文件在 zip 中使用 zip64，留出压缩后变大的余量
+const ZIP64_THRESHOLD: u64 = 0xF000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Zip,
+    TarGz,
+}
+
+impl Format {
+    pub fn parse(value: &str) -> Option<Format> {
+        match value {
+            "zip" => Some(Format::Zip),
+            "tar.gz" | "tgz" => Some(Format::TarGz),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Zip => "zip",
+            Format::TarGz => "tar.gz",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Zip => "application/zip",
+            Format::TarGz => "application/gzip",
+        }
+    }
+}
+
+// 打包的结果
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct PackStats {
+    pub files: u64,
+    pub dirs: u64,
+    // 文件内容的总字节数
+    pub bytes: u64,
+    // 无法读取而跳过的文件和目录
+    pub skipped: u64,
+}
+
+// 写入的数据攒够一块后发送到 channel，只能在 spawn_blocking 中使用
+pub struct ChannelWriter {
+    sender: mpsc::Sender<io::Result<Bytes>>,
+    buffer: Vec<u8>,
+}
+
+impl ChannelWriter {
+    pub fn new(sender: mpsc::Sender<io::Result<Bytes>>) -> ChannelWriter {
+        ChannelWriter {
+            sender,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buffer, Vec::with_capacity(CHUNK_SIZE));
+        // 接收方关闭，例如客户端取消了下载
+        self.sender
+            .blocking_send(Ok(Bytes::from(chunk)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "archive receiver closed"))
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(data);
+        if self.buffer.len() >= CHUNK_SIZE {
+            self.send()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
+
+// 同步读取存储中的文件，读取出错时记录日志并当作文件结束，不中断整个打包
+struct StreamReader<'a> {
+    stream: ByteStream,
+    chunk: Bytes,
+    handle: &'a Handle,
+    path: &'a str,
+    failed: bool,
+}
+
+impl Read for StreamReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            if self.failed {
+                return Ok(0);
+            }
+            match self.handle.block_on(self.stream.next()) {
+                Some(Ok(chunk)) => self.chunk = chunk,
+                Some(Err(err)) => {
+                    log::warn!("failed to read {} while packing: {}", self.path, err);
+                    self.failed = true;
+                }
+                None => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.chunk.len());
+        buf[..len].copy_from_slice(&self.chunk.split_to(len));
+        Ok(len)
+    }
+}
+
+trait ArchiveWriter {
+    fn add_dir(&mut self, name: &str, modified: SystemTime) -> io::Result<()>;
+    // reader 最多读取 len 字节
+    fn add_file(
+        &mut self,
+        name: &str,
+        modified: SystemTime,
+        len: u64,
+        reader: &mut dyn Read,
+    ) -> io::Result<()>;
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+struct TarWriter<W: Write> {
+    builder: tar::Builder<GzEncoder<W>>,
+}
+
+impl<W: Write> ArchiveWriter for TarWriter<W> {
+    fn add_dir(&mut self, name: &str, modified: SystemTime) -> io::Result<()> {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
+        header.set_mode(0o755);
+        header.set_mtime(unix_time(modified));
+        self.builder.append_data(&mut header, name, io::empty())
+    }
+
+    fn add_file(
+        &mut self,
+        name: &str,
+        modified: SystemTime,
+        len: u64,
+        reader: &mut dyn Read,
+    ) -> io::Result<()> {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(len);
+        header.set_mode(0o644);
+        header.set_mtime(unix_time(modified));
+        // 大小已经写在头部，文件变短或读取出错时用 0 补齐
+        let data = reader.take(len).chain(io::repeat(0)).take(len);
+        self.builder.append_data(&mut header, name, data)
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        self.builder.into_inner()?.finish()?.flush()
+    }
+}
+
+// 记录写入的字节数，zip 需要每个成员的偏移
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(data)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct ZipEntry {
+    name: String,
+    modified: SystemTime,
+    is_dir: bool,
+    zip64: bool,
+    crc: u32,
+    compressed: u64,
+    uncompressed: u64,
+    offset: u64,
+}
+
+// 流式生成 zip：不能回头修改本地头，大小和 CRC 写在每个成员后的数据描述符中
+struct ZipWriter<W: Write> {
+    out: CountingWriter<W>,
+    entries: Vec<ZipEntry>,
+}
+
+// MS-DOS 格式的本地时间，早于 1980 年的按 1980 年处理
+fn dos_time(time: SystemTime) -> (u16, u16) {
+    let time = DateTime::<Local>::from(time);
+    if time.year() < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let dos_time = (time.hour() << 11) | (time.minute() << 5) | (time.second() / 2);
+    let dos_date = (((time.year() - 1980) as u32) << 9) | (time.month() << 5) | time.day();
+    (dos_time as u16, dos_date as u16)
+}
+
+impl<W: Write> ZipWriter<W> {
+    // 扩展时间戳，保存精确到秒的 UTC 修改时间
+    fn timestamp_extra(modified: SystemTime) -> Vec<u8> {
+        let mut extra = Vec::with_capacity(9);
+        extra.extend_from_slice(&0x5455u16.to_le_bytes());
+        extra.extend_from_slice(&5u16.to_le_bytes());
+        extra.push(1);
+        extra.extend_from_slice(&(unix_time(modified).min(u32::MAX as u64) as u32).to_le_bytes());
+        extra
+    }
+
+    fn write_local_header(&mut self, entry: &ZipEntry) -> io::Result<()> {
+        let (time, date) = dos_time(entry.modified);
+        let mut extra = Vec::new();
+        if entry.zip64 {
+            extra.extend_from_slice(&1u16.to_le_bytes());
+            extra.extend_from_slice(&16u16.to_le_bytes());
+            extra.extend_from_slice(&[0; 16]);
+        }
+        extra.extend_from_slice(&Self::timestamp_extra(entry.modified));
+        let size = if entry.zip64 { u32::MAX } else { 0 };
+        let mut header = Vec::with_capacity(30 + entry.name.len() + extra.len());
+        header.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        header.extend_from_slice(&(if entry.zip64 { 45u16 } else { 20u16 }).to_le_bytes());
+        header.extend_from_slice(&Self::flags(entry).to_le_bytes());
+        header.extend_from_slice(&Self::method(entry).to_le_bytes());
+        header.extend_from_slice(&time.to_le_bytes());
+        header.extend_from_slice(&date.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&size.to_le_bytes());
+        header.extend_from_slice(&size.to_le_bytes());
+        header.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+        header.extend_from_slice(entry.name.as_bytes());
+        header.extend_from_slice(&extra);
+        self.out.write_all(&header)
+    }
+
+    // 文件名使用 UTF-8，文件的大小和 CRC 在数据描述符中
+    fn flags(entry: &ZipEntry) -> u16 {
+        if entry.is_dir {
+            0x0800
+        } else {
+            0x0808
+        }
+    }
+
+    fn method(entry: &ZipEntry) -> u16 {
+        if entry.is_dir {
+            0
+        } else {
+            8
+        }
+    }
+
+    fn write_central_header(&mut self, index: usize) -> io::Result<()> {
+        let entry = &self.entries[index];
+        let (time, date) = dos_time(entry.modified);
+        let offset_overflow = entry.offset >= u32::MAX as u64;
+        let mut zip64 = Vec::new();
+        if entry.zip64 {
+            zip64.extend_from_slice(&entry.uncompressed.to_le_bytes());
+            zip64.extend_from_slice(&entry.compressed.to_le_bytes());
+        }
+        if offset_overflow {
+            zip64.extend_from_slice(&entry.offset.to_le_bytes());
+        }
+        let mut extra = Vec::new();
+        if !zip64.is_empty() {
+            extra.extend_from_slice(&1u16.to_le_bytes());
+            extra.extend_from_slice(&(zip64.len() as u16).to_le_bytes());
+            extra.extend_from_slice(&zip64);
+        }
+        extra.extend_from_slice(&Self::timestamp_extra(entry.modified));
+        let (compressed, uncompressed) = if entry.zip64 {
+            (u32::MAX, u32::MAX)
+        } else {
+            (entry.compressed as u32, entry.uncompressed as u32)
+        };
+        let offset = if offset_overflow {
+            u32::MAX
+        } else {
+            entry.offset as u32
+        };
+        let version = if zip64.is_empty() { 20u16 } else { 45u16 };
+        // unix 权限放在外部属性的高 16 位，目录同时设置 MS-DOS 的目录属性
+        let external = if entry.is_dir {
+            (0o40755u32 << 16) | 0x10
+        } else {
+            0o100644u32 << 16
+        };
+        let mut header = Vec::with_capacity(46 + entry.name.len() + extra.len());
+        header.extend_from_slice(&0x02014b50u32.to_le_bytes());
+        header.extend_from_slice(&((3u16 << 8) | version).to_le_bytes());
+        header.extend_from_slice(&version.to_le_bytes());
+        header.extend_from_slice(&Self::flags(entry).to_le_bytes());
+        header.extend_from_slice(&Self::method(entry).to_le_bytes());
+        header.extend_from_slice(&time.to_le_bytes());
+        header.extend_from_slice(&date.to_le_bytes());
+        header.extend_from_slice(&entry.crc.to_le_bytes());
+        header.extend_from_slice(&compressed.to_le_bytes());
+        header.extend_from_slice(&uncompressed.to_le_bytes());
+        header.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+        // 注释长度、起始磁盘号、内部属性
+        header.extend_from_slice(&[0; 6]);
+        header.extend_from_slice(&external.to_le_bytes());
+        header.extend_from_slice(&offset.to_le_bytes());
+        header.extend_from_slice(entry.name.as_bytes());
+        header.extend_from_slice(&extra);
+        self.out.write_all(&header)
+    }
+}
+
+impl<W: Write> ArchiveWriter for ZipWriter<W> {
+    fn add_dir(&mut self, name: &str, modified: SystemTime) -> io::Result<()> {
+        let entry = ZipEntry {
+            name: name.to_string(),
+            modified,
+            is_dir: true,
+            zip64: false,
+            crc: 0,
+            compressed: 0,
+            uncompressed: 0,
+            offset: self.out.count,
+        };
+        self.write_local_header(&entry)?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    fn add_file(
+        &mut self,
+        name: &str,
+        modified: SystemTime,
+        len: u64,
+        reader: &mut dyn Read,
+    ) -> io::Result<()> {
+        let mut entry = ZipEntry {
+            name: name.to_string(),
+            modified,
+            is_dir: false,
+            zip64: len >= ZIP64_THRESHOLD,
+            crc: 0,
+            compressed: 0,
+            uncompressed: 0,
+            offset: self.out.count,
+        };
+        self.write_local_header(&entry)?;
+        let start = self.out.count;
+        let mut crc = Crc::new();
+        let mut encoder = DeflateEncoder::new(&mut self.out, Compression::default());
+        let mut reader = reader.take(len);
+        let mut buffer = vec![0; CHUNK_SIZE];
+        loop {
+            let read = reader.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            crc.update(&buffer[..read]);
+            encoder.write_all(&buffer[..read])?;
+        }
+        encoder.finish()?;
+        entry.crc = crc.sum();
+        entry.uncompressed = crc.amount() as u64;
+        entry.compressed = self.out.count - start;
+        let mut descriptor = Vec::with_capacity(24);
+        descriptor.extend_from_slice(&0x08074b50u32.to_le_bytes());
+        descriptor.extend_from_slice(&entry.crc.to_le_bytes());
+        if entry.zip64 {
+            descriptor.extend_from_slice(&entry.compressed.to_le_bytes());
+            descriptor.extend_from_slice(&entry.uncompressed.to_le_bytes());
+        } else {
+            descriptor.extend_from_slice(&(entry.compressed as u32).to_le_bytes());
+            descriptor.extend_from_slice(&(entry.uncompressed as u32).to_le_bytes());
+        }
+        self.out.write_all(&descriptor)?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        let start = self.out.count;
+        for index in 0..self.entries.len() {
+            self.write_central_header(index)?;
+        }
+        let size = self.out.count - start;
+        let count = self.entries.len() as u64;
+        let mut end = Vec::new();
+        if count >= u16::MAX as u64 || start >= u32::MAX as u64 || size >= u32::MAX as u64 {
+            // zip64 目录结束记录及其定位器
+            let record = self.out.count;
+            end.extend_from_slice(&0x06064b50u32.to_le_bytes());
+            end.extend_from_slice(&44u64.to_le_bytes());
+            end.extend_from_slice(&((3u16 << 8) | 45).to_le_bytes());
+            end.extend_from_slice(&45u16.to_le_bytes());
+            end.extend_from_slice(&[0; 8]);
+            end.extend_from_slice(&count.to_le_bytes());
+            end.extend_from_slice(&count.to_le_bytes());
+            end.extend_from_slice(&size.to_le_bytes());
+            end.extend_from_slice(&start.to_le_bytes());
+            end.extend_from_slice(&0x07064b50u32.to_le_bytes());
+            end.extend_from_slice(&0u32.to_le_bytes());
+            end.extend_from_slice(&record.to_le_bytes());
+            end.extend_from_slice(&1u32.to_le_bytes());
+        }
+        let count = count.min(u16::MAX as u64) as u16;
+        end.extend_from_slice(&0x06054b50u32.to_le_bytes());
+        end.extend_from_slice(&[0; 4]);
+        end.extend_from_slice(&count.to_le_bytes());
+        end.extend_from_slice(&count.to_le_bytes());
+        end.extend_from_slice(&(size.min(u32::MAX as u64) as u32).to_le_bytes());
+        end.extend_from_slice(&(start.min(u32::MAX as u64) as u32).to_le_bytes());
+        end.extend_from_slice(&0u16.to_le_bytes());
+        self.out.write_all(&end)?;
+        self.out.flush()
+    }
+}
+
+struct Packer<'a> {
+    storage: Arc<dyn Storage>,
+    handle: Handle,
+    filter: &'a dyn Fn(&str) -> bool,
+    writer: Box<dyn ArchiveWriter>,
+    stats: PackStats,
+    progress: &'a mut dyn FnMut(&PackStats),
+}
+
+impl Packer<'_> {
+    // 成员名为相对打包目录的路径，目录以 / 结尾
+    fn add_tree(&mut self, path: &str, prefix: &str) -> io::Result<()> {
+        let mut entries = match self.handle.block_on(self.storage.list(path)) {
+            Ok(entries) => entries,
+            Err(err) => {
+                log::warn!("skipped unreadable directory {}: {}", path, err);
+                self.stats.skipped += 1;
+                return Ok(());
+            }
+        };
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        for entry in entries {
+            let entry_path = join_path(path, &entry.name);
+            if !(self.filter)(&entry_path) {
+                continue;
+            }
+            let name = format!("{}{}", prefix, entry.name);
+            if entry.metadata.is_dir {
+                let name = format!("{}/", name);
+                self.writer.add_dir(&name, entry.metadata.modified)?;
+                self.stats.dirs += 1;
+                (self.progress)(&self.stats);
+                self.add_tree(&entry_path, &name)?;
+                continue;
+            }
+            let stream = match self.handle.block_on(self.storage.read(&entry_path, None)) {
+                Ok(stream) => stream,
+                Err(err) => {
+                    log::warn!("skipped unreadable file {}: {}", entry_path, err);
+                    self.stats.skipped += 1;
+                    continue;
+                }
+            };
+            let mut reader = StreamReader {
+                stream,
+                chunk: Bytes::new(),
+                handle: &self.handle,
+                path: &entry_path,
+                failed: false,
+            };
+            self.writer.add_file(
+                &name,
+                entry.metadata.modified,
+                entry.metadata.len,
+                &mut reader,
+            )?;
+            if reader.failed {
+                self.stats.skipped += 1;
+            } else {
+                self.stats.files += 1;
+                self.stats.bytes += entry.metadata.len;
+            }
+            (self.progress)(&self.stats);
+        }
+        Ok(())
+    }
+}
+
+// 把 root 目录下 filter 允许的文件和目录打包写入 out，每个成员完成后调用 progress，只能在 spawn_blocking 中使用
+pub fn pack<W: Write + 'static>(
+    storage: Arc<dyn Storage>,
+    handle: Handle,
+    root: &str,
+    format: Format,
+    filter: &dyn Fn(&str) -> bool,
+    out: W,
+    progress: &mut dyn FnMut(&PackStats),
+) -> io::Result<PackStats> {
+    let writer: Box<dyn ArchiveWriter> = match format {
+        Format::Zip => Box::new(ZipWriter {
+            out: CountingWriter {
+                inner: out,
+                count: 0,
+            },
+            entries: Vec::new(),
+        }),
+        Format::TarGz => Box::new(TarWriter {
+            builder: tar::Builder::new(GzEncoder::new(out, Compression::default())),
+        }),
+    };
+    let mut packer = Packer {
+        storage,
+        handle,
+        filter,
+        writer,
+        stats: PackStats::default(),
+        progress,
+    };
+    packer.add_tree(root, "")?;
+    let Packer { writer, stats, .. } = packer;
+    writer.finish()?;
+    Ok(stats)
+}
+
+// 解压的限制，防止解压炸弹
+#[derive(Debug, Clone, Copy)]
+pub struct ExtractLimits {
+    // 解压后的总字节数
+    pub max_size: u64,
+    pub max_entries: u64,
+    // 解压后与压缩包大小之比
+    pub max_ratio: u64,
+}
+
+// 解压后小于这个大小时不检查压缩比，小压缩包的压缩比可能很高
+const RATIO_CHECK_SIZE: u64 = 16 * 1024 * 1024;
+
+impl ExtractLimits {
+    pub fn from_config(config: Option<&ExtractConfig>) -> ExtractLimits {
+        let config = config.cloned().unwrap_or_default();
+        ExtractLimits {
+            max_size: parse_size(&config.max_size).unwrap_or(10 * 1024 * 1024 * 1024),
+            max_entries: if config.max_entries == 0 {
+                100_000
+            } else {
+                config.max_entries
+            },
+            max_ratio: if config.max_ratio == 0 {
+                200
+            } else {
+                config.max_ratio
+            },
+        }
+    }
+}
+
+fn limit_exceeded(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// 统计实际解压出的字节数，超过限制时返回错误，不信任压缩包中声明的大小
+struct LimitedReader<'a, R> {
+    inner: R,
+    total: &'a Cell<u64>,
+    limits: &'a ExtractLimits,
+    archive_len: u64,
+}
+
+impl<R: Read> Read for LimitedReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        let total = self.total.get() + read as u64;
+        self.total.set(total);
+        if total > self.limits.max_size {
+            return Err(limit_exceeded("extracted size exceeds the limit"));
+        }
+        if total > RATIO_CHECK_SIZE
+            && total > self.archive_len.saturating_mul(self.limits.max_ratio)
+        {
+            return Err(limit_exceeded("compression ratio exceeds the limit"));
+        }
+        Ok(read)
+    }
+}
+
+// 压缩包成员的路径转为相对路径，含 .. 或为空时返回 None
+pub fn relative_path(name: &str) -> Option<String> {
+    let mut parts = Vec::new();
+    for part in name.split(['/', '\\']) {
+        match part {
+            "" | "." => {}
+            ".." => return None,
+            part if part.contains('\0') => return None,
+            part => parts.push(part),
+        }
+    }
+    if parts.is_empty() {
+        return None;
+    }
+    Some(parts.join("/"))
+}
+
+// 解压出的一个成员，name 为相对路径
+pub enum Member<'a> {
+    Dir(String),
+    File(String, &'a mut dyn Read),
+}
+
+// 依次把压缩包的成员交给 extract，extract 返回 false 表示跳过。
+// 符号链接等特殊成员和路径不安全的成员会被跳过，超过 limits 时中止。
+// 每个成员处理后调用 progress，只能在 spawn_blocking 中使用
+pub fn unpack<R: Read + Seek>(
+    reader: R,
+    kind: ArchiveKind,
+    archive_len: u64,
+    limits: &ExtractLimits,
+    extract: &mut dyn FnMut(Member) -> io::Result<bool>,
+    progress: &mut dyn FnMut(&PackStats),
+) -> io::Result<PackStats> {
+    let total = Cell::new(0);
+    let mut stats = PackStats::default();
+    let mut entries = 0;
+    // reader 为 None 时是目录
+    let mut handle_member =
+        |name: &str, reader: Option<&mut dyn Read>, stats: &mut PackStats| -> io::Result<()> {
+            entries += 1;
+            if entries > limits.max_entries {
+                return Err(limit_exceeded("number of entries exceeds the limit"));
+            }
+            let relative = match relative_path(name) {
+                Some(relative) => relative,
+                None => {
+                    // "./" 之类的根目录项直接忽略
+                    if !name.trim_matches(['/', '\\', '.']).is_empty() {
+                        log::warn!("skipped unsafe archive entry {}", name);
+                        stats.skipped += 1;
+                    }
+                    return Ok(());
+                }
+            };
+            let before = total.get();
+            let is_file = reader.is_some();
+            let extracted = match reader {
+                Some(reader) => {
+                    let mut limited = LimitedReader {
+                        inner: reader,
+                        total: &total,
+                        limits,
+                        archive_len,
+                    };
+                    extract(Member::File(relative, &mut limited))?
+                }
+                None => extract(Member::Dir(relative))?,
+            };
+            if !extracted {
+                stats.skipped += 1;
+            } else if is_file {
+                stats.files += 1;
+                stats.bytes += total.get() - before;
+            } else {
+                stats.dirs += 1;
+            }
+            Ok(())
+        };
+    match kind {
+        ArchiveKind::Zip => {
+            let mut archive = zip::ZipArchive::new(reader).map_err(invalid_archive)?;
+            for index in 0..archive.len() {
+                let mut file = archive.by_index(index).map_err(invalid_archive)?;
+                let name = file.name().to_string();
+                // 不解压符号链接
+                if file.unix_mode().unwrap_or(0) & 0o170000 == 0o120000 {
+                    log::warn!("skipped symlink archive entry {}", name);
+                    stats.skipped += 1;
+                    continue;
+                }
+                if file.is_dir() {
+                    handle_member(&name, None, &mut stats)?;
+                } else {
+                    handle_member(&name, Some(&mut file), &mut stats)?;
+                }
+                progress(&stats);
+            }
+        }
+        ArchiveKind::Tar | ArchiveKind::TarGz => {
+            let reader: Box<dyn Read> = if kind == ArchiveKind::TarGz {
+                Box::new(GzDecoder::new(reader))
+            } else {
+                Box::new(reader)
+            };
+            let mut archive = tar::Archive::new(reader);
+            for entry in archive.entries().map_err(invalid_archive)? {
+                let mut entry = entry.map_err(invalid_archive)?;
+                let entry_type = entry.header().entry_type();
+                let name = entry
+                    .path()
+                    .map_err(invalid_archive)?
+                    .to_string_lossy()
+                    .to_string();
+                if !entry_type.is_file() && !entry_type.is_dir() {
+                    log::warn!("skipped special archive entry {}", name);
+                    stats.skipped += 1;
+                    continue;
+                }
+                if entry_type.is_dir() {
+                    handle_member(&name, None, &mut stats)?;
+                } else {
+                    handle_member(&name, Some(&mut entry), &mut stats)?;
+                }
+                progress(&stats);
+            }
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, sync::Mutex};
+
+    use futures_util::stream;
+    use zip::extra_fields::ExtraField;
+
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    // pack 要求 out 为 'static，打包完成后从这里取出数据
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    async fn write(storage: &dyn Storage, path: &str, data: Vec<u8>) {
+        let body: ByteStream = Box::pin(stream::iter([Ok(Bytes::from(data))]));
+        storage.write(path, body).await.unwrap();
+    }
+
+    // 返回打包目录下每个成员的名字、大小和修改时间
+    async fn tree(storage: &dyn Storage) -> Vec<(String, u64, u64)> {
+        storage.mkdir("/docs").await.unwrap();
+        storage.mkdir("/docs/empty").await.unwrap();
+        storage.mkdir("/docs/sub").await.unwrap();
+        write(storage, "/docs/a.txt", b"hello".to_vec()).await;
+        let data = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        write(storage, "/docs/sub/b.bin", data).await;
+        let mut members = Vec::new();
+        for (name, path) in [
+            ("a.txt", "/docs/a.txt"),
+            ("empty/", "/docs/empty"),
+            ("sub/", "/docs/sub"),
+            ("sub/b.bin", "/docs/sub/b.bin"),
+        ] {
+            let metadata = storage.stat(path).await.unwrap();
+            let len = if metadata.is_dir { 0 } else { metadata.len };
+            members.push((name.to_string(), len, unix_time(metadata.modified)));
+        }
+        members
+    }
+
+    async fn pack_docs(storage: Arc<dyn Storage>, format: Format) -> Vec<u8> {
+        let buffer = SharedBuffer::default();
+        let out = buffer.clone();
+        let handle = Handle::current();
+        let stats = tokio::task::spawn_blocking(move || {
+            pack(
+                storage,
+                handle,
+                "/docs",
+                format,
+                &|_| true,
+                out,
+                &mut |_| {},
+            )
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!((stats.files, stats.dirs, stats.skipped), (2, 2, 0));
+        assert_eq!(stats.bytes, 200_005);
+        let data = buffer.0.lock().unwrap().clone();
+        data
+    }
+
+    #[tokio::test]
+    async fn zip_round_trip() {
+        let storage = Arc::new(MemoryStorage::new(u64::MAX));
+        let expected = tree(storage.as_ref()).await;
+        let data = pack_docs(storage.clone(), Format::Zip).await;
+        let mut archive = zip::ZipArchive::new(Cursor::new(data)).unwrap();
+        let mut members = Vec::new();
+        for index in 0..archive.len() {
+            let mut file = archive.by_index(index).unwrap();
+            let modified = file
+                .extra_data_fields()
+                .find_map(|field| match field {
+                    ExtraField::ExtendedTimestamp(timestamp) => timestamp.mod_time(),
+                    _ => None,
+                })
+                .unwrap();
+            let mut content = Vec::new();
+            file.read_to_end(&mut content).unwrap();
+            assert_eq!(content.len() as u64, file.size());
+            members.push((file.name().to_string(), file.size(), modified as u64));
+        }
+        assert_eq!(members, expected);
+        let mut content = String::new();
+        archive
+            .by_name("a.txt")
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "hello");
+    }
+
+    #[tokio::test]
+    async fn tar_gz_round_trip() {
+        let storage = Arc::new(MemoryStorage::new(u64::MAX));
+        let expected = tree(storage.as_ref()).await;
+        let data = pack_docs(storage.clone(), Format::TarGz).await;
+        let mut archive = tar::Archive::new(GzDecoder::new(Cursor::new(data)));
+        let mut members = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let header = entry.header().clone();
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content).unwrap();
+            assert_eq!(content.len() as u64, header.size().unwrap());
+            let name = entry.path().unwrap().to_string_lossy().to_string();
+            members.push((name, header.size().unwrap(), header.mtime().unwrap()));
+        }
+        assert_eq!(members, expected);
+    }
+
+    fn central_header(offset: u64, zip64: bool) -> Vec<u8> {
+        let mut writer = ZipWriter {
+            out: CountingWriter {
+                inner: Vec::new(),
+                count: 0,
+            },
+            entries: vec![ZipEntry {
+                name: "big.bin".to_string(),
+                modified: UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000),
+                is_dir: false,
+                zip64,
+                crc: 0x1234_5678,
+                compressed: 0x1_0000_0001,
+                uncompressed: 0x1_0000_0002,
+                offset,
+            }],
+        };
+        writer.write_central_header(0).unwrap();
+        writer.out.inner
+    }
+
+    fn u16_at(data: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(data[at..at + 2].try_into().unwrap())
+    }
+
+    fn u32_at(data: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    fn u64_at(data: &[u8], at: usize) -> u64 {
+        u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn central_header_zip64_extra() {
+        // 大小和偏移都超过 32 位：extra 依次为原始大小、压缩后大小、偏移
+        let header = central_header(0x1_0000_0003, true);
+        assert_eq!(u32_at(&header, 0), 0x02014b50);
+        assert_eq!(u16_at(&header, 6), 45);
+        assert_eq!(u32_at(&header, 16), 0x1234_5678);
+        assert_eq!(u32_at(&header, 20), u32::MAX);
+        assert_eq!(u32_at(&header, 24), u32::MAX);
+        assert_eq!(u16_at(&header, 30), 24 + 4 + 9);
+        assert_eq!(u32_at(&header, 42), u32::MAX);
+        let extra = 46 + "big.bin".len();
+        assert_eq!(&header[46..extra], b"big.bin");
+        assert_eq!(u16_at(&header, extra), 1);
+        assert_eq!(u16_at(&header, extra + 2), 24);
+        assert_eq!(u64_at(&header, extra + 4), 0x1_0000_0002);
+        assert_eq!(u64_at(&header, extra + 12), 0x1_0000_0001);
+        assert_eq!(u64_at(&header, extra + 20), 0x1_0000_0003);
+        assert_eq!(u16_at(&header, extra + 28), 0x5455);
+        assert_eq!(u32_at(&header, extra + 33), 1_700_000_000);
+        assert_eq!(header.len(), extra + 28 + 9);
+
+        // 只有偏移超过 32 位时 extra 中只有偏移，大小照常写在头部
+        let header = central_header(0x1_0000_0003, false);
+        assert_eq!(u16_at(&header, 6), 45);
+        assert_eq!(u32_at(&header, 20), 0x0000_0001);
+        assert_eq!(u32_at(&header, 24), 0x0000_0002);
+        assert_eq!(u32_at(&header, 42), u32::MAX);
+        assert_eq!(u16_at(&header, extra), 1);
+        assert_eq!(u16_at(&header, extra + 2), 8);
+        assert_eq!(u64_at(&header, extra + 4), 0x1_0000_0003);
+
+        // 都不超过时没有 zip64 extra
+        let header = central_header(0x100, false);
+        assert_eq!(u16_at(&header, 6), 20);
+        assert_eq!(u32_at(&header, 42), 0x100);
+        assert_eq!(u16_at(&header, 30), 9);
+        assert_eq!(u16_at(&header, extra), 0x5455);
+    }
+
+    #[test]
+    fn relative_path_rejects_unsafe_names() {
+        assert_eq!(relative_path("a/b.txt").as_deref(), Some("a/b.txt"));
+        assert_eq!(relative_path("./a//b/").as_deref(), Some("a/b"));
+        // 绝对路径和反斜杠分隔的路径转为相对路径
+        assert_eq!(relative_path("/etc/passwd").as_deref(), Some("etc/passwd"));
+        assert_eq!(relative_path("dir\\a.txt").as_deref(), Some("dir/a.txt"));
+        assert_eq!(relative_path("../x"), None);
+        assert_eq!(relative_path("a/../../b"), None);
+        assert_eq!(relative_path("a/../b"), None);
+        assert_eq!(relative_path("..\\x"), None);
+        assert_eq!(relative_path("a\0b"), None);
+        assert_eq!(relative_path("./"), None);
+        assert_eq!(relative_path(""), None);
+    }
+
+    fn limits() -> ExtractLimits {
+        ExtractLimits::from_config(None)
+    }
+
+    // 解压全部成员，返回交给 extract 的名字，目录以 / 结尾
+    fn extract_all(
+        data: Vec<u8>,
+        kind: ArchiveKind,
+        limits: &ExtractLimits,
+    ) -> io::Result<(Vec<String>, PackStats)> {
+        let len = data.len() as u64;
+        let mut names = Vec::new();
+        let stats = unpack(
+            Cursor::new(data),
+            kind,
+            len,
+            limits,
+            &mut |member| {
+                match member {
+                    Member::Dir(name) => names.push(format!("{}/", name)),
+                    Member::File(name, reader) => {
+                        io::copy(reader, &mut io::sink())?;
+                        names.push(name);
+                    }
+                }
+                Ok(true)
+            },
+            &mut |_| {},
+        )?;
+        Ok((names, stats))
+    }
+
+    fn zip_archive(files: &[(&str, &[u8])]) -> zip::ZipWriter<Cursor<Vec<u8>>> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in files {
+            writer
+                .start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer
+    }
+
+    // 直接写入头部的名字，tar::Builder 会拒绝含 .. 的路径
+    fn tar_entry(
+        builder: &mut tar::Builder<Vec<u8>>,
+        name: &str,
+        entry_type: tar::EntryType,
+        data: &[u8],
+    ) {
+        let mut header = tar::Header::new_gnu();
+        header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+        header.set_entry_type(entry_type);
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        if entry_type == tar::EntryType::Symlink || entry_type == tar::EntryType::Link {
+            header.set_link_name("/etc/passwd").unwrap();
+        }
+        header.set_cksum();
+        builder.append(&header, data).unwrap();
+    }
+
+    #[test]
+    fn unpack_zip_skips_unsafe_names_and_symlinks() {
+        let mut writer = zip_archive(&[
+            ("../x", b"x"),
+            ("a/../../b", b"b"),
+            ("/abs/c.txt", b"c"),
+            ("dir\\d.txt", b"d"),
+            ("n\0ul", b"n"),
+            ("ok.txt", b"ok"),
+        ]);
+        writer
+            .add_symlink("link", "/etc", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        writer
+            .add_directory("sub/", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        let data = writer.finish().unwrap().into_inner();
+        let (names, stats) = extract_all(data, ArchiveKind::Zip, &limits()).unwrap();
+        assert_eq!(names, vec!["abs/c.txt", "dir/d.txt", "ok.txt", "sub/"]);
+        assert_eq!((stats.files, stats.dirs, stats.skipped), (3, 1, 4));
+        assert_eq!(stats.bytes, 4);
+    }
+
+    #[test]
+    fn unpack_tar_skips_unsafe_names_and_special_entries() {
+        let mut builder = tar::Builder::new(Vec::new());
+        tar_entry(&mut builder, "./", tar::EntryType::Directory, b"");
+        tar_entry(&mut builder, "../x", tar::EntryType::Regular, b"x");
+        tar_entry(&mut builder, "a/../../b", tar::EntryType::Regular, b"b");
+        tar_entry(&mut builder, "link", tar::EntryType::Symlink, b"");
+        tar_entry(&mut builder, "hard", tar::EntryType::Link, b"");
+        tar_entry(&mut builder, "pipe", tar::EntryType::Fifo, b"");
+        tar_entry(&mut builder, "dev", tar::EntryType::Char, b"");
+        tar_entry(&mut builder, "d/", tar::EntryType::Directory, b"");
+        tar_entry(&mut builder, "d/ok.txt", tar::EntryType::Regular, b"ok");
+        let data = builder.into_inner().unwrap();
+        let (names, stats) = extract_all(data, ArchiveKind::Tar, &limits()).unwrap();
+        assert_eq!(names, vec!["d/", "d/ok.txt"]);
+        assert_eq!((stats.files, stats.dirs, stats.skipped), (1, 1, 6));
+    }
+
+    fn assert_limit(result: io::Result<(Vec<String>, PackStats)>, message: &str) {
+        let err = result.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains(message), "{}", err);
+    }
+
+    #[test]
+    fn unpack_stops_at_max_entries() {
+        let data = zip_archive(&[("a", b"a"), ("b", b"b"), ("c", b"c")])
+            .finish()
+            .unwrap()
+            .into_inner();
+        let limits = ExtractLimits {
+            max_entries: 2,
+            ..limits()
+        };
+        assert_limit(
+            extract_all(data.clone(), ArchiveKind::Zip, &limits),
+            "number of entries",
+        );
+        let limits = ExtractLimits {
+            max_entries: 3,
+            ..limits
+        };
+        assert!(extract_all(data, ArchiveKind::Zip, &limits).is_ok());
+    }
+
+    #[test]
+    fn unpack_counts_extracted_bytes_against_max_size() {
+        // 压缩包中声明的大小不可信，按实际解压出的字节数计算
+        let mut builder = tar::Builder::new(Vec::new());
+        tar_entry(&mut builder, "a", tar::EntryType::Regular, &[1; 600]);
+        tar_entry(&mut builder, "b", tar::EntryType::Regular, &[2; 600]);
+        let data = builder.into_inner().unwrap();
+        let limits = ExtractLimits {
+            max_size: 1000,
+            ..limits()
+        };
+        assert_limit(
+            extract_all(data.clone(), ArchiveKind::Tar, &limits),
+            "extracted size",
+        );
+        let limits = ExtractLimits {
+            max_size: 1200,
+            ..limits
+        };
+        assert!(extract_all(data, ArchiveKind::Tar, &limits).is_ok());
+    }
+
+    #[test]
+    fn unpack_stops_at_max_ratio() {
+        // 超过 RATIO_CHECK_SIZE 的全零文件，gzip 后只有几十 KB
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::fast()));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(RATIO_CHECK_SIZE + 1024 * 1024);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(
+                &mut header,
+                "zeros",
+                io::repeat(0).take(RATIO_CHECK_SIZE + 1024 * 1024),
+            )
+            .unwrap();
+        let data = builder.into_inner().unwrap().finish().unwrap();
+        assert!((data.len() as u64) * 100 < RATIO_CHECK_SIZE);
+        assert_limit(
+            extract_all(data.clone(), ArchiveKind::TarGz, &limits()),
+            "compression ratio",
+        );
+        let limits = ExtractLimits {
+            max_ratio: 10_000,
+            ..limits()
+        };
+        assert!(extract_all(data, ArchiveKind::TarGz, &limits).is_ok());
+    }
+}
@@ -0,0 +1,500 @@
+use std::{fmt, io, ops::BitOr};
+
+use hyper::{Method, Request};
+
+use crate::{
+    config::Rule,
+    exmethod::ExtendMethod,
+    http_methods::{download, post::ConflictPolicy},
+    storage::Storage,
+    util::{get_destination_path, get_query_param},
+};
+
+// 兼容原来的权限字母
+pub const LETTERS: &str = "RWDU";
+
+// rule.permission 解析后的权限集合
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Permissions(u16);
+
+impl Permissions {
+    pub const NONE: Permissions = Permissions(0);
+    // PROPFIND 和浏览目录
+    pub const LIST: Permissions = Permissions(1);
+    // 下载文件
+    pub const READ: Permissions = Permissions(1 << 1);
+    // 新建文件和目录
+    pub const CREATE: Permissions = Permissions(1 << 2);
+    // 覆盖已有的文件
+    pub const OVERWRITE: Permissions = Permissions(1 << 3);
+    // 改名和移动
+    pub const MOVE: Permissions = Permissions(1 << 4);
+    pub const DELETE: Permissions = Permissions(1 << 5);
+    // PROPPATCH
+    pub const SET_PROPERTIES: Permissions = Permissions(1 << 6);
+    // LOCK 和 UNLOCK
+    pub const LOCK: Permissions = Permissions(1 << 7);
+    // 创建分享链接
+    pub const SHARE: Permissions = Permissions(1 << 8);
+    // 投递箱，见 dropbox
+    pub const DROP_BOX: Permissions = Permissions(1 << 9);
+
+    // "R" 和 "W" 对应的权限，"W" 原来包含除删除外的所有修改
+    pub const R: Permissions = Permissions(Self::LIST.0 | Self::READ.0);
+    pub const W: Permissions = Permissions(
+        Self::CREATE.0
+            | Self::OVERWRITE.0
+            | Self::MOVE.0
+            | Self::SET_PROPERTIES.0
+            | Self::LOCK.0
+            | Self::SHARE.0,
+    );
+
+    const NAMES: [(&'static str, Permissions); 10] = [
+        ("list", Self::LIST),
+        ("read", Self::READ),
+        ("create", Self::CREATE),
+        ("overwrite", Self::OVERWRITE),
+        ("move", Self::MOVE),
+        ("delete", Self::DELETE),
+        ("set-properties", Self::SET_PROPERTIES),
+        ("lock", Self::LOCK),
+        ("share", Self::SHARE),
+        ("drop-box", Self::DROP_BOX),
+    ];
+
+    pub fn contains(self, other: Permissions) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersects(self, other: Permissions) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub fn intersection(self, other: Permissions) -> Permissions {
+        Permissions(self.0 & other.0)
+    }
+
+    // self 中 other 没有的权限
+    pub fn difference(self, other: Permissions) -> Permissions {
+        Permissions(self.0 & !other.0)
+    }
+
+    fn letter(letter: char) -> Option<Permissions> {
+        match letter {
+            'R' => Some(Self::R),
+            'W' => Some(Self::W),
+            'D' => Some(Self::DELETE),
+            'U' => Some(Self::DROP_BOX),
+            _ => None,
+        }
+    }
+
+    // 逗号或空格分隔的权限名或权限字母，例如 "RW"、"list,read,create"、"R, delete"，
+    // 为空时与原来一样只读，"none" 为没有权限。返回不认识的部分
+    pub fn parse(value: &str) -> Result<Permissions, String> {
+        if value.trim().is_empty() {
+            return Ok(Self::R);
+        }
+        let mut permissions = Self::NONE;
+        for token in value
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|token| !token.is_empty())
+        {
+            let lower = token.to_lowercase();
+            // "rename" 是 "move" 的别名，"none" 表示没有权限
+            let lower = match lower.as_str() {
+                "rename" => "move".to_string(),
+                "none" => continue,
+                _ => lower,
+            };
+            if let Some((_, named)) = Self::NAMES.iter().find(|(name, _)| *name == lower) {
+                permissions = permissions | *named;
+                continue;
+            }
+            for letter in token.to_uppercase().chars() {
+                permissions =
+                    permissions | Self::letter(letter).ok_or_else(|| token.to_string())?;
+            }
+        }
+        Ok(permissions)
+    }
+
+    // 配置校验会拒绝无法解析的权限，这里按没有权限处理
+    pub fn of(rule: &Rule) -> Permissions {
+        Self::parse(&rule.permission).unwrap_or(Self::NONE)
+    }
+
+    pub fn names(self) -> Vec<&'static str> {
+        Self::NAMES
+            .iter()
+            .filter(|(_, named)| self.contains(*named))
+            .map(|(name, _)| *name)
+            .collect()
+    }
+
+    pub fn all_names() -> String {
+        Self::NAMES
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl BitOr for Permissions {
+    type Output = Permissions;
+
+    fn bitor(self, other: Permissions) -> Permissions {
+        Permissions(self.0 | other.0)
+    }
+}
+
+// 可以再由 parse 解析，没有权限时为 "none"
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if *self == Self::NONE {
+            return write!(f, "none");
+        }
+        write!(f, "{}", self.names().join(","))
+    }
+}
+
+async fn exists(storage: &dyn Storage, path: &str) -> io::Result<Option<bool>> {
+    match storage.stat(path).await {
+        Ok(metadata) => Ok(Some(metadata.is_dir)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+// 写入目标需要的权限：不存在时新建，存在时覆盖
+async fn write_target(storage: &dyn Storage, path: &str) -> Permissions {
+    match exists(storage, path).await {
+        Ok(None) => Permissions::CREATE,
+        _ => Permissions::OVERWRITE,
+    }
+}
+
+// 表单上传、解压和打包遇到同名文件时可能覆盖
+fn upload_target(rule: &Rule) -> Permissions {
+    match ConflictPolicy::parse(&rule.upload_conflict) {
+        Some(ConflictPolicy::Overwrite) => Permissions::CREATE | Permissions::OVERWRITE,
+        _ => Permissions::CREATE,
+    }
+}
+
+// 请求需要的权限，包括对 path 和 Destination 的。不认识的方法返回 None，不能当作不需要权限
+pub async fn required<B>(
+    req: &Request<B>,
+    rule: &Rule,
+    storage: &dyn Storage,
+    path: &str,
+) -> Option<Permissions> {
+    let method = req.method();
+    if *method == Method::GET || *method == Method::HEAD {
+        if get_query_param(req, "shares").is_some() {
+            return Some(Permissions::SHARE);
+        }
+        return Some(match exists(storage, path).await {
+            // 打包下载目录需要列出并读取其中的文件
+            Ok(Some(true)) if matches!(download::requested_format(req), Some(Ok(_))) => {
+                Permissions::R
+            }
+            Ok(Some(true)) => Permissions::LIST,
+            _ => Permissions::READ,
+        });
+    }
+    if *method == Method::from(ExtendMethod::PROPFIND) {
+        return Some(Permissions::LIST);
+    }
+    if *method == Method::from(ExtendMethod::REPORT) {
+        return Some(Permissions::READ);
+    }
+    if *method == Method::PUT {
+        return Some(write_target(storage, path).await);
+    }
+    if *method == Method::from(ExtendMethod::MKCOL) {
+        return Some(Permissions::CREATE);
+    }
+    if *method == Method::DELETE {
+        return Some(Permissions::DELETE);
+    }
+    if *method == Method::from(ExtendMethod::COPY) || *method == Method::from(ExtendMethod::MOVE) {
+        let source = if *method == Method::from(ExtendMethod::COPY) {
+            Permissions::READ
+        } else {
+            Permissions::MOVE
+        };
+        let destination = match get_destination_path(req, rule) {
+            Some(destination) => destination,
+            None => return Some(source),
+        };
+        // 移动或复制到新位置需要 create，替换已有的目标需要 overwrite
+        return Some(source | write_target(storage, &destination).await);
+    }
+    if *method == Method::from(ExtendMethod::PROPPATCH) {
+        return Some(Permissions::SET_PROPERTIES);
+    }
+    if *method == Method::from(ExtendMethod::LOCK) {
+        // 锁定不存在的资源时会创建空文件
+        return Some(match exists(storage, path).await {
+            Ok(None) => Permissions::LOCK | Permissions::CREATE,
+            _ => Permissions::LOCK,
+        });
+    }
+    if *method == Method::from(ExtendMethod::UNLOCK) {
+        return Some(Permissions::LOCK);
+    }
+    if *method == Method::from(ExtendMethod::VERSIONCONTROL)
+        || *method == Method::from(ExtendMethod::CHECKOUT)
+        || *method == Method::from(ExtendMethod::CHECKIN)
+        || *method == Method::from(ExtendMethod::UNCHECKOUT)
+    {
+        return Some(Permissions::OVERWRITE);
+    }
+    if *method == Method::POST {
+        return Some(match get_query_param(req, "action").as_deref() {
+            Some("share") | Some("unshare") => Permissions::SHARE,
+            Some("extract") => Permissions::READ | upload_target(rule),
+            Some("pack") => Permissions::R | upload_target(rule),
+            _ => upload_target(rule),
+        });
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::stream;
+    use hyper::body::Bytes;
+
+    use super::*;
+    use crate::storage::{memory::MemoryStorage, ByteStream};
+
+    const HOST: &str = "127.0.0.1:9988";
+
+    fn request(method: Method, uri: &str, destination: Option<&str>) -> Request<()> {
+        let mut builder = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Host", HOST);
+        if let Some(destination) = destination {
+            builder = builder.header("Destination", format!("http://{}{}", HOST, destination));
+        }
+        builder.body(()).unwrap()
+    }
+
+    async fn storage() -> MemoryStorage {
+        let storage = MemoryStorage::new(1024);
+        storage.mkdir("/dir").await.unwrap();
+        let body: ByteStream = Box::pin(stream::iter([Ok(Bytes::from("a"))]));
+        storage.write("/file", body).await.unwrap();
+        storage
+    }
+
+    async fn required_for(
+        method: Method,
+        uri: &str,
+        destination: Option<&str>,
+    ) -> Option<Permissions> {
+        let rule = Rule {
+            server_prefix: "/a".to_string(),
+            ..Rule::default()
+        };
+        let req = request(method, uri, destination);
+        let path = uri.trim_start_matches("/a").split('?').next().unwrap();
+        required(&req, &rule, &storage().await, path).await
+    }
+
+    fn extended(method: ExtendMethod) -> Method {
+        Method::from(method)
+    }
+
+    #[test]
+    fn parse_letters_and_names() {
+        assert_eq!(Permissions::parse(""), Ok(Permissions::R));
+        assert_eq!(Permissions::parse("R"), Ok(Permissions::R));
+        assert_eq!(
+            Permissions::parse("rw"),
+            Ok(Permissions::R | Permissions::W)
+        );
+        assert_eq!(
+            Permissions::parse("RWD"),
+            Ok(Permissions::R | Permissions::W | Permissions::DELETE)
+        );
+        assert_eq!(Permissions::parse("U"), Ok(Permissions::DROP_BOX));
+        assert_eq!(
+            Permissions::parse("list,read, create"),
+            Ok(Permissions::LIST | Permissions::READ | Permissions::CREATE)
+        );
+        assert_eq!(
+            Permissions::parse("R, delete"),
+            Ok(Permissions::R | Permissions::DELETE)
+        );
+        assert_eq!(Permissions::parse("Rename"), Ok(Permissions::MOVE));
+        assert_eq!(Permissions::parse("none"), Ok(Permissions::NONE));
+        assert_eq!(Permissions::parse("read,bogus"), Err("bogus".to_string()));
+        assert_eq!(Permissions::parse("RX"), Err("RX".to_string()));
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for value in ["none", "R", "RW", "RWD", "U", "list,delete,share"] {
+            let permissions = Permissions::parse(value).unwrap();
+            assert_eq!(
+                Permissions::parse(&permissions.to_string()),
+                Ok(permissions)
+            );
+        }
+        assert_eq!(Permissions::NONE.to_string(), "none");
+        assert_eq!(Permissions::R.to_string(), "list,read");
+    }
+
+    #[tokio::test]
+    async fn required_for_reads() {
+        assert_eq!(
+            required_for(Method::GET, "/a/file", None).await,
+            Some(Permissions::READ)
+        );
+        assert_eq!(
+            required_for(Method::HEAD, "/a/file", None).await,
+            Some(Permissions::READ)
+        );
+        assert_eq!(
+            required_for(Method::GET, "/a/dir", None).await,
+            Some(Permissions::LIST)
+        );
+        assert_eq!(
+            required_for(Method::GET, "/a/dir?archive=zip", None).await,
+            Some(Permissions::R)
+        );
+        assert_eq!(
+            required_for(Method::GET, "/a/dir?shares", None).await,
+            Some(Permissions::SHARE)
+        );
+        assert_eq!(
+            required_for(extended(ExtendMethod::PROPFIND), "/a/dir", None).await,
+            Some(Permissions::LIST)
+        );
+        assert_eq!(
+            required_for(extended(ExtendMethod::REPORT), "/a/file", None).await,
+            Some(Permissions::READ)
+        );
+    }
+
+    #[tokio::test]
+    async fn required_for_writes() {
+        assert_eq!(
+            required_for(Method::PUT, "/a/new", None).await,
+            Some(Permissions::CREATE)
+        );
+        assert_eq!(
+            required_for(Method::PUT, "/a/file", None).await,
+            Some(Permissions::OVERWRITE)
+        );
+        assert_eq!(
+            required_for(extended(ExtendMethod::MKCOL), "/a/new", None).await,
+            Some(Permissions::CREATE)
+        );
+        assert_eq!(
+            required_for(Method::DELETE, "/a/file", None).await,
+            Some(Permissions::DELETE)
+        );
+        assert_eq!(
+            required_for(extended(ExtendMethod::PROPPATCH), "/a/file", None).await,
+            Some(Permissions::SET_PROPERTIES)
+        );
+        assert_eq!(
+            required_for(extended(ExtendMethod::LOCK), "/a/new", None).await,
+            Some(Permissions::LOCK | Permissions::CREATE)
+        );
+        assert_eq!(
+            required_for(extended(ExtendMethod::LOCK), "/a/file", None).await,
+            Some(Permissions::LOCK)
+        );
+        assert_eq!(
+            required_for(extended(ExtendMethod::UNLOCK), "/a/file", None).await,
+            Some(Permissions::LOCK)
+        );
+        for method in [
+            ExtendMethod::VERSIONCONTROL,
+            ExtendMethod::CHECKOUT,
+            ExtendMethod::CHECKIN,
+            ExtendMethod::UNCHECKOUT,
+        ] {
+            assert_eq!(
+                required_for(extended(method), "/a/file", None).await,
+                Some(Permissions::OVERWRITE)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn required_for_copy_and_move() {
+        let copy = || extended(ExtendMethod::COPY);
+        let moving = || extended(ExtendMethod::MOVE);
+        assert_eq!(
+            required_for(copy(), "/a/file", Some("/a/new")).await,
+            Some(Permissions::READ | Permissions::CREATE)
+        );
+        assert_eq!(
+            required_for(copy(), "/a/file", Some("/a/dir")).await,
+            Some(Permissions::READ | Permissions::OVERWRITE)
+        );
+        assert_eq!(
+            required_for(moving(), "/a/file", Some("/a/new")).await,
+            Some(Permissions::MOVE | Permissions::CREATE)
+        );
+        assert_eq!(
+            required_for(moving(), "/a/dir", Some("/a/file")).await,
+            Some(Permissions::MOVE | Permissions::OVERWRITE)
+        );
+        // Destination 不在当前 rule 下时只检查源
+        assert_eq!(
+            required_for(moving(), "/a/file", Some("/b/new")).await,
+            Some(Permissions::MOVE)
+        );
+    }
+
+    #[tokio::test]
+    async fn required_for_post() {
+        assert_eq!(
+            required_for(Method::POST, "/a/dir", None).await,
+            Some(Permissions::CREATE)
+        );
+        assert_eq!(
+            required_for(Method::POST, "/a/dir?action=share", None).await,
+            Some(Permissions::SHARE)
+        );
+        assert_eq!(
+            required_for(Method::POST, "/a/dir?action=unshare&token=x", None).await,
+            Some(Permissions::SHARE)
+        );
+        assert_eq!(
+            required_for(Method::POST, "/a/file?action=extract", None).await,
+            Some(Permissions::READ | Permissions::CREATE)
+        );
+        assert_eq!(
+            required_for(Method::POST, "/a/dir?action=pack", None).await,
+            Some(Permissions::R | Permissions::CREATE)
+        );
+        let rule = Rule {
+            server_prefix: "/a".to_string(),
+            upload_conflict: "overwrite".to_string(),
+            ..Rule::default()
+        };
+        let req = request(Method::POST, "/a/dir", None);
+        assert_eq!(
+            required(&req, &rule, &storage().await, "/dir").await,
+            Some(Permissions::CREATE | Permissions::OVERWRITE)
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_method_is_not_allowed() {
+        let method = Method::from_bytes(b"BREW").unwrap();
+        assert_eq!(required_for(method, "/a/file", None).await, None);
+        assert_eq!(required_for(Method::TRACE, "/a/file", None).await, None);
+    }
+}
@@ -0,0 +1,401 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use lazy_static::lazy_static;
+
+use crate::{
+    config::{
+        check_config, get_config, load_config, parse_log_level, set_config, AdminConfig, Config,
+        ConfigOverrides, Rule, SharesConfig,
+    },
+    share,
+    storage::redact_path,
+};
+
+// 检查配置文件修改时间的间隔
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+lazy_static! {
+    // 当前配置来自的文件及命令行覆盖，管理接口修改配置后据此写回并重新加载
+    static ref SOURCE: Mutex<Option<(String, ConfigOverrides)>> = Mutex::new(None);
+    // 当前生效的配置文件内容摘要，内容没变时不重复加载
+    static ref APPLIED_DIGEST: Mutex<Option<md5::Digest>> = Mutex::new(None);
+}
+
+pub fn config_source() -> Option<(String, ConfigOverrides)> {
+    SOURCE.lock().unwrap().clone()
+}
+
+// 配置文件修改或收到 SIGHUP 时重新加载配置
+pub fn spawn_watcher(path: String, overrides: ConfigOverrides) {
+    *SOURCE.lock().unwrap() = Some((path.to_string(), overrides.clone()));
+    *APPLIED_DIGEST.lock().unwrap() = file_digest(&path);
+    #[cfg(unix)]
+    tokio::spawn(watch_sighup(path.to_string(), overrides.clone()));
+    tokio::spawn(watch_file(path, overrides));
+}
+
+async fn watch_file(path: String, overrides: ConfigOverrides) {
+    let mut last_modified = modified_time(&path);
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        let modified = modified_time(&path);
+        if modified != last_modified {
+            last_modified = modified;
+            if file_digest(&path) == *APPLIED_DIGEST.lock().unwrap() {
+                continue;
+            }
+            log::info!("{} changed, reloading", path);
+            reload(&path, &overrides);
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn watch_sighup(path: String, overrides: ConfigOverrides) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            log::error!("failed to listen for SIGHUP: {}", err);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        log::info!("received SIGHUP, reloading {}", path);
+        reload(&path, &overrides);
+    }
+}
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn file_digest(path: &str) -> Option<md5::Digest> {
+    fs::read(path).ok().map(md5::compute)
+}
+
+// 新配置校验通过后才替换，否则继续使用旧配置
+pub fn reload(path: &str, overrides: &ConfigOverrides) -> bool {
+    let digest = file_digest(path);
+    let mut config = match load_config(path) {
+        Ok(config) => config,
+        Err(err) => {
+            log::error!("reload failed, keep the current config: {}", err);
+            return false;
+        }
+    };
+    overrides.apply(&mut config);
+    match check_config(&config) {
+        Ok(warnings) => {
+            for warning in warnings {
+                log::warn!("{}", warning);
+            }
+        }
+        Err(err) => {
+            log::error!("reload failed, keep the current config: {}", err);
+            return false;
+        }
+    }
+    let current = get_config();
+    if current.port != config.port {
+        log::warn!(
+            "port changed from {} to {}, restart to apply it",
+            current.port,
+            config.port
+        );
+    }
+    if let Ok(level) = parse_log_level(&config.log) {
+        log::set_max_level(level);
+    }
+    let changes = diff_config(&current, &config);
+    if changes.is_empty() {
+        log::info!("config reloaded, no changes");
+    }
+    for change in changes {
+        log::info!("config reloaded, {}", change);
+    }
+    set_config(config);
+    *APPLIED_DIGEST.lock().unwrap() = digest;
+    true
+}
+
+// 以用户名区分规则，游客规则为 "guest"
+fn rules_by_user(config: &Config) -> BTreeMap<String, &Rule> {
+    config
+        .rules
+        .iter()
+        .map(|rule| {
+            let user = if rule.user.is_empty() || rule.password.is_empty() {
+                "guest".to_string()
+            } else {
+                rule.user.to_string()
+            };
+            (user, rule)
+        })
+        .collect()
+}
+
+fn describe_rule(rule: &Rule) -> String {
+    format!(
+        "{} -> {} ({})",
+        rule.server_prefix,
+        redact_path(&rule.path),
+        rule.permission
+    )
+}
+
+pub fn diff_config(old: &Config, new: &Config) -> Vec<String> {
+    let old_rules = rules_by_user(old);
+    let new_rules = rules_by_user(new);
+    let mut changes = Vec::new();
+    for (user, rule) in &old_rules {
+        if !new_rules.contains_key(user) {
+            changes.push(format!("removed {}: {}", user, describe_rule(rule)));
+        }
+    }
+    for (user, rule) in &new_rules {
+        let old_rule = match old_rules.get(user) {
+            Some(old_rule) => old_rule,
+            None => {
+                changes.push(format!("added {}: {}", user, describe_rule(rule)));
+                continue;
+            }
+        };
+        let mut fields = Vec::new();
+        if old_rule.path != rule.path {
+            fields.push(format!(
+                "path {} -> {}",
+                redact_path(&old_rule.path),
+                redact_path(&rule.path)
+            ));
+        }
+        if old_rule.server_prefix != rule.server_prefix {
+            fields.push(format!(
+                "server_prefix {} -> {}",
+                old_rule.server_prefix, rule.server_prefix
+            ));
+        }
+        if old_rule.permission != rule.permission {
+            fields.push(format!(
+                "permission {} -> {}",
+                old_rule.permission, rule.permission
+            ));
+        }
+        if old_rule.browse_archives != rule.browse_archives {
+            fields.push(format!(
+                "browse_archives {} -> {}",
+                old_rule.browse_archives, rule.browse_archives
+            ));
+        }
+        if old_rule.sources != rule.sources {
+            fields.push("sources changed".to_string());
+        }
+        if old_rule.encryption != rule.encryption {
+            fields.push("encryption changed".to_string());
+        }
+        if old_rule.trash != rule.trash {
+            fields.push("trash changed".to_string());
+        }
+        if old_rule.versions != rule.versions {
+            fields.push("versions changed".to_string());
+        }
+        if old_rule.upload_conflict != rule.upload_conflict {
+            fields.push(format!(
+                "upload_conflict {} -> {}",
+                old_rule.upload_conflict, rule.upload_conflict
+            ));
+        }
+        if old_rule.extract != rule.extract {
+            fields.push("extract changed".to_string());
+        }
+        if old_rule.thumbnails != rule.thumbnails {
+            fields.push("thumbnails changed".to_string());
+        }
+        if old_rule.media_metadata != rule.media_metadata {
+            fields.push(format!(
+                "media_metadata {} -> {}",
+                old_rule.media_metadata, rule.media_metadata
+            ));
+        }
+        if old_rule.password != rule.password {
+            fields.push("password changed".to_string());
+        }
+        if !fields.is_empty() {
+            changes.push(format!("changed {}: {}", user, fields.join(", ")));
+        }
+    }
+    if old.log != new.log {
+        changes.push(format!("log {} -> {}", old.log, new.log));
+    }
+    match (&old.admin, &new.admin) {
+        (None, Some(admin)) => changes.push(format!("added admin: {}", describe_admin(admin))),
+        (Some(admin), None) => changes.push(format!("removed admin: {}", describe_admin(admin))),
+        (Some(old_admin), Some(admin)) => {
+            let mut fields = Vec::new();
+            if old_admin.prefix != admin.prefix {
+                fields.push(format!("prefix {} -> {}", old_admin.prefix, admin.prefix));
+            }
+            if old_admin.port != admin.port {
+                fields.push(format!("port {} -> {}", old_admin.port, admin.port));
+            }
+            if old_admin.user != admin.user {
+                fields.push(format!("user {} -> {}", old_admin.user, admin.user));
+            }
+            if old_admin.password != admin.password {
+                fields.push("password changed".to_string());
+            }
+            if !fields.is_empty() {
+                changes.push(format!("changed admin: {}", fields.join(", ")));
+            }
+        }
+        (None, None) => {}
+    }
+    match (&old.shares, &new.shares) {
+        (None, Some(shares)) => {
+            changes.push(format!("enabled shares: {}", describe_shares(shares)))
+        }
+        (Some(_), None) => changes.push("disabled shares".to_string()),
+        (Some(old_shares), Some(shares)) => {
+            let mut fields = Vec::new();
+            if old_shares.prefix != shares.prefix {
+                fields.push(format!(
+                    "prefix {} -> {}",
+                    share::prefix(old_shares),
+                    share::prefix(shares)
+                ));
+            }
+            if old_shares.file != shares.file {
+                fields.push(format!("file {} -> {}", old_shares.file, shares.file));
+            }
+            if !fields.is_empty() {
+                changes.push(format!("changed shares: {}", fields.join(", ")));
+            }
+        }
+        (None, None) => {}
+    }
+    changes
+}
+
+fn describe_admin(admin: &AdminConfig) -> String {
+    if admin.port != 0 {
+        format!("{} on port {} ({})", admin.prefix, admin.port, admin.user)
+    } else {
+        format!("{} ({})", admin.prefix, admin.user)
+    }
+}
+
+fn describe_shares(shares: &SharesConfig) -> String {
+    if shares.file.is_empty() {
+        format!("{} (in memory)", share::prefix(shares))
+    } else {
+        format!("{} ({})", share::prefix(shares), shares.file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ThumbnailsConfig, TrashConfig};
+
+    fn config(rules: Vec<Rule>) -> Config {
+        Config {
+            rules,
+            ..Config::default()
+        }
+    }
+
+    fn rule(user: &str, server_prefix: &str) -> Rule {
+        Rule {
+            path: "/data".to_string(),
+            user: user.to_string(),
+            password: "pw".to_string(),
+            server_prefix: server_prefix.to_string(),
+            permission: "R".to_string(),
+            ..Rule::default()
+        }
+    }
+
+    #[test]
+    fn same_config_has_no_changes() {
+        let old = config(vec![rule("a", "/a")]);
+        assert!(diff_config(&old, &old.clone()).is_empty());
+    }
+
+    #[test]
+    fn added_removed_and_changed_rules() {
+        let old = config(vec![rule("a", "/a"), rule("b", "/b")]);
+        let mut changed = rule("a", "/a2");
+        changed.permission = "RW".to_string();
+        changed.password = "secret".to_string();
+        changed.browse_archives = true;
+        changed.trash = Some(TrashConfig::default());
+        changed.thumbnails = Some(ThumbnailsConfig::default());
+        changed.upload_conflict = "reject".to_string();
+        let new = config(vec![changed, rule("c", "/c")]);
+        assert_eq!(
+            diff_config(&old, &new),
+            vec![
+                "removed b: /b -> /data (R)",
+                "changed a: server_prefix /a -> /a2, permission R -> RW, \
+                 browse_archives false -> true, trash changed, upload_conflict  -> reject, \
+                 thumbnails changed, password changed",
+                "added c: /c -> /data (R)",
+            ]
+        );
+    }
+
+    #[test]
+    fn admin_and_shares_changes() {
+        let old = config(Vec::new());
+        let mut new = old.clone();
+        new.admin = Some(AdminConfig {
+            prefix: "/_admin".to_string(),
+            user: "root".to_string(),
+            password: "pw".to_string(),
+            ..AdminConfig::default()
+        });
+        new.shares = Some(SharesConfig::default());
+        assert_eq!(
+            diff_config(&old, &new),
+            vec![
+                "added admin: /_admin (root)",
+                "enabled shares: /s (in memory)"
+            ]
+        );
+
+        let mut changed = new.clone();
+        if let Some(admin) = changed.admin.as_mut() {
+            admin.port = 9000;
+            admin.password = "other".to_string();
+        }
+        changed.shares = Some(SharesConfig {
+            prefix: "/share".to_string(),
+            file: "shares.json".to_string(),
+        });
+        changed.log = "debug".to_string();
+        assert_eq!(
+            diff_config(&new, &changed),
+            vec![
+                "log  -> debug",
+                "changed admin: port 0 -> 9000, password changed",
+                "changed shares: prefix /s -> /share, file  -> shares.json",
+            ]
+        );
+
+        assert_eq!(
+            diff_config(&changed, &old),
+            vec![
+                "log debug -> ",
+                "removed admin: /_admin on port 9000 (root)",
+                "disabled shares",
+            ]
+        );
+    }
+}
@@ -1,26 +1,45 @@
 use crate::exmethod::ExtendMethod;
-use crate::http_methods::{copy, delete, exmove, get, head, mkcol, options, propfind, put};
-use crate::util::{empty, get_base_dir, get_current_user_rule, get_req_path, get_server_prefix};
+use crate::http_methods::{
+    copy, delete, deltav, exmove, get, head, lock, mkcol, options, post, propfind, put, unlock,
+};
+use crate::permission::{self, Permissions};
+use crate::config::Rule;
+use crate::{admin, config, dropbox, session, share, storage, trash, versions};
+use crate::util::{empty, get_current_user_rule, get_query_param, get_req_path, map_io_result};
 use chrono::Local;
 use http_body_util::combinators::BoxBody;
 use hyper::body::{Bytes, Incoming};
-use hyper::header::{CONNECTION, WWW_AUTHENTICATE};
+use hyper::header::{CONNECTION, SET_COOKIE, WWW_AUTHENTICATE};
 use hyper::http::HeaderValue;
 use hyper::{Method, Request, Response, StatusCode};
-use std::path::Path;
+use std::net::SocketAddr;
 
-pub async fn handle_request(req: Request<Incoming>) -> Result<Response<BoxBody<Bytes, std::io::Error>>, std::io::Error> {
+pub async fn handle_request(req: Request<Incoming>, remote_addr: SocketAddr) -> Result<Response<BoxBody<Bytes, std::io::Error>>, std::io::Error> {
     log::info!("req: {:?}", &req);
     let mut resp = Response::new(empty());
     let method = req.method().clone();
+    // 整个请求使用同一份配置快照
+    let cfg = config::get_config();
+    if admin::is_admin_request(&cfg, &req) {
+        return Ok(admin::handle_request(req, cfg).await);
+    }
     if method == Method::OPTIONS {
         resp = options::handle_resp().await;
         return Ok(resp);
     }
     let headers = req.headers().clone();
+    // 分享链接不需要登录，由 token 和分享的密码校验
+    let share_rule = match share::resolve(&cfg, &req).await {
+        Some(Ok(rule)) => Some(rule),
+        Some(Err(resp)) => return Ok(resp),
+        None => None,
+    };
     // Basic Authentication
-    let current_user_rule = get_current_user_rule(&req);
-    if method != Method::OPTIONS && current_user_rule.is_none() {
+    let current_user_rule = match &share_rule {
+        Some(rule) => Some(rule),
+        None => get_current_user_rule(&cfg, &req).await,
+    };
+    if current_user_rule.is_none() {
         *resp.status_mut() = StatusCode::UNAUTHORIZED;
         resp.headers_mut().insert(
             WWW_AUTHENTICATE,
@@ -28,76 +47,181 @@ pub async fn handle_request(req: Request<Incoming>) -> Result<Response<BoxBody<B
         );
         return Ok(resp);
     }
+    let rule = current_user_rule.unwrap();
+    let user = match &rule.share {
+        Some(share) => format!("share:{}", share.token),
+        None if rule.user.is_empty() => "guest".to_string(),
+        None => rule.user.to_string(),
+    };
+    let user = user.as_str();
+    let _session = session::begin(user, remote_addr);
     log::info!("pass auth");
     // 要挂载的目录
-    let base_dir = get_base_dir(&req);
-    if base_dir.len() == 0 {
+    let base_dir = &rule.path;
+    if base_dir.is_empty() {
         *resp.status_mut() = StatusCode::NOT_FOUND;
         return Ok(resp);
     }
     log::info!("pass mount");
     // webdav 访问路径前缀
-    let server_prefix = get_server_prefix(&req);
+    let server_prefix = &rule.server_prefix;
     let req_path = get_req_path(&req);
     let mut path = req_path.to_string();
-    path = path.replacen(&server_prefix, "", 1);
-    // 被访问资源绝对路径
-    let file_path = Path::new(&base_dir).join(path.trim_start_matches('/'));
-    log::info!("req_path: {}, path: {}, file_path: {:?}", req_path, path, file_path);
-    if (method != Method::from(ExtendMethod::MKCOL)
-        && method != Method::PUT
-        && !file_path.exists()
-        && !file_path.is_dir())
-        || !req_path.starts_with(&server_prefix)
-    {
+    path = path.replacen(server_prefix, "", 1);
+    // 被访问资源相对共享根目录的路径
+    let path = storage::normalize_path(&path);
+    // 浏览压缩包时压缩包显示为目录，GET ?raw 下载压缩包文件本身，
+    // COPY 不在压缩包内的路径时复制压缩包文件本身，而不是把它展开成目录
+    let mut plain = rule.clone();
+    plain.browse_archives = false;
+    let raw = rule.browse_archives
+        && if method == Method::from(ExtendMethod::COPY) {
+            is_outside_archives(&plain, &path).await
+        } else {
+            (method == Method::GET || method == Method::HEAD)
+                && get_query_param(&req, "raw").is_some()
+        };
+    let storage = if raw {
+        storage::for_rule(&plain)
+    } else {
+        storage::for_rule(rule)
+    };
+    let storage = match storage {
+        Ok(storage) => storage,
+        Err(err) => {
+            log::error!(
+                "failed to open storage {}: {}",
+                storage::redact_path(base_dir),
+                err
+            );
+            *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            return Ok(resp);
+        }
+    };
+    let storage = storage.as_ref();
+    log::info!("req_path: {}, path: {}", req_path, path);
+    if !req_path.starts_with(server_prefix) {
         *resp.status_mut() = StatusCode::NOT_FOUND;
         log::info!("not found");
         return Ok(resp);
     }
+    // 投递箱看不到别人的内容，在判断资源是否存在之前检查
+    let drop_box = dropbox::is_drop_box(rule);
+    let mut change = None;
+    let drop_session = drop_box.then(|| dropbox::begin(&req, user));
+    if let Some(drop_session) = &drop_session {
+        match dropbox::check(&req, rule, drop_session, storage, &path).await {
+            Ok(allowed) => change = allowed,
+            Err(status) => {
+                *resp.status_mut() = status;
+                return Ok(resp);
+            }
+        }
+    }
+    if method != Method::from(ExtendMethod::MKCOL)
+        && method != Method::PUT
+        && method != Method::from(ExtendMethod::LOCK)
+    {
+        if let Err(err) = storage.stat(&path).await {
+            // 远程存储连接失败等错误不当作资源不存在
+            if err.kind() != std::io::ErrorKind::NotFound {
+                log::error!(
+                    "failed to stat {} in {}: {}",
+                    path,
+                    storage::redact_path(base_dir),
+                    err
+                );
+            }
+            *resp.status_mut() = map_io_result::<()>(Err(err), StatusCode::OK);
+            log::info!("not found");
+            return Ok(resp);
+        }
+    }
     log::info!("pass source");
-    // 权限校验
-    let permission = current_user_rule.unwrap().permission.to_uppercase();
-    let mut has_permission = true;
-    let read_methods = [
-        Method::GET,
-        Method::HEAD,
-        Method::from(ExtendMethod::PROPFIND),
-    ];
-    if !read_methods.contains(&method) {
-        if method == Method::DELETE {
-            has_permission = permission.contains("D");
-        } else {
-            has_permission = permission.contains("W");
+    // 权限校验，每个方法需要的权限见 permission::required，投递箱的请求已由 dropbox::check 检查
+    let mut has_permission = drop_box;
+    if !drop_box {
+        // 不认识的方法不能当作不需要权限
+        match permission::required(&req, rule, storage, &path).await {
+            Some(required) => {
+                let missing = required.difference(Permissions::of(rule));
+                has_permission = missing == Permissions::NONE;
+                if !has_permission {
+                    log::warn!("refused {} {} for {}: missing {}", method, path, user, missing);
+                }
+            }
+            None => {
+                *resp.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+                return Ok(resp);
+            }
         }
     }
+    // 回收站和历史版本中不能新建或修改文件
+    if (trash::is_trash_path(rule, &path) || versions::is_versions_path(rule, &path))
+        && (method == Method::PUT
+            || method == Method::POST
+            || method == Method::from(ExtendMethod::MKCOL)
+            || method == Method::from(ExtendMethod::LOCK))
+    {
+        log::warn!("refused {} {}: trash and versions are read only", method, path);
+        has_permission = false;
+    }
     if !has_permission {
-        log::error!("111114");
         *resp.status_mut() = StatusCode::FORBIDDEN;
         return Ok(resp);
     }
     log::info!("pass permission");
+    // 被别人锁定或没有带上锁的 token 时不能修改
+    if !lock::check(&req, user, rule, &path) {
+        *resp.status_mut() = StatusCode::LOCKED;
+        return Ok(resp);
+    }
+    if let Some(share) = &rule.share {
+        if let Err(status) = share::check_download(&cfg, &req, share, storage, &path).await {
+            *resp.status_mut() = status;
+            return Ok(resp);
+        }
+    }
     // 实现各个 HTTP 方法
     if method == Method::from(ExtendMethod::PROPFIND) {
-        resp = propfind::handle_resp(&req, file_path).await;
+        let visible = drop_session.as_ref().map(|session| session.created());
+        resp = propfind::handle_resp(req, rule, storage, &path, visible.as_ref()).await;
     } else if method == Method::from(ExtendMethod::COPY) {
-        resp = copy::handle_resp(&req, &file_path).await;
+        resp = copy::handle_resp(&req, rule, storage, &path).await;
     } else if method == Method::from(ExtendMethod::MKCOL) {
-        resp = mkcol::handle_resp(&file_path).await;
+        resp = mkcol::handle_resp(storage, &path).await;
     } else if method == Method::from(ExtendMethod::MOVE) {
-        resp = exmove::handle_resp(&req, &file_path).await;
+        resp = exmove::handle_resp(&req, rule, storage, &path).await;
+    } else if method == Method::from(ExtendMethod::LOCK) {
+        resp = lock::handle_resp(req, user, rule, storage, &path).await;
+    } else if method == Method::from(ExtendMethod::UNLOCK) {
+        resp = unlock::handle_resp(&req, user, rule, &path).await;
+    } else if method == Method::from(ExtendMethod::VERSIONCONTROL) {
+        resp = deltav::version_control(rule, storage, &path).await;
+    } else if method == Method::from(ExtendMethod::CHECKOUT) {
+        resp = deltav::checkout(rule, storage, &path).await;
+    } else if method == Method::from(ExtendMethod::CHECKIN) {
+        resp = deltav::checkin(rule, storage, &path).await;
+    } else if method == Method::from(ExtendMethod::UNCHECKOUT) {
+        resp = deltav::uncheckout(rule, storage, &path).await;
+    } else if method == Method::from(ExtendMethod::REPORT) {
+        resp = deltav::report(req, rule, storage, &path).await;
     } else {
         match method {
             Method::GET => {
-                resp = get::handle_resp(&req, &file_path).await;
+                resp = get::handle_resp(&req, &cfg, rule, storage, &path).await;
             }
             Method::HEAD => {
-                resp = head::handle_resp(&file_path).await;
+                resp = head::handle_resp(storage, &path).await;
             }
             Method::DELETE => {
-                resp = delete::handle_resp(&file_path).await;
+                resp = delete::handle_resp(rule, user, storage, &path).await;
             }
             Method::PUT => {
-                resp = put::handle_resp(req, &file_path).await.unwrap();
+                resp = put::handle_resp(req, rule, storage, &path).await;
+            }
+            Method::POST => {
+                resp = post::handle_resp(req, &cfg, rule, storage, &path).await;
             }
             _ => {
                 *resp.status_mut() = StatusCode::OK;
@@ -105,10 +229,23 @@ pub async fn handle_request(req: Request<Incoming>) -> Result<Response<BoxBody<B
         }
     }
 
+    if let Some(drop_session) = &drop_session {
+        if resp.status().is_success() {
+            dropbox::record(
+                drop_session,
+                change,
+                resp.extensions().get::<dropbox::Uploaded>(),
+            );
+        }
+        if let Some(cookie) = drop_session.cookie(rule) {
+            resp.headers_mut().insert(SET_COOKIE, cookie);
+        }
+    }
+
     if method != Method::GET {
         log::info!(
             "{}---resp: {:?}",
-            Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            Local::now().format("%Y-%m-%d %H:%M:%S"),
             resp
         );
     } else {
@@ -122,3 +259,11 @@ pub async fn handle_request(req: Request<Incoming>) -> Result<Response<BoxBody<B
     log::info!("resp header: {:?}", resp.headers());
     Ok(resp)
 }
+
+// 不把压缩包当作目录时路径是否存在，即路径不在压缩包内
+async fn is_outside_archives(plain: &Rule, path: &str) -> bool {
+    match storage::for_rule(plain) {
+        Ok(storage) => storage.stat(path).await.is_ok(),
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,101 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use crate::util::format_date_time;
+
+// 超过这个时间没有请求的会话不再显示
+pub const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+lazy_static! {
+    // key 为 "用户@IP"，同一个客户端的多个连接算作一个会话
+    static ref SESSIONS: Mutex<HashMap<String, Session>> = Mutex::new(HashMap::new());
+}
+
+struct Session {
+    user: String,
+    remote_ip: String,
+    started: SystemTime,
+    last_seen: SystemTime,
+    requests: u64,
+    active: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionView {
+    pub user: String,
+    pub remote_ip: String,
+    pub started: String,
+    pub last_seen: String,
+    pub requests: u64,
+    pub active_requests: u32,
+}
+
+// 请求处理期间持有，drop 时减少进行中的请求数
+pub struct SessionGuard {
+    key: String,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        let mut sessions = SESSIONS.lock().unwrap();
+        if let Some(session) = sessions.get_mut(&self.key) {
+            session.active = session.active.saturating_sub(1);
+        }
+    }
+}
+
+fn is_idle(session: &Session, now: SystemTime) -> bool {
+    session.active == 0
+        && now
+            .duration_since(session.last_seen)
+            .map(|idle| idle >= SESSION_IDLE_TIMEOUT)
+            .unwrap_or(false)
+}
+
+// 记录一次已通过认证的请求
+pub fn begin(user: &str, remote_addr: SocketAddr) -> SessionGuard {
+    let remote_ip = remote_addr.ip().to_string();
+    let key = format!("{}@{}", user, remote_ip);
+    let now = SystemTime::now();
+    let mut sessions = SESSIONS.lock().unwrap();
+    // 空闲超时的会话在这里清理，否则不打开会话列表时会一直累积，同一个客户端也会重新开始会话
+    sessions.retain(|_, session| !is_idle(session, now));
+    let session = sessions.entry(key.to_string()).or_insert_with(|| Session {
+        user: user.to_string(),
+        remote_ip,
+        started: now,
+        last_seen: now,
+        requests: 0,
+        active: 0,
+    });
+    session.last_seen = now;
+    session.requests += 1;
+    session.active += 1;
+    SessionGuard { key }
+}
+
+pub fn list() -> Vec<SessionView> {
+    let mut sessions = SESSIONS.lock().unwrap();
+    let now = SystemTime::now();
+    sessions.retain(|_, session| !is_idle(session, now));
+    let mut views: Vec<SessionView> = sessions
+        .values()
+        .map(|session| SessionView {
+            user: session.user.to_string(),
+            remote_ip: session.remote_ip.to_string(),
+            started: format_date_time(session.started),
+            last_seen: format_date_time(session.last_seen),
+            requests: session.requests,
+            active_requests: session.active,
+        })
+        .collect();
+    views.sort_by(|a, b| (&a.user, &a.remote_ip).cmp(&(&b.user, &b.remote_ip)));
+    views
+}
@@ -0,0 +1,567 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Read, Seek, SeekFrom},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use chrono::NaiveDate;
+use flate2::read::GzDecoder;
+use futures_util::StreamExt;
+use hyper::body::Bytes;
+use lazy_static::lazy_static;
+use tokio::{runtime::Handle, sync::mpsc};
+
+use super::{
+    file_name, normalize_path, parent_path, receiver_stream, ByteStream, Entry, Metadata, Storage,
+};
+
+// 缓存的压缩包目录数量上限
+const MAX_CACHED_INDEXES: usize = 64;
+// 读取压缩包时每次向存储请求的字节数
+const BLOCK_SIZE: u64 = 1024 * 1024;
+const CHUNK_SIZE: usize = 64 * 1024;
+
+lazy_static! {
+    // key 为 rule 的 path 加压缩包路径，压缩包的大小或修改时间变化后重新读取
+    static ref INDEXES: Mutex<HashMap<String, Arc<ArchiveIndex>>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+// 成员内容的位置
+#[derive(Debug, Clone)]
+enum Source {
+    // 未压缩，内容位于压缩包的 offset 处，可以直接按范围读取
+    Raw { offset: u64 },
+    // zip 中压缩过的成员
+    Zip { index: usize },
+    // tar.gz 中的成员，只能从头解压
+    TarGz,
+}
+
+#[derive(Debug, Clone)]
+struct Member {
+    metadata: Metadata,
+    source: Source,
+}
+
+#[derive(Debug)]
+struct ArchiveIndex {
+    kind: ArchiveKind,
+    len: u64,
+    modified: SystemTime,
+    // key 为成员在压缩包内的路径，例如 "/docs/readme.md"，包含隐含的目录
+    members: BTreeMap<String, Member>,
+}
+
+pub fn archive_kind(name: &str) -> Option<ArchiveKind> {
+    let name = name.to_lowercase();
+    if name.ends_with(".zip") {
+        Some(ArchiveKind::Zip)
+    } else if name.ends_with(".tar") {
+        Some(ArchiveKind::Tar)
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Some(ArchiveKind::TarGz)
+    } else {
+        None
+    }
+}
+
+fn read_only(path: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("{} is inside an archive and read-only", path),
+    )
+}
+
+pub fn invalid_archive<E: std::fmt::Display>(err: E) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid archive: {}", err),
+    )
+}
+
+fn dir_metadata(modified: SystemTime) -> Metadata {
+    Metadata {
+        is_dir: true,
+        len: 0,
+        modified,
+    }
+}
+
+// 以同步 Read + Seek 的方式按范围读取存储中的文件，只能在 spawn_blocking 中使用
+pub struct RangeReader {
+    storage: Arc<dyn Storage>,
+    path: String,
+    len: u64,
+    pos: u64,
+    buffer: Bytes,
+    buffer_start: u64,
+    handle: Handle,
+}
+
+impl RangeReader {
+    pub fn new(storage: Arc<dyn Storage>, path: &str, len: u64, handle: Handle) -> RangeReader {
+        RangeReader {
+            storage,
+            path: path.to_string(),
+            len,
+            pos: 0,
+            buffer: Bytes::new(),
+            buffer_start: 0,
+            handle,
+        }
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        let end = (self.pos + BLOCK_SIZE).min(self.len) - 1;
+        let storage = self.storage.clone();
+        let path = self.path.to_string();
+        let start = self.pos;
+        let data = self.handle.block_on(async move {
+            let mut stream = storage.read(&path, Some((start, end))).await?;
+            let mut data = Vec::with_capacity((end - start + 1) as usize);
+            while let Some(chunk) = stream.next().await {
+                data.extend_from_slice(&chunk?);
+            }
+            Ok::<_, io::Error>(data)
+        })?;
+        self.buffer = Bytes::from(data);
+        self.buffer_start = start;
+        Ok(())
+    }
+}
+
+impl Read for RangeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let buffer_end = self.buffer_start + self.buffer.len() as u64;
+        if self.pos < self.buffer_start || self.pos >= buffer_end {
+            self.fill()?;
+        }
+        let offset = (self.pos - self.buffer_start) as usize;
+        let available = &self.buffer[offset..];
+        if available.is_empty() {
+            return Ok(0);
+        }
+        let size = available.len().min(buf.len());
+        buf[..size].copy_from_slice(&available[..size]);
+        self.pos += size as u64;
+        Ok(size)
+    }
+}
+
+impl Seek for RangeReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => self.len as i64 + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if pos < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start",
+            ));
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+fn zip_time(time: Option<zip::DateTime>) -> SystemTime {
+    let time = match time {
+        Some(time) => time,
+        None => return UNIX_EPOCH,
+    };
+    NaiveDate::from_ymd_opt(time.year().into(), time.month().into(), time.day().into())
+        .and_then(|date| {
+            date.and_hms_opt(
+                time.hour().into(),
+                time.minute().into(),
+                time.second().into(),
+            )
+        })
+        .map(|time| SystemTime::from(time.and_utc()))
+        .unwrap_or(UNIX_EPOCH)
+}
+
+// 添加成员及其上级目录
+fn insert_member(members: &mut BTreeMap<String, Member>, path: String, member: Member) {
+    let mut parent = parent_path(&path);
+    while parent != "/" && !members.contains_key(&parent) {
+        members.insert(
+            parent.to_string(),
+            Member {
+                metadata: dir_metadata(member.metadata.modified),
+                source: Source::TarGz,
+            },
+        );
+        parent = parent_path(&parent);
+    }
+    // 目录项可能出现在其成员之后
+    match members.get(&path) {
+        Some(existing) if existing.metadata.is_dir && member.metadata.is_dir => {}
+        _ => {
+            members.insert(path, member);
+        }
+    }
+}
+
+fn build_index(
+    kind: ArchiveKind,
+    reader: RangeReader,
+    modified: SystemTime,
+) -> io::Result<ArchiveIndex> {
+    let len = reader.len;
+    let mut members = BTreeMap::new();
+    match kind {
+        ArchiveKind::Zip => {
+            let mut archive = zip::ZipArchive::new(reader).map_err(invalid_archive)?;
+            for index in 0..archive.len() {
+                let file = archive.by_index_raw(index).map_err(invalid_archive)?;
+                let path = normalize_path(file.name());
+                if path == "/" {
+                    continue;
+                }
+                let source = if file.compression() == zip::CompressionMethod::Stored {
+                    Source::Raw {
+                        offset: file.data_start(),
+                    }
+                } else {
+                    Source::Zip { index }
+                };
+                let member = Member {
+                    metadata: Metadata {
+                        is_dir: file.is_dir(),
+                        len: if file.is_dir() { 0 } else { file.size() },
+                        modified: zip_time(file.last_modified()),
+                    },
+                    source,
+                };
+                insert_member(&mut members, path, member);
+            }
+        }
+        ArchiveKind::Tar | ArchiveKind::TarGz => {
+            let reader: Box<dyn Read> = if kind == ArchiveKind::TarGz {
+                Box::new(GzDecoder::new(reader))
+            } else {
+                Box::new(reader)
+            };
+            let mut archive = tar::Archive::new(reader);
+            for entry in archive.entries()? {
+                let entry = entry?;
+                let entry_type = entry.header().entry_type();
+                if !entry_type.is_file() && !entry_type.is_dir() {
+                    continue;
+                }
+                let path = normalize_path(&entry.path()?.to_string_lossy());
+                if path == "/" {
+                    continue;
+                }
+                let source = if kind == ArchiveKind::Tar {
+                    Source::Raw {
+                        offset: entry.raw_file_position(),
+                    }
+                } else {
+                    Source::TarGz
+                };
+                let member = Member {
+                    metadata: Metadata {
+                        is_dir: entry_type.is_dir(),
+                        len: if entry_type.is_dir() { 0 } else { entry.size() },
+                        modified: UNIX_EPOCH
+                            + Duration::from_secs(entry.header().mtime().unwrap_or(0)),
+                    },
+                    source,
+                };
+                insert_member(&mut members, path, member);
+            }
+        }
+    }
+    Ok(ArchiveIndex {
+        kind,
+        len,
+        modified,
+        members,
+    })
+}
+
+// 跳过 range 之前的内容，把 range 内的内容发送给 sender
+fn send_range<R: Read>(
+    mut reader: R,
+    len: u64,
+    range: Option<(u64, u64)>,
+    sender: &mpsc::Sender<io::Result<Bytes>>,
+) -> io::Result<()> {
+    let (start, end) = range.unwrap_or((0, len.saturating_sub(1)));
+    io::copy(&mut (&mut reader).take(start), &mut io::sink())?;
+    let mut remaining = if len == 0 { 0 } else { end - start + 1 };
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    while remaining > 0 {
+        let size = remaining.min(CHUNK_SIZE as u64) as usize;
+        let read = reader.read(&mut buffer[..size])?;
+        if read == 0 {
+            break;
+        }
+        remaining -= read as u64;
+        if sender
+            .blocking_send(Ok(Bytes::copy_from_slice(&buffer[..read])))
+            .is_err()
+        {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// 把 zip/tar/tar.gz 文件当作只读目录，成员可以浏览和下载
+pub struct ArchiveStorage {
+    inner: Arc<dyn Storage>,
+    // 区分不同 rule 的压缩包缓存
+    cache_key: String,
+}
+
+impl ArchiveStorage {
+    pub fn new(cache_key: &str, inner: Arc<dyn Storage>) -> ArchiveStorage {
+        ArchiveStorage {
+            inner,
+            cache_key: cache_key.to_string(),
+        }
+    }
+
+    // 路径经过压缩包时拆分为压缩包路径和包内路径
+    async fn split(&self, path: &str) -> io::Result<Option<(String, String)>> {
+        let path = normalize_path(path);
+        let mut archive = String::new();
+        let mut segments = path.split('/').filter(|s| !s.is_empty());
+        for segment in segments.by_ref() {
+            archive = format!("{}/{}", archive, segment);
+            if archive_kind(segment).is_none() {
+                continue;
+            }
+            match self.inner.stat(&archive).await {
+                Ok(metadata) if !metadata.is_dir => {
+                    let rest: Vec<&str> = segments.collect();
+                    return Ok(Some((archive, format!("/{}", rest.join("/")))));
+                }
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(None)
+    }
+
+    async fn index(&self, archive: &str) -> io::Result<Arc<ArchiveIndex>> {
+        let metadata = self.inner.stat(archive).await?;
+        let key = format!("{}\n{}", self.cache_key, archive);
+        if let Some(index) = INDEXES.lock().unwrap().get(&key) {
+            if index.len == metadata.len && index.modified == metadata.modified {
+                return Ok(index.clone());
+            }
+        }
+        let kind = archive_kind(file_name(archive)).ok_or_else(|| invalid_archive(archive))?;
+        let reader = RangeReader::new(self.inner.clone(), archive, metadata.len, Handle::current());
+        let modified = metadata.modified;
+        let index = tokio::task::spawn_blocking(move || build_index(kind, reader, modified))
+            .await
+            .map_err(io::Error::other)??;
+        let index = Arc::new(index);
+        let mut indexes = INDEXES.lock().unwrap();
+        if indexes.len() >= MAX_CACHED_INDEXES {
+            indexes.clear();
+        }
+        indexes.insert(key, index.clone());
+        Ok(index)
+    }
+
+    async fn member(
+        &self,
+        archive: &str,
+        inner_path: &str,
+    ) -> io::Result<(Arc<ArchiveIndex>, Member)> {
+        let index = self.index(archive).await?;
+        let member = index.members.get(inner_path).cloned().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} not found in {}", inner_path, archive),
+            )
+        })?;
+        Ok((index, member))
+    }
+
+    // 写操作不能落在压缩包内部，压缩包本身按普通文件处理
+    async fn check_writable(&self, path: &str) -> io::Result<()> {
+        match self.split(path).await? {
+            Some((_, inner_path)) if inner_path != "/" => Err(read_only(path)),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for ArchiveStorage {
+    async fn stat(&self, path: &str) -> io::Result<Metadata> {
+        match self.split(path).await? {
+            None => self.inner.stat(path).await,
+            Some((archive, inner_path)) if inner_path == "/" => {
+                let metadata = self.inner.stat(&archive).await?;
+                Ok(dir_metadata(metadata.modified))
+            }
+            Some((archive, inner_path)) => Ok(self.member(&archive, &inner_path).await?.1.metadata),
+        }
+    }
+
+    async fn list(&self, path: &str) -> io::Result<Vec<Entry>> {
+        let (archive, dir) = match self.split(path).await? {
+            Some(split) => split,
+            None => {
+                let mut entries = self.inner.list(path).await?;
+                for entry in entries.iter_mut() {
+                    if !entry.metadata.is_dir && archive_kind(&entry.name).is_some() {
+                        entry.metadata = dir_metadata(entry.metadata.modified);
+                    }
+                }
+                return Ok(entries);
+            }
+        };
+        let index = self.index(&archive).await?;
+        if dir != "/" {
+            match index.members.get(&dir) {
+                Some(member) if member.metadata.is_dir => {}
+                Some(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotADirectory,
+                        format!("{} is not a directory", path),
+                    ))
+                }
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("{} not found", path),
+                    ))
+                }
+            }
+        }
+        let prefix = format!("{}/", dir.trim_end_matches('/'));
+        Ok(index
+            .members
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .filter(|(key, _)| parent_path(key) == dir)
+            .map(|(key, member)| Entry {
+                name: file_name(key).to_string(),
+                metadata: member.metadata.clone(),
+            })
+            .collect())
+    }
+
+    async fn read(&self, path: &str, range: Option<(u64, u64)>) -> io::Result<ByteStream> {
+        let (archive, inner_path) = match self.split(path).await? {
+            Some((_, inner_path)) if inner_path == "/" => {
+                return Err(io::Error::new(
+                    io::ErrorKind::IsADirectory,
+                    format!("{} is a directory", path),
+                ))
+            }
+            Some(split) => split,
+            None => return self.inner.read(path, range).await,
+        };
+        let (index, member) = self.member(&archive, &inner_path).await?;
+        if member.metadata.is_dir {
+            return Err(io::Error::new(
+                io::ErrorKind::IsADirectory,
+                format!("{} is a directory", path),
+            ));
+        }
+        let len = member.metadata.len;
+        if let Source::Raw { offset } = member.source {
+            if len == 0 {
+                return Ok(Box::pin(futures_util::stream::empty()));
+            }
+            let (start, end) = range.unwrap_or((0, len - 1));
+            return self
+                .inner
+                .read(&archive, Some((offset + start, offset + end)))
+                .await;
+        }
+        // 压缩过的成员在后台线程中解压
+        let reader = RangeReader::new(self.inner.clone(), &archive, index.len, Handle::current());
+        let kind = index.kind;
+        let (sender, receiver) = mpsc::channel(4);
+        tokio::task::spawn_blocking(move || {
+            let result = match (kind, member.source) {
+                (ArchiveKind::Zip, Source::Zip { index }) => zip::ZipArchive::new(reader)
+                    .map_err(invalid_archive)
+                    .and_then(|mut archive| {
+                        let file = archive.by_index(index).map_err(invalid_archive)?;
+                        send_range(file, len, range, &sender)
+                    }),
+                _ => (|| {
+                    let mut archive = tar::Archive::new(GzDecoder::new(reader));
+                    for entry in archive.entries()? {
+                        let entry = entry?;
+                        if normalize_path(&entry.path()?.to_string_lossy()) == inner_path {
+                            return send_range(entry, len, range, &sender);
+                        }
+                    }
+                    Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("{} not found in archive", inner_path),
+                    ))
+                })(),
+            };
+            if let Err(err) = result {
+                let _ = sender.blocking_send(Err(err));
+            }
+        });
+        Ok(receiver_stream(receiver))
+    }
+
+    async fn write(&self, path: &str, body: ByteStream) -> io::Result<u64> {
+        self.check_writable(path).await?;
+        self.inner.write(path, body).await
+    }
+
+    async fn mkdir(&self, path: &str) -> io::Result<()> {
+        self.check_writable(path).await?;
+        self.inner.mkdir(path).await
+    }
+
+    async fn remove(&self, path: &str) -> io::Result<()> {
+        self.check_writable(path).await?;
+        self.inner.remove(path).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        self.check_writable(from).await?;
+        self.check_writable(to).await?;
+        self.inner.rename(from, to).await
+    }
+
+    // 从压缩包复制出成员时先解压再写入
+    async fn copy(&self, from: &str, to: &str) -> io::Result<()> {
+        self.check_writable(to).await?;
+        match self.split(from).await? {
+            Some((_, inner_path)) if inner_path != "/" => {
+                let body = self.read(from, None).await?;
+                self.inner.write(to, body).await.map(|_| ())
+            }
+            _ => self.inner.copy(from, to).await,
+        }
+    }
+
+    async fn available_space(&self) -> Option<u64> {
+        self.inner.available_space().await
+    }
+}
@@ -0,0 +1,615 @@
+use std::{io, path::Path, sync::Arc};
+
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::BytesMut;
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use futures_util::{stream, StreamExt};
+use hmac::{Hmac, Mac};
+use hyper::body::Bytes;
+use sha2::Sha256;
+use sync_wrapper::SyncStream;
+
+use super::{ByteStream, Entry, Metadata, Storage};
+
+// 加密文件的格式：MAGIC + 16 字节随机文件 id，之后是若干加密块，
+// 每块最多 CHUNK_SIZE 字节明文，加密后多 TAG_LEN 字节。
+// 第 i 块的 nonce 为文件 id + i，附加数据标记是否为最后一块，防止截断
+const MAGIC: &[u8; 8] = b"RHFSENC1";
+const FILE_ID_LEN: usize = 16;
+const HEADER_LEN: u64 = (MAGIC.len() + FILE_ID_LEN) as u64;
+const CHUNK_SIZE: u64 = 64 * 1024;
+const TAG_LEN: u64 = 16;
+const NONCE_LEN: usize = 24;
+// 加密后的文件名不能超过大多数文件系统的 255 字节限制
+const MAX_ENCRYPTED_NAME_LEN: usize = 255;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// 从密钥文件读取 32 字节密钥，文件内容为原始字节或 64 个十六进制字符
+pub fn read_key_file(path: &str) -> io::Result<[u8; 32]> {
+    let data = std::fs::read(Path::new(path))?;
+    let text = String::from_utf8_lossy(&data);
+    let key = if data.len() == 32 {
+        data
+    } else {
+        hex::decode(text.trim()).map_err(|_| invalid_key(path))?
+    };
+    key.try_into().map_err(|_| invalid_key(path))
+}
+
+fn invalid_key(path: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "{} must contain a 32 byte key, raw or as 64 hex characters",
+            path
+        ),
+    )
+}
+
+fn corrupted(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// 用主密钥派生不同用途的密钥
+fn derive_key(master: &[u8; 32], label: &str) -> [u8; 32] {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(master).expect("hmac accepts any key");
+    mac.update(label.as_bytes());
+    mac.finalize().into_bytes().into()
+}
+
+// 加密文件长度对应的明文长度
+fn plain_len(len: u64) -> u64 {
+    if len <= HEADER_LEN {
+        return 0;
+    }
+    let body = len - HEADER_LEN;
+    let chunks = body.div_ceil(CHUNK_SIZE + TAG_LEN);
+    body.saturating_sub(chunks * TAG_LEN)
+}
+
+fn chunk_nonce(file_id: &[u8; FILE_ID_LEN], index: u64) -> XNonce {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..FILE_ID_LEN].copy_from_slice(file_id);
+    nonce[FILE_ID_LEN..].copy_from_slice(&index.to_be_bytes());
+    XNonce::from(nonce)
+}
+
+fn plain_metadata(metadata: Metadata) -> Metadata {
+    if metadata.is_dir {
+        return metadata;
+    }
+    Metadata {
+        len: plain_len(metadata.len),
+        ..metadata
+    }
+}
+
+struct EncryptState {
+    body: ByteStream,
+    buffer: BytesMut,
+    cipher: Arc<XChaCha20Poly1305>,
+    file_id: [u8; FILE_ID_LEN],
+    index: u64,
+    header_sent: bool,
+    finished: bool,
+}
+
+impl EncryptState {
+    fn seal(&mut self, chunk: &[u8], last: bool) -> io::Result<Bytes> {
+        let nonce = chunk_nonce(&self.file_id, self.index);
+        self.index += 1;
+        let payload = Payload {
+            msg: chunk,
+            aad: &[last as u8],
+        };
+        self.cipher
+            .encrypt(&nonce, payload)
+            .map(Bytes::from)
+            .map_err(|_| io::Error::other("failed to encrypt"))
+    }
+
+    async fn next(&mut self) -> Option<io::Result<Bytes>> {
+        if !self.header_sent {
+            self.header_sent = true;
+            let mut header = MAGIC.to_vec();
+            header.extend_from_slice(&self.file_id);
+            return Some(Ok(Bytes::from(header)));
+        }
+        loop {
+            if self.finished {
+                return None;
+            }
+            // 多于一块时才能确定当前块不是最后一块
+            if self.buffer.len() as u64 > CHUNK_SIZE {
+                let chunk = self.buffer.split_to(CHUNK_SIZE as usize);
+                return Some(self.seal(&chunk, false));
+            }
+            match self.body.next().await {
+                Some(Ok(data)) => self.buffer.extend_from_slice(&data),
+                Some(Err(err)) => {
+                    self.finished = true;
+                    return Some(Err(err));
+                }
+                None => {
+                    self.finished = true;
+                    let chunk = self.buffer.split();
+                    return Some(self.seal(&chunk, true));
+                }
+            }
+        }
+    }
+}
+
+struct DecryptState {
+    body: ByteStream,
+    buffer: BytesMut,
+    cipher: Arc<XChaCha20Poly1305>,
+    file_id: [u8; FILE_ID_LEN],
+    index: u64,
+    chunk_count: u64,
+    // 第一块中 range 之前的字节数
+    skip: usize,
+    remaining: u64,
+    ended: bool,
+}
+
+impl DecryptState {
+    fn open(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = chunk_nonce(&self.file_id, self.index);
+        let last = self.index + 1 == self.chunk_count;
+        self.index += 1;
+        let payload = Payload {
+            msg: chunk,
+            aad: &[last as u8],
+        };
+        self.cipher
+            .decrypt(&nonce, payload)
+            .map_err(|_| corrupted("encrypted file is corrupted or the key is wrong"))
+    }
+
+    async fn next(&mut self) -> Option<io::Result<Bytes>> {
+        let sealed_size = (CHUNK_SIZE + TAG_LEN) as usize;
+        loop {
+            if self.remaining == 0 {
+                return None;
+            }
+            if self.buffer.len() >= sealed_size || (self.ended && !self.buffer.is_empty()) {
+                let size = self.buffer.len().min(sealed_size);
+                let chunk = self.buffer.split_to(size);
+                let plain = match self.open(&chunk) {
+                    Ok(plain) => plain,
+                    Err(err) => {
+                        self.remaining = 0;
+                        return Some(Err(err));
+                    }
+                };
+                let skip = self.skip.min(plain.len());
+                self.skip = 0;
+                let size = (plain.len() - skip).min(self.remaining as usize);
+                self.remaining -= size as u64;
+                return Some(Ok(Bytes::copy_from_slice(&plain[skip..skip + size])));
+            }
+            if self.ended {
+                self.remaining = 0;
+                return Some(Err(corrupted("encrypted file is truncated")));
+            }
+            match self.body.next().await {
+                Some(Ok(data)) => self.buffer.extend_from_slice(&data),
+                Some(Err(err)) => {
+                    self.remaining = 0;
+                    return Some(Err(err));
+                }
+                None => self.ended = true,
+            }
+        }
+    }
+}
+
+// 文件名使用确定性加密，同一个名字总是得到同一个结果，才能按名字查找：
+// nonce 为名字的 HMAC，解密后再校验一次
+struct NameCipher {
+    mac_key: [u8; 32],
+    cipher: XChaCha20Poly1305,
+}
+
+impl NameCipher {
+    fn mac(&self, name: &str) -> HmacSha256 {
+        let mut mac =
+            <HmacSha256 as Mac>::new_from_slice(&self.mac_key).expect("hmac accepts any key");
+        mac.update(name.as_bytes());
+        mac
+    }
+
+    fn encrypt(&self, name: &str) -> io::Result<String> {
+        let tag = self.mac(name).finalize().into_bytes();
+        let nonce = XNonce::from_slice(&tag[..NONCE_LEN]);
+        let mut data = nonce.to_vec();
+        data.extend(
+            self.cipher
+                .encrypt(nonce, name.as_bytes())
+                .map_err(|_| io::Error::other("failed to encrypt file name"))?,
+        );
+        let encrypted = URL_SAFE_NO_PAD.encode(data);
+        if encrypted.len() > MAX_ENCRYPTED_NAME_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("file name is too long to encrypt: {}", name),
+            ));
+        }
+        Ok(encrypted)
+    }
+
+    fn decrypt(&self, encrypted: &str) -> Option<String> {
+        let data = URL_SAFE_NO_PAD.decode(encrypted).ok()?;
+        if data.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, sealed) = data.split_at(NONCE_LEN);
+        let plain = self
+            .cipher
+            .decrypt(XNonce::from_slice(nonce), sealed)
+            .ok()?;
+        let name = String::from_utf8(plain).ok()?;
+        self.mac(&name).verify_truncated_left(nonce).ok()?;
+        Some(name)
+    }
+}
+
+// 写入时加密、读取时解密的存储，文件长度和文件名对调用方都是明文
+pub struct CryptStorage {
+    inner: Arc<dyn Storage>,
+    cipher: Arc<XChaCha20Poly1305>,
+    names: Option<NameCipher>,
+}
+
+impl CryptStorage {
+    pub fn new(inner: Arc<dyn Storage>, key: &[u8; 32], encrypt_names: bool) -> CryptStorage {
+        let content_key = derive_key(key, "rhfs content");
+        let names = encrypt_names.then(|| NameCipher {
+            mac_key: derive_key(key, "rhfs name nonce"),
+            cipher: XChaCha20Poly1305::new(&derive_key(key, "rhfs name").into()),
+        });
+        CryptStorage {
+            inner,
+            cipher: Arc::new(XChaCha20Poly1305::new(&content_key.into())),
+            names,
+        }
+    }
+
+    // 明文路径转换为底层存储中的路径
+    fn resolve(&self, path: &str) -> io::Result<String> {
+        let names = match &self.names {
+            Some(names) => names,
+            None => return Ok(path.to_string()),
+        };
+        let mut result = String::new();
+        for segment in path.split('/').filter(|s| !s.is_empty()) {
+            result.push('/');
+            result.push_str(&names.encrypt(segment)?);
+        }
+        if result.is_empty() {
+            result.push('/');
+        }
+        Ok(result)
+    }
+
+    async fn read_header(&self, path: &str) -> io::Result<[u8; FILE_ID_LEN]> {
+        let mut stream = self.inner.read(path, Some((0, HEADER_LEN - 1))).await?;
+        let mut header = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            header.extend_from_slice(&chunk?);
+        }
+        if header.len() as u64 != HEADER_LEN || &header[..MAGIC.len()] != MAGIC {
+            return Err(corrupted("file is not encrypted"));
+        }
+        Ok(header[MAGIC.len()..].try_into().unwrap())
+    }
+}
+
+#[async_trait]
+impl Storage for CryptStorage {
+    async fn stat(&self, path: &str) -> io::Result<Metadata> {
+        Ok(plain_metadata(self.inner.stat(&self.resolve(path)?).await?))
+    }
+
+    async fn list(&self, path: &str) -> io::Result<Vec<Entry>> {
+        let entries = self.inner.list(&self.resolve(path)?).await?;
+        Ok(entries
+            .into_iter()
+            .filter_map(|entry| {
+                // 无法解密的文件名不是由这个存储写入的，不显示
+                let name = match &self.names {
+                    Some(names) => names.decrypt(&entry.name)?,
+                    None => entry.name,
+                };
+                Some(Entry {
+                    name,
+                    metadata: plain_metadata(entry.metadata),
+                })
+            })
+            .collect())
+    }
+
+    // 只读取并解密 range 所在的块
+    async fn read(&self, path: &str, range: Option<(u64, u64)>) -> io::Result<ByteStream> {
+        let path = self.resolve(path)?;
+        let len = self.inner.stat(&path).await?.len;
+        let file_id = self.read_header(&path).await?;
+        // 空文件也有一个只含校验码的块，最后一块不足 TAG_LEN 说明被截断
+        let sealed_size = CHUNK_SIZE + TAG_LEN;
+        if len < HEADER_LEN + TAG_LEN || (1..TAG_LEN).contains(&((len - HEADER_LEN) % sealed_size))
+        {
+            return Err(corrupted("encrypted file is truncated"));
+        }
+        let size = plain_len(len);
+        let (start, end) = match range {
+            Some(range) => range,
+            None if size == 0 => (0, 0),
+            None => (0, size - 1),
+        };
+        let first = start / CHUNK_SIZE;
+        let last = end / CHUNK_SIZE;
+        let sealed_start = HEADER_LEN + first * sealed_size;
+        let sealed_end = (HEADER_LEN + (last + 1) * sealed_size).min(len) - 1;
+        let body = self
+            .inner
+            .read(&path, Some((sealed_start, sealed_end)))
+            .await?;
+        let state = DecryptState {
+            body,
+            buffer: BytesMut::new(),
+            cipher: self.cipher.clone(),
+            file_id,
+            index: first,
+            chunk_count: (len - HEADER_LEN).div_ceil(sealed_size),
+            skip: (start - first * CHUNK_SIZE) as usize,
+            remaining: if size == 0 { 0 } else { end - start + 1 },
+            ended: false,
+        };
+        let stream = stream::unfold(state, |mut state| async move {
+            state.next().await.map(|item| (item, state))
+        });
+        Ok(Box::pin(SyncStream::new(stream)))
+    }
+
+    async fn write(&self, path: &str, body: ByteStream) -> io::Result<u64> {
+        let mut file_id = [0u8; FILE_ID_LEN];
+        OsRng.fill_bytes(&mut file_id);
+        let state = EncryptState {
+            body,
+            buffer: BytesMut::new(),
+            cipher: self.cipher.clone(),
+            file_id,
+            index: 0,
+            header_sent: false,
+            finished: false,
+        };
+        let stream = stream::unfold(state, |mut state| async move {
+            state.next().await.map(|item| (item, state))
+        });
+        let written = self
+            .inner
+            .write(&self.resolve(path)?, Box::pin(SyncStream::new(stream)))
+            .await?;
+        Ok(plain_len(written))
+    }
+
+    async fn mkdir(&self, path: &str) -> io::Result<()> {
+        self.inner.mkdir(&self.resolve(path)?).await
+    }
+
+    async fn remove(&self, path: &str) -> io::Result<()> {
+        self.inner.remove(&self.resolve(path)?).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        self.inner
+            .rename(&self.resolve(from)?, &self.resolve(to)?)
+            .await
+    }
+
+    // 密文原样复制，两个文件使用相同的文件 id 和相同的内容
+    async fn copy(&self, from: &str, to: &str) -> io::Result<()> {
+        self.inner
+            .copy(&self.resolve(from)?, &self.resolve(to)?)
+            .await
+    }
+
+    async fn available_space(&self) -> Option<u64> {
+        self.inner.available_space().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    fn storage(encrypt_names: bool) -> (Arc<dyn Storage>, CryptStorage) {
+        let inner: Arc<dyn Storage> = Arc::new(MemoryStorage::new(u64::MAX));
+        let crypt = CryptStorage::new(inner.clone(), &KEY, encrypt_names);
+        (inner, crypt)
+    }
+
+    // 多个块，最后一块不满
+    fn sample() -> Vec<u8> {
+        (0..CHUNK_SIZE * 2 + 1000)
+            .map(|i| (i % 251) as u8)
+            .collect()
+    }
+
+    fn body(data: &[u8]) -> ByteStream {
+        // 分成不对齐块大小的片段写入
+        let pieces: Vec<io::Result<Bytes>> = data
+            .chunks(10000)
+            .map(|piece| Ok(Bytes::copy_from_slice(piece)))
+            .collect();
+        Box::pin(stream::iter(pieces))
+    }
+
+    async fn collect(mut stream: ByteStream) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            data.extend_from_slice(&chunk?);
+        }
+        Ok(data)
+    }
+
+    async fn read(
+        storage: &dyn Storage,
+        path: &str,
+        range: Option<(u64, u64)>,
+    ) -> io::Result<Vec<u8>> {
+        collect(storage.read(path, range).await?).await
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let (inner, crypt) = storage(false);
+        let data = sample();
+        assert_eq!(
+            crypt.write("/a", body(&data)).await.unwrap(),
+            data.len() as u64
+        );
+        assert_eq!(crypt.stat("/a").await.unwrap().len, data.len() as u64);
+        assert_eq!(read(&crypt, "/a", None).await.unwrap(), data);
+        // 底层存储中是密文
+        let sealed = read(inner.as_ref(), "/a", None).await.unwrap();
+        assert_eq!(&sealed[..MAGIC.len()], MAGIC);
+        assert_eq!(plain_len(sealed.len() as u64), data.len() as u64);
+        assert!(!sealed.windows(64).any(|window| window == &data[..64]));
+    }
+
+    #[tokio::test]
+    async fn round_trip_empty_and_exact_chunk() {
+        let (_, crypt) = storage(false);
+        for len in [0, 1, CHUNK_SIZE, CHUNK_SIZE * 2] {
+            let data = vec![1u8; len as usize];
+            crypt.write("/a", body(&data)).await.unwrap();
+            assert_eq!(crypt.stat("/a").await.unwrap().len, len);
+            assert_eq!(read(&crypt, "/a", None).await.unwrap(), data);
+        }
+    }
+
+    #[tokio::test]
+    async fn encrypted_names() {
+        let (inner, crypt) = storage(true);
+        crypt.mkdir("/dir").await.unwrap();
+        crypt.write("/dir/file.txt", body(b"hello")).await.unwrap();
+        let entries = crypt.list("/dir").await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "file.txt");
+        assert_eq!(entries[0].metadata.len, 5);
+        let names: Vec<String> = inner
+            .list("/")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(names.len(), 1);
+        assert_ne!(names[0], "dir");
+        assert_eq!(read(&crypt, "/dir/file.txt", None).await.unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn range_across_chunks() {
+        let (_, crypt) = storage(false);
+        let data = sample();
+        crypt.write("/a", body(&data)).await.unwrap();
+        let last = data.len() as u64 - 1;
+        for (start, end) in [
+            (0, 0),
+            (10, 20),
+            (CHUNK_SIZE - 10, CHUNK_SIZE + 10),
+            (CHUNK_SIZE - 1, CHUNK_SIZE),
+            (CHUNK_SIZE, CHUNK_SIZE * 2 - 1),
+            (CHUNK_SIZE - 5, CHUNK_SIZE * 2 + 5),
+            (CHUNK_SIZE * 2, last),
+            (last, last),
+            (0, last),
+        ] {
+            assert_eq!(
+                read(&crypt, "/a", Some((start, end))).await.unwrap(),
+                &data[start as usize..=end as usize],
+                "range {}-{}",
+                start,
+                end
+            );
+        }
+    }
+
+    async fn tamper(inner: &dyn Storage, change: impl FnOnce(&mut Vec<u8>)) {
+        let mut sealed = read(inner, "/a", None).await.unwrap();
+        change(&mut sealed);
+        inner.write("/a", body(&sealed)).await.unwrap();
+    }
+
+    fn is_invalid_data(result: io::Result<Vec<u8>>) -> bool {
+        matches!(result, Err(err) if err.kind() == io::ErrorKind::InvalidData)
+    }
+
+    #[tokio::test]
+    async fn detects_truncation() {
+        let sealed_size = (CHUNK_SIZE + TAG_LEN) as usize;
+        let header = HEADER_LEN as usize;
+        // 在块边界截断：剩下的最后一块加密时并未标记为最后一块
+        let (inner, crypt) = storage(false);
+        crypt.write("/a", body(&sample())).await.unwrap();
+        tamper(inner.as_ref(), |sealed| {
+            sealed.truncate(header + sealed_size * 2)
+        })
+        .await;
+        assert!(is_invalid_data(read(&crypt, "/a", None).await));
+        // 在块中间截断
+        let (inner, crypt) = storage(false);
+        crypt.write("/a", body(&sample())).await.unwrap();
+        tamper(inner.as_ref(), |sealed| sealed.truncate(sealed.len() - 100)).await;
+        assert!(is_invalid_data(read(&crypt, "/a", None).await));
+        // 只剩文件头
+        let (inner, crypt) = storage(false);
+        crypt.write("/a", body(&sample())).await.unwrap();
+        tamper(inner.as_ref(), |sealed| sealed.truncate(header)).await;
+        assert!(is_invalid_data(read(&crypt, "/a", None).await));
+    }
+
+    #[tokio::test]
+    async fn detects_chunk_swap() {
+        let sealed_size = (CHUNK_SIZE + TAG_LEN) as usize;
+        let header = HEADER_LEN as usize;
+        let (inner, crypt) = storage(false);
+        crypt.write("/a", body(&sample())).await.unwrap();
+        tamper(inner.as_ref(), |sealed| {
+            let (first, rest) = sealed[header..].split_at_mut(sealed_size);
+            first.swap_with_slice(&mut rest[..sealed_size]);
+        })
+        .await;
+        assert!(is_invalid_data(read(&crypt, "/a", None).await));
+        assert!(is_invalid_data(
+            read(&crypt, "/a", Some((CHUNK_SIZE, CHUNK_SIZE + 1))).await
+        ));
+    }
+
+    #[tokio::test]
+    async fn detects_modification_and_wrong_key() {
+        let (inner, crypt) = storage(false);
+        crypt.write("/a", body(&sample())).await.unwrap();
+        let other = CryptStorage::new(inner.clone(), &[8; 32], false);
+        assert!(is_invalid_data(read(&other, "/a", None).await));
+        tamper(inner.as_ref(), |sealed| {
+            sealed[HEADER_LEN as usize + 5] ^= 1
+        })
+        .await;
+        assert!(is_invalid_data(read(&crypt, "/a", Some((0, 10))).await));
+        // 未加密的文件
+        inner.write("/b", body(b"plain text file")).await.unwrap();
+        assert!(is_invalid_data(read(&crypt, "/b", None).await));
+    }
+}
@@ -0,0 +1,113 @@
+use std::{
+    io::{self, SeekFrom},
+    path::PathBuf,
+};
+
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+use tokio_util::io::ReaderStream;
+
+use super::{ByteStream, Entry, Metadata, Storage};
+
+// 本地目录，rule 未指定其它存储时使用
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: &str) -> LocalStorage {
+        LocalStorage {
+            root: PathBuf::from(root),
+        }
+    }
+
+    fn resolve(&self, path: &str) -> PathBuf {
+        self.root
+            .join(super::normalize_path(path).trim_start_matches('/'))
+    }
+}
+
+fn to_metadata(metadata: std::fs::Metadata) -> io::Result<Metadata> {
+    Ok(Metadata {
+        is_dir: metadata.is_dir(),
+        len: metadata.len(),
+        modified: metadata.modified()?,
+    })
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn stat(&self, path: &str) -> io::Result<Metadata> {
+        to_metadata(fs::metadata(self.resolve(path)).await?)
+    }
+
+    async fn list(&self, path: &str) -> io::Result<Vec<Entry>> {
+        let mut entries = fs::read_dir(self.resolve(path)).await?;
+        let mut result = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            // 无法读取的文件（例如失效的软链接）直接跳过
+            let metadata = match fs::metadata(entry.path()).await {
+                Ok(metadata) => to_metadata(metadata)?,
+                Err(_) => continue,
+            };
+            result.push(Entry {
+                name: entry.file_name().to_string_lossy().to_string(),
+                metadata,
+            });
+        }
+        Ok(result)
+    }
+
+    async fn read(&self, path: &str, range: Option<(u64, u64)>) -> io::Result<ByteStream> {
+        let mut file = fs::File::open(self.resolve(path)).await?;
+        match range {
+            Some((start, end)) => {
+                file.seek(SeekFrom::Start(start)).await?;
+                let reader = file.take(end - start + 1);
+                Ok(Box::pin(ReaderStream::new(reader)))
+            }
+            None => Ok(Box::pin(ReaderStream::new(file))),
+        }
+    }
+
+    async fn write(&self, path: &str, mut body: ByteStream) -> io::Result<u64> {
+        let mut file = fs::File::create(self.resolve(path)).await?;
+        let mut written = 0;
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk?;
+            file.write_all(&chunk).await?;
+            written += chunk.len() as u64;
+        }
+        file.flush().await?;
+        Ok(written)
+    }
+
+    async fn mkdir(&self, path: &str) -> io::Result<()> {
+        fs::create_dir_all(self.resolve(path)).await
+    }
+
+    async fn remove(&self, path: &str) -> io::Result<()> {
+        let file_path = self.resolve(path);
+        if fs::remove_file(&file_path).await.is_ok() {
+            return Ok(());
+        }
+        fs::remove_dir(&file_path).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        fs::rename(self.resolve(from), self.resolve(to)).await
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> io::Result<()> {
+        fs::copy(self.resolve(from), self.resolve(to)).await?;
+        Ok(())
+    }
+
+    async fn available_space(&self) -> Option<u64> {
+        fs4::available_space(&self.root).ok()
+    }
+}
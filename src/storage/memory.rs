@@ -0,0 +1,356 @@
+use std::{collections::BTreeMap, io, sync::Mutex, time::SystemTime};
+
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt};
+use hyper::body::Bytes;
+
+use super::{parent_path, ByteStream, Entry, Metadata, Storage};
+
+// 未指定 max_size 时的容量上限
+pub const DEFAULT_MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+enum Node {
+    File { data: Bytes, modified: SystemTime },
+    Dir { modified: SystemTime },
+}
+
+impl Node {
+    fn metadata(&self) -> Metadata {
+        match self {
+            Node::File { data, modified } => Metadata {
+                is_dir: false,
+                len: data.len() as u64,
+                modified: *modified,
+            },
+            Node::Dir { modified } => Metadata {
+                is_dir: true,
+                len: 0,
+                modified: *modified,
+            },
+        }
+    }
+
+    fn len(&self) -> u64 {
+        match self {
+            Node::File { data, .. } => data.len() as u64,
+            Node::Dir { .. } => 0,
+        }
+    }
+}
+
+// 内存中的文件树，重启后内容丢失。key 为规范化后的路径，根目录为 "/"
+pub struct MemoryStorage {
+    nodes: Mutex<BTreeMap<String, Node>>,
+    max_size: u64,
+}
+
+fn not_found(path: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path))
+}
+
+fn storage_full(max_size: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::StorageFull,
+        format!("memory storage is full ({} bytes)", max_size),
+    )
+}
+
+// path 下的所有子孙节点，不含 path 本身（根目录的前缀 "/" 也匹配它自己）
+fn descendants(nodes: &BTreeMap<String, Node>, path: &str) -> Vec<String> {
+    let prefix = format!("{}/", path.trim_end_matches('/'));
+    nodes
+        .range(prefix.clone()..)
+        .take_while(|(key, _)| key.starts_with(&prefix))
+        .filter(|(key, _)| key.as_str() != path)
+        .map(|(key, _)| key.to_string())
+        .collect()
+}
+
+impl MemoryStorage {
+    pub fn new(max_size: u64) -> MemoryStorage {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(
+            "/".to_string(),
+            Node::Dir {
+                modified: SystemTime::now(),
+            },
+        );
+        MemoryStorage {
+            nodes: Mutex::new(nodes),
+            max_size,
+        }
+    }
+
+    fn used(nodes: &BTreeMap<String, Node>) -> u64 {
+        nodes.values().map(Node::len).sum()
+    }
+
+    // 父目录必须存在
+    fn check_parent(nodes: &BTreeMap<String, Node>, path: &str) -> io::Result<()> {
+        let parent = parent_path(path);
+        match nodes.get(&parent) {
+            Some(Node::Dir { .. }) => Ok(()),
+            Some(Node::File { .. }) => Err(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                format!("{} is not a directory", parent),
+            )),
+            None => Err(not_found(&parent)),
+        }
+    }
+
+    // 写入文件内容，超出容量时不做修改
+    fn put_file(&self, path: &str, data: Bytes) -> io::Result<()> {
+        let mut nodes = self.nodes.lock().unwrap();
+        Self::check_parent(&nodes, path)?;
+        let old_len = match nodes.get(path) {
+            Some(Node::Dir { .. }) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::IsADirectory,
+                    format!("{} is a directory", path),
+                ))
+            }
+            Some(node) => node.len(),
+            None => 0,
+        };
+        if Self::used(&nodes) - old_len + data.len() as u64 > self.max_size {
+            return Err(storage_full(self.max_size));
+        }
+        nodes.insert(
+            path.to_string(),
+            Node::File {
+                data,
+                modified: SystemTime::now(),
+            },
+        );
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn stat(&self, path: &str) -> io::Result<Metadata> {
+        let path = super::normalize_path(path);
+        let nodes = self.nodes.lock().unwrap();
+        nodes
+            .get(&path)
+            .map(Node::metadata)
+            .ok_or_else(|| not_found(&path))
+    }
+
+    async fn list(&self, path: &str) -> io::Result<Vec<Entry>> {
+        let path = super::normalize_path(path);
+        let nodes = self.nodes.lock().unwrap();
+        match nodes.get(&path) {
+            Some(Node::Dir { .. }) => {}
+            Some(Node::File { .. }) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotADirectory,
+                    format!("{} is not a directory", path),
+                ))
+            }
+            None => return Err(not_found(&path)),
+        }
+        let entries = descendants(&nodes, &path)
+            .into_iter()
+            .filter(|key| parent_path(key) == path)
+            .map(|key| Entry {
+                name: super::file_name(&key).to_string(),
+                metadata: nodes[&key].metadata(),
+            })
+            .collect();
+        Ok(entries)
+    }
+
+    async fn read(&self, path: &str, range: Option<(u64, u64)>) -> io::Result<ByteStream> {
+        let path = super::normalize_path(path);
+        let data = match self.nodes.lock().unwrap().get(&path) {
+            Some(Node::File { data, .. }) => data.clone(),
+            Some(Node::Dir { .. }) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::IsADirectory,
+                    format!("{} is a directory", path),
+                ))
+            }
+            None => return Err(not_found(&path)),
+        };
+        let data = match range {
+            Some((start, end)) => {
+                let len = data.len() as u64;
+                let start = start.min(len) as usize;
+                let end = (end + 1).min(len) as usize;
+                data.slice(start..end.max(start))
+            }
+            None => data,
+        };
+        Ok(Box::pin(stream::once(async move { Ok(data) })))
+    }
+
+    async fn write(&self, path: &str, mut body: ByteStream) -> io::Result<u64> {
+        let path = super::normalize_path(path);
+        // 先确认能写入，避免把请求体读完才发现父目录不存在。
+        // 覆盖时原文件的空间可以重用，写入完成时 put_file 会按当时的用量再检查一次
+        let available = {
+            let nodes = self.nodes.lock().unwrap();
+            Self::check_parent(&nodes, &path)?;
+            let old_len = nodes.get(&path).map(Node::len).unwrap_or(0);
+            self.max_size
+                .saturating_sub(Self::used(&nodes))
+                .saturating_add(old_len)
+        };
+        let mut data = Vec::new();
+        while let Some(chunk) = body.next().await {
+            data.extend_from_slice(&chunk?);
+            if data.len() as u64 > available {
+                return Err(storage_full(self.max_size));
+            }
+        }
+        let written = data.len() as u64;
+        self.put_file(&path, Bytes::from(data))?;
+        Ok(written)
+    }
+
+    async fn mkdir(&self, path: &str) -> io::Result<()> {
+        let path = super::normalize_path(path);
+        let mut nodes = self.nodes.lock().unwrap();
+        let mut current = String::new();
+        for segment in path.split('/').filter(|s| !s.is_empty()) {
+            current = format!("{}/{}", current, segment);
+            match nodes.get(&current) {
+                Some(Node::Dir { .. }) => {}
+                Some(Node::File { .. }) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("{} already exists", current),
+                    ))
+                }
+                None => {
+                    nodes.insert(
+                        current.to_string(),
+                        Node::Dir {
+                            modified: SystemTime::now(),
+                        },
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn remove(&self, path: &str) -> io::Result<()> {
+        let path = super::normalize_path(path);
+        let mut nodes = self.nodes.lock().unwrap();
+        if path == "/" {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "cannot remove the root",
+            ));
+        }
+        if !nodes.contains_key(&path) {
+            return Err(not_found(&path));
+        }
+        if !descendants(&nodes, &path).is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::DirectoryNotEmpty,
+                format!("{} is not empty", path),
+            ));
+        }
+        nodes.remove(&path);
+        Ok(())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let from = super::normalize_path(from);
+        let to = super::normalize_path(to);
+        let mut nodes = self.nodes.lock().unwrap();
+        if !nodes.contains_key(&from) {
+            return Err(not_found(&from));
+        }
+        if from == to {
+            return Ok(());
+        }
+        if from == "/" || to.starts_with(&format!("{}/", from)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("cannot move {} into itself", from),
+            ));
+        }
+        Self::check_parent(&nodes, &to)?;
+        // 与 rename(2) 一致：目标是文件时直接覆盖，是非空目录时报错
+        if !descendants(&nodes, &to).is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::DirectoryNotEmpty,
+                format!("{} is not empty", to),
+            ));
+        }
+        nodes.remove(&to);
+        let mut moved = descendants(&nodes, &from);
+        moved.push(from.to_string());
+        for key in moved {
+            let node = nodes.remove(&key).unwrap();
+            nodes.insert(format!("{}{}", to, &key[from.len()..]), node);
+        }
+        Ok(())
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> io::Result<()> {
+        let from = super::normalize_path(from);
+        let to = super::normalize_path(to);
+        let data = match self.nodes.lock().unwrap().get(&from) {
+            Some(Node::File { data, .. }) => data.clone(),
+            Some(Node::Dir { .. }) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::IsADirectory,
+                    format!("{} is a directory", from),
+                ))
+            }
+            None => return Err(not_found(&from)),
+        };
+        self.put_file(&to, data)
+    }
+
+    async fn available_space(&self) -> Option<u64> {
+        let nodes = self.nodes.lock().unwrap();
+        Some(self.max_size.saturating_sub(Self::used(&nodes)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(data: &'static str) -> ByteStream {
+        Box::pin(stream::iter(
+            data.as_bytes()
+                .chunks(2)
+                .map(|chunk| Ok(Bytes::from_static(chunk))),
+        ))
+    }
+
+    #[tokio::test]
+    async fn write_is_limited_by_remaining_space() {
+        let storage = MemoryStorage::new(10);
+        storage.write("/a", body("123456")).await.unwrap();
+        // 超出剩余空间时立即停止读取请求体
+        let overflowing: ByteStream = Box::pin(stream::iter([
+            Ok(Bytes::from("123")),
+            Ok(Bytes::from("45")),
+            Err(io::Error::other("read past the remaining space")),
+        ]));
+        let err = storage.write("/b", overflowing).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert!(storage.stat("/b").await.is_err());
+        storage.write("/b", body("1234")).await.unwrap();
+        assert_eq!(storage.available_space().await, Some(0));
+    }
+
+    #[tokio::test]
+    async fn overwrite_reuses_the_old_space() {
+        let storage = MemoryStorage::new(10);
+        storage.write("/a", body("12345678")).await.unwrap();
+        storage.write("/a", body("1234567890")).await.unwrap();
+        assert_eq!(storage.stat("/a").await.unwrap().len, 10);
+        let err = storage.write("/a", body("12345678901")).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert_eq!(storage.stat("/a").await.unwrap().len, 10);
+    }
+}
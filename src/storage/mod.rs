@@ -0,0 +1,348 @@
+use std::{
+    collections::HashMap,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use futures_util::{stream, Stream};
+use hyper::body::Bytes;
+use lazy_static::lazy_static;
+use sync_wrapper::SyncStream;
+use tokio::sync::mpsc;
+
+use crate::{config::Rule, trash, versions};
+
+pub mod archive;
+pub mod crypt;
+pub mod local;
+pub mod memory;
+pub mod s3;
+pub mod sftp;
+pub mod sub;
+pub mod union;
+
+// 读写文件内容使用的字节流
+pub type ByteStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send + Sync>>;
+
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    pub is_dir: bool,
+    pub len: u64,
+    pub modified: SystemTime,
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub name: String,
+    pub metadata: Metadata,
+}
+
+// 各个 HTTP 方法通过这个接口访问共享目录。
+// path 为相对共享根目录的路径，以 "/" 开头，例如 "/a/b.txt"，根目录为 "/"
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn stat(&self, path: &str) -> io::Result<Metadata>;
+
+    // 列出目录下的文件和子目录
+    async fn list(&self, path: &str) -> io::Result<Vec<Entry>>;
+
+    // 读取文件内容，range 为闭区间 [start, end]
+    async fn read(&self, path: &str, range: Option<(u64, u64)>) -> io::Result<ByteStream>;
+
+    // 创建或覆盖文件，返回写入的字节数
+    async fn write(&self, path: &str, body: ByteStream) -> io::Result<u64>;
+
+    // 创建目录，父目录不存在时一并创建
+    async fn mkdir(&self, path: &str) -> io::Result<()>;
+
+    // 删除文件或空目录
+    async fn remove(&self, path: &str) -> io::Result<()>;
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+
+    // 复制文件，目录由调用方逐层复制
+    async fn copy(&self, from: &str, to: &str) -> io::Result<()>;
+
+    // 剩余可写入的字节数，无法获取时返回 None
+    async fn available_space(&self) -> Option<u64> {
+        None
+    }
+}
+
+lazy_static! {
+    // key 为 rule 的 path（合并挂载或加密时加上对应配置），重新加载配置后同一个 path 仍使用同一个实例
+    static ref STORAGES: Mutex<HashMap<String, Arc<dyn Storage>>> = Mutex::new(HashMap::new());
+}
+
+// 返回 rule 对应的存储
+pub fn for_rule(rule: &Rule) -> io::Result<Arc<dyn Storage>> {
+    let key = if rule.sources.is_empty() && rule.encryption.is_none() {
+        rule.path.to_string()
+    } else {
+        format!("{}\n{:?}\n{:?}", rule.path, rule.sources, rule.encryption)
+    };
+    let storage = {
+        let mut storages = STORAGES.lock().unwrap();
+        match storages.get(&key) {
+            Some(storage) => storage.clone(),
+            None => {
+                let mut storage = open(rule)?;
+                if let Some(encryption) = &rule.encryption {
+                    let secret = crypt::read_key_file(&encryption.key_file)?;
+                    storage = Arc::new(crypt::CryptStorage::new(
+                        storage,
+                        &secret,
+                        encryption.encrypt_names,
+                    ));
+                }
+                storages.insert(key, storage.clone());
+                storage
+            }
+        }
+    };
+    let storage: Arc<dyn Storage> = if rule.browse_archives {
+        Arc::new(archive::ArchiveStorage::new(&rule.path, storage))
+    } else {
+        storage
+    };
+    // 分享链接只能访问分享的路径，看不到创建者的回收站和历史版本
+    match &rule.share {
+        Some(share) => Ok(Arc::new(sub::SubStorage::new(
+            storage,
+            &share.path,
+            &[trash::TRASH_DIR, versions::VERSIONS_DIR],
+        ))),
+        None => Ok(storage),
+    }
+}
+
+// 把后台线程通过 channel 发送的数据转换为 ByteStream
+pub fn receiver_stream(receiver: mpsc::Receiver<io::Result<Bytes>>) -> ByteStream {
+    let stream = stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|item| (item, receiver))
+    });
+    Box::pin(SyncStream::new(stream))
+}
+
+fn open(rule: &Rule) -> io::Result<Arc<dyn Storage>> {
+    let location = Location::parse(&rule.path);
+    if location.scheme != "union" {
+        return open_path(&rule.path);
+    }
+    let policy = union::WritePolicy::parse(&location)?;
+    let mut branches = Vec::new();
+    for source in rule.sources.iter() {
+        if Location::parse(&source.path).scheme == "union" {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "union storage cannot contain another union",
+            ));
+        }
+        branches.push(union::Branch {
+            path: redact_path(&source.path),
+            storage: open_path(&source.path)?,
+            read_only: source.read_only,
+        });
+    }
+    if branches.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "union storage needs at least one source",
+        ));
+    }
+    Ok(Arc::new(union::UnionStorage::new(branches, policy)))
+}
+
+fn open_path(path: &str) -> io::Result<Arc<dyn Storage>> {
+    let location = Location::parse(path);
+    match location.scheme.as_str() {
+        "" => Ok(Arc::new(local::LocalStorage::new(path))),
+        "memory" => {
+            let max_size = location.size_option("max_size", memory::DEFAULT_MAX_SIZE)?;
+            Ok(Arc::new(memory::MemoryStorage::new(max_size)))
+        }
+        "s3" => Ok(Arc::new(s3::S3Storage::new(&location)?)),
+        "sftp" => Ok(Arc::new(sftp::SftpStorage::new(&location)?)),
+        scheme => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("unsupported storage \"{}://\"", scheme),
+        )),
+    }
+}
+
+// rule 的 path，本地目录或 "scheme://target?key=value" 形式的其它存储，
+// 例如 "memory://scratch?max_size=64M"
+#[derive(Debug, Clone, Default)]
+pub struct Location {
+    pub scheme: String,
+    pub target: String,
+    pub options: HashMap<String, String>,
+}
+
+impl Location {
+    pub fn parse(path: &str) -> Location {
+        let (scheme, rest) = match path.split_once("://") {
+            Some((scheme, rest)) => (scheme.to_lowercase(), rest),
+            None => {
+                return Location {
+                    target: path.to_string(),
+                    ..Default::default()
+                }
+            }
+        };
+        let (target, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let options = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (key.to_string(), crate::util::decode_uri(value))
+            })
+            .collect();
+        Location {
+            scheme,
+            target: crate::util::decode_uri(target),
+            options,
+        }
+    }
+
+    pub fn is_local(&self) -> bool {
+        self.scheme.is_empty()
+    }
+
+    pub fn size_option(&self, key: &str, default_value: u64) -> io::Result<u64> {
+        match self.options.get(key) {
+            Some(value) => parse_size(value).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid {}: {}", key, value),
+                )
+            }),
+            None => Ok(default_value),
+        }
+    }
+}
+
+// 存储参数中的密钥和密码，写入日志或通过管理接口返回前隐藏
+const SECRET_OPTIONS: [&str; 3] = ["secret_key", "password", "passphrase"];
+
+// 隐藏 path 中 secret_key、password 等参数的值，例如 "s3://bucket?secret_key=***"
+pub fn redact_path(path: &str) -> String {
+    let (base, query) = match path.split_once('?') {
+        Some((base, query)) if base.contains("://") => (base, query),
+        _ => return path.to_string(),
+    };
+    let query = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((key, _)) if SECRET_OPTIONS.contains(&key) => format!("{}=***", key),
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    format!("{}?{}", base, query)
+}
+
+// 检查 rule 的 path 能否作为存储使用，返回错误信息
+pub fn check_location(path: &str) -> Result<(), String> {
+    let location = Location::parse(path);
+    match location.scheme.as_str() {
+        "" => {
+            let path = std::path::Path::new(path);
+            if !path.exists() {
+                return Err(format!("{} does not exist", path.display()));
+            }
+            if !path.is_dir() {
+                return Err(format!("{} is not a directory", path.display()));
+            }
+            Ok(())
+        }
+        "memory" => {
+            if location.target.is_empty() {
+                return Err("memory storage needs a name, e.g. memory://scratch".to_string());
+            }
+            location
+                .size_option("max_size", memory::DEFAULT_MAX_SIZE)
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        }
+        "s3" => s3::S3Storage::new(&location)
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+        "sftp" => sftp::SftpStorage::new(&location)
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+        // 各个目录在 rule 的 sources 中单独检查
+        "union" => {
+            if location.target.is_empty() {
+                return Err("union storage needs a name, e.g. union://media".to_string());
+            }
+            union::WritePolicy::parse(&location)
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        }
+        scheme => Err(format!("unsupported storage \"{}://\"", scheme)),
+    }
+}
+
+// 解析 "1024"、"512K"、"64M"、"1G"（也接受 KB/MB/GB/KiB/MiB/GiB），单位为 1024
+pub fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let digits_end = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let number = value[..digits_end].parse::<u64>().ok()?;
+    let multiplier: u64 = match value[digits_end..].trim().to_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1024,
+        "M" | "MB" | "MIB" => 1024 * 1024,
+        "G" | "GB" | "GIB" => 1024 * 1024 * 1024,
+        "T" | "TB" | "TIB" => 1024 * 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    number.checked_mul(multiplier)
+}
+
+// 规范化请求路径：去掉 "."、空段，".." 不能超出根目录
+pub fn normalize_path(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+    format!("/{}", segments.join("/"))
+}
+
+// 删除文件或整个目录
+#[async_recursion::async_recursion]
+pub async fn remove_all(storage: &dyn Storage, path: &str) -> io::Result<()> {
+    if storage.stat(path).await?.is_dir {
+        for entry in storage.list(path).await? {
+            remove_all(storage, &join_path(path, &entry.name)).await?;
+        }
+    }
+    storage.remove(path).await
+}
+
+pub fn join_path(parent: &str, name: &str) -> String {
+    format!("{}/{}", parent.trim_end_matches('/'), name)
+}
+
+pub fn parent_path(path: &str) -> String {
+    match path.trim_end_matches('/').rsplit_once('/') {
+        Some((parent, _)) if !parent.is_empty() => parent.to_string(),
+        _ => "/".to_string(),
+    }
+}
+
+pub fn file_name(path: &str) -> &str {
+    path.trim_end_matches('/').rsplit('/').next().unwrap_or("")
+}
@@ -0,0 +1,504 @@
+use std::{
+    env, fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use hyper::body::Bytes;
+use ssh2::{CheckResult, ErrorCode, KnownHostFileKind, Session, Sftp};
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+
+use super::{normalize_path, receiver_stream, ByteStream, Entry, Location, Metadata, Storage};
+
+// 同一个 rule 最多同时打开的连接数
+pub const DEFAULT_POOL_SIZE: usize = 4;
+const DEFAULT_PORT: u16 = 22;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// 单个 sftp 请求的超时时间（毫秒）
+const SESSION_TIMEOUT_MS: u32 = 30_000;
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// libssh2 的 sftp 状态码
+const FX_PERMISSION_DENIED: i32 = 3;
+const FX_FILE_ALREADY_EXISTS: i32 = 11;
+const FX_DIR_NOT_EMPTY: i32 = 18;
+
+#[derive(Debug, Clone)]
+struct SftpConfig {
+    host: String,
+    port: u16,
+    user: String,
+    // 远程目录，不以 "/" 结尾，根目录为空字符串
+    root: String,
+    key: Option<PathBuf>,
+    passphrase: Option<String>,
+    password: Option<String>,
+    known_hosts: PathBuf,
+    strict_host_key: bool,
+}
+
+struct Connection {
+    // sftp 依赖 session，一起保存
+    _session: Session,
+    sftp: Sftp,
+}
+
+// 通过 SFTP 访问远程目录，例如 "sftp://user@host:22/srv/share?key=/home/me/.ssh/id_ed25519"。
+// libssh2 是阻塞接口，所有操作都在 spawn_blocking 中执行
+pub struct SftpStorage {
+    config: Arc<SftpConfig>,
+    // 空闲的连接
+    idle: Arc<Mutex<Vec<Connection>>>,
+    // 限制同时使用的连接数
+    permits: Arc<Semaphore>,
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn read_secret(location: &Location, key: &str) -> io::Result<Option<String>> {
+    if let Some(value) = location.options.get(key) {
+        return Ok(Some(value.to_string()));
+    }
+    match location.options.get(&format!("{}_file", key)) {
+        Some(file) => fs::read_to_string(file)
+            .map(|value| Some(value.trim().to_string()))
+            .map_err(|e| invalid_input(format!("failed to read {}: {}", file, e))),
+        None => Ok(None),
+    }
+}
+
+fn map_error(err: ssh2::Error) -> io::Error {
+    let kind = match err.code() {
+        ErrorCode::SFTP(FX_PERMISSION_DENIED) => io::ErrorKind::PermissionDenied,
+        ErrorCode::SFTP(FX_FILE_ALREADY_EXISTS) => io::ErrorKind::AlreadyExists,
+        ErrorCode::SFTP(FX_DIR_NOT_EMPTY) => io::ErrorKind::DirectoryNotEmpty,
+        _ => return io::Error::from(err),
+    };
+    io::Error::new(kind, err.message().to_string())
+}
+
+// 服务器返回的 sftp 状态错误不影响连接，其它错误时丢弃连接
+fn is_connection_error(err: &ssh2::Error) -> bool {
+    matches!(err.code(), ErrorCode::Session(_))
+}
+
+fn to_metadata(stat: &ssh2::FileStat) -> Metadata {
+    Metadata {
+        is_dir: stat.is_dir(),
+        len: stat.size.unwrap_or(0),
+        modified: UNIX_EPOCH + Duration::from_secs(stat.mtime.unwrap_or(0)),
+    }
+}
+
+fn connect(config: &SftpConfig) -> io::Result<Connection> {
+    let address = format!("{}:{}", config.host, config.port);
+    let socket_addr = std::net::ToSocketAddrs::to_socket_addrs(&address)?
+        .next()
+        .ok_or_else(|| invalid_input(format!("cannot resolve {}", address)))?;
+    let tcp = TcpStream::connect_timeout(&socket_addr, CONNECT_TIMEOUT)?;
+    let mut session = Session::new().map_err(map_error)?;
+    session.set_tcp_stream(tcp);
+    session.set_timeout(SESSION_TIMEOUT_MS);
+    session.handshake().map_err(map_error)?;
+    check_host_key(config, &session)?;
+
+    let user = config.user.as_str();
+    if let Some(key) = &config.key {
+        session
+            .userauth_pubkey_file(user, None, key, config.passphrase.as_deref())
+            .map_err(map_error)?;
+    } else if let Some(password) = &config.password {
+        session
+            .userauth_password(user, password)
+            .map_err(map_error)?;
+    } else {
+        session.userauth_agent(user).map_err(map_error)?;
+    }
+    if !session.authenticated() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("authentication failed for {}@{}", user, address),
+        ));
+    }
+    let sftp = session.sftp().map_err(map_error)?;
+    log::info!("sftp connected to {}@{}", user, address);
+    Ok(Connection {
+        _session: session,
+        sftp,
+    })
+}
+
+// 按 known_hosts 校验服务器公钥，主机不在其中时拒绝连接，除非明确配置 strict_host_key=false
+fn check_host_key(config: &SftpConfig, session: &Session) -> io::Result<()> {
+    let (key, _) = session
+        .host_key()
+        .ok_or_else(|| io::Error::other("server sent no host key"))?;
+    let mut known_hosts = session.known_hosts().map_err(map_error)?;
+    if config.known_hosts.exists() {
+        known_hosts
+            .read_file(&config.known_hosts, KnownHostFileKind::OpenSSH)
+            .map_err(map_error)?;
+    }
+    match known_hosts.check_port(&config.host, config.port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "host key of {} does not match {}",
+                config.host,
+                config.known_hosts.display()
+            ),
+        )),
+        CheckResult::NotFound | CheckResult::Failure if config.strict_host_key => {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "{} is not in {}, add its host key or set strict_host_key=false",
+                    config.host,
+                    config.known_hosts.display()
+                ),
+            ))
+        }
+        _ => {
+            log::warn!(
+                "{} is not in {}, its host key is not verified",
+                config.host,
+                config.known_hosts.display()
+            );
+            Ok(())
+        }
+    }
+}
+
+// 逐级创建目录
+fn create_dir_all(sftp: &Sftp, path: &Path) -> Result<(), ssh2::Error> {
+    let mut current = PathBuf::from("/");
+    for component in path.components().skip(1) {
+        current.push(component);
+        match sftp.stat(&current) {
+            Ok(stat) if stat.is_dir() => continue,
+            Ok(_) => {
+                return Err(ssh2::Error::new(
+                    ErrorCode::SFTP(FX_FILE_ALREADY_EXISTS),
+                    "a file with the same name already exists",
+                ))
+            }
+            Err(_) => sftp.mkdir(&current, 0o755)?,
+        }
+    }
+    Ok(())
+}
+
+impl SftpStorage {
+    pub fn new(location: &Location) -> io::Result<SftpStorage> {
+        let (authority, root) = location
+            .target
+            .split_once('/')
+            .unwrap_or((&location.target, ""));
+        let (user, host_port) = match authority.rsplit_once('@') {
+            Some((user, host_port)) => (user.to_string(), host_port),
+            None => (env::var("USER").unwrap_or_default(), authority),
+        };
+        let (host, port) = match host_port.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse::<u16>()
+                    .map_err(|_| invalid_input(format!("invalid port: {}", port)))?,
+            ),
+            None => (host_port, DEFAULT_PORT),
+        };
+        if host.is_empty() {
+            return Err(invalid_input(
+                "sftp storage needs a host, e.g. sftp://user@host/path".to_string(),
+            ));
+        }
+        if user.is_empty() {
+            return Err(invalid_input("sftp storage needs a user".to_string()));
+        }
+        let key = location.options.get("key").map(PathBuf::from);
+        if let Some(key) = &key {
+            if !key.is_file() {
+                return Err(invalid_input(format!("{} does not exist", key.display())));
+            }
+        }
+        let known_hosts = match location.options.get("known_hosts") {
+            Some(path) => PathBuf::from(path),
+            None => PathBuf::from(env::var("HOME").unwrap_or_default()).join(".ssh/known_hosts"),
+        };
+        let strict_host_key = match location.options.get("strict_host_key").map(|v| v.as_str()) {
+            Some("true") | None => true,
+            Some("false") => false,
+            Some(value) => {
+                return Err(invalid_input(format!("invalid strict_host_key: {}", value)))
+            }
+        };
+        let pool_size = match location.options.get("pool") {
+            Some(value) => match value.parse::<usize>() {
+                Ok(size) if size > 0 => size,
+                _ => return Err(invalid_input(format!("invalid pool: {}", value))),
+            },
+            None => DEFAULT_POOL_SIZE,
+        };
+        let config = SftpConfig {
+            host: host.to_string(),
+            port,
+            user,
+            root: format!("/{}", root.trim_matches('/'))
+                .trim_end_matches('/')
+                .to_string(),
+            key,
+            passphrase: read_secret(location, "passphrase")?,
+            password: read_secret(location, "password")?,
+            known_hosts,
+            strict_host_key,
+        };
+        Ok(SftpStorage {
+            config: Arc::new(config),
+            idle: Arc::new(Mutex::new(Vec::new())),
+            permits: Arc::new(Semaphore::new(pool_size)),
+        })
+    }
+
+    fn resolve(&self, path: &str) -> PathBuf {
+        let path = normalize_path(path);
+        if path == "/" && !self.config.root.is_empty() {
+            return PathBuf::from(&self.config.root);
+        }
+        PathBuf::from(format!("{}{}", self.config.root, path))
+    }
+
+    // 从连接池取出一个连接执行 f，连接正常时放回
+    async fn run<T, F>(&self, f: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: Fn(&Sftp) -> Result<T, ssh2::Error> + Send + 'static,
+    {
+        let (connection, result, _permit) = self.take_connection(f).await?;
+        self.idle.lock().unwrap().push(connection);
+        Ok(result)
+    }
+
+    // 取出一个连接并执行 open（打开文件等），之后由调用者长时间占用连接，用完后自行放回。
+    // 空闲连接可能已被服务器关闭，此时换一个新连接重试一次
+    async fn take_connection<T, F>(
+        &self,
+        open: F,
+    ) -> io::Result<(Connection, T, OwnedSemaphorePermit)>
+    where
+        T: Send + 'static,
+        F: Fn(&Sftp) -> Result<T, ssh2::Error> + Send + 'static,
+    {
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(io::Error::other)?;
+        let config = self.config.clone();
+        let idle = self.idle.clone();
+        let (connection, opened) = tokio::task::spawn_blocking(move || {
+            let pooled = idle.lock().unwrap().pop();
+            if let Some(connection) = pooled {
+                match open(&connection.sftp) {
+                    Ok(opened) => return Ok((connection, opened)),
+                    Err(err) if is_connection_error(&err) => {
+                        log::debug!("dropped a broken sftp connection: {}", err);
+                    }
+                    Err(err) => {
+                        idle.lock().unwrap().push(connection);
+                        return Err(map_error(err));
+                    }
+                }
+            }
+            let connection = connect(&config)?;
+            match open(&connection.sftp) {
+                Ok(opened) => Ok((connection, opened)),
+                Err(err) => {
+                    if !is_connection_error(&err) {
+                        idle.lock().unwrap().push(connection);
+                    }
+                    Err(map_error(err))
+                }
+            }
+        })
+        .await
+        .map_err(io::Error::other)??;
+        Ok((connection, opened, permit))
+    }
+}
+
+#[async_trait]
+impl Storage for SftpStorage {
+    async fn stat(&self, path: &str) -> io::Result<Metadata> {
+        let remote = self.resolve(path);
+        self.run(move |sftp| sftp.stat(&remote).map(|stat| to_metadata(&stat)))
+            .await
+    }
+
+    async fn list(&self, path: &str) -> io::Result<Vec<Entry>> {
+        let remote = self.resolve(path);
+        let items = self.run(move |sftp| sftp.readdir(&remote)).await?;
+        Ok(items
+            .iter()
+            .filter_map(|(path, stat)| {
+                let name = path.file_name()?.to_string_lossy().to_string();
+                Some(Entry {
+                    name,
+                    metadata: to_metadata(stat),
+                })
+            })
+            .collect())
+    }
+
+    async fn read(&self, path: &str, range: Option<(u64, u64)>) -> io::Result<ByteStream> {
+        let remote = self.resolve(path);
+        let (connection, mut file, permit) =
+            self.take_connection(move |sftp| sftp.open(&remote)).await?;
+        let remaining = match range {
+            Some((start, end)) => {
+                file.seek(SeekFrom::Start(start))?;
+                end - start + 1
+            }
+            None => u64::MAX,
+        };
+        let idle = self.idle.clone();
+        let (sender, receiver) = mpsc::channel::<io::Result<Bytes>>(4);
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let mut remaining = remaining;
+            let mut buffer = vec![0u8; CHUNK_SIZE];
+            while remaining > 0 {
+                let size = (remaining.min(CHUNK_SIZE as u64)) as usize;
+                match file.read(&mut buffer[..size]) {
+                    Ok(0) => break,
+                    Ok(read) => {
+                        remaining -= read as u64;
+                        let chunk = Bytes::copy_from_slice(&buffer[..read]);
+                        // 客户端断开时停止读取
+                        if sender.blocking_send(Ok(chunk)).is_err() {
+                            return;
+                        }
+                    }
+                    Err(err) => {
+                        let _ = sender.blocking_send(Err(err));
+                        return;
+                    }
+                }
+            }
+            drop(file);
+            idle.lock().unwrap().push(connection);
+        });
+        Ok(receiver_stream(receiver))
+    }
+
+    async fn write(&self, path: &str, mut body: ByteStream) -> io::Result<u64> {
+        let remote = self.resolve(path);
+        let (connection, mut file, permit) = self
+            .take_connection(move |sftp| sftp.create(&remote))
+            .await?;
+        let idle = self.idle.clone();
+        let (sender, mut receiver) = mpsc::channel::<Bytes>(4);
+        // 边接收请求体边写入远程文件
+        let writer = tokio::task::spawn_blocking(move || -> io::Result<u64> {
+            let _permit = permit;
+            let mut written = 0;
+            while let Some(chunk) = receiver.blocking_recv() {
+                file.write_all(&chunk)?;
+                written += chunk.len() as u64;
+            }
+            file.flush()?;
+            drop(file);
+            idle.lock().unwrap().push(connection);
+            Ok(written)
+        });
+        let mut body_error = None;
+        while let Some(chunk) = body.next().await {
+            match chunk {
+                Ok(chunk) => {
+                    if sender.send(chunk).await.is_err() {
+                        // 写入端出错，错误由 writer 返回
+                        break;
+                    }
+                }
+                Err(err) => {
+                    body_error = Some(err);
+                    break;
+                }
+            }
+        }
+        drop(sender);
+        let written = writer.await.map_err(io::Error::other)??;
+        match body_error {
+            Some(err) => Err(err),
+            None => Ok(written),
+        }
+    }
+
+    async fn mkdir(&self, path: &str) -> io::Result<()> {
+        let remote = self.resolve(path);
+        self.run(move |sftp| create_dir_all(sftp, &remote)).await
+    }
+
+    async fn remove(&self, path: &str) -> io::Result<()> {
+        if normalize_path(path) == "/" {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "cannot remove the root",
+            ));
+        }
+        let remote = self.resolve(path);
+        self.run(move |sftp| {
+            if sftp.lstat(&remote)?.is_dir() {
+                sftp.rmdir(&remote)
+            } else {
+                sftp.unlink(&remote)
+            }
+        })
+        .await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let from = self.resolve(from);
+        let to = self.resolve(to);
+        self.run(move |sftp| match sftp.rename(&from, &to, None) {
+            Ok(()) => Ok(()),
+            // SFTP v3 不能覆盖已存在的文件，先删除目标再重试
+            Err(err) => match sftp.stat(&to) {
+                Ok(stat) if !stat.is_dir() => {
+                    sftp.unlink(&to)?;
+                    sftp.rename(&from, &to, None)
+                }
+                _ => Err(err),
+            },
+        })
+        .await
+    }
+
+    // SFTP 没有服务端复制，在同一个连接上读出再写入
+    async fn copy(&self, from: &str, to: &str) -> io::Result<()> {
+        let from = self.resolve(from);
+        let to = self.resolve(to);
+        let (connection, (mut source, mut target), permit) = self
+            .take_connection(move |sftp| Ok((sftp.open(&from)?, sftp.create(&to)?)))
+            .await?;
+        let idle = self.idle.clone();
+        tokio::task::spawn_blocking(move || -> io::Result<()> {
+            let _permit = permit;
+            io::copy(&mut source, &mut target)?;
+            target.flush()?;
+            drop(source);
+            drop(target);
+            idle.lock().unwrap().push(connection);
+            Ok(())
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+}
@@ -0,0 +1,92 @@
+use std::{io, sync::Arc};
+
+use async_trait::async_trait;
+
+use super::{join_path, ByteStream, Entry, Metadata, Storage};
+
+// 只能访问 inner 中 root 下的内容，root 可以是文件，此时 "/" 即为该文件。
+// hidden 为 inner 中不能访问的路径，列目录时也不显示
+pub struct SubStorage {
+    inner: Arc<dyn Storage>,
+    root: String,
+    hidden: Vec<String>,
+}
+
+impl SubStorage {
+    pub fn new(inner: Arc<dyn Storage>, root: &str, hidden: &[&str]) -> SubStorage {
+        SubStorage {
+            inner,
+            root: root.trim_end_matches('/').to_string(),
+            hidden: hidden.iter().map(|path| path.to_string()).collect(),
+        }
+    }
+
+    fn is_hidden(&self, path: &str) -> bool {
+        self.hidden
+            .iter()
+            .any(|hidden| path == hidden || path.starts_with(&format!("{}/", hidden)))
+    }
+
+    fn map(&self, path: &str) -> io::Result<String> {
+        let path = match path {
+            "/" if self.root.is_empty() => "/".to_string(),
+            "/" => self.root.to_string(),
+            _ => format!("{}{}", self.root, path),
+        };
+        if self.is_hidden(&path) {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+        Ok(path)
+    }
+}
+
+#[async_trait]
+impl Storage for SubStorage {
+    async fn stat(&self, path: &str) -> io::Result<Metadata> {
+        self.inner.stat(&self.map(path)?).await
+    }
+
+    async fn list(&self, path: &str) -> io::Result<Vec<Entry>> {
+        let path = self.map(path)?;
+        let entries = self.inner.list(&path).await?;
+        Ok(entries
+            .into_iter()
+            .filter(|entry| !self.is_hidden(&join_path(&path, &entry.name)))
+            .collect())
+    }
+
+    async fn read(&self, path: &str, range: Option<(u64, u64)>) -> io::Result<ByteStream> {
+        self.inner.read(&self.map(path)?, range).await
+    }
+
+    async fn write(&self, path: &str, body: ByteStream) -> io::Result<u64> {
+        self.inner.write(&self.map(path)?, body).await
+    }
+
+    async fn mkdir(&self, path: &str) -> io::Result<()> {
+        self.inner.mkdir(&self.map(path)?).await
+    }
+
+    // 不能删除或移动分享的根目录
+    async fn remove(&self, path: &str) -> io::Result<()> {
+        if path == "/" {
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+        }
+        self.inner.remove(&self.map(path)?).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        if from == "/" || to == "/" {
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+        }
+        self.inner.rename(&self.map(from)?, &self.map(to)?).await
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> io::Result<()> {
+        self.inner.copy(&self.map(from)?, &self.map(to)?).await
+    }
+
+    async fn available_space(&self) -> Option<u64> {
+        self.inner.available_space().await
+    }
+}
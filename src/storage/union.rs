@@ -0,0 +1,455 @@
+use std::{collections::HashSet, io, sync::Arc};
+
+use async_trait::async_trait;
+use futures_util::future::join_all;
+
+use super::{join_path, parent_path, remove_all, ByteStream, Entry, Location, Metadata, Storage};
+
+// 新文件和新目录写到哪个目录
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WritePolicy {
+    // 第一个可写的目录
+    FirstWritable,
+    // 剩余空间最多的可写目录
+    MostFree,
+    // 已有父目录的第一个可写目录，都没有时同 FirstWritable
+    SameParent,
+}
+
+impl WritePolicy {
+    pub fn parse(location: &Location) -> io::Result<WritePolicy> {
+        match location.options.get("write").map(String::as_str) {
+            None | Some("first_writable") => Ok(WritePolicy::FirstWritable),
+            Some("most_free") => Ok(WritePolicy::MostFree),
+            Some("same_parent") => Ok(WritePolicy::SameParent),
+            Some(value) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "invalid write: {}, expected first_writable, most_free or same_parent",
+                    value
+                ),
+            )),
+        }
+    }
+}
+
+pub struct Branch {
+    // 用于错误信息，已隐藏密钥
+    pub path: String,
+    pub storage: Arc<dyn Storage>,
+    pub read_only: bool,
+}
+
+// 把多个目录合并为一个，同名时排在前面的目录优先
+pub struct UnionStorage {
+    branches: Vec<Branch>,
+    policy: WritePolicy,
+}
+
+fn read_only(path: &str, branch: &Branch) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("{} is in read-only source {}", path, branch.path),
+    )
+}
+
+fn not_found(path: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path))
+}
+
+impl UnionStorage {
+    pub fn new(branches: Vec<Branch>, policy: WritePolicy) -> UnionStorage {
+        UnionStorage { branches, policy }
+    }
+
+    // 包含 path 的目录序号及其元数据，按优先级排列
+    async fn owners(&self, path: &str) -> io::Result<Vec<(usize, Metadata)>> {
+        let results = join_all(self.branches.iter().map(|b| b.storage.stat(path))).await;
+        let mut owners = Vec::new();
+        for (index, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(metadata) => owners.push((index, metadata)),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(owners)
+    }
+
+    // 同名时只有优先级最高的可见
+    async fn owner(&self, path: &str) -> io::Result<Option<(usize, Metadata)>> {
+        Ok(self.owners(path).await?.into_iter().next())
+    }
+
+    // 所有包含 path 的目录都要可写
+    async fn writable_owners(&self, path: &str) -> io::Result<Vec<usize>> {
+        let owners = self.owners(path).await?;
+        if owners.is_empty() {
+            return Err(not_found(path));
+        }
+        if let Some((index, _)) = owners.iter().find(|(i, _)| self.branches[*i].read_only) {
+            return Err(read_only(path, &self.branches[*index]));
+        }
+        Ok(owners.into_iter().map(|(index, _)| index).collect())
+    }
+
+    // 选择写入 path 的目录：已存在时写回原处，否则按写入策略选择
+    async fn write_target(&self, path: &str) -> io::Result<usize> {
+        if let Some((index, _)) = self.owner(path).await? {
+            let branch = &self.branches[index];
+            if branch.read_only {
+                return Err(read_only(path, branch));
+            }
+            return Ok(index);
+        }
+        let writable: Vec<usize> = (0..self.branches.len())
+            .filter(|index| !self.branches[*index].read_only)
+            .collect();
+        let first = *writable.first().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::PermissionDenied, "all sources are read-only")
+        })?;
+        match self.policy {
+            WritePolicy::FirstWritable => Ok(first),
+            WritePolicy::MostFree => {
+                let spaces = join_all(
+                    writable
+                        .iter()
+                        .map(|index| self.branches[*index].storage.available_space()),
+                )
+                .await;
+                // 无法获取剩余空间的目录排在最后
+                Ok(writable
+                    .iter()
+                    .zip(spaces)
+                    .filter_map(|(index, space)| space.map(|space| (*index, space)))
+                    .max_by_key(|(index, space)| (*space, std::cmp::Reverse(*index)))
+                    .map(|(index, _)| index)
+                    .unwrap_or(first))
+            }
+            WritePolicy::SameParent => {
+                let parent = parent_path(path);
+                for index in writable {
+                    match self.branches[index].storage.stat(&parent).await {
+                        Ok(metadata) if metadata.is_dir => return Ok(index),
+                        Ok(_) => {}
+                        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                        Err(err) => return Err(err),
+                    }
+                }
+                Ok(first)
+            }
+        }
+    }
+
+    // 在选中的目录里补齐合并视图中已存在的父目录
+    async fn prepare_parent(&self, index: usize, path: &str) -> io::Result<()> {
+        let parent = parent_path(path);
+        if parent == "/" {
+            return Ok(());
+        }
+        match self.owner(&parent).await? {
+            Some((_, metadata)) if metadata.is_dir => {
+                self.branches[index].storage.mkdir(&parent).await
+            }
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                format!("{} is not a directory", parent),
+            )),
+            None => Err(not_found(&parent)),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for UnionStorage {
+    async fn stat(&self, path: &str) -> io::Result<Metadata> {
+        match self.owner(path).await? {
+            Some((_, metadata)) => Ok(metadata),
+            None => Err(not_found(path)),
+        }
+    }
+
+    async fn list(&self, path: &str) -> io::Result<Vec<Entry>> {
+        let owners = self.owners(path).await?;
+        match owners.first() {
+            None => return Err(not_found(path)),
+            Some((_, metadata)) if !metadata.is_dir => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotADirectory,
+                    format!("{} is not a directory", path),
+                ))
+            }
+            _ => {}
+        }
+        let mut names = HashSet::new();
+        let mut result = Vec::new();
+        for (index, metadata) in owners {
+            if !metadata.is_dir {
+                continue;
+            }
+            for entry in self.branches[index].storage.list(path).await? {
+                if names.insert(entry.name.to_string()) {
+                    result.push(entry);
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    async fn read(&self, path: &str, range: Option<(u64, u64)>) -> io::Result<ByteStream> {
+        match self.owner(path).await? {
+            Some((index, _)) => self.branches[index].storage.read(path, range).await,
+            None => Err(not_found(path)),
+        }
+    }
+
+    async fn write(&self, path: &str, body: ByteStream) -> io::Result<u64> {
+        let index = self.write_target(path).await?;
+        self.prepare_parent(index, path).await?;
+        self.branches[index].storage.write(path, body).await
+    }
+
+    async fn mkdir(&self, path: &str) -> io::Result<()> {
+        if let Some((_, metadata)) = self.owner(path).await? {
+            if metadata.is_dir {
+                return Ok(());
+            }
+        }
+        let index = self.write_target(path).await?;
+        self.branches[index].storage.mkdir(path).await
+    }
+
+    // 同名的文件或目录在每个目录中都删除，否则低优先级的会重新出现
+    async fn remove(&self, path: &str) -> io::Result<()> {
+        for index in self.writable_owners(path).await? {
+            self.branches[index].storage.remove(path).await?;
+        }
+        Ok(())
+    }
+
+    // 先在每个包含 from 的目录中移动，失败时目标保持原样；
+    // 再删除其它目录中同名的旧目标，否则会遮住移动过来的内容或在之后重新出现
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        if from == to {
+            return Ok(());
+        }
+        let sources = self.writable_owners(from).await?;
+        let parent = parent_path(to);
+        if parent != "/" {
+            match self.owner(&parent).await? {
+                Some((_, metadata)) if metadata.is_dir => {}
+                Some(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotADirectory,
+                        format!("{} is not a directory", parent),
+                    ))
+                }
+                None => return Err(not_found(&parent)),
+            }
+        }
+        let targets = self.owners(to).await?;
+        if let Some((index, _)) = targets.iter().find(|(i, _)| self.branches[*i].read_only) {
+            return Err(read_only(to, &self.branches[*index]));
+        }
+        for index in sources.iter() {
+            let storage = &self.branches[*index].storage;
+            if parent != "/" {
+                storage.mkdir(&parent).await?;
+            }
+            storage.rename(from, to).await?;
+        }
+        for (index, _) in targets {
+            if !sources.contains(&index) {
+                remove_all(self.branches[index].storage.as_ref(), to).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // 目录按合并视图逐个复制，文件在不同目录之间复制时读出再写入
+    async fn copy(&self, from: &str, to: &str) -> io::Result<()> {
+        let (source, metadata) = self.owner(from).await?.ok_or_else(|| not_found(from))?;
+        if metadata.is_dir {
+            if to.starts_with(&format!("{}/", from.trim_end_matches('/'))) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("cannot copy {} into itself", from),
+                ));
+            }
+            let target = self.write_target(to).await?;
+            self.prepare_parent(target, to).await?;
+            self.branches[target].storage.mkdir(to).await?;
+            for entry in self.list(from).await? {
+                self.copy(&join_path(from, &entry.name), &join_path(to, &entry.name))
+                    .await?;
+            }
+            return Ok(());
+        }
+        let target = self.write_target(to).await?;
+        self.prepare_parent(target, to).await?;
+        if source == target {
+            return self.branches[target].storage.copy(from, to).await;
+        }
+        let body = self.branches[source].storage.read(from, None).await?;
+        self.branches[target]
+            .storage
+            .write(to, body)
+            .await
+            .map(|_| ())
+    }
+
+    async fn available_space(&self) -> Option<u64> {
+        let spaces = join_all(
+            self.branches
+                .iter()
+                .filter(|branch| !branch.read_only)
+                .map(|branch| branch.storage.available_space()),
+        )
+        .await;
+        spaces.into_iter().flatten().max()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{stream, TryStreamExt};
+    use hyper::body::Bytes;
+
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    fn body(data: &str) -> ByteStream {
+        Box::pin(stream::iter([Ok(Bytes::from(data.to_string()))]))
+    }
+
+    async fn read(storage: &dyn Storage, path: &str) -> String {
+        let chunks: Vec<Bytes> = storage
+            .read(path, None)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        String::from_utf8(chunks.concat()).unwrap()
+    }
+
+    // 每个目录的容量和是否只读
+    fn union(
+        branches: &[(u64, bool)],
+        policy: WritePolicy,
+    ) -> (UnionStorage, Vec<Arc<MemoryStorage>>) {
+        let storages: Vec<Arc<MemoryStorage>> = branches
+            .iter()
+            .map(|(max_size, _)| Arc::new(MemoryStorage::new(*max_size)))
+            .collect();
+        let branches = storages
+            .iter()
+            .zip(branches)
+            .enumerate()
+            .map(|(index, (storage, (_, read_only)))| Branch {
+                path: format!("memory://{}", index),
+                storage: storage.clone(),
+                read_only: *read_only,
+            })
+            .collect();
+        (UnionStorage::new(branches, policy), storages)
+    }
+
+    #[tokio::test]
+    async fn earlier_branch_wins_and_list_dedups() {
+        let (union, storages) = union(&[(1024, false), (1024, false)], WritePolicy::FirstWritable);
+        storages[0].write("/same.txt", body("first")).await.unwrap();
+        storages[1]
+            .write("/same.txt", body("second"))
+            .await
+            .unwrap();
+        storages[1].write("/only.txt", body("only")).await.unwrap();
+        assert_eq!(read(&union, "/same.txt").await, "first");
+        assert_eq!(union.stat("/same.txt").await.unwrap().len, 5);
+        assert_eq!(read(&union, "/only.txt").await, "only");
+        let mut names: Vec<String> = union
+            .list("/")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["only.txt", "same.txt"]);
+    }
+
+    #[tokio::test]
+    async fn first_writable_skips_read_only() {
+        let (union, storages) = union(&[(1024, true), (1024, false)], WritePolicy::FirstWritable);
+        union.write("/new.txt", body("new")).await.unwrap();
+        assert!(storages[0].stat("/new.txt").await.is_err());
+        assert!(storages[1].stat("/new.txt").await.is_ok());
+        // 已存在于只读目录的文件不能覆盖
+        storages[0].write("/ro.txt", body("ro")).await.unwrap();
+        let err = union.write("/ro.txt", body("x")).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn most_free_picks_the_emptiest_branch() {
+        let (union, storages) = union(
+            &[(1024, false), (4096, false), (8192, true)],
+            WritePolicy::MostFree,
+        );
+        union.write("/new.txt", body("new")).await.unwrap();
+        assert!(storages[1].stat("/new.txt").await.is_ok());
+        assert!(storages[0].stat("/new.txt").await.is_err());
+        assert!(storages[2].stat("/new.txt").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn same_parent_follows_the_existing_folder() {
+        let (union, storages) = union(&[(1024, false), (1024, false)], WritePolicy::SameParent);
+        storages[1].mkdir("/docs").await.unwrap();
+        union.write("/docs/a.txt", body("a")).await.unwrap();
+        assert!(storages[1].stat("/docs/a.txt").await.is_ok());
+        assert!(storages[0].stat("/docs").await.is_err());
+        // 没有父目录的目录时写到第一个可写的目录
+        union.write("/b.txt", body("b")).await.unwrap();
+        assert!(storages[0].stat("/b.txt").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn rename_replaces_shadowing_targets() {
+        let (union, storages) = union(&[(1024, false), (1024, false)], WritePolicy::FirstWritable);
+        storages[0].write("/to.txt", body("old")).await.unwrap();
+        storages[1].write("/from.txt", body("moved")).await.unwrap();
+        union.rename("/from.txt", "/to.txt").await.unwrap();
+        assert_eq!(read(&union, "/to.txt").await, "moved");
+        assert!(storages[0].stat("/to.txt").await.is_err());
+        assert!(union.stat("/from.txt").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn failed_rename_keeps_the_target() {
+        let (union, storages) = union(&[(1024, false), (1024, false)], WritePolicy::FirstWritable);
+        storages[0].mkdir("/a/sub").await.unwrap();
+        storages[0]
+            .write("/a/sub/keep.txt", body("keep"))
+            .await
+            .unwrap();
+        storages[1].mkdir("/a/sub").await.unwrap();
+        storages[1]
+            .write("/a/sub/other.txt", body("other"))
+            .await
+            .unwrap();
+        assert!(union.rename("/a", "/a/sub").await.is_err());
+        assert_eq!(read(&union, "/a/sub/keep.txt").await, "keep");
+        assert_eq!(read(&union, "/a/sub/other.txt").await, "other");
+    }
+
+    #[tokio::test]
+    async fn rename_refuses_read_only_targets() {
+        let (union, storages) = union(&[(1024, true), (1024, false)], WritePolicy::FirstWritable);
+        storages[0].write("/to.txt", body("ro")).await.unwrap();
+        storages[1].write("/from.txt", body("moved")).await.unwrap();
+        let err = union.rename("/from.txt", "/to.txt").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(read(&union, "/from.txt").await, "moved");
+    }
+}
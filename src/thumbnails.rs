@@ -0,0 +1,410 @@
+use std::{
+    collections::HashMap,
+    io::{self, Cursor},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::UNIX_EPOCH,
+};
+
+use image::{
+    codecs::jpeg::JpegEncoder, DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits,
+};
+use lazy_static::lazy_static;
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard, Semaphore, SemaphorePermit};
+
+use crate::{
+    config::{Rule, ThumbnailsConfig},
+    share,
+    storage::{parse_size, Metadata},
+};
+
+const DEFAULT_MAX_DIMENSION: u32 = 1024;
+const DEFAULT_MAX_SOURCE_SIZE: u64 = 50 * 1024 * 1024;
+const DEFAULT_MAX_SOURCE_DIMENSION: u32 = 10000;
+const JPEG_QUALITY: u8 = 80;
+// 同时生成的缩略图数量，每个都要把原图读入内存并解码
+const RENDER_WORKERS: usize = 4;
+
+// 区分同时写入的临时文件
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref RENDERS: Semaphore = Semaphore::new(RENDER_WORKERS);
+    // 正在生成的缩略图，同一个缩略图的其他请求等它生成后读取缓存
+    static ref RENDERING: Mutex<HashMap<String, Arc<AsyncMutex<()>>>> = Mutex::new(HashMap::new());
+}
+
+pub fn max_dimension(config: &ThumbnailsConfig) -> u32 {
+    if config.max_dimension == 0 {
+        DEFAULT_MAX_DIMENSION
+    } else {
+        config.max_dimension
+    }
+}
+
+pub fn max_source_size(config: &ThumbnailsConfig) -> u64 {
+    parse_size(&config.max_source_size).unwrap_or(DEFAULT_MAX_SOURCE_SIZE)
+}
+
+pub fn max_source_dimension(config: &ThumbnailsConfig) -> u32 {
+    if config.max_source_dimension == 0 {
+        DEFAULT_MAX_SOURCE_DIMENSION
+    } else {
+        config.max_source_dimension
+    }
+}
+
+// 持有期间同一个缩略图的其他请求等待，释放时删掉不再使用的锁
+pub struct RenderLock {
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for RenderLock {
+    fn drop(&mut self) {
+        self.guard.take();
+        RENDERING
+            .lock()
+            .unwrap()
+            .retain(|_, lock| Arc::strong_count(lock) > 1);
+    }
+}
+
+pub async fn lock_render(
+    rule: &Rule,
+    path: &str,
+    key: &str,
+    width: u32,
+    height: u32,
+) -> RenderLock {
+    let name = format!(
+        "{}\n{}\n{}",
+        rule.path,
+        share::owner_path(rule, path),
+        cache_name(key, width, height)
+    );
+    let lock = RENDERING.lock().unwrap().entry(name).or_default().clone();
+    RenderLock {
+        guard: Some(lock.lock_owned().await),
+    }
+}
+
+// 限制同时读入内存并解码的原图数量
+pub async fn render_permit() -> SemaphorePermit<'static> {
+    RENDERS.acquire().await.expect("semaphore is never closed")
+}
+
+// 每个 rule 一个缓存目录，其下的目录结构与存储相同，
+// 例如 /a/b.jpg 的缩略图都在 <rule 目录>/a/b.jpg/ 中，移动或删除目录时可以整个删掉
+fn source_dir(config: &ThumbnailsConfig, rule: &Rule, path: &str) -> Option<PathBuf> {
+    let root = if config.cache_dir.is_empty() {
+        std::env::temp_dir().join("rhfs-thumbnails")
+    } else {
+        PathBuf::from(&config.cache_dir)
+    };
+    // 通过分享访问时与创建者使用同一份缓存
+    let path = share::owner_path(rule, path);
+    let relative = Path::new(path.trim_start_matches('/'));
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return None;
+    }
+    Some(
+        root.join(format!("{:x}", md5::compute(&rule.path)))
+            .join(relative),
+    )
+}
+
+// 文件的大小或修改时间变化后缓存的缩略图不再使用
+pub fn source_key(metadata: &Metadata) -> String {
+    let modified = metadata
+        .modified
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or(0);
+    format!(
+        "{:x}",
+        md5::compute(format!("{}-{}", modified, metadata.len))
+    )
+}
+
+fn cache_name(key: &str, width: u32, height: u32) -> String {
+    format!("{}-{}x{}", key, width, height)
+}
+
+pub async fn read_cached(
+    config: &ThumbnailsConfig,
+    rule: &Rule,
+    path: &str,
+    key: &str,
+    width: u32,
+    height: u32,
+) -> Option<Vec<u8>> {
+    let file = source_dir(config, rule, path)?.join(cache_name(key, width, height));
+    tokio::fs::read(file).await.ok()
+}
+
+// 写入缓存并删掉同一文件旧版本的缩略图，失败时只记录日志
+pub async fn write_cached(
+    config: &ThumbnailsConfig,
+    rule: &Rule,
+    path: &str,
+    key: &str,
+    width: u32,
+    height: u32,
+    data: &[u8],
+) {
+    let dir = match source_dir(config, rule, path) {
+        Some(dir) => dir,
+        None => return,
+    };
+    let result = async {
+        tokio::fs::create_dir_all(&dir).await?;
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let stale = entry.file_type().await?.is_file()
+                && !entry.file_name().to_string_lossy().starts_with(key);
+            if stale {
+                tokio::fs::remove_file(entry.path()).await?;
+            }
+        }
+        // 先写临时文件再改名，同时生成同一个缩略图的请求不会读到一半的文件
+        let name = cache_name(key, width, height);
+        let temp = dir.join(format!(
+            "{}.{}.tmp",
+            name,
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        tokio::fs::write(&temp, data).await?;
+        tokio::fs::rename(&temp, dir.join(name)).await
+    }
+    .await;
+    if let Err(err) = result {
+        log::warn!("failed to cache thumbnail of {}: {}", path, err);
+    }
+}
+
+// 文件被覆盖、移动或删除后删掉它（或目录下所有文件）的缩略图
+pub async fn invalidate(rule: &Rule, path: &str) {
+    let config = match &rule.thumbnails {
+        Some(config) => config,
+        None => return,
+    };
+    // 不会整个删掉 rule 的缓存
+    if path.trim_matches('/').is_empty() {
+        return;
+    }
+    let dir = match source_dir(config, rule, path) {
+        Some(dir) => dir,
+        None => return,
+    };
+    match tokio::fs::remove_dir_all(&dir).await {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => log::warn!("failed to remove thumbnails of {}: {}", path, err),
+    }
+}
+
+fn invalid_image<E: std::fmt::Display>(err: E) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid image: {}", err),
+    )
+}
+
+pub fn content_type(data: &[u8]) -> &'static str {
+    if data.starts_with(b"\x89PNG") {
+        "image/png"
+    } else {
+        "image/jpeg"
+    }
+}
+
+// 生成不超过 width x height 的缩略图，按 EXIF 方向旋转，GIF 取第一帧。
+// 有透明通道时输出 PNG，否则输出 JPEG，只能在 spawn_blocking 中使用。
+// 原图宽高超过 max_source 时不解码
+pub fn render(data: &[u8], width: u32, height: u32, max_source: u32) -> io::Result<Vec<u8>> {
+    let reader = ImageReader::new(Cursor::new(data)).with_guessed_format()?;
+    match reader.format() {
+        Some(ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP | ImageFormat::Gif) => {}
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unsupported image format",
+            ))
+        }
+    }
+    let mut decoder = reader.into_decoder().map_err(invalid_image)?;
+    // 很小的文件也可能声明巨大的尺寸，解码前拒绝
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(max_source);
+    limits.max_image_height = Some(max_source);
+    decoder.set_limits(limits).map_err(invalid_image)?;
+    let orientation = decoder.orientation().map_err(invalid_image)?;
+    let mut image = DynamicImage::from_decoder(decoder).map_err(invalid_image)?;
+    image.apply_orientation(orientation);
+    // 不放大比目标小的图片
+    if image.width() > width || image.height() > height {
+        image = image.thumbnail(width, height);
+    }
+    let mut out = Cursor::new(Vec::new());
+    if image.color().has_alpha() {
+        image
+            .write_to(&mut out, ImageFormat::Png)
+            .map_err(invalid_image)?;
+    } else {
+        let encoder = JpegEncoder::new_with_quality(&mut out, JPEG_QUALITY);
+        image
+            .to_rgb8()
+            .write_with_encoder(encoder)
+            .map_err(invalid_image)?;
+    }
+    Ok(out.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{codecs::gif::GifEncoder, Frame, Rgb, RgbImage, Rgba, RgbaImage};
+
+    use super::*;
+
+    fn encode(image: DynamicImage, format: ImageFormat) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        image.write_to(&mut out, format).unwrap();
+        out.into_inner()
+    }
+
+    fn is_red(pixel: [u8; 3]) -> bool {
+        pixel[0] > 200 && pixel[1] < 60 && pixel[2] < 60
+    }
+
+    fn is_blue(pixel: [u8; 3]) -> bool {
+        pixel[0] < 60 && pixel[1] < 60 && pixel[2] > 200
+    }
+
+    fn pixel(image: &DynamicImage, x: u32, y: u32) -> [u8; 3] {
+        image.to_rgb8().get_pixel(x, y).0
+    }
+
+    // 在 SOI 之后插入只有方向标签的 EXIF
+    fn with_orientation(jpeg: Vec<u8>, orientation: u16) -> Vec<u8> {
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01".to_vec();
+        exif.extend_from_slice(&orientation.to_be_bytes());
+        exif.extend_from_slice(&[0; 6]);
+        let mut out = jpeg[..2].to_vec();
+        out.extend_from_slice(&[0xFF, 0xE1]);
+        out.extend_from_slice(&(exif.len() as u16 + 2).to_be_bytes());
+        out.extend_from_slice(&exif);
+        out.extend_from_slice(&jpeg[2..]);
+        out
+    }
+
+    #[test]
+    fn render_applies_exif_orientation() {
+        // 左半红右半蓝，顺时针旋转 90 度后上红下蓝
+        let image = RgbImage::from_fn(40, 20, |x, _| {
+            if x < 20 {
+                Rgb([255, 0, 0])
+            } else {
+                Rgb([0, 0, 255])
+            }
+        });
+        let jpeg = encode(DynamicImage::ImageRgb8(image), ImageFormat::Jpeg);
+        let data = render(&with_orientation(jpeg, 6), 100, 100, 1000).unwrap();
+        assert_eq!(content_type(&data), "image/jpeg");
+        let thumbnail = image::load_from_memory(&data).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (20, 40));
+        assert!(is_red(pixel(&thumbnail, 10, 5)));
+        assert!(is_blue(pixel(&thumbnail, 10, 35)));
+    }
+
+    #[test]
+    fn render_uses_the_first_gif_frame() {
+        let mut data = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut data);
+            let red = RgbaImage::from_pixel(8, 8, Rgba([255, 0, 0, 255]));
+            let blue = RgbaImage::from_pixel(8, 8, Rgba([0, 0, 255, 255]));
+            encoder
+                .encode_frames([Frame::new(red), Frame::new(blue)])
+                .unwrap();
+        }
+        let thumbnail = image::load_from_memory(&render(&data, 4, 4, 1000).unwrap()).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (4, 4));
+        assert!(is_red(pixel(&thumbnail, 2, 2)));
+    }
+
+    #[test]
+    fn render_keeps_alpha_as_png_and_does_not_enlarge() {
+        let rgba = RgbaImage::from_pixel(200, 100, Rgba([0, 0, 255, 128]));
+        let png = encode(DynamicImage::ImageRgba8(rgba), ImageFormat::Png);
+        let data = render(&png, 50, 50, 1000).unwrap();
+        assert_eq!(content_type(&data), "image/png");
+        let thumbnail = image::load_from_memory(&data).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (50, 25));
+        assert!(thumbnail.color().has_alpha());
+
+        // 没有透明通道的 PNG 输出 JPEG
+        let rgb = RgbImage::from_pixel(30, 20, Rgb([255, 0, 0]));
+        let png = encode(DynamicImage::ImageRgb8(rgb), ImageFormat::Png);
+        let data = render(&png, 100, 100, 1000).unwrap();
+        assert_eq!(content_type(&data), "image/jpeg");
+        let thumbnail = image::load_from_memory(&data).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (30, 20));
+    }
+
+    #[test]
+    fn render_refuses_large_sources_and_other_formats() {
+        let rgb = RgbImage::from_pixel(200, 100, Rgb([255, 0, 0]));
+        let png = encode(DynamicImage::ImageRgb8(rgb), ImageFormat::Png);
+        assert!(render(&png, 50, 50, 100).is_err());
+        let err = render(b"not an image", 50, 50, 1000).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[tokio::test]
+    async fn write_cached_removes_stale_entries() {
+        let cache_dir =
+            std::env::temp_dir().join(format!("rhfs-thumbnails-test-{}", std::process::id()));
+        let config = ThumbnailsConfig {
+            cache_dir: cache_dir.to_string_lossy().to_string(),
+            ..ThumbnailsConfig::default()
+        };
+        let rule = Rule {
+            path: "/srv/photos".to_string(),
+            ..Rule::default()
+        };
+        let path = "/a/b.jpg";
+        write_cached(&config, &rule, path, "old", 10, 10, b"old small").await;
+        write_cached(&config, &rule, path, "old", 20, 20, b"old large").await;
+        assert_eq!(
+            read_cached(&config, &rule, path, "old", 10, 10).await,
+            Some(b"old small".to_vec())
+        );
+        // 文件变化后写入新的缩略图，旧的都删掉
+        write_cached(&config, &rule, path, "new", 10, 10, b"new small").await;
+        assert_eq!(read_cached(&config, &rule, path, "old", 10, 10).await, None);
+        assert_eq!(read_cached(&config, &rule, path, "old", 20, 20).await, None);
+        assert_eq!(
+            read_cached(&config, &rule, path, "new", 10, 10).await,
+            Some(b"new small".to_vec())
+        );
+        let dir = source_dir(&config, &rule, path).unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        invalidate(
+            &Rule {
+                thumbnails: Some(config.clone()),
+                ..rule.clone()
+            },
+            "/a",
+        )
+        .await;
+        assert!(!dir.exists());
+        std::fs::remove_dir_all(&cache_dir).unwrap();
+    }
+}
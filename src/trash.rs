@@ -0,0 +1,238 @@
+use std::{
+    collections::HashSet,
+    io,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_recursion::async_recursion;
+use chrono::Local;
+use futures_util::{stream, StreamExt};
+use hyper::body::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{self, Rule, TrashConfig},
+    storage::{self, file_name, join_path, parse_size, redact_path, remove_all, Storage},
+    util::parse_duration,
+    versions,
+};
+
+// 回收站位于共享根目录下，列出根目录时隐藏
+pub const TRASH_DIR: &str = "/.trash";
+// 每个被删除的文件在这里有一个同名的 .json 记录
+const INFO_DIR: &str = "/.trash/.info";
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashInfo {
+    // 删除前相对共享根目录的路径
+    pub path: String,
+    // 删除时间，unix 秒
+    pub deleted_at: u64,
+    #[serde(default)]
+    pub deleted_by: String,
+    // 文件或目录的总字节数
+    #[serde(default)]
+    pub size: u64,
+}
+
+impl TrashInfo {
+    pub fn deleted_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.deleted_at)
+    }
+}
+
+// rule 启用了回收站并且 path 在回收站中
+pub fn is_trash_path(rule: &Rule, path: &str) -> bool {
+    rule.trash.is_some() && (path == TRASH_DIR || path.starts_with(&format!("{}/", TRASH_DIR)))
+}
+
+// 列出目录时不显示的路径
+pub fn is_hidden(rule: &Rule, path: &str) -> bool {
+    rule.trash.is_some() && (path == TRASH_DIR || path == INFO_DIR)
+}
+
+// 回收站中直接存放的条目名，例如 "/.trash/20240101-120000 a.txt" ==> "20240101-120000 a.txt"
+pub fn item_name(path: &str) -> Option<&str> {
+    let name = path.strip_prefix(TRASH_DIR)?.strip_prefix('/')?;
+    if name.is_empty() || name.contains('/') || format!("{}/{}", TRASH_DIR, name) == INFO_DIR {
+        return None;
+    }
+    Some(name)
+}
+
+fn info_path(name: &str) -> String {
+    format!("{}/{}.json", INFO_DIR, name)
+}
+
+#[async_recursion]
+async fn tree_size(storage: &dyn Storage, path: &str) -> io::Result<u64> {
+    let metadata = storage.stat(path).await?;
+    if !metadata.is_dir {
+        return Ok(metadata.len);
+    }
+    let mut size = 0;
+    for entry in storage.list(path).await? {
+        size += tree_size(storage, &join_path(path, &entry.name)).await?;
+    }
+    Ok(size)
+}
+
+async fn write_info(storage: &dyn Storage, name: &str, info: &TrashInfo) -> io::Result<()> {
+    let data = Bytes::from(serde_json::to_vec(info)?);
+    let body = Box::pin(stream::once(async move { Ok(data) }));
+    storage.write(&info_path(name), body).await.map(|_| ())
+}
+
+pub async fn read_info(storage: &dyn Storage, name: &str) -> io::Result<TrashInfo> {
+    let mut body = storage.read(&info_path(name), None).await?;
+    let mut data = Vec::new();
+    while let Some(chunk) = body.next().await {
+        data.extend_from_slice(&chunk?);
+    }
+    Ok(serde_json::from_slice(&data)?)
+}
+
+// 把文件或目录移到回收站，名字前加上删除时间
+pub async fn move_to_trash(
+    storage: &dyn Storage,
+    trash: &TrashConfig,
+    user: &str,
+    path: &str,
+) -> io::Result<()> {
+    let size = tree_size(storage, path).await?;
+    storage.mkdir(INFO_DIR).await?;
+    let stamp = Local::now().format("%Y%m%d-%H%M%S").to_string();
+    let mut name = format!("{} {}", stamp, file_name(path));
+    let mut counter = 1;
+    while storage.stat(&join_path(TRASH_DIR, &name)).await.is_ok() {
+        counter += 1;
+        name = format!("{}-{} {}", stamp, counter, file_name(path));
+    }
+    let info = TrashInfo {
+        path: path.to_string(),
+        deleted_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        deleted_by: user.to_string(),
+        size,
+    };
+    storage.rename(path, &join_path(TRASH_DIR, &name)).await?;
+    // 历史版本随文件进入回收站，恢复时再随 MOVE 移回
+    if let Err(err) = versions::moved(storage, path, &join_path(TRASH_DIR, &name)).await {
+        log::error!("failed to move versions of {} to trash: {}", path, err);
+    }
+    if let Err(err) = write_info(storage, &name, &info).await {
+        log::error!("failed to record {} in trash: {}", path, err);
+    }
+    log::info!("moved {} to trash as {}", path, name);
+    if let Err(err) = purge(storage, trash).await {
+        log::error!("failed to purge trash: {}", err);
+    }
+    Ok(())
+}
+
+// 彻底删除回收站中的文件，删除 /.trash 时清空回收站
+pub async fn delete(storage: &dyn Storage, path: &str) -> io::Result<()> {
+    remove_all(storage, path).await?;
+    if let Some(name) = item_name(path) {
+        forget(storage, name).await;
+    }
+    versions::forget(storage, path).await;
+    Ok(())
+}
+
+// 文件被移出回收站或删除后去掉它的记录
+pub async fn forget(storage: &dyn Storage, name: &str) {
+    match storage.remove(&info_path(name)).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => log::error!("failed to remove trash record of {}: {}", name, err),
+    }
+}
+
+// 按保留时间和总大小清除回收站，先清除最早删除的
+pub async fn purge(storage: &dyn Storage, trash: &TrashConfig) -> io::Result<()> {
+    let max_age = parse_duration(&trash.max_age);
+    let max_size = parse_size(&trash.max_size);
+    if max_age.is_none() && max_size.is_none() {
+        return Ok(());
+    }
+    let entries = match storage.list(TRASH_DIR).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    let mut items = Vec::new();
+    for entry in entries {
+        let name = entry.name;
+        if item_name(&join_path(TRASH_DIR, &name)).is_none() {
+            continue;
+        }
+        // 没有记录的文件按修改时间和大小处理
+        let info = match read_info(storage, &name).await {
+            Ok(info) => info,
+            Err(_) => TrashInfo {
+                path: String::new(),
+                deleted_at: entry
+                    .metadata
+                    .modified
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                deleted_by: String::new(),
+                size: entry.metadata.len,
+            },
+        };
+        items.push((name, info));
+    }
+    items.sort_by_key(|(_, info)| info.deleted_at);
+    let mut total: u64 = items.iter().map(|(_, info)| info.size).sum();
+    let now = SystemTime::now();
+    for (name, info) in items {
+        let expired = max_age
+            .map(|max_age| info.deleted_time() + max_age < now)
+            .unwrap_or(false);
+        let oversize = max_size.map(|max_size| total > max_size).unwrap_or(false);
+        if !expired && !oversize {
+            continue;
+        }
+        delete(storage, &join_path(TRASH_DIR, &name)).await?;
+        total -= info.size;
+        log::info!("purged {} from trash", name);
+    }
+    Ok(())
+}
+
+// 定期清除所有 rule 的回收站，重新加载配置后使用新的设置
+pub fn spawn_purger() {
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let cfg = config::get_config();
+            let mut purged = HashSet::new();
+            for rule in cfg.rules.iter() {
+                let trash = match &rule.trash {
+                    Some(trash) => trash,
+                    None => continue,
+                };
+                if !purged.insert(rule.path.to_string()) {
+                    continue;
+                }
+                let result = match storage::for_rule(rule) {
+                    Ok(storage) => purge(storage.as_ref(), trash).await,
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = result {
+                    log::error!(
+                        "failed to purge trash of {}: {}",
+                        redact_path(&rule.path),
+                        err
+                    );
+                }
+            }
+        }
+    });
+}
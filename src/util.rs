@@ -1,20 +1,22 @@
 use std::{
-    collections::HashMap, io::{self, Error}, path::PathBuf
+    collections::HashMap, io::{self, Error}, path::Path
 };
 
 use chrono::{DateTime, Utc};
+use futures_util::TryStreamExt;
 use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
-use hyper::{body::{Bytes, Incoming}, HeaderMap, Request, StatusCode};
+use hyper::{body::{Bytes, Incoming}, HeaderMap, Request, Response, StatusCode};
 use url::Url;
 use urlencoding::{decode, encode};
 
 use crate::{
-    cache::get,
-    config::{self, Rule},
+    auth::{parse_basic_auth, verify_password},
+    config::{Config, Rule},
+    storage::{normalize_path, ByteStream},
 };
 
-pub fn get_header<'a>(
-    req: &'a Request<Incoming>,
+pub fn get_header<'a, B>(
+    req: &'a Request<B>,
     name: &'a str,
     default_value: &'a str,
 ) -> &'a str {
@@ -33,7 +35,7 @@ pub fn get_protocol(req: &Request<Incoming>) -> &str {
     result
 }
 
-pub fn get_req_path(req: &Request<Incoming>) -> String {
+pub fn get_req_path<B>(req: &Request<B>) -> String {
     let path = req.uri().path();
     decode_uri(path)
     // encode_uri(path)
@@ -47,12 +49,12 @@ pub fn encode_uri(uri: &str) -> String {
     encode(uri).to_string().replace("%2F", "/")
 }
 
-pub fn decode_path(path: &PathBuf) -> String {
+pub fn decode_path(path: &Path) -> String {
     let uri = path.to_str().unwrap();
     decode(uri).unwrap().to_string()
 }
 
-pub fn encode_path(path: &PathBuf) -> String {
+pub fn encode_path(path: &Path) -> String {
     let uri = path.to_str().unwrap();
     encode(uri).to_string().replace("%2F", "/")
 }
@@ -62,6 +64,10 @@ pub fn extract_relative_path(full_url: &str, domain: &str) -> Option<String> {
     if let Ok(url) = Url::parse(full_url) {
         // 获取 URL 的主机部分（域名）
         if let Some(host) = url.host_str() {
+            // 默认端口时 Host 头中没有端口
+            if host == domain {
+                return Some(url.path().to_string());
+            }
             if let Some(port) = url.port_or_known_default() {
                 let host_port = format!("{}:{}", host, port);
                 // 检查主机是否与给定的域名匹配
@@ -76,7 +82,29 @@ pub fn extract_relative_path(full_url: &str, domain: &str) -> Option<String> {
     None
 }
 
-pub fn get_header_value<'a>(req: &'a Request<Incoming>, header_name: &'a str) -> Option<&'a str> {
+// Destination 头对应的资源路径（相对共享根目录），不在当前 rule 下时返回 None
+pub fn get_destination_path<B>(req: &Request<B>, rule: &Rule) -> Option<String> {
+    let destination = get_header(req, "destination", "");
+    let host = get_header(req, "host", "");
+    let rel_path = decode_uri(&extract_relative_path(destination, host)?);
+    let path = rel_path.strip_prefix(rule.server_prefix.trim_end_matches('/'))?;
+    Some(normalize_path(path))
+}
+
+// 把请求体转为存储写入使用的字节流
+pub fn body_stream(req: Request<Incoming>) -> ByteStream {
+    Box::pin(req.into_body().into_data_stream().map_err(io::Error::other))
+}
+
+// 获取查询参数，例如 ?sort=size 中的 sort
+pub fn get_query_param<B>(req: &Request<B>, name: &str) -> Option<String> {
+    let query = req.uri().query()?;
+    url::form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+pub fn get_header_value<'a, B>(req: &'a Request<B>, header_name: &'a str) -> Option<&'a str> {
     // 获取HTTP请求的头部
     let headers: &HeaderMap = req.headers();
     // 使用header_name获取特定的头部值
@@ -89,34 +117,26 @@ pub fn get_header_value<'a>(req: &'a Request<Incoming>, header_name: &'a str) ->
     None
 }
 
-pub fn is_guest_by_req(req: &Request<Incoming>) -> bool {
-    if let Some(guest_server_prefix) = get("guest_server_prefix") {
-        return req.uri().to_string().starts_with(&guest_server_prefix);
+pub fn is_guest_by_req(cfg: &Config, req: &Request<Incoming>) -> bool {
+    if let Some(guest_rule) = cfg.user_rule.get("guest") {
+        return req.uri().to_string().starts_with(&guest_rule.server_prefix);
     }
     false
 }
 
-pub fn get_server_prefix(req: &Request<Incoming>) -> String {
-    if let Some(rule) = self::get_current_user_rule(req) {
-        return rule.server_prefix.to_string();
+pub async fn get_current_user_rule<'a>(
+    cfg: &'a Config,
+    req: &Request<Incoming>,
+) -> Option<&'a Rule> {
+    if self::is_guest_by_req(cfg, req) {
+        return cfg.user_rule.get("guest");
     }
-    "".to_string()
-}
-
-pub fn get_base_dir(req: &Request<Incoming>) -> String {
-    if let Some(rule) = self::get_current_user_rule(req) {
-        return rule.path.to_string();
+    let (user, password) = parse_basic_auth(get_header(req, "Authorization", ""))?;
+    let rule = cfg.user_rule.get(&user)?;
+    if !verify_password(&rule.password, &password).await {
+        return None;
     }
-    "".to_string()
-}
-
-pub fn get_current_user_rule(req: &Request<Incoming>) -> Option<&Rule> {
-    let cfg = config::get_config();
-    let mut user_key = "guest";
-    if !self::is_guest_by_req(req) {
-        user_key = get_header(req, "Authorization", "");
-    }
-    cfg.user_rule.get(user_key)
+    Some(rule)
 }
 
 // 格式化日期时间为RFC1123格式
@@ -126,6 +146,26 @@ pub fn format_date_time(dt: std::time::SystemTime) -> String {
         .to_string()
 }
 
+// 解析 "90s"、"30m"、"12h"、"30d"、"2w"，没有单位时为秒
+pub fn parse_duration(value: &str) -> Option<std::time::Duration> {
+    let value = value.trim();
+    let digits_end = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let number = value[..digits_end].parse::<u64>().ok()?;
+    let seconds: u64 = match value[digits_end..].trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    number
+        .checked_mul(seconds)
+        .map(std::time::Duration::from_secs)
+}
+
 lazy_static::lazy_static! {
     static ref ERROR_KIND_TO_STATUS_CODE: HashMap<io::ErrorKind, StatusCode> = {
         let mut m = HashMap::new();
@@ -133,6 +173,8 @@ lazy_static::lazy_static! {
         m.insert(io::ErrorKind::ConnectionRefused, StatusCode::BAD_GATEWAY);
         m.insert(io::ErrorKind::NotFound, StatusCode::NOT_FOUND);
         m.insert(io::ErrorKind::AlreadyExists, StatusCode::CONFLICT);
+        m.insert(io::ErrorKind::DirectoryNotEmpty, StatusCode::CONFLICT);
+        m.insert(io::ErrorKind::StorageFull, StatusCode::INSUFFICIENT_STORAGE);
         m
     };
 }
@@ -154,6 +196,13 @@ pub fn empty() -> BoxBody<Bytes, Error> {
     Empty::<Bytes>::new().map_err(|e| match e {}).boxed()
 }
 
+// 只有状态码、没有内容的响应
+pub fn status_response(status: StatusCode) -> Response<BoxBody<Bytes, Error>> {
+    let mut response = Response::new(empty());
+    *response.status_mut() = status;
+    response
+}
+
 pub fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, Error> {
     Full::new(chunk.into()).map_err(|e| match e {}).boxed()
 }
@@ -0,0 +1,607 @@
+use std::{collections::HashMap, env, fmt, path::Path};
+
+use crate::{
+    auth::is_hashed,
+    config::{
+        parse_log_level, unknown_env_overrides, AdminConfig, Config, EncryptionConfig, Rule,
+        SharesConfig,
+    },
+    http_methods::post::ConflictPolicy,
+    permission::{self, Permissions},
+    share,
+    storage::{check_location, crypt::read_key_file, parse_size, redact_path, Location},
+    util::parse_duration,
+};
+
+// 共享给游客时需要警告的路径
+const SENSITIVE_PATHS: [&str; 10] = [
+    "/", "/etc", "/root", "/home", "/var", "/usr", "/boot", "/proc", "/sys", "/dev",
+];
+const SENSITIVE_NAMES: [&str; 4] = [".ssh", ".gnupg", ".aws", ".kube"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConfigIssue {
+    pub severity: Severity,
+    // 出问题的字段，例如 rules[1].path
+    pub location: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: {}: {}", severity, self.location, self.message)
+    }
+}
+
+struct Issues(Vec<ConfigIssue>);
+
+impl Issues {
+    fn error(&mut self, location: &str, message: String) {
+        self.push(Severity::Error, location, message);
+    }
+
+    fn warn(&mut self, location: &str, message: String) {
+        self.push(Severity::Warning, location, message);
+    }
+
+    fn push(&mut self, severity: Severity, location: &str, message: String) {
+        self.0.push(ConfigIssue {
+            severity,
+            location: location.to_string(),
+            message,
+        });
+    }
+}
+
+pub fn has_errors(issues: &[ConfigIssue]) -> bool {
+    issues.iter().any(|issue| issue.severity == Severity::Error)
+}
+
+// 检查配置，返回发现的全部问题
+pub fn validate_config(config: &Config) -> Vec<ConfigIssue> {
+    let mut issues = Issues(Vec::new());
+    if let Err(err) = parse_log_level(&config.log) {
+        issues.error("log", err.to_string());
+    }
+    if config.port == 0 {
+        issues.warn("port", "port is 0, a random port will be used".to_string());
+    }
+    if !config.mode.is_empty() && config.mode != "dev" {
+        issues.warn(
+            "mode",
+            format!("unknown mode \"{}\", expected \"\" or \"dev\"", config.mode),
+        );
+    }
+    for name in unknown_env_overrides() {
+        issues.warn(
+            &name,
+            "environment variable matches no config field, expected RHFS_PORT, RHFS_LOG, \
+             RHFS_MODE, RHFS_ADMIN, RHFS_SHARES or RHFS_RULES"
+                .to_string(),
+        );
+    }
+    if config.rules.is_empty() {
+        issues.warn(
+            "rules",
+            "no rules, every request will be rejected".to_string(),
+        );
+    }
+    for (index, rule) in config.rules.iter().enumerate() {
+        validate_rule(&mut issues, &format!("rules[{}]", index), rule);
+    }
+    validate_duplicates(&mut issues, &config.rules);
+    validate_prefix_overlaps(&mut issues, &config.rules);
+    if let Some(admin) = &config.admin {
+        validate_admin(&mut issues, admin, config);
+    }
+    if let Some(shares) = &config.shares {
+        validate_shares(&mut issues, shares, config);
+    }
+    issues.0
+}
+
+fn validate_admin(issues: &mut Issues, admin: &AdminConfig, config: &Config) {
+    if admin.user.is_empty() || admin.password.is_empty() {
+        issues.error("admin", "admin user and password are required".to_string());
+    } else if admin.password_file.is_empty() && !is_hashed(&admin.password) {
+        issues.warn(
+            "admin.password",
+            "password is stored in plain text, use `rhfs hash-password` to hash it".to_string(),
+        );
+    }
+    if admin.port != 0 {
+        if admin.port == config.port {
+            issues.error(
+                "admin.port",
+                "admin port must differ from port, or be 0 to share it with a prefix".to_string(),
+            );
+        }
+        return;
+    }
+    if !admin.prefix.starts_with('/') || normalize_prefix(&admin.prefix) == "/" {
+        issues.error(
+            "admin.prefix",
+            "admin prefix like \"/_admin\" is required when admin port is 0".to_string(),
+        );
+        return;
+    }
+    // 管理接口优先匹配，落在管理前缀下的规则无法访问
+    let admin_prefix = normalize_prefix(&admin.prefix);
+    for (index, rule) in config.rules.iter().enumerate() {
+        let prefix = normalize_prefix(&rule.server_prefix);
+        if prefix == admin_prefix || prefix.starts_with(&format!("{}/", admin_prefix)) {
+            issues.error(
+                &format!("rules[{}].server_prefix", index),
+                format!(
+                    "server_prefix \"{}\" is shadowed by admin prefix \"{}\"",
+                    rule.server_prefix, admin.prefix
+                ),
+            );
+        }
+    }
+}
+
+fn validate_shares(issues: &mut Issues, shares: &SharesConfig, config: &Config) {
+    if shares.file.is_empty() {
+        issues.warn(
+            "shares.file",
+            "shares are kept in memory only and are lost on restart".to_string(),
+        );
+    }
+    if !shares.prefix.is_empty()
+        && (!shares.prefix.starts_with('/') || normalize_prefix(&shares.prefix) == "/")
+    {
+        issues.error(
+            "shares.prefix",
+            "shares prefix must start with \"/\" and cannot be \"/\"".to_string(),
+        );
+        return;
+    }
+    // 分享链接优先于规则匹配，落在分享前缀下的规则无法访问
+    let share_prefix = normalize_prefix(share::prefix(shares));
+    for (index, rule) in config.rules.iter().enumerate() {
+        let prefix = normalize_prefix(&rule.server_prefix);
+        if prefix == share_prefix || prefix.starts_with(&format!("{}/", share_prefix)) {
+            issues.error(
+                &format!("rules[{}].server_prefix", index),
+                format!(
+                    "server_prefix \"{}\" is shadowed by shares prefix \"{}\"",
+                    rule.server_prefix,
+                    share::prefix(shares)
+                ),
+            );
+        }
+    }
+}
+
+// 合并挂载的各个目录
+fn validate_sources(issues: &mut Issues, location: &str, rule: &Rule) {
+    let is_union = Location::parse(&rule.path).scheme == "union";
+    if !is_union {
+        if !rule.sources.is_empty() {
+            issues.warn(
+                &format!("{}.sources", location),
+                "sources are ignored unless path is \"union://name\"".to_string(),
+            );
+        }
+        return;
+    }
+    if rule.sources.is_empty() {
+        issues.error(
+            &format!("{}.sources", location),
+            "union storage needs at least one source".to_string(),
+        );
+    } else if rule.sources.iter().all(|source| source.read_only) {
+        issues.warn(
+            &format!("{}.sources", location),
+            "all sources are read-only, writes will be rejected".to_string(),
+        );
+    }
+    for (index, source) in rule.sources.iter().enumerate() {
+        let source_location = format!("{}.sources[{}].path", location, index);
+        if source.path.is_empty() {
+            issues.error(&source_location, "path is required".to_string());
+        } else if Location::parse(&source.path).scheme == "union" {
+            issues.error(
+                &source_location,
+                "union storage cannot contain another union".to_string(),
+            );
+        } else if let Err(message) = check_location(&source.path) {
+            issues.error(&source_location, message);
+        }
+    }
+}
+
+fn validate_encryption(
+    issues: &mut Issues,
+    location: &str,
+    rule: &Rule,
+    encryption: &EncryptionConfig,
+) {
+    let key_location = format!("{}.encryption.key_file", location);
+    if let Err(err) = read_key_file(&encryption.key_file) {
+        issues.error(
+            &key_location,
+            format!("cannot read key {}: {}", encryption.key_file, err),
+        );
+        return;
+    }
+    // 密钥在共享目录里时可以被下载
+    let key_path = Path::new(&encryption.key_file).canonicalize();
+    let shared = Path::new(&rule.path).canonicalize();
+    if let (Ok(key_path), Ok(shared)) = (key_path, shared) {
+        if key_path.starts_with(shared) {
+            issues.error(
+                &key_location,
+                "key file must not be inside the shared directory".to_string(),
+            );
+        }
+    }
+}
+
+fn validate_rule(issues: &mut Issues, location: &str, rule: &Rule) {
+    let path_location = format!("{}.path", location);
+    if rule.path.is_empty() {
+        issues.error(&path_location, "path is required".to_string());
+    } else if let Err(message) = check_location(&rule.path) {
+        issues.error(&path_location, message);
+    }
+    validate_sources(issues, location, rule);
+    if let Some(encryption) = &rule.encryption {
+        validate_encryption(issues, location, rule, encryption);
+    }
+    if let Some(versions) = &rule.versions {
+        if !versions.max_age.is_empty() && parse_duration(&versions.max_age).is_none() {
+            issues.error(
+                &format!("{}.versions.max_age", location),
+                format!(
+                    "invalid max_age \"{}\", expected e.g. \"12h\" or \"30d\"",
+                    versions.max_age
+                ),
+            );
+        }
+    }
+    if let Some(trash) = &rule.trash {
+        if !trash.max_age.is_empty() && parse_duration(&trash.max_age).is_none() {
+            issues.error(
+                &format!("{}.trash.max_age", location),
+                format!(
+                    "invalid max_age \"{}\", expected e.g. \"12h\" or \"30d\"",
+                    trash.max_age
+                ),
+            );
+        }
+        if !trash.max_size.is_empty() && parse_size(&trash.max_size).is_none() {
+            issues.error(
+                &format!("{}.trash.max_size", location),
+                format!(
+                    "invalid max_size \"{}\", expected e.g. \"10G\"",
+                    trash.max_size
+                ),
+            );
+        }
+    }
+    if let Some(extract) = &rule.extract {
+        if !extract.max_size.is_empty() && parse_size(&extract.max_size).is_none() {
+            issues.error(
+                &format!("{}.extract.max_size", location),
+                format!(
+                    "invalid max_size \"{}\", expected e.g. \"10G\"",
+                    extract.max_size
+                ),
+            );
+        }
+    }
+    if let Some(thumbnails) = &rule.thumbnails {
+        // 缩略图缓存在本地的临时目录中，不加密
+        if rule.encryption.is_some() {
+            issues.error(
+                &format!("{}.thumbnails", location),
+                "thumbnails cannot be used with encryption, they are cached unencrypted"
+                    .to_string(),
+            );
+        }
+        if !thumbnails.max_source_size.is_empty()
+            && parse_size(&thumbnails.max_source_size).is_none()
+        {
+            issues.error(
+                &format!("{}.thumbnails.max_source_size", location),
+                format!(
+                    "invalid max_source_size \"{}\", expected e.g. \"50M\"",
+                    thumbnails.max_source_size
+                ),
+            );
+        }
+    }
+    if ConflictPolicy::parse(&rule.upload_conflict).is_none() {
+        issues.error(
+            &format!("{}.upload_conflict", location),
+            format!(
+                "invalid upload_conflict \"{}\", expected reject, overwrite or rename",
+                rule.upload_conflict
+            ),
+        );
+    }
+
+    let prefix_location = format!("{}.server_prefix", location);
+    if !rule.server_prefix.starts_with('/') {
+        issues.error(
+            &prefix_location,
+            format!(
+                "server_prefix \"{}\" must start with \"/\"",
+                rule.server_prefix
+            ),
+        );
+    }
+
+    let permissions = match Permissions::parse(&rule.permission) {
+        Ok(permissions) => permissions,
+        Err(unknown) => {
+            issues.error(
+                &format!("{}.permission", location),
+                format!(
+                    "unknown permission \"{}\", expected letters of \"{}\" or names: {}",
+                    unknown,
+                    permission::LETTERS,
+                    Permissions::all_names()
+                ),
+            );
+            Permissions::NONE
+        }
+    };
+    if permissions.contains(Permissions::DROP_BOX) && permissions != Permissions::DROP_BOX {
+        issues.warn(
+            &format!("{}.permission", location),
+            "\"U\" (drop-box) makes the rule an upload-only drop box, other permissions are ignored"
+                .to_string(),
+        );
+    }
+
+    let is_guest = rule.user.is_empty() && rule.password.is_empty();
+    if rule.user.is_empty() != rule.password.is_empty() {
+        issues.error(
+            location,
+            "user and password must be set together, otherwise the rule is treated as guest"
+                .to_string(),
+        );
+    } else if rule.user == "guest" {
+        issues.error(
+            &format!("{}.user", location),
+            "\"guest\" is reserved for rules without user and password".to_string(),
+        );
+    } else if !is_guest && rule.password_file.is_empty() && !is_hashed(&rule.password) {
+        issues.warn(
+            &format!("{}.password", location),
+            "password is stored in plain text, use `rhfs hash-password` to hash it".to_string(),
+        );
+    }
+
+    if is_guest {
+        let modify = Permissions::CREATE
+            | Permissions::OVERWRITE
+            | Permissions::MOVE
+            | Permissions::DELETE
+            | Permissions::SET_PROPERTIES;
+        if permissions.contains(Permissions::DROP_BOX) {
+            issues.warn(
+                &format!("{}.permission", location),
+                format!("guests can upload to {}", redact_path(&rule.path)),
+            );
+        } else if permissions.intersects(modify) {
+            issues.warn(
+                &format!("{}.permission", location),
+                format!("guests can modify {}", redact_path(&rule.path)),
+            );
+        }
+        if let Some(sensitive) = sensitive_path(&rule.path) {
+            issues.warn(
+                &path_location,
+                format!(
+                    "{} is shared to guests and exposes {}",
+                    rule.path, sensitive
+                ),
+            );
+        }
+    }
+}
+
+fn validate_duplicates(issues: &mut Issues, rules: &[Rule]) {
+    let mut prefixes: HashMap<String, usize> = HashMap::new();
+    let mut users: HashMap<&str, usize> = HashMap::new();
+    let mut guest: Option<usize> = None;
+    for (index, rule) in rules.iter().enumerate() {
+        let prefix = normalize_prefix(&rule.server_prefix);
+        if let Some(first) = prefixes.get(&prefix) {
+            issues.error(
+                &format!("rules[{}].server_prefix", index),
+                format!(
+                    "server_prefix \"{}\" is already used by rules[{}]",
+                    rule.server_prefix, first
+                ),
+            );
+        } else {
+            prefixes.insert(prefix, index);
+        }
+        if rule.user.is_empty() || rule.password.is_empty() {
+            if let Some(first) = guest {
+                issues.error(
+                    &format!("rules[{}]", index),
+                    format!(
+                        "only one guest rule is allowed, rules[{}] is already a guest rule",
+                        first
+                    ),
+                );
+            } else {
+                guest = Some(index);
+            }
+        } else if let Some(first) = users.get(rule.user.as_str()) {
+            issues.error(
+                &format!("rules[{}].user", index),
+                format!(
+                    "user \"{}\" is already defined in rules[{}]",
+                    rule.user, first
+                ),
+            );
+        } else {
+            users.insert(&rule.user, index);
+        }
+    }
+}
+
+// 游客按前缀匹配请求路径，前缀重叠时请求可能落到意料之外的规则
+fn validate_prefix_overlaps(issues: &mut Issues, rules: &[Rule]) {
+    for (i, a) in rules.iter().enumerate() {
+        for (j, b) in rules.iter().enumerate() {
+            let prefix_a = normalize_prefix(&a.server_prefix);
+            let prefix_b = normalize_prefix(&b.server_prefix);
+            // 按路径段比较，"/a" 和 "/ab" 不算重叠
+            let overlaps = prefix_a == "/" || prefix_b.starts_with(&format!("{}/", prefix_a));
+            if i == j || prefix_a == prefix_b || !overlaps {
+                continue;
+            }
+            issues.warn(
+                &format!("rules[{}].server_prefix", j),
+                format!(
+                    "server_prefix \"{}\" overlaps with \"{}\" of rules[{}]",
+                    b.server_prefix, a.server_prefix, i
+                ),
+            );
+        }
+    }
+}
+
+fn normalize_prefix(prefix: &str) -> String {
+    let trimmed = prefix.trim_end_matches('/');
+    if trimmed.is_empty() {
+        return "/".to_string();
+    }
+    trimmed.to_string()
+}
+
+// 返回共享目录所暴露的敏感路径
+fn sensitive_path(path: &str) -> Option<String> {
+    let shared = Path::new(path).canonicalize().ok()?;
+    let mut sensitive: Vec<String> = SENSITIVE_PATHS.iter().map(|p| p.to_string()).collect();
+    if let Ok(home) = env::var("HOME") {
+        sensitive.push(home);
+    }
+    for candidate in sensitive {
+        if Path::new(&candidate).starts_with(&shared) {
+            return Some(candidate);
+        }
+    }
+    for name in SENSITIVE_NAMES {
+        let candidate = shared.join(name);
+        if candidate.exists() {
+            return Some(candidate.to_string_lossy().to_string());
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ThumbnailsConfig;
+
+    fn rule(user: &str, server_prefix: &str) -> Rule {
+        Rule {
+            path: format!("memory://{}", user),
+            user: user.to_string(),
+            password: "pw".to_string(),
+            password_file: "/dev/null".to_string(),
+            server_prefix: server_prefix.to_string(),
+            ..Rule::default()
+        }
+    }
+
+    fn config(rules: Vec<Rule>) -> Config {
+        Config {
+            port: 8080,
+            rules,
+            ..Config::default()
+        }
+    }
+
+    fn locations(issues: &[ConfigIssue], severity: Severity) -> Vec<&str> {
+        issues
+            .iter()
+            .filter(|issue| issue.severity == severity)
+            .map(|issue| issue.location.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn prefix_overlap_on_segment_boundary() {
+        let issues = validate_config(&config(vec![
+            rule("a", "/a"),
+            rule("b", "/ab"),
+            rule("c", "/a/c/"),
+        ]));
+        assert_eq!(
+            locations(&issues, Severity::Warning),
+            vec!["rules[2].server_prefix"]
+        );
+        assert!(!has_errors(&issues));
+    }
+
+    #[test]
+    fn root_prefix_overlaps_everything() {
+        let issues = validate_config(&config(vec![rule("a", "/"), rule("b", "/b")]));
+        assert_eq!(
+            locations(&issues, Severity::Warning),
+            vec!["rules[1].server_prefix"]
+        );
+    }
+
+    #[test]
+    fn duplicate_prefixes_and_users_are_errors() {
+        let issues = validate_config(&config(vec![
+            rule("a", "/a"),
+            rule("a", "/b"),
+            rule("c", "/a/"),
+        ]));
+        assert_eq!(
+            locations(&issues, Severity::Error),
+            vec!["rules[1].user", "rules[2].server_prefix"]
+        );
+    }
+
+    #[test]
+    fn invalid_rule_fields_are_errors() {
+        let mut invalid = rule("a", "a");
+        invalid.permission = "RX".to_string();
+        invalid.upload_conflict = "skip".to_string();
+        let issues = validate_config(&config(vec![invalid]));
+        assert_eq!(
+            locations(&issues, Severity::Error),
+            vec![
+                "rules[0].upload_conflict",
+                "rules[0].server_prefix",
+                "rules[0].permission"
+            ]
+        );
+    }
+
+    #[test]
+    fn thumbnails_with_encryption_is_an_error() {
+        let mut encrypted = rule("a", "/a");
+        encrypted.thumbnails = Some(ThumbnailsConfig::default());
+        assert!(!has_errors(&validate_config(&config(vec![
+            encrypted.clone()
+        ]))));
+        encrypted.encryption = Some(EncryptionConfig::default());
+        let issues = validate_config(&config(vec![encrypted]));
+        assert!(locations(&issues, Severity::Error).contains(&"rules[0].thumbnails"));
+    }
+}
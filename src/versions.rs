@@ -0,0 +1,491 @@
+use std::{
+    collections::HashSet,
+    io,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use async_recursion::async_recursion;
+
+use crate::{
+    config::{self, Rule, VersionsConfig},
+    storage::{self, file_name, join_path, parent_path, redact_path, remove_all, Entry, Storage},
+    util::parse_duration,
+};
+
+// 文件 /a/b.txt 的历史版本保存在 /.versions/a/b.txt/ 下，依次为 1.txt、2.txt ...
+pub const VERSIONS_DIR: &str = "/.versions";
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// rule 启用了历史版本并且 path 在版本目录中
+pub fn is_versions_path(rule: &Rule, path: &str) -> bool {
+    rule.versions.is_some()
+        && (path == VERSIONS_DIR || path.starts_with(&format!("{}/", VERSIONS_DIR)))
+}
+
+// 列出目录时不显示的路径
+pub fn is_hidden(rule: &Rule, path: &str) -> bool {
+    if !is_versions_path(rule, path) {
+        return false;
+    }
+    let name = file_name(path);
+    path == VERSIONS_DIR || name == CONTROLLED_MARKER || name == CHECKED_OUT_MARKER
+}
+
+// 保存 path 历史版本的目录
+pub fn versions_dir(path: &str) -> String {
+    format!("{}{}", VERSIONS_DIR, path)
+}
+
+fn version_number(name: &str) -> Option<u64> {
+    name.split('.').next()?.parse().ok()
+}
+
+// 版本文件保留原文件的扩展名，下载时使用相同的类型
+fn version_name(number: u64, path: &str) -> String {
+    match Path::new(file_name(path)).extension() {
+        Some(extension) => format!("{}.{}", number, extension.to_string_lossy()),
+        None => number.to_string(),
+    }
+}
+
+// 受 DeltaV 版本控制的文件在版本目录中有这个标记，最新版本总是与当前内容一致
+const CONTROLLED_MARKER: &str = ".version-controlled";
+// CHECKOUT 之后、CHECKIN 之前的标记
+const CHECKED_OUT_MARKER: &str = ".checked-out";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    // 没有执行过 VERSION-CONTROL，覆盖时只保存旧内容
+    Unversioned,
+    CheckedIn,
+    CheckedOut,
+}
+
+async fn exists(storage: &dyn Storage, path: &str) -> io::Result<bool> {
+    match storage.stat(path).await {
+        Ok(_) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+async fn touch(storage: &dyn Storage, path: &str) -> io::Result<()> {
+    storage
+        .write(path, Box::pin(futures_util::stream::empty()))
+        .await
+        .map(|_| ())
+}
+
+pub async fn state(storage: &dyn Storage, path: &str) -> io::Result<State> {
+    let dir = versions_dir(path);
+    if !exists(storage, &join_path(&dir, CONTROLLED_MARKER)).await? {
+        return Ok(State::Unversioned);
+    }
+    if exists(storage, &join_path(&dir, CHECKED_OUT_MARKER)).await? {
+        return Ok(State::CheckedOut);
+    }
+    Ok(State::CheckedIn)
+}
+
+// path 的全部版本，按编号从小到大排列
+pub async fn list(storage: &dyn Storage, path: &str) -> io::Result<Vec<(u64, Entry)>> {
+    let entries = match storage.list(&versions_dir(path)).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let mut versions: Vec<_> = entries
+        .into_iter()
+        .filter(|entry| !entry.metadata.is_dir)
+        .filter_map(|entry| version_number(&entry.name).map(|number| (number, entry)))
+        .collect();
+    versions.sort_by_key(|(number, _)| *number);
+    Ok(versions)
+}
+
+// 把 path 的当前内容保存为新版本，返回版本的路径。
+// skip_unchanged 时最新版本在文件最后修改之后保存且大小相同，则认为内容未变，不再保存
+async fn save_version(
+    storage: &dyn Storage,
+    path: &str,
+    skip_unchanged: bool,
+) -> io::Result<Option<String>> {
+    let metadata = match storage.stat(path).await {
+        Ok(metadata) if !metadata.is_dir => metadata,
+        Ok(_) => return Ok(None),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let dir = versions_dir(path);
+    storage.mkdir(&dir).await?;
+    let versions = list(storage, path).await?;
+    if let Some((_, latest)) = versions.last() {
+        if skip_unchanged
+            && latest.metadata.modified > metadata.modified
+            && latest.metadata.len == metadata.len
+        {
+            return Ok(None);
+        }
+    }
+    let last = versions.last().map(|(number, _)| *number).unwrap_or(0);
+    let version_path = join_path(&dir, &version_name(last + 1, path));
+    storage.copy(path, &version_path).await?;
+    log::info!("saved {} as {}", path, version_path);
+    Ok(Some(version_path))
+}
+
+// 覆盖 path 之前调用，返回写入后是否要把新内容保存为版本
+pub async fn before_write(
+    storage: &dyn Storage,
+    versions: &VersionsConfig,
+    path: &str,
+) -> io::Result<bool> {
+    match state(storage, path).await? {
+        State::CheckedOut => Ok(false),
+        State::CheckedIn if !versions.auto_version => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is checked in, CHECKOUT it first", path),
+        )),
+        // 相当于自动 CHECKOUT 再 CHECKIN，文件在外部被修改过时先保存
+        State::CheckedIn => {
+            save_version(storage, path, true).await?;
+            Ok(true)
+        }
+        State::Unversioned => {
+            if versions.auto_version {
+                save_version(storage, path, false).await?;
+            }
+            Ok(false)
+        }
+    }
+}
+
+// 写入完成后调用，再清除旧版本，恢复时复制的源文件可能正是最早的版本
+pub async fn after_write(
+    storage: &dyn Storage,
+    versions: &VersionsConfig,
+    path: &str,
+    save_new: bool,
+) {
+    if save_new {
+        if let Err(err) = save_version(storage, path, false).await {
+            log::error!("failed to save version of {}: {}", path, err);
+        }
+    }
+    prune(storage, versions, path).await;
+}
+
+// MOVE 成功后调用，历史版本跟随文件或目录移动。
+// 目标文件原有的历史版本保留，移动过来的版本接着编号
+pub async fn moved(storage: &dyn Storage, from: &str, to: &str) -> io::Result<()> {
+    let from_dir = versions_dir(from);
+    let to_dir = versions_dir(to);
+    if !exists(storage, &from_dir).await? {
+        return Ok(());
+    }
+    let is_dir = storage.stat(to).await?.is_dir;
+    if is_dir || !exists(storage, &to_dir).await? {
+        // 目录只能移动到不存在或空的目录，原有的记录属于已经不存在的文件
+        if is_dir && exists(storage, &to_dir).await? {
+            remove_all(storage, &to_dir).await?;
+        }
+        storage.mkdir(&parent_path(&to_dir)).await?;
+        return storage.rename(&from_dir, &to_dir).await;
+    }
+    let last = list(storage, to)
+        .await?
+        .last()
+        .map(|(number, _)| *number)
+        .unwrap_or(0);
+    for (index, (_, entry)) in list(storage, from).await?.into_iter().enumerate() {
+        let name = version_name(last + 1 + index as u64, to);
+        storage
+            .rename(
+                &join_path(&from_dir, &entry.name),
+                &join_path(&to_dir, &name),
+            )
+            .await?;
+    }
+    for marker in [CONTROLLED_MARKER, CHECKED_OUT_MARKER] {
+        let path = join_path(&from_dir, marker);
+        if exists(storage, &path).await? {
+            storage.rename(&path, &join_path(&to_dir, marker)).await?;
+        }
+    }
+    remove_all(storage, &from_dir).await
+}
+
+// 文件或目录被彻底删除后去掉它的历史版本
+pub async fn forget(storage: &dyn Storage, path: &str) {
+    match remove_all(storage, &versions_dir(path)).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => log::error!("failed to remove versions of {}: {}", path, err),
+    }
+}
+
+// VERSION-CONTROL：开始对 path 进行版本控制，当前内容作为最新版本
+pub async fn version_control(storage: &dyn Storage, path: &str) -> io::Result<()> {
+    if state(storage, path).await? != State::Unversioned {
+        return Ok(());
+    }
+    save_version(storage, path, true).await?;
+    touch(storage, &join_path(&versions_dir(path), CONTROLLED_MARKER)).await
+}
+
+pub async fn checkout(storage: &dyn Storage, path: &str) -> io::Result<()> {
+    touch(storage, &join_path(&versions_dir(path), CHECKED_OUT_MARKER)).await
+}
+
+// CHECKIN：保存当前内容为新版本，返回版本的路径
+pub async fn checkin(
+    storage: &dyn Storage,
+    versions: &VersionsConfig,
+    path: &str,
+) -> io::Result<String> {
+    let version_path = save_version(storage, path, false)
+        .await?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path)))?;
+    storage
+        .remove(&join_path(&versions_dir(path), CHECKED_OUT_MARKER))
+        .await?;
+    prune(storage, versions, path).await;
+    Ok(version_path)
+}
+
+// UNCHECKOUT：放弃修改，恢复为最新版本的内容
+pub async fn uncheckout(storage: &dyn Storage, path: &str) -> io::Result<()> {
+    if let Some((_, latest)) = list(storage, path).await?.last() {
+        storage
+            .copy(&join_path(&versions_dir(path), &latest.name), path)
+            .await?;
+    }
+    storage
+        .remove(&join_path(&versions_dir(path), CHECKED_OUT_MARKER))
+        .await
+}
+
+// 清除 path 超出数量或过期的版本
+pub async fn prune(storage: &dyn Storage, versions: &VersionsConfig, path: &str) {
+    let dir = versions_dir(path);
+    match prune_dir(storage, versions, &dir).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => log::error!("failed to prune versions of {}: {}", path, err),
+    }
+}
+
+// 按数量和保存时间清除一个文件的旧版本，编号最大的为最新版本
+async fn prune_dir(storage: &dyn Storage, versions: &VersionsConfig, dir: &str) -> io::Result<()> {
+    let max_age = parse_duration(&versions.max_age);
+    let mut entries: Vec<_> = storage
+        .list(dir)
+        .await?
+        .into_iter()
+        .filter(|entry| !entry.metadata.is_dir)
+        .filter_map(|entry| version_number(&entry.name).map(|number| (number, entry)))
+        .collect();
+    entries.sort_by_key(|(number, _)| std::cmp::Reverse(*number));
+    let now = SystemTime::now();
+    for (index, (_, entry)) in entries.into_iter().enumerate() {
+        let too_many = versions.max_count > 0 && index >= versions.max_count as usize;
+        let expired = max_age
+            .map(|max_age| entry.metadata.modified + max_age < now)
+            .unwrap_or(false);
+        if too_many || expired {
+            let path = join_path(dir, &entry.name);
+            storage.remove(&path).await?;
+            log::info!("pruned version {}", path);
+        }
+    }
+    Ok(())
+}
+
+#[async_recursion]
+async fn prune_tree(storage: &dyn Storage, versions: &VersionsConfig, dir: &str) -> io::Result<()> {
+    let entries = match storage.list(dir).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    if entries.iter().any(|entry| !entry.metadata.is_dir) {
+        prune_dir(storage, versions, dir).await?;
+    }
+    for entry in entries.iter().filter(|entry| entry.metadata.is_dir) {
+        prune_tree(storage, versions, &join_path(dir, &entry.name)).await?;
+    }
+    Ok(())
+}
+
+// 定期按保存时间清除所有 rule 的历史版本
+pub fn spawn_pruner() {
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let cfg = config::get_config();
+            let mut pruned = HashSet::new();
+            for rule in cfg.rules.iter() {
+                let versions = match &rule.versions {
+                    Some(versions) if !versions.max_age.is_empty() => versions,
+                    _ => continue,
+                };
+                if !pruned.insert(rule.path.to_string()) {
+                    continue;
+                }
+                let result = match storage::for_rule(rule) {
+                    Ok(storage) => prune_tree(storage.as_ref(), versions, VERSIONS_DIR).await,
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = result {
+                    log::error!(
+                        "failed to prune versions of {}: {}",
+                        redact_path(&rule.path),
+                        err
+                    );
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{stream, TryStreamExt};
+    use hyper::body::Bytes;
+
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    fn config(max_count: u32, max_age: &str) -> VersionsConfig {
+        VersionsConfig {
+            max_count,
+            max_age: max_age.to_string(),
+            ..VersionsConfig::default()
+        }
+    }
+
+    async fn read(storage: &dyn Storage, path: &str) -> String {
+        let chunks: Vec<Bytes> = storage
+            .read(path, None)
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        String::from_utf8(chunks.concat()).unwrap()
+    }
+
+    // 与 PUT 一样在覆盖前后调用 before_write 和 after_write
+    async fn put(storage: &dyn Storage, versions: &VersionsConfig, path: &str, data: &str) {
+        let save_new = before_write(storage, versions, path).await.unwrap();
+        let body = Box::pin(stream::iter([Ok(Bytes::from(data.to_string()))]));
+        storage.write(path, body).await.unwrap();
+        after_write(storage, versions, path, save_new).await;
+    }
+
+    async fn names(storage: &dyn Storage, path: &str) -> Vec<String> {
+        list(storage, path)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(_, entry)| entry.name)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn overwrite_saves_numbered_versions() {
+        let storage = MemoryStorage::new(u64::MAX);
+        let versions = config(0, "");
+        storage.mkdir("/docs").await.unwrap();
+        for data in ["one", "two", "three"] {
+            put(&storage, &versions, "/docs/a.txt", data).await;
+        }
+        assert_eq!(names(&storage, "/docs/a.txt").await, vec!["1.txt", "2.txt"]);
+        assert_eq!(read(&storage, "/.versions/docs/a.txt/1.txt").await, "one");
+        assert_eq!(read(&storage, "/.versions/docs/a.txt/2.txt").await, "two");
+        assert_eq!(read(&storage, "/docs/a.txt").await, "three");
+    }
+
+    #[tokio::test]
+    async fn prune_keeps_the_newest_max_count() {
+        let storage = MemoryStorage::new(u64::MAX);
+        let versions = config(2, "");
+        for data in ["1", "2", "3", "4", "5"] {
+            put(&storage, &versions, "/a", data).await;
+        }
+        assert_eq!(names(&storage, "/a").await, vec!["3", "4"]);
+        assert_eq!(read(&storage, "/.versions/a/4").await, "4");
+    }
+
+    #[tokio::test]
+    async fn prune_removes_expired_versions() {
+        let storage = MemoryStorage::new(u64::MAX);
+        let versions = config(0, "1s");
+        put(&storage, &versions, "/a", "old").await;
+        put(&storage, &versions, "/a", "new").await;
+        assert_eq!(names(&storage, "/a").await, vec!["1"]);
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        prune(&storage, &versions, "/a").await;
+        assert!(names(&storage, "/a").await.is_empty());
+        assert_eq!(read(&storage, "/a").await, "new");
+    }
+
+    #[tokio::test]
+    async fn copy_from_versions_restores() {
+        let storage = MemoryStorage::new(u64::MAX);
+        let versions = config(0, "");
+        put(&storage, &versions, "/a.txt", "first").await;
+        put(&storage, &versions, "/a.txt", "second").await;
+        // 与 COPY 的 copy_file 相同的步骤
+        let save_new = before_write(&storage, &versions, "/a.txt").await.unwrap();
+        storage
+            .copy("/.versions/a.txt/1.txt", "/a.txt")
+            .await
+            .unwrap();
+        after_write(&storage, &versions, "/a.txt", save_new).await;
+        assert_eq!(read(&storage, "/a.txt").await, "first");
+        assert_eq!(names(&storage, "/a.txt").await, vec!["1.txt", "2.txt"]);
+        assert_eq!(read(&storage, "/.versions/a.txt/2.txt").await, "second");
+    }
+
+    #[tokio::test]
+    async fn history_follows_moves() {
+        let storage = MemoryStorage::new(u64::MAX);
+        let versions = config(0, "");
+        put(&storage, &versions, "/a.txt", "a1").await;
+        put(&storage, &versions, "/a.txt", "a2").await;
+        storage.mkdir("/dir").await.unwrap();
+        storage.rename("/a.txt", "/dir/b.txt").await.unwrap();
+        moved(&storage, "/a.txt", "/dir/b.txt").await.unwrap();
+        assert!(names(&storage, "/a.txt").await.is_empty());
+        assert_eq!(names(&storage, "/dir/b.txt").await, vec!["1.txt"]);
+
+        // 目标已有历史版本时接着编号
+        put(&storage, &versions, "/c.txt", "c1").await;
+        put(&storage, &versions, "/c.txt", "c2").await;
+        before_write(&storage, &versions, "/c.txt").await.unwrap();
+        storage.rename("/dir/b.txt", "/c.txt").await.unwrap();
+        moved(&storage, "/dir/b.txt", "/c.txt").await.unwrap();
+        assert_eq!(
+            names(&storage, "/c.txt").await,
+            vec!["1.txt", "2.txt", "3.txt"]
+        );
+        assert_eq!(read(&storage, "/.versions/c.txt/1.txt").await, "c1");
+        assert_eq!(read(&storage, "/.versions/c.txt/2.txt").await, "c2");
+        assert_eq!(read(&storage, "/.versions/c.txt/3.txt").await, "a1");
+        assert!(storage.stat("/.versions/dir/b.txt").await.is_err());
+
+        // 目录移动时其中文件的历史版本一起移动
+        storage.rename("/dir", "/moved").await.unwrap();
+        put(&storage, &versions, "/moved/d", "d1").await;
+        put(&storage, &versions, "/moved/d", "d2").await;
+        storage.rename("/moved", "/dir").await.unwrap();
+        moved(&storage, "/moved", "/dir").await.unwrap();
+        assert_eq!(names(&storage, "/dir/d").await, vec!["1"]);
+
+        forget(&storage, "/dir").await;
+        assert!(storage.stat("/.versions/dir").await.is_err());
+    }
+}
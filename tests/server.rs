@@ -0,0 +1,505 @@
+// 启动真实的 HTTP 服务，在内存存储上走完整的请求处理流程
+use std::{
+    io::{Cursor, Write},
+    net::SocketAddr,
+    sync::Once,
+};
+
+use hyper::{server::conn::http1, service::service_fn};
+use hyper_util::rt::TokioIo;
+use reqwest::{header::HeaderMap, Method, StatusCode};
+use rhfs::{
+    config::{self, Config},
+    server::handle_request,
+};
+use tokio::net::TcpListener;
+
+static CONFIG: Once = Once::new();
+
+// 所有测试共用一份全局配置，每个测试使用自己的用户和内存存储
+fn init_config() {
+    CONFIG.call_once(|| {
+        let mut cfg: Config = serde_json::from_str(
+            r#"{
+                "rules": [
+                    {"path": "memory://it-files", "user": "files", "password": "pw",
+                     "permission": "RWD", "server_prefix": "/files"},
+                    {"path": "memory://it-trash", "user": "trash", "password": "pw",
+                     "permission": "RWD", "server_prefix": "/trash", "trash": {}},
+                    {"path": "memory://it-versions", "user": "versions", "password": "pw",
+                     "permission": "RWD", "server_prefix": "/versions", "versions": {}},
+                    {"path": "memory://it-drop", "user": "drop", "password": "pw",
+                     "permission": "U", "server_prefix": "/drop"},
+                    {"path": "memory://it-drop", "user": "owner", "password": "pw",
+                     "permission": "RWD", "server_prefix": "/owner"},
+                    {"path": "memory://it-history", "user": "history", "password": "pw",
+                     "permission": "RWD", "server_prefix": "/history",
+                     "trash": {}, "versions": {}},
+                    {"path": "memory://it-archives", "user": "archives", "password": "pw",
+                     "permission": "RWD", "server_prefix": "/archives", "browse_archives": true},
+                    {"path": "memory://it-sharer", "user": "sharer", "password": "pw",
+                     "permission": "RWD", "server_prefix": "/sharer"}
+                ],
+                "shares": {"prefix": "/s"}
+            }"#,
+        )
+        .unwrap();
+        config::init_user(&mut cfg);
+        config::set_config(cfg);
+    });
+}
+
+// 在随机端口上启动服务，返回地址
+async fn start() -> SocketAddr {
+    init_config();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, remote_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(_) => return,
+            };
+            tokio::spawn(async move {
+                let _ = http1::Builder::new()
+                    .serve_connection(
+                        TokioIo::new(stream),
+                        service_fn(move |req| handle_request(req, remote_addr)),
+                    )
+                    .await;
+            });
+        }
+    });
+    addr
+}
+
+struct Client {
+    addr: SocketAddr,
+    user: &'static str,
+    client: reqwest::Client,
+}
+
+impl Client {
+    async fn new(user: &'static str) -> Client {
+        Client {
+            addr: start().await,
+            user,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("http://{}/{}{}", self.addr, self.user, path)
+    }
+
+    fn request(&self, method: &str, path: &str) -> reqwest::RequestBuilder {
+        self.client
+            .request(
+                Method::from_bytes(method.as_bytes()).unwrap(),
+                self.url(path),
+            )
+            .basic_auth(self.user, Some("pw"))
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder) -> (StatusCode, HeaderMap, String) {
+        let response = request.send().await.unwrap();
+        let status = response.status();
+        let headers = response.headers().clone();
+        (status, headers, response.text().await.unwrap())
+    }
+
+    async fn status(&self, method: &str, path: &str) -> StatusCode {
+        self.send(self.request(method, path)).await.0
+    }
+
+    async fn put(&self, path: &str, body: impl Into<reqwest::Body>) -> StatusCode {
+        self.send(self.request("PUT", path).body(body)).await.0
+    }
+
+    async fn get(&self, path: &str) -> (StatusCode, String) {
+        let (status, _, body) = self.send(self.request("GET", path)).await;
+        (status, body)
+    }
+
+    async fn propfind(&self, path: &str) -> (StatusCode, String) {
+        let (status, _, body) = self
+            .send(self.request("PROPFIND", path).header("Depth", "1"))
+            .await;
+        (status, body)
+    }
+
+    // MOVE 或 COPY 到同一个用户下的 destination
+    async fn transfer(&self, method: &str, from: &str, to: &str) -> StatusCode {
+        let request = self
+            .request(method, from)
+            .header("Destination", self.url(to));
+        self.send(request).await.0
+    }
+}
+
+// PROPFIND 响应中的 href，去掉用户前缀后 url 解码
+fn hrefs(xml: &str, user: &str) -> Vec<String> {
+    xml.split("<D:href>")
+        .skip(1)
+        .filter_map(|part| part.split("</D:href>").next())
+        .map(|href| {
+            let href = href.strip_prefix(&format!("/{}", user)).unwrap_or(href);
+            urlencoding::decode(href).unwrap().to_string()
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn put_get_propfind_move_copy_delete() {
+    let client = Client::new("files").await;
+    assert_eq!(client.status("MKCOL", "/docs").await, StatusCode::CREATED);
+    assert_eq!(
+        client.put("/docs/a.txt", "hello").await,
+        StatusCode::CREATED
+    );
+    assert_eq!(
+        client.get("/docs/a.txt").await,
+        (StatusCode::OK, "hello".to_string())
+    );
+
+    let (status, xml) = client.propfind("/docs/").await;
+    assert_eq!(status, StatusCode::MULTI_STATUS);
+    assert!(hrefs(&xml, "files").contains(&"/docs/a.txt".to_string()));
+
+    assert_eq!(
+        client.transfer("COPY", "/docs/a.txt", "/docs/b.txt").await,
+        StatusCode::CREATED
+    );
+    assert_eq!(
+        client.transfer("MOVE", "/docs/b.txt", "/c.txt").await,
+        StatusCode::CREATED
+    );
+    assert_eq!(
+        client.get("/c.txt").await,
+        (StatusCode::OK, "hello".to_string())
+    );
+    assert_eq!(client.get("/docs/b.txt").await.0, StatusCode::NOT_FOUND);
+
+    // 没有回收站时只能删除文件和空目录
+    assert_eq!(client.status("DELETE", "/docs").await, StatusCode::CONFLICT);
+    assert_eq!(
+        client.status("DELETE", "/docs/a.txt").await,
+        StatusCode::NO_CONTENT
+    );
+    assert_eq!(client.get("/docs/a.txt").await.0, StatusCode::NOT_FOUND);
+    assert_eq!(
+        client.status("DELETE", "/docs").await,
+        StatusCode::NO_CONTENT
+    );
+    assert_eq!(client.get("/c.txt").await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn delete_to_trash_and_restore() {
+    let client = Client::new("trash").await;
+    assert_eq!(client.put("/report.txt", "v1").await, StatusCode::CREATED);
+    assert_eq!(
+        client.status("DELETE", "/report.txt").await,
+        StatusCode::NO_CONTENT
+    );
+    assert_eq!(client.get("/report.txt").await.0, StatusCode::NOT_FOUND);
+
+    let (status, xml) = client.propfind("/.trash/").await;
+    assert_eq!(status, StatusCode::MULTI_STATUS);
+    let item = hrefs(&xml, "trash")
+        .into_iter()
+        .find(|href| href.starts_with("/.trash/") && href.ends_with("report.txt"))
+        .expect("deleted file is listed in the trash");
+
+    assert_eq!(
+        client.transfer("MOVE", &item, "/report.txt").await,
+        StatusCode::CREATED
+    );
+    assert_eq!(
+        client.get("/report.txt").await,
+        (StatusCode::OK, "v1".to_string())
+    );
+    let (_, xml) = client.propfind("/.trash/").await;
+    assert!(!hrefs(&xml, "trash").contains(&item));
+}
+
+#[tokio::test]
+async fn overwrite_keeps_versions_and_copy_restores() {
+    let client = Client::new("versions").await;
+    assert_eq!(client.put("/doc.txt", "first").await, StatusCode::CREATED);
+    assert!(client.put("/doc.txt", "second").await.is_success());
+    assert_eq!(client.get("/doc.txt").await.1, "second");
+
+    let (status, xml) = client.propfind("/.versions/doc.txt/").await;
+    assert_eq!(status, StatusCode::MULTI_STATUS);
+    assert!(hrefs(&xml, "versions").contains(&"/.versions/doc.txt/1.txt".to_string()));
+    assert_eq!(
+        client.get("/.versions/doc.txt/1.txt").await,
+        (StatusCode::OK, "first".to_string())
+    );
+    // 历史版本不能修改
+    assert_eq!(
+        client.put("/.versions/doc.txt/1.txt", "x").await,
+        StatusCode::FORBIDDEN
+    );
+
+    assert!(client
+        .transfer("COPY", "/.versions/doc.txt/1.txt", "/doc.txt")
+        .await
+        .is_success());
+    assert_eq!(client.get("/doc.txt").await.1, "first");
+    // 恢复前的内容成为新的版本
+    assert_eq!(client.get("/.versions/doc.txt/2.txt").await.1, "second");
+}
+
+#[tokio::test]
+async fn propfind_lists_version_state_only_when_asked() {
+    let client = Client::new("versions").await;
+    assert_eq!(client.status("MKCOL", "/state").await, StatusCode::CREATED);
+    client.put("/state/doc.txt", "first").await;
+    assert_eq!(
+        client.status("VERSION-CONTROL", "/state/doc.txt").await,
+        StatusCode::OK
+    );
+
+    let (_, xml) = client.propfind("/state/").await;
+    assert!(!xml.contains("checked-in"));
+    let body = r#"<?xml version="1.0"?><D:propfind xmlns:D="DAV:"><D:prop><D:checked-in/></D:prop></D:propfind>"#;
+    let request = client
+        .request("PROPFIND", "/state/")
+        .header("Depth", "1")
+        .body(body);
+    assert!(client.send(request).await.2.contains("<D:checked-in>"));
+    let request = client
+        .request("PROPFIND", "/state/doc.txt")
+        .header("Depth", "0");
+    assert!(client.send(request).await.2.contains("<D:checked-in>"));
+}
+
+#[tokio::test]
+async fn history_follows_move_delete_and_restore() {
+    let client = Client::new("history").await;
+    assert_eq!(client.put("/a.txt", "one").await, StatusCode::CREATED);
+    assert!(client.put("/a.txt", "two").await.is_success());
+    assert_eq!(
+        client.transfer("MOVE", "/a.txt", "/b.txt").await,
+        StatusCode::CREATED
+    );
+    assert_eq!(
+        client.get("/.versions/a.txt/1.txt").await.0,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(client.get("/.versions/b.txt/1.txt").await.1, "one");
+
+    // 删除到回收站再恢复，历史版本一起恢复
+    assert_eq!(
+        client.status("DELETE", "/b.txt").await,
+        StatusCode::NO_CONTENT
+    );
+    assert_eq!(
+        client.get("/.versions/b.txt/1.txt").await.0,
+        StatusCode::NOT_FOUND
+    );
+    let (_, xml) = client.propfind("/.trash/").await;
+    let item = hrefs(&xml, "history")
+        .into_iter()
+        .find(|href| href.starts_with("/.trash/") && href.ends_with("b.txt"))
+        .unwrap();
+    assert_eq!(
+        client.transfer("MOVE", &item, "/b.txt").await,
+        StatusCode::CREATED
+    );
+    assert_eq!(client.get("/b.txt").await.1, "two");
+    assert_eq!(client.get("/.versions/b.txt/1.txt").await.1, "one");
+}
+
+#[tokio::test]
+async fn delete_without_trash_drops_history() {
+    let client = Client::new("versions").await;
+    assert_eq!(client.put("/gone.txt", "one").await, StatusCode::CREATED);
+    assert!(client.put("/gone.txt", "two").await.is_success());
+    assert_eq!(client.get("/.versions/gone.txt/1.txt").await.1, "one");
+    assert_eq!(
+        client.status("DELETE", "/gone.txt").await,
+        StatusCode::NO_CONTENT
+    );
+    assert_eq!(
+        client.status("PROPFIND", "/.versions/gone.txt/").await,
+        StatusCode::NOT_FOUND
+    );
+    // 同名的新文件不会继承之前的历史
+    assert_eq!(client.put("/gone.txt", "new").await, StatusCode::CREATED);
+    assert!(client.put("/gone.txt", "newer").await.is_success());
+    assert_eq!(client.get("/.versions/gone.txt/1.txt").await.1, "new");
+}
+
+#[tokio::test]
+async fn drop_box_hides_other_uploads() {
+    let owner = Client::new("owner").await;
+    assert_eq!(
+        owner.put("/existing.txt", "secret").await,
+        StatusCode::CREATED
+    );
+
+    let drop = Client::new("drop").await;
+    let (status, headers, _) = drop
+        .send(drop.request("PUT", "/mine.txt").body("upload"))
+        .await;
+    assert_eq!(status, StatusCode::CREATED);
+    let cookie = headers
+        .get("set-cookie")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .expect("drop box sets a session cookie")
+        .to_string();
+
+    // 带着 cookie 可以看到自己上传的文件
+    let (status, _, xml) = drop
+        .send(
+            drop.request("PROPFIND", "/")
+                .header("Depth", "1")
+                .header("Cookie", &cookie),
+        )
+        .await;
+    assert_eq!(status, StatusCode::MULTI_STATUS);
+    let listed = hrefs(&xml, "drop");
+    assert!(listed.contains(&"/mine.txt".to_string()));
+    assert!(!listed.contains(&"/existing.txt".to_string()));
+    let (status, _, body) = drop
+        .send(drop.request("GET", "/mine.txt").header("Cookie", &cookie))
+        .await;
+    assert_eq!((status, body.as_str()), (StatusCode::OK, "upload"));
+
+    // 其他人的文件读不到也改不了，新的会话看不到之前的上传
+    assert_eq!(drop.get("/existing.txt").await.0, StatusCode::NOT_FOUND);
+    assert_eq!(drop.put("/existing.txt", "x").await, StatusCode::FORBIDDEN);
+    assert_eq!(
+        drop.status("DELETE", "/existing.txt").await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(drop.get("/mine.txt").await.0, StatusCode::NOT_FOUND);
+    let (_, xml) = drop.propfind("/").await;
+    assert!(!hrefs(&xml, "drop").contains(&"/mine.txt".to_string()));
+
+    assert_eq!(
+        owner.get("/mine.txt").await,
+        (StatusCode::OK, "upload".to_string())
+    );
+    assert_eq!(owner.get("/existing.txt").await.1, "secret");
+}
+
+// 只有一个文件 dir/a.txt 的 zip
+#[tokio::test]
+async fn upload_share_hides_existing_files() {
+    let sharer = Client::new("sharer").await;
+    assert_eq!(sharer.status("MKCOL", "/inbox").await, StatusCode::CREATED);
+    assert_eq!(
+        sharer.put("/inbox/existing.txt", "secret").await,
+        StatusCode::CREATED
+    );
+    let (status, _, body) = sharer
+        .send(
+            sharer
+                .request("POST", "/inbox/?action=share")
+                .body(r#"{"mode": "upload"}"#),
+        )
+        .await;
+    assert_eq!(status, StatusCode::CREATED);
+    let share: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let base = format!("http://{}{}", sharer.addr, share["href"].as_str().unwrap());
+    let request = |method: &str, path: &str, cookie: &str| {
+        sharer
+            .client
+            .request(
+                Method::from_bytes(method.as_bytes()).unwrap(),
+                format!("{}{}", base, path),
+            )
+            .header("Cookie", cookie)
+    };
+
+    let (status, headers, _) = sharer
+        .send(request("PUT", "/mine.txt", "").body("upload"))
+        .await;
+    assert_eq!(status, StatusCode::CREATED);
+    let cookie = headers["set-cookie"]
+        .to_str()
+        .unwrap()
+        .split(';')
+        .next()
+        .unwrap()
+        .to_string();
+
+    // 对根目录 MKCOL 不能把整个分享变成自己新建的
+    assert_eq!(
+        sharer.send(request("MKCOL", "/", &cookie)).await.0,
+        StatusCode::METHOD_NOT_ALLOWED
+    );
+    assert_eq!(
+        sharer
+            .send(request("GET", "/existing.txt", &cookie))
+            .await
+            .0,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        sharer
+            .send(request("PUT", "/existing.txt", &cookie).body("x"))
+            .await
+            .0,
+        StatusCode::FORBIDDEN
+    );
+    let (status, _, xml) = sharer
+        .send(request("PROPFIND", "/", &cookie).header("Depth", "1"))
+        .await;
+    assert_eq!(status, StatusCode::MULTI_STATUS);
+    assert!(xml.contains("mine.txt"));
+    assert!(!xml.contains("existing.txt"));
+    assert_eq!(sharer.get("/inbox/existing.txt").await.1, "secret");
+}
+
+fn zip_file() -> Vec<u8> {
+    let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    writer
+        .start_file("dir/a.txt", zip::write::SimpleFileOptions::default())
+        .unwrap();
+    writer.write_all(b"inside").unwrap();
+    writer.finish().unwrap().into_inner()
+}
+
+#[tokio::test]
+async fn copy_archive_copies_the_file() {
+    let client = Client::new("archives").await;
+    let data = zip_file();
+    assert_eq!(client.status("MKCOL", "/in").await, StatusCode::CREATED);
+    assert_eq!(
+        client.put("/in/x.zip", data.clone()).await,
+        StatusCode::CREATED
+    );
+    assert_eq!(
+        client.get("/in/x.zip/dir/a.txt").await,
+        (StatusCode::OK, "inside".to_string())
+    );
+
+    // 复制压缩包和包含压缩包的目录都复制文件本身
+    assert_eq!(
+        client.transfer("COPY", "/in/x.zip", "/y.zip").await,
+        StatusCode::CREATED
+    );
+    assert_eq!(
+        client.transfer("COPY", "/in", "/out").await,
+        StatusCode::CREATED
+    );
+    for path in ["/y.zip?raw", "/out/x.zip?raw"] {
+        let response = client.request("GET", path).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.bytes().await.unwrap().as_ref(), data.as_slice());
+    }
+    assert_eq!(client.get("/y.zip/dir/a.txt").await.1, "inside");
+
+    // 压缩包内的目录复制出来是普通目录
+    assert_eq!(
+        client.transfer("COPY", "/in/x.zip/dir", "/dir").await,
+        StatusCode::CREATED
+    );
+    assert_eq!(client.get("/dir/a.txt").await.1, "inside");
+}